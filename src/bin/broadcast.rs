@@ -1,164 +1,805 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::io::{BufRead, Write};
-use std::sync::mpsc;
-use std::sync::mpsc::{RecvTimeoutError, Sender};
-use std::thread;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
-use serde::de::DeserializeOwned;
 
-use goofy_goobers::message::Envelope;
+use goofy_goobers::clock::{Clock, SystemClock};
+use goofy_goobers::gossip::{missing_from, range_digest, Gossip, GossipConfig, PeerLiveness, SyncAction, SyncPolicy, SyncSchedule, Topology};
+use goofy_goobers::{impl_error_message, impl_init_message, impl_type_tag};
+use goofy_goobers::log;
+use goofy_goobers::message;
+use goofy_goobers::message::{Envelope, MessageIdGenerator, NodeId};
+use goofy_goobers::runtime;
+use goofy_goobers::runtime::{Cluster, DeadLetters, InputHandler, InputHandlerHandle, OutputHandler};
+use goofy_goobers::validate;
+use goofy_goobers::validate::Step;
 
 
-const SYNC_INTERVAL: Duration = Duration::from_millis(250);
-
-// Problem 3d
-// const FANOUT: usize = 2;
-// Problem 3e
-const FANOUT: usize = 4;
-
-struct NodeHandler {
-    unacked_messages: Vec<u64>,
-}
-
-impl NodeHandler {
-    fn new() -> NodeHandler {
-        NodeHandler {
-            unacked_messages: Default::default(),
-        }
-    }
-
-    fn send_message(&mut self, message: u64) {
-        self.unacked_messages.push(message);
-    }
-
-    pub fn sync_ok(&mut self, messages: &Vec<u64>) {
-        self.unacked_messages.retain(|m| !messages.contains(m));
-        eprintln!("acked {:?}, left {:?}", messages, self.unacked_messages);
-    }
-}
-
-#[derive(Deserialize, Serialize, Debug)]
+// Broadcasts and reads can name a topic with key, and each topic has its own set of messages; those
+// that don't are for the default topic, and replies only carry a key if the request did. Messages
+// between nodes carry the key of the topic they're about in the same way.
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
 enum Message {
     Init { node_id: String, node_ids: Vec<String> },
     InitOk,
     Broadcast {
         message: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
     },
     BroadcastOk,
-    Read,
-    ReadOk { messages: Vec<u64> },
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    ReadOk {
+        messages: Vec<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
     Topology {
-        topology: HashMap<String, Vec<String>>
+        topology: Topology
     },
     TopologyOk,
-    Sync { messages: Vec<u64> },
-    SyncOk { messages: Vec<u64> }
+    Sync {
+        messages: Vec<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    SyncOk {
+        messages: Vec<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    // Anti-entropy: a node sends its whole message set as a range_digest (the default topic's in
+    // ranges, and every other topic's in topics), and the other node replies with whatever isn't
+    // in it, one full_sync per topic
+    StateDigest {
+        ranges: Vec<(u64, u64)>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        topics: HashMap<String, Vec<(u64, u64)>>,
+    },
+    FullSync {
+        messages: Vec<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    // Liveness checks for neighbours we haven't heard from in a while
+    Ping,
+    Pong,
+    Error { code: u64, text: String },
 }
 
-fn dispatch_message(message: &Envelope<Message>) {
-    let mut stdout = std::io::stdout().lock();
-    serde_json::to_writer(&mut stdout, message).unwrap();
-    stdout.write(b"\n").unwrap();
-    stdout.flush().unwrap();
+impl_init_message!(Message);
+impl_error_message!(Message);
+impl_type_tag!(Message { Init, InitOk, Broadcast, BroadcastOk, Read, ReadOk, Topology, TopologyOk, Sync, SyncOk, StateDigest, FullSync, Ping, Pong, Error });
+
+// The topic broadcasts and reads without a key go to. A key of "" is the same topic.
+const DEFAULT_TOPIC: &str = "";
+
+// Set GG_BROADCAST_STATE=path to keep every message we've seen in a file, one per line, so a node
+// that's killed and restarted picks up where it left off. Without it messages are only kept in
+// memory.
+const STATE_ENV_VAR: &str = "GG_BROADCAST_STATE";
+// Set GG_BROADCAST_WINDOW=n to stop remembering messages more than n below the highest one seen in
+// the same topic, for long runs of workloads whose messages count upwards, so the message sets
+// don't grow forever. Reads then only return the messages still remembered. Without it every
+// message is kept.
+//
+// A message is only forgotten once every neighbour has acked it, so forgetting it can't stop it
+// reaching them. Anything at or below the highest forgotten message is treated as already seen,
+// so one that's gossiped back to us isn't added and forwarded all over again. That's only safe if
+// no message turns up for the first time more than n below the highest one seen; one that does is
+// dropped.
+const WINDOW_ENV_VAR: &str = "GG_BROADCAST_WINDOW";
+// Set GG_SYNC_ACK_DELAY_MS=n to ack syncs every n ms instead of as each one arrives: the messages
+// from every sync a node has sent us since the last ack go back in one sync_ok, answering the
+// latest of them. A node syncs with each neighbour once per GG_SYNC_INTERVAL_MS, so n has to be
+// longer than that for there to be anything to merge; meanwhile the neighbour resends whatever
+// hasn't been acked yet, which costs a few extra syncs but far fewer than the acks saved. The round
+// trip times used to rank neighbours include the delay.
+const SYNC_ACK_DELAY_ENV_VAR: &str = "GG_SYNC_ACK_DELAY_MS";
+// Set GG_BACKLOG_INTERVAL_MS=n to log every n ms how many messages each neighbour has yet to ack,
+// across all topics, and which neighbours are presumed dead. A neighbour whose backlog keeps
+// growing is the one holding up convergence.
+const BACKLOG_INTERVAL_ENV_VAR: &str = "GG_BACKLOG_INTERVAL_MS";
+
+struct MessageStore {
+    file: File,
 }
 
-fn read_stdin<B: Debug + DeserializeOwned>(incoming_messages: Sender<Envelope<B>>) {
-    for line in std::io::stdin().lock().lines().map(Result::unwrap) {
-        let env = serde_json::from_str(&line).unwrap();
-        incoming_messages.send(env).unwrap();
+impl MessageStore {
+    // Opens the state file, if one is configured, returning the messages already in it by topic.
+    // Each line is a message, after its key and a space if it has one.
+    fn open() -> (Option<MessageStore>, HashMap<String, HashSet<u64>>) {
+        let Ok(path) = std::env::var(STATE_ENV_VAR) else { return (None, HashMap::new()) };
+        let file = OpenOptions::new().create(true).read(true).append(true).open(&path)
+            .unwrap_or_else(|e| panic!("can't open {path}: {e}"));
+
+        let mut topics: HashMap<String, HashSet<u64>> = HashMap::new();
+        for line in BufReader::new(&file).lines().map(Result::unwrap) {
+            let (name, message) = line.rsplit_once(' ').unwrap_or((DEFAULT_TOPIC, &line));
+            // A crash mid-write can leave a partial last line
+            match message.parse() {
+                Ok(message) => { topics.entry(name.to_string()).or_default().insert(message); }
+                Err(_) => log::debug!("ignoring bad line in {path}: {line:?}"),
+            }
+        }
+        (Some(MessageStore { file }), topics)
+    }
+
+    // Returns once the message is on disk
+    fn append(&mut self, name: &str, message: u64) {
+        if name == DEFAULT_TOPIC {
+            writeln!(self.file, "{message}").unwrap();
+        } else {
+            writeln!(self.file, "{name} {message}").unwrap();
+        }
+        self.file.sync_data().unwrap();
     }
 }
 
-fn main() {
-    let mut my_node_id = Default::default();
-    let mut node_topology: HashMap<String, Vec<String>> = Default::default();
+fn topic_name(key: &Option<String>) -> &str {
+    key.as_deref().unwrap_or(DEFAULT_TOPIC)
+}
 
-    let mut messages = HashSet::new();
+fn topic_key(name: &str) -> Option<String> {
+    (name != DEFAULT_TOPIC).then(|| name.to_string())
+}
 
-    let mut node_handlers: HashMap<String, NodeHandler> = HashMap::new();
+// The messages broadcast under one key, and the gossip that spreads them. Each topic is gossiped
+// separately, so its messages only ever reach other nodes, and get acked, as that topic's.
+struct Topic {
+    messages: HashSet<u64>,
+    // The same messages in ascending order, so read_ok is a copy rather than a walk over the set,
+    // and comes out the same way every time
+    sorted_messages: Vec<u64>,
+    gossip: Gossip<u64>,
+    // The highest message forgotten so far (see WINDOW_ENV_VAR)
+    forgotten_up_to: Option<u64>,
+}
 
-    let (incoming_sender, incoming_receiver) = mpsc::channel();
-    thread::spawn(move || read_stdin(incoming_sender));
+impl Topic {
+    fn new(gossip: Gossip<u64>, messages: HashSet<u64>) -> Topic {
+        let mut topic = Topic { messages: HashSet::new(), sorted_messages: Vec::new(), gossip, forgotten_up_to: None };
+        // Our neighbours may have missed some of these while we were down
+        for message in messages {
+            topic.add(message, None);
+        }
+        topic
+    }
 
-    let mut deadline = Instant::now() + SYNC_INTERVAL;
+    // Records a message, and forwards it if it's new. Returns whether it was.
+    fn add(&mut self, message: u64, source: Option<&NodeId>) -> bool {
+        if self.forgotten_up_to.is_some_and(|forgotten| message <= forgotten) || !self.messages.insert(message) {
+            return false
+        }
+        let position = self.sorted_messages.partition_point(|m| *m < message);
+        self.sorted_messages.insert(position, message);
+        self.gossip.forward_except(message, source.map(NodeId::as_str));
+        true
+    }
 
-    loop {
-        match incoming_receiver.recv_timeout(deadline - Instant::now()) {
-            Ok(env) => {
-                // if env.is_from_node() {
-                //     node_handlers.get_mut(&env.src).unwrap().handle_incoming_message(&env);
-                // }
-
-                match env.message() {
-                    Message::Init { node_id, node_ids } => {
-                        my_node_id = node_id.clone();
-                        for (idx, node_id) in node_ids.iter().enumerate() {
-                            node_handlers.insert(node_id.clone(), NodeHandler::new());
-                            node_topology.insert(node_id.clone(), node_ids.iter().skip((idx + 1) % FANOUT).step_by(FANOUT).cloned().collect());
-                        }
-                        eprintln!("generated topology: {:?}", node_topology);
+    // A range_digest of our messages. Forgotten messages count as ones we have, so nobody sends
+    // them back to us.
+    fn digest(&self) -> Vec<(u64, u64)> {
+        let Some(forgotten) = self.forgotten_up_to else { return range_digest(&self.messages) };
+        let mut ranges = vec![(0, forgotten)];
+        for (first, last) in range_digest(self.messages.iter().filter(|message| **message > forgotten)) {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == first => *end = last,
+                _ => ranges.push((first, last)),
+            }
+        }
+        ranges
+    }
 
-                        dispatch_message(&env.reply(Message::InitOk));
-                    }
+    // Forgets messages that have fallen out of the window and that every neighbour has acked
+    fn forget_old_messages(&mut self, name: &str, window: u64) {
+        let Some(highest) = self.sorted_messages.last() else { return };
+        let Some(cutoff) = highest.checked_sub(window) else { return };
+        let undelivered = self.gossip.undelivered();
+        let old: Vec<u64> = self.sorted_messages.iter()
+            .take_while(|message| **message < cutoff)
+            .filter(|message| !undelivered.contains(message))
+            .copied()
+            .collect();
+        let Some(newest_old) = old.last() else { return };
 
-                    Message::Topology { .. } => {
-                        // node_topology = topology.clone();
-                        dispatch_message(&env.reply(Message::TopologyOk));
-                    }
+        self.forgotten_up_to = Some(self.forgotten_up_to.map_or(*newest_old, |forgotten| forgotten.max(*newest_old)));
+        for message in &old {
+            self.messages.remove(message);
+        }
+        self.sorted_messages.retain(|message| self.messages.contains(message));
+        log::debug!("forgot {} messages from {name:?}, up to {newest_old}; {} left", old.len(), self.messages.len());
+    }
+}
 
-                    Message::Broadcast { message } => {
-                        if messages.insert(*message) {
-                            for neighbour in node_topology.get(&my_node_id).unwrap() {
-                                node_handlers.get_mut(neighbour).unwrap().send_message(*message);
-                            }
-                        }
+// The msg_id of the latest sync from a node that we haven't acked yet, and the messages from all
+// the syncs it's sent since the last ack, by topic
+type PendingAcks = (u64, HashMap<String, Vec<u64>>);
 
-                        dispatch_message(&env.reply(Message::BroadcastOk));
-                    }
+// All of a node's broadcast state. Each method handles one event and returns the envelopes to send,
+// without doing any I/O itself (apart from the optional MessageStore), so several nodes can be run
+// in one process with whatever delivery schedule a test wants (see the tests below), each with its
+// own MessageIdGenerator.
+struct BroadcastNode<'a> {
+    node_id: NodeId,
+    ids: &'a MessageIdGenerator,
+    node_ids: Vec<String>,
+    // Every other node's id, made once so that addressing a sync or digest to one doesn't allocate
+    peer_ids: HashMap<String, NodeId>,
+    neighbours: Vec<String>,
+    // Every topic we've heard of, by name. DEFAULT_TOPIC is always there.
+    topics: HashMap<String, Topic>,
+    peers: SyncPolicy,
+    store: Option<MessageStore>,
+    // Digests go to one neighbour at a time, round robin
+    next_digest_neighbour: usize,
+    // See WINDOW_ENV_VAR
+    window: Option<u64>,
+    // See SYNC_ACK_DELAY_ENV_VAR
+    ack_delay: Option<Duration>,
+    // Each node whose syncs we haven't acked yet
+    pending_acks: HashMap<NodeId, PendingAcks>,
+    // The msg_id and send time of the latest sync to each node, to time the round trip when it's
+    // acked. Acks for earlier syncs aren't timed.
+    syncs_in_flight: HashMap<NodeId, (u64, Instant)>,
+    clock: Arc<dyn Clock>,
+}
 
-                    Message::BroadcastOk => {}
+impl<'a> BroadcastNode<'a> {
+    fn new(cluster: &Cluster, ids: &'a MessageIdGenerator, clock: Arc<dyn Clock>, neighbours: Vec<String>, peer_timeout: Duration, messages: HashMap<String, HashSet<u64>>, store: Option<MessageStore>) -> BroadcastNode<'a> {
+        let peers = SyncPolicy::new(PeerLiveness::with_clock(cluster.others(), peer_timeout, clock.clone()));
+        let mut node = BroadcastNode {
+            node_id: NodeId::from(&cluster.local),
+            ids,
+            node_ids: cluster.all.clone(),
+            peer_ids: cluster.others().iter().map(|node| (node.clone(), NodeId::from(node))).collect(),
+            neighbours,
+            topics: HashMap::new(),
+            peers,
+            store,
+            next_digest_neighbour: 0,
+            window: None,
+            ack_delay: None,
+            pending_acks: HashMap::new(),
+            syncs_in_flight: HashMap::new(),
+            clock,
+        };
+        node.topic(DEFAULT_TOPIC);
+        for (name, messages) in messages {
+            log::debug!("restored {} messages to {name:?}", messages.len());
+            let gossip = node.new_gossip();
+            node.topics.insert(name, Topic::new(gossip, messages));
+        }
+        node
+    }
 
-                    Message::Sync { messages: incoming_messages } => {
-                        for message in incoming_messages {
-                            if messages.insert(*message) {
-                                for neighbour in node_topology.get(&my_node_id).unwrap() {
-                                    node_handlers.get_mut(neighbour).unwrap().send_message(*message);
-                                }
-                            }
-                        }
-                        dispatch_message(&env.reply(Message::SyncOk { messages: incoming_messages.clone() }));
-                    }
+    // Gossip for a new topic. It starts out with the round trip times the default topic has
+    // measured, so it ranks our neighbours the same way.
+    fn new_gossip(&self) -> Gossip<u64> {
+        let mut gossip = Gossip::new(&self.node_ids, self.neighbours.clone());
+        if let Some(default) = self.topics.get(DEFAULT_TOPIC) {
+            for neighbour in &self.neighbours {
+                if let Some(rtt) = default.gossip.rtt(neighbour) {
+                    gossip.record_rtt(neighbour, rtt);
+                }
+            }
+        }
+        gossip
+    }
+
+    // The topic with this name, which is started the first time anything mentions it
+    fn topic(&mut self, name: &str) -> &mut Topic {
+        if !self.topics.contains_key(name) {
+            log::debug!("new topic {name:?}");
+            let topic = Topic::new(self.new_gossip(), HashSet::new());
+            self.topics.insert(name.to_string(), topic);
+        }
+        self.topics.get_mut(name).unwrap()
+    }
+
+    // Topic names in order, so a run is the same each time
+    fn topic_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.topics.keys().cloned().collect();
+        names.sort_unstable();
+        names
+    }
+
+    // Records a message, and forwards it if it's new. `source` is the node it came from, if it
+    // wasn't a client, so it isn't sent straight back there.
+    fn add(&mut self, name: &str, message: u64, source: Option<&NodeId>) {
+        if self.topic(name).add(message, source) {
+            if let Some(store) = self.store.as_mut() { store.append(name, message) }
+        }
+    }
+
+    fn step(&mut self, env: &Envelope<Message>) -> Vec<Envelope<Message>> {
+        if env.is_from_node() {
+            self.peers.heard_from(&env.src);
+        }
+
+        match env.message() {
+            // We gossip over the topology from GossipConfig instead, but Maelstrom's is still checked
+            // so a bad one shows up in the logs
+            Message::Topology { topology } => {
+                if !topology.validate() {
+                    log::debug_envelope!(env, "topology from {} isn't connected", env.src);
+                }
+                env.try_reply_with_ids(self.ids, Message::TopologyOk).into_iter().collect()
+            }
 
-                    Message::SyncOk { messages: acked_messages } => {
-                        eprintln!("sync_ok from {}", env.src);
-                        node_handlers.get_mut(&env.src).unwrap().sync_ok(acked_messages);
+            Message::Broadcast { message, key } => {
+                self.add(topic_name(key), *message, None);
+                env.try_reply_with_ids(self.ids, Message::BroadcastOk).into_iter().collect()
+            }
+
+            Message::BroadcastOk => vec![],
+
+            Message::Sync { messages: incoming_messages, key } => {
+                let name = topic_name(key);
+                for message in incoming_messages {
+                    self.add(name, *message, Some(&env.src));
+                }
+                // Including ones we already had and were still due to send it
+                self.topic(name).gossip.already_has(&env.src, incoming_messages);
+                if self.ack_delay.is_none() {
+                    return env.try_reply_with_ids(self.ids, Message::SyncOk { messages: incoming_messages.clone(), key: key.clone() }).into_iter().collect()
+                }
+                let Some(msg_id) = env.msg_id() else { return vec![] };
+                let (latest, acks) = self.pending_acks.entry(env.src.clone()).or_default();
+                *latest = msg_id;
+                acks.entry(name.to_string()).or_default().extend(incoming_messages);
+                vec![]
+            }
+
+            Message::SyncOk { messages: acked_messages, key } => {
+                log::debug_envelope!(env, "sync_ok");
+                if let Some((msg_id, sent_at)) = self.syncs_in_flight.get(&env.src) {
+                    if env.in_reply_to() == Some(*msg_id) {
+                        let rtt = self.clock.now() - *sent_at;
+                        for topic in self.topics.values_mut() {
+                            topic.gossip.record_rtt(&env.src, rtt);
+                        }
+                        self.syncs_in_flight.remove(&env.src);
                     }
+                }
+                self.topic(topic_name(key)).gossip.sync_ok(&env.src, acked_messages);
+                vec![]
+            }
 
-                    Message::Read => {
-                        dispatch_message(&env.reply(Message::ReadOk { messages: messages.iter().copied().collect() }));
+            // A topic the digest doesn't mention is one the sender hasn't heard of, so it's missing
+            // all of it
+            Message::StateDigest { ranges, topics } => {
+                let mut outbound = vec![];
+                for name in self.topic_names() {
+                    let digest = if name == DEFAULT_TOPIC { ranges } else { topics.get(&name).map_or(&[][..], Vec::as_slice) };
+                    let missing = missing_from(digest, &self.topics[&name].messages);
+                    if missing.is_empty() {
+                        continue
                     }
+                    log::debug_envelope!(env, "{} is missing {} messages from {name:?}", env.src, missing.len());
+                    outbound.extend(env.try_reply_with_ids(self.ids, Message::FullSync { messages: missing, key: topic_key(&name) }));
+                }
+                outbound
+            }
 
-                    _ => unimplemented!()
+            Message::FullSync { messages: incoming_messages, key } => {
+                log::debug_envelope!(env, "full sync of {} messages", incoming_messages.len());
+                let name = topic_name(key);
+                for message in incoming_messages {
+                    self.add(name, *message, Some(&env.src));
                 }
+                self.topic(name).gossip.already_has(&env.src, incoming_messages);
+                vec![]
+            }
+
+            Message::Ping => env.try_reply_with_ids(self.ids, Message::Pong).into_iter().collect(),
+
+            Message::Pong => vec![],
+
+            Message::Read { key } => {
+                let messages = self.topics.get(topic_name(key)).map_or(vec![], |topic| topic.sorted_messages.clone());
+                env.try_reply_with_ids(self.ids, Message::ReadOk { messages, key: key.clone() }).into_iter().collect()
             }
 
-            Err(RecvTimeoutError::Timeout) => {}
-            Err(RecvTimeoutError::Disconnected) => {}
+            _ => {
+                let mut outbound = vec![];
+                DeadLetters::from_env().handle_with_ids(self.ids, env, |e| outbound.push(e));
+                outbound
+            }
         }
+    }
 
-        if Instant::now() >= deadline {
-            for (remote_node, handler) in node_handlers.iter() {
-                if !handler.unacked_messages.is_empty() {
-                    eprintln!("to {}: {:?}", remote_node, handler.unacked_messages);
-                    dispatch_message(&Envelope::new(my_node_id.clone(), remote_node.clone(), None,
-                                                           Message::Sync { messages: handler.unacked_messages.clone() }));
-                }
+    fn peer_id(&self, node: &str) -> NodeId {
+        self.peer_ids.get(node).cloned().unwrap_or_else(|| NodeId::from(node))
+    }
+
+    // Resends everything a neighbour hasn't acked yet, one sync per topic, or pings it if it's gone
+    // quiet. Dead neighbours are only contacted now and then (see SyncPolicy). New messages reach our
+    // slower neighbours a sync after our fastest ones (see Gossip::forward).
+    fn sync(&mut self, neighbour: &str) -> Vec<Envelope<Message>> {
+        let SyncAction::Sync { ping } = self.peers.on_sync(neighbour) else { return vec![] };
+        let dest = self.peer_id(neighbour);
+
+        let mut outbound = vec![];
+        for name in self.topic_names() {
+            let topic = self.topics.get_mut(&name).unwrap();
+            let unacked_messages = topic.gossip.pending_to(neighbour);
+            if !unacked_messages.is_empty() {
+                log::debug!("to {} in {name:?}: {:?}", neighbour, unacked_messages);
+                let sync = Envelope::new_with_ids(self.ids, self.node_id.clone(), dest.clone(), None,
+                                                  Message::Sync { messages: unacked_messages.to_vec(), key: topic_key(&name) });
+                self.syncs_in_flight.insert(sync.dest.clone(), (sync.msg_id().unwrap(), self.clock.now()));
+                outbound.push(sync);
             }
-            deadline = deadline + SYNC_INTERVAL;
+            topic.gossip.release_deferred_to(neighbour);
+        }
+
+        if ping {
+            outbound.push(Envelope::new_with_ids(self.ids, self.node_id.clone(), dest, None, Message::Ping));
+        }
+        outbound
+    }
+
+    // One sync_ok per topic to each node we owe acks to, covering every sync it's sent since the
+    // last one
+    fn flush_acks(&mut self) -> Vec<Envelope<Message>> {
+        let mut acks: Vec<(NodeId, PendingAcks)> = self.pending_acks.drain().collect();
+        // In node and topic order, so a run is the same each time
+        acks.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut outbound = vec![];
+        for (node, (latest, topics)) in acks {
+            let mut topics: Vec<(String, Vec<u64>)> = topics.into_iter().collect();
+            topics.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (name, mut messages) in topics {
+                messages.sort_unstable();
+                messages.dedup();
+                outbound.push(Envelope::new_with_ids(self.ids, self.node_id.clone(), node.clone(), Some(latest),
+                                                     Message::SyncOk { messages, key: topic_key(&name) }));
+            }
+        }
+        outbound
+    }
+
+    // Sends the next neighbour a digest of everything we have, so it can send back what we're missing
+    fn anti_entropy(&mut self) -> Vec<Envelope<Message>> {
+        if self.neighbours.is_empty() {
+            return vec![]
+        }
+        let neighbour = self.peer_id(&self.neighbours[self.next_digest_neighbour % self.neighbours.len()]);
+        self.next_digest_neighbour += 1;
+        let topics = self.topics.iter()
+            .filter(|(name, _)| *name != DEFAULT_TOPIC)
+            .map(|(name, topic)| (name.clone(), topic.digest()))
+            .collect();
+        vec![Envelope::new_with_ids(self.ids, self.node_id.clone(), neighbour, None,
+                                    Message::StateDigest { ranges: self.topics[DEFAULT_TOPIC].digest(), topics })]
+    }
+
+    // Logs each neighbour's backlog (see BACKLOG_INTERVAL_ENV_VAR)
+    fn log_backlog(&self) {
+        let backlog: Vec<String> = self.neighbours.iter()
+            .map(|neighbour| {
+                let pending: usize = self.topics.values().map(|topic| topic.gossip.pending_count(neighbour)).sum();
+                let dead = if self.peers.is_peer_alive(neighbour) { "" } else { " (dead)" };
+                format!("{neighbour} {pending}{dead}")
+            })
+            .collect();
+        log::debug!("backlog: {}", backlog.join(", "));
+    }
+
+    fn forget_old_messages(&mut self) {
+        let Some(window) = self.window else { return };
+        for (name, topic) in &mut self.topics {
+            topic.forget_old_messages(name, window);
+        }
+    }
+}
+
+// What GG_VALIDATE=1 plays to this binary (see goofy_goobers::validate)
+const VALIDATE_SCRIPT: &[Step] = &[
+    validate::INIT,
+    Step::client(r#"{"type": "topology", "topology": {"n1": []}}"#, "topology_ok"),
+    Step::client(r#"{"type": "broadcast", "message": 7}"#, "broadcast_ok"),
+    Step::client(r#"{"type": "broadcast", "message": 8, "key": "other"}"#, "broadcast_ok"),
+    Step::client(r#"{"type": "read"}"#, "read_ok"),
+    Step::client(r#"{"type": "read", "key": "other"}"#, "read_ok"),
+];
+
+fn main() {
+    validate::run_if_requested(VALIDATE_SCRIPT);
+    let (store, messages) = MessageStore::open();
+
+    let (output_sender, output_thread) = OutputHandler::start::<Message>();
+    runtime::exit_on_panic(&output_sender);
+    let (main_sender, main_receiver) = channel();
+    let _input_handler: InputHandlerHandle<Message> = InputHandler::start::<Message>(vec![main_sender]);
+
+    let Some(init) = runtime::await_init(&main_receiver, |e| output_sender.send(e).unwrap()) else { return };
+    let config = GossipConfig::from_env();
+    log::debug!("gossip config: {config:?}");
+    let cluster = Cluster::from(init);
+    let node_topology = config.build_topology(&cluster.all);
+    log::debug!("generated topology: {:?}", node_topology);
+    node_topology.validate();
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let neighbours = node_topology.neighbours(&cluster.local).to_vec();
+    let mut sync_schedule = SyncSchedule::new(&cluster.all, &cluster.local, &neighbours, config.sync_interval, clock.now());
+    let mut node = BroadcastNode::new(&cluster, message::default_ids(), clock.clone(), neighbours, config.peer_timeout, messages, store);
+    node.window = runtime::env_var(WINDOW_ENV_VAR, 1).map(|window| window as u64);
+    if let Some(window) = node.window {
+        log::debug!("remembering messages within {window} of the highest");
+    }
+    node.ack_delay = runtime::env_var(SYNC_ACK_DELAY_ENV_VAR, 1).map(|ms| Duration::from_millis(ms as u64));
+    if let Some(ack_delay) = node.ack_delay {
+        log::debug!("acking syncs every {ack_delay:?}");
+    }
+    let backlog_interval = runtime::env_var(BACKLOG_INTERVAL_ENV_VAR, 1).map(|ms| Duration::from_millis(ms as u64));
+
+    let mut anti_entropy_deadline = clock.now() + config.anti_entropy_interval;
+    let mut ack_deadline = node.ack_delay.map(|ack_delay| clock.now() + ack_delay);
+    let mut backlog_deadline = backlog_interval.map(|interval| clock.now() + interval);
+
+    loop {
+        let deadline = [sync_schedule.next_deadline(), ack_deadline, backlog_deadline].into_iter().flatten().fold(anti_entropy_deadline, Instant::min);
+        let mut outbound = match main_receiver.recv_timeout(clock.until(deadline)) {
+            Ok(env) if output_sender.resend_cached_reply(&env) => vec![],
+            Ok(env) if runtime::reply_to_repeated_init(&env, |e| output_sender.send(e).unwrap()) => vec![],
+            Ok(env) => node.step(&env),
+            Err(RecvTimeoutError::Timeout) => vec![],
+            // stdin was closed
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        for neighbour in sync_schedule.due(clock.now()) {
+            outbound.extend(node.sync(&neighbour));
+        }
+
+        if let (Some(deadline), Some(ack_delay)) = (ack_deadline.as_mut(), node.ack_delay) {
+            if clock.now() >= *deadline {
+                outbound.extend(node.flush_acks());
+                *deadline += ack_delay;
+            }
+        }
+
+        if let (Some(deadline), Some(interval)) = (backlog_deadline.as_mut(), backlog_interval) {
+            if clock.now() >= *deadline {
+                node.log_backlog();
+                *deadline += interval;
+            }
+        }
+
+        if clock.now() >= anti_entropy_deadline {
+            outbound.extend(node.anti_entropy());
+            node.forget_old_messages();
+            anti_entropy_deadline += config.anti_entropy_interval;
+        }
+
+        output_sender.send_all(outbound).unwrap();
+    }
+
+    // stdin was closed; wait for everything we've sent to be written out
+    OutputHandler::flush_and_join(output_sender, output_thread);
+}
+
+#[cfg(test)]
+mod tests {
+    use goofy_goobers::clock::ManualClock;
+    use goofy_goobers::runtime::Init;
+    use goofy_goobers::sim::{Rng, Scheduler, StateMachine};
+
+    use super::*;
+
+    const SIMULATED_NODES: usize = 5;
+    const SIMULATED_BROADCASTS: u64 = 200;
+    const SIMULATED_DROP_PERCENT: u64 = 15;
+    const SIMULATED_KEY: &str = "other";
+
+    // A tick is a round of syncs with every neighbour, plus an anti-entropy digest and, if acks are
+    // delayed, the acks that have built up
+    impl StateMachine<Message> for BroadcastNode<'_> {
+        fn step(&mut self, envelope: &Envelope<Message>) -> Vec<Envelope<Message>> {
+            BroadcastNode::step(self, envelope)
+        }
+
+        fn tick(&mut self) -> Vec<Envelope<Message>> {
+            let mut outbound = vec![];
+            for neighbour in self.neighbours.clone() {
+                outbound.extend(self.sync(&neighbour));
+            }
+            outbound.extend(self.anti_entropy());
+            if self.ack_delay.is_some() {
+                outbound.extend(self.flush_acks());
+            }
+            outbound
+        }
+    }
+
+    // Broadcasts SIMULATED_BROADCASTS messages, some of them under SIMULATED_KEY, to random nodes
+    // while the scheduler reorders and drops messages, then stops dropping and ticks until nothing
+    // is left in flight. Every node must end up with every message that got a broadcast_ok, and
+    // nothing else, in each topic.
+    fn simulate(seed: u64, ack_delay: Option<Duration>) {
+        let node_ids: Vec<String> = (1..=SIMULATED_NODES).map(|i| format!("n{i}")).collect();
+        let config = GossipConfig::default();
+        let topology = config.build_topology(&node_ids);
+        let ids: Vec<MessageIdGenerator> = node_ids.iter().map(|_| MessageIdGenerator::new()).collect();
+        // Time never moves, so every node stays alive and nobody is pinged
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let mut scheduler = Scheduler::new(seed);
+        for (node_id, ids) in node_ids.iter().zip(&ids) {
+            let cluster = Cluster::from(Init { node_id: node_id.clone(), node_ids: node_ids.clone() });
+            let neighbours = topology.neighbours(node_id).to_vec();
+            let mut node = BroadcastNode::new(&cluster, ids, clock.clone(), neighbours, config.peer_timeout, HashMap::new(), None);
+            node.ack_delay = ack_delay;
+            scheduler.add_node(node_id.clone(), node);
+        }
+        scheduler.set_drop_percent(SIMULATED_DROP_PERCENT);
+
+        let client_ids = MessageIdGenerator::new();
+        let mut rng = Rng::new(!seed);
+        // The topic and message of each broadcast, by msg_id
+        let mut broadcasts: HashMap<u64, (&str, u64)> = HashMap::new();
+        for message in 0..SIMULATED_BROADCASTS {
+            let name = if rng.below(4) == 0 { SIMULATED_KEY } else { DEFAULT_TOPIC };
+            let node = node_ids[rng.below(node_ids.len())].clone();
+            let broadcast = Envelope::new_with_ids(&client_ids, "c1", node, None, Message::Broadcast { message, key: topic_key(name) });
+            broadcasts.insert(broadcast.msg_id().unwrap(), (name, message));
+            scheduler.send(broadcast);
+            for _ in 0..rng.below(6) {
+                scheduler.step();
+            }
+            if message % 20 == 0 {
+                scheduler.tick();
+            }
+        }
+
+        scheduler.set_drop_percent(0);
+        for _ in 0..10 {
+            scheduler.tick();
+            assert!(scheduler.run(1_000_000), "seed {seed}: messages still in flight after 1000000 steps");
+        }
+
+        let mut expected: HashMap<&str, HashSet<u64>> = HashMap::from([(DEFAULT_TOPIC, HashSet::new()), (SIMULATED_KEY, HashSet::new())]);
+        for reply in scheduler.replies() {
+            assert!(matches!(reply.message(), Message::BroadcastOk), "seed {seed}: unexpected reply {reply:?}");
+            let (name, message) = broadcasts[&reply.in_reply_to().unwrap()];
+            expected.get_mut(name).unwrap().insert(message);
+        }
+        assert!(!expected[DEFAULT_TOPIC].is_empty() && !expected[SIMULATED_KEY].is_empty(), "seed {seed}: no broadcasts were acked");
+
+        for (node_id, node) in scheduler.nodes() {
+            for (name, messages) in &expected {
+                let topic = &node.topics[*name];
+                assert_eq!(&topic.messages, messages, "seed {seed}: {node_id} has the wrong messages in {name:?}");
+                assert!(topic.gossip.undelivered().is_empty(), "seed {seed}: {node_id} still has unacked messages in {name:?}");
+            }
+        }
+
+        let (delivered, dropped) = scheduler.counts();
+        log::debug!("simulation with seed {seed}: {delivered} messages delivered, {dropped} dropped; all {SIMULATED_NODES} nodes have all {} messages", expected.values().map(HashSet::len).sum::<usize>());
+    }
+
+    #[test]
+    fn simulated_nodes_converge() {
+        for seed in [1, 2, 3, 42, 1234, 99999] {
+            simulate(seed, None);
+        }
+    }
+
+    #[test]
+    fn simulated_nodes_converge_with_delayed_acks() {
+        for seed in [1, 2, 3, 42, 1234, 99999] {
+            simulate(seed, Some(Duration::from_millis(500)));
+        }
+    }
+
+    // n1, in a cluster of n1, n2 and n3
+    fn node_with_neighbours<'a>(ids: &'a MessageIdGenerator, neighbours: &[&str]) -> BroadcastNode<'a> {
+        let cluster = Cluster::from(Init { node_id: "n1".to_string(), node_ids: ["n1", "n2", "n3"].map(String::from).to_vec() });
+        let neighbours = neighbours.iter().map(|node| node.to_string()).collect();
+        BroadcastNode::new(&cluster, ids, Arc::new(ManualClock::new()), neighbours, GossipConfig::default().peer_timeout, HashMap::new(), None)
+    }
+
+    // read_ok lists the messages in ascending order, whatever order they arrived in
+    #[test]
+    fn reads_are_sorted() {
+        let (ids, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut node = node_with_neighbours(&ids, &[]);
+        let mut rng = Rng::new(5);
+        let mut expected: Vec<u64> = (0..1000).map(|_| rng.next_u64() % 5000).collect();
+        for message in &expected {
+            node.step(&Envelope::new_with_ids(&client_ids, "c1", "n1", None, Message::Broadcast { message: *message, key: None }));
+        }
+        expected.sort();
+        expected.dedup();
+
+        let reply = node.step(&Envelope::new_with_ids(&client_ids, "c1", "n1", None, Message::Read { key: None }));
+        assert!(matches!(reply.as_slice(), [e] if matches!(e.message(), Message::ReadOk { messages, .. } if *messages == expected)), "{reply:?}");
+    }
+
+    // Only messages that are out of the window and that every neighbour has acked are forgotten, and
+    // a forgotten message arriving again is treated as one we already have
+    #[test]
+    fn old_messages_are_forgotten_once_delivered() {
+        let node_ids = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut topic = Topic::new(Gossip::new(&node_ids, vec!["n2".to_string(), "n3".to_string()]), HashSet::new());
+        let all: Vec<u64> = (0..100).collect();
+        for message in &all {
+            topic.add(*message, None);
+        }
+        topic.gossip.release_deferred();
+        // n3 hasn't acked 20 yet
+        topic.gossip.sync_ok("n2", &all);
+        topic.gossip.sync_ok("n3", &all.iter().copied().filter(|message| *message != 20).collect::<Vec<_>>());
+
+        topic.forget_old_messages("", 50);
+        assert_eq!(topic.forgotten_up_to, Some(48));
+        assert_eq!(topic.sorted_messages, [20].into_iter().chain(49..100).collect::<Vec<_>>());
+        assert_eq!(topic.digest(), vec![(0, 99)]);
+        assert!(!topic.add(5, None));
+        assert!(topic.gossip.pending_to("n2").is_empty());
+        // Anything new is still taken in and passed on
+        assert!(topic.add(100, None));
+    }
+
+    // With acks delayed, every sync from a node between flushes is acked by one sync_ok, in reply to
+    // the latest of them
+    #[test]
+    fn delayed_acks_are_coalesced() {
+        let (ids, peer_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut node = node_with_neighbours(&ids, &["n2"]);
+        node.ack_delay = Some(Duration::from_millis(500));
+        let syncs: Vec<Envelope<Message>> = [vec![1, 2], vec![3], vec![2, 4]].into_iter()
+            .map(|messages| Envelope::new_with_ids(&peer_ids, "n2", "n1", None, Message::Sync { messages, key: None }))
+            .collect();
+        for sync in &syncs {
+            assert!(node.step(sync).is_empty());
+        }
+
+        let acks = node.flush_acks();
+        assert!(matches!(acks.as_slice(), [ack] if ack.is_reply_to(&syncs[2]) && matches!(ack.message(), Message::SyncOk { messages, key: None } if *messages == [1, 2, 3, 4])), "{acks:?}");
+        assert!(node.flush_acks().is_empty());
+    }
+
+    // Messages from a sync are passed on to our other neighbours, but not back to the sender
+    #[test]
+    fn synced_messages_are_not_echoed_back() {
+        let (ids, peer_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut node = node_with_neighbours(&ids, &["n2", "n3"]);
+        node.step(&Envelope::new_with_ids(&peer_ids, "n2", "n1", None, Message::Sync { messages: vec![7, 8], key: None }));
+        let gossip = &mut node.topic(DEFAULT_TOPIC).gossip;
+        gossip.release_deferred();
+        assert!(gossip.pending_to("n2").is_empty());
+        assert_eq!(gossip.pending_to("n3"), [7, 8]);
+    }
+
+    // Messages broadcast to one key are read and gossiped under that key only, and the keyless
+    // topic is kept apart from both
+    #[test]
+    fn keys_do_not_share_messages() {
+        let (ids, sender_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut node = node_with_neighbours(&ids, &["n2"]);
+        let key = |name: &str| Some(name.to_string());
+        for (message, topic) in [(1, key("a")), (2, key("b")), (3, None), (4, key("a"))] {
+            node.step(&Envelope::new_with_ids(&sender_ids, "c1", "n1", None, Message::Broadcast { message, key: topic }));
+        }
+        node.step(&Envelope::new_with_ids(&sender_ids, "n2", "n1", None, Message::Sync { messages: vec![5], key: key("b") }));
+
+        for (topic, expected) in [(key("a"), vec![1, 4]), (key("b"), vec![2, 5]), (None, vec![3])] {
+            let reply = node.step(&Envelope::new_with_ids(&sender_ids, "c1", "n1", None, Message::Read { key: topic.clone() }));
+            assert!(matches!(reply.as_slice(), [e] if matches!(e.message(), Message::ReadOk { messages, key } if *messages == expected && *key == topic)), "{topic:?}: {reply:?}");
         }
+        let gossip = &mut node.topic("a").gossip;
+        gossip.release_deferred();
+        assert_eq!(gossip.pending_to("n2"), [1, 4]);
     }
 }
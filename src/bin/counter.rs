@@ -1,173 +1,1151 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
-use std::io::{BufRead, Write};
 use std::sync::mpsc;
-use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::mpsc::RecvTimeoutError;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
-use serde::de::DeserializeOwned;
-use goofy_goobers::error::{Error, ErrorCode};
+use goofy_goobers::clock::{Clock, SystemClock};
+use goofy_goobers::error::{Error, ErrorCode, ErrorMessage};
+use goofy_goobers::impl_type_tag;
 
+use goofy_goobers::kv::{InitKey, KvMessage, Update, UpdateStep, LIN_KV, SEQ_KV};
+use goofy_goobers::log;
+use goofy_goobers::metrics;
 use goofy_goobers::message::Envelope;
+use goofy_goobers::runtime;
+use goofy_goobers::runtime::{Cluster, DeadLetters, Init, InitMessage, OutputHandler};
+use goofy_goobers::safe_int;
+use goofy_goobers::sim::Rng;
+use goofy_goobers::validate;
+use goofy_goobers::validate::Step;
 
-const SEQ_KV: &str = "seq-kv";
-const KV_KEY: &str = "total";
+// Set GG_COUNTER_STORE=lin-kv to keep the counter in the linearizable store instead of seq-kv.
+// seq-kv reads can be stale, so the cas strategy's CASes fail and get retried more often after
+// reading an old total; lin-kv always returns the latest value but each request costs more.
+// Neither changes correctness: a CAS against a stale total fails with precondition-failed either way.
+const STORE_ENV_VAR: &str = "GG_COUNTER_STORE";
+// The counter adds and reads without a key go to. In the cas strategy each counter is kept in the
+// kv store under its own name, so this one is still the "total" key.
+const DEFAULT_COUNTER: &str = "total";
+// Prefix of each node's own key in the g-counter strategy. Counters other than the default one
+// have their name after it: count:<counter>/<node>.
+const NODE_KEY_PREFIX: &str = "count:";
+// Set GG_COUNTER_SHARDS to split each node's count in the g-counter strategy across that many keys,
+// count:<node>:<shard>, with adds going to each shard in turn. The default of 1 keeps the single
+// count:<node> key.
+const SHARDS_ENV_VAR: &str = "GG_COUNTER_SHARDS";
+// How often the cas strategy asks the other nodes for their committed value when it's idle
+const PEER_POLL_INTERVAL: Duration = Duration::from_millis(1000);
+// How often pending quorum reads are checked while some are waiting
+const QUORUM_READ_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// How long the g-counter strategy waits for a write_ok before writing the shard again
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_millis(1000);
 
-#[derive(Deserialize, Serialize, Debug)]
+// Set GG_COUNTER_READ_QUORUM to make client reads in the cas strategy wait until that many other
+// nodes have sent us their committed value since the read arrived, and GG_COUNTER_READ_TIMEOUT_MS
+// for how long to wait before giving up and replying with the best value we have. The default
+// quorum of 0 replies straight away.
+const READ_QUORUM_ENV_VAR: &str = "GG_COUNTER_READ_QUORUM";
+const READ_TIMEOUT_ENV_VAR: &str = "GG_COUNTER_READ_TIMEOUT_MS";
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+// After a CAS in the cas strategy fails (usually because another node moved the total first), the
+// next one waits GG_COUNTER_CAS_BACKOFF_MS, doubling with each failure in a row up to
+// GG_COUNTER_CAS_BACKOFF_MAX_MS. Each wait is cut by a random amount of up to half, so nodes that
+// failed together don't all retry together. GG_COUNTER_CAS_BACKOFF_MS=0 retries straight away.
+const CAS_BACKOFF_ENV_VAR: &str = "GG_COUNTER_CAS_BACKOFF_MS";
+const CAS_BACKOFF_MAX_ENV_VAR: &str = "GG_COUNTER_CAS_BACKOFF_MAX_MS";
+const DEFAULT_CAS_BACKOFF: Duration = Duration::from_millis(10);
+const DEFAULT_CAS_BACKOFF_MAX: Duration = Duration::from_millis(1000);
+// Set GG_COUNTER_MAX_IN_FLIGHT to let the cas strategy have up to that many CASes on one counter
+// outstanding at once instead of waiting a round trip between them. Each starts from the total the
+// one before it would leave, so they all succeed unless another node writes in between or the
+// network delivers them out of order; after a failure, no more go out until every one in flight
+// has been answered. The default is 1.
+const MAX_IN_FLIGHT_ENV_VAR: &str = "GG_COUNTER_MAX_IN_FLIGHT";
+
+// Messages from clients and other counter nodes. Adds and reads can name a counter with key; those
+// that don't are for DEFAULT_COUNTER, and replies only carry a key if the request did. In the cas
+// strategy the other nodes send a peer_read to ask for our committed value of a counter.
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
-enum Message {
+enum NodeMessage {
     Init { node_id: String, node_ids: Vec<String> },
     InitOk,
     Topology { topology: HashMap<String, Vec<String>> },
     TopologyOk,
-    Add { delta: u64 },
+    Add {
+        #[serde(with = "safe_int")]
+        delta: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
     AddOk,
-    // read and read_ok are used by both the workload and the seq-kv store, but key is only used by seq-kv
     Read {
-        #[serde(skip_serializing_if = "Option::is_none")]
-        key: Option<String>
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
     },
-    ReadOk { value: u64 },
-    Write { key: String, value: u64 },
-    WriteOk,
-    Cas {
-        key: String,
-        from: u64,
-        to: u64,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        create_if_not_exists: Option<bool>,
+    ReadOk {
+        #[serde(with = "safe_int")]
+        value: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
     },
-    CasOk,
-
-    Error {
-        code: u64,
-        text: String
+    PeerRead {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    PeerReadOk {
+        #[serde(with = "safe_int")]
+        value: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
     },
 }
 
-fn dispatch_message(message: &Envelope<Message>) {
-    let mut stdout = std::io::stdout().lock();
-    serde_json::to_writer(&mut stdout, message).unwrap();
-    stdout.write(b"\n").unwrap();
-    stdout.flush().unwrap();
+impl_type_tag!(NodeMessage { Init, InitOk, Topology, TopologyOk, Add, AddOk, Read, ReadOk, PeerRead, PeerReadOk });
+
+// The kv store's read_ok looks just like the one we send clients, so it always parses as a
+// NodeMessage; sent_by_store moves it over to KvMessage. Nothing else sends us a read_ok. Everything
+// else the store sends back only parses as a KvMessage.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+enum Message {
+    Node(NodeMessage),
+    Kv(KvMessage),
+}
+
+impl_type_tag!(Message(Node, Kv));
+
+impl Message {
+    fn sent_by_store(self) -> Message {
+        match self {
+            Message::Node(NodeMessage::ReadOk { value, .. }) => Message::Kv(KvMessage::ReadOk { value }),
+            message => message,
+        }
+    }
+}
+
+impl From<NodeMessage> for Message {
+    fn from(value: NodeMessage) -> Self {
+        Message::Node(value)
+    }
+}
+
+impl From<KvMessage> for Message {
+    fn from(value: KvMessage) -> Self {
+        Message::Kv(value)
+    }
 }
 
-fn read_stdin<B: Debug + DeserializeOwned>(incoming_messages: Sender<Envelope<B>>) {
-    for line in std::io::stdin().lock().lines().map(Result::unwrap) {
-        let env = serde_json::from_str(&line).unwrap();
-        incoming_messages.send(env).unwrap();
+impl ErrorMessage for Message {
+    fn error(code: ErrorCode, text: String) -> Self {
+        KvMessage::error(code, text).into()
     }
 }
 
+impl InitMessage for Message {
+    fn as_init(&self) -> Option<Init> {
+        match self {
+            Message::Node(NodeMessage::Init { node_id, node_ids }) => Some(Init { node_id: node_id.clone(), node_ids: node_ids.clone() }),
+            _ => None,
+        }
+    }
+
+    fn init_ok() -> Self {
+        Message::Node(NodeMessage::InitOk)
+    }
+}
+
+// The counter an add or read is for
+fn counter_name(key: &Option<String>) -> &str {
+    key.as_deref().unwrap_or(DEFAULT_COUNTER)
+}
+
+// Exponential backoff with jitter between retries against one kv key
+struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+    retry_at: Instant,
+    rng: Rng,
+}
+
+impl Backoff {
+    fn new(base: Duration, max: Duration, clock: &dyn Clock, rng: Rng) -> Backoff {
+        Backoff { base, max, failures: 0, retry_at: clock.now(), rng }
+    }
+
+    fn failed(&mut self, clock: &dyn Clock) {
+        let delay = self.base.saturating_mul(1 << self.failures.min(31)).min(self.max);
+        let jitter = self.rng.next_u64() % (delay.as_nanos() as u64 / 2 + 1);
+        self.retry_at = clock.now() + delay - Duration::from_nanos(jitter);
+        self.failures += 1;
+    }
+
+    fn succeeded(&mut self, clock: &dyn Clock) {
+        self.failures = 0;
+        self.retry_at = clock.now();
+    }
+
+    fn is_ready(&self, clock: &dyn Clock) -> bool {
+        clock.now() >= self.retry_at
+    }
+}
+
+// A client read in the cas strategy that's waiting to hear from a quorum of other nodes
+struct QuorumRead {
+    request: Envelope<Message>,
+    counter: String,
+    received: Instant,
+}
+
+// What GG_VALIDATE=1 plays to this binary (see goofy_goobers::validate)
+const VALIDATE_SCRIPT: &[Step] = &[
+    validate::INIT,
+    Step::client(r#"{"type": "add", "delta": 3}"#, "add_ok"),
+    Step::client(r#"{"type": "add", "delta": 4, "key": "other"}"#, "add_ok"),
+    Step::client(r#"{"type": "read"}"#, "read_ok"),
+    Step::client(r#"{"type": "read", "key": "other"}"#, "read_ok"),
+];
+
 fn main() {
-    let mut my_node_id: String = Default::default();
-    let mut all_node_ids: Vec<String> = Default::default();
-    let mut to_add: u64 = 0;
-    let mut value: u64 = 0;
-    let mut last_cas_to: u64 = 0;
-    let mut last_cas_id: usize = 0;
-    let mut cas_outstanding: bool = false;
+    validate::run_if_requested(VALIDATE_SCRIPT);
+    let store = match std::env::var(STORE_ENV_VAR).as_deref() {
+        Ok(SEQ_KV) | Err(_) => SEQ_KV,
+        Ok(LIN_KV) => LIN_KV,
+        Ok(store) => panic!("unknown {STORE_ENV_VAR} {store}, expected {SEQ_KV} or {LIN_KV}"),
+    };
+
+    match std::env::var("GG_COUNTER_STRATEGY").as_deref() {
+        Ok("g-counter") => g_counter(store),
+        Ok("cas") | Err(_) => cas_counter(store),
+        Ok(strategy) => panic!("unknown GG_COUNTER_STRATEGY {strategy}, expected cas or g-counter"),
+    }
+    metrics::dump();
+}
+
+// One counter in the cas strategy.
+//
+// `value` is the last total we know to be committed to the kv store and `to_add` is the sum of the
+// deltas added on this node that haven't been committed yet. A client read returns both, so a
+// client always sees its own adds, and since neither part ever shrinks without the other growing
+// by the same amount (a successful CAS moves its delta from `to_add` into `value`), reads from a
+// node never go backwards unless the kv store itself hands us a stale total.
+struct CasCounter {
+    name: String,
+    to_add: u64,
+    value: u64,
+    // The updates waiting on the store, by the msg_id of the request each last sent, so in the order
+    // they were sent. Each covers a different part of to_add.
+    in_flight: BTreeMap<u64, InFlightCas>,
+    // Updates whose CAS lost to another writer, waiting out the backoff before they read the total
+    // again
+    retrying: Vec<InFlightCas>,
+    backoff: Backoff,
+    // When each other node last sent us its committed value
+    last_heard: HashMap<String, Instant>,
+    // Creating the counter's key, and the msg_id of the request waiting on the store, until the
+    // counter's initialized
+    init: Option<(u64, InitKey)>,
+    // Whether we know the counter's value in the kv store yet. A previous run may have left it at
+    // anything, so until the create CAS succeeds (it was 0) or the read after it fails comes back,
+    // client adds and reads wait in `waiting`.
+    initialized: bool,
+    waiting: Vec<Envelope<Message>>,
+    // Every CAS sent and every one that's failed, for the summary at shutdown
+    cas_attempts: u64,
+    cas_failures: u64,
+}
+
+// A kv update adding delta to the counter's key
+struct InFlightCas {
+    update: Update,
+    delta: u64,
+}
+
+impl CasCounter {
+    // Creates the counter's key in the kv store at 0 if it isn't there yet, or finds the value it
+    // has. send sends a request to the store and returns its msg_id.
+    fn start(name: &str, backoff: Backoff, send: &mut dyn FnMut(KvMessage) -> u64) -> CasCounter {
+        let init = InitKey::new(name, 0);
+        CasCounter {
+            name: name.to_string(),
+            to_add: 0,
+            value: 0,
+            in_flight: BTreeMap::new(),
+            retrying: Vec::new(),
+            backoff,
+            last_heard: HashMap::new(),
+            init: Some((send(init.request()), init)),
+            initialized: false,
+            waiting: Vec::new(),
+            cas_attempts: 0,
+            cas_failures: 0,
+        }
+    }
+
+    // Holds a client request until the counter's initialized, returning false if there's no need
+    fn wait_for_init(&mut self, env: &Envelope<Message>) -> bool {
+        if self.initialized {
+            return false
+        }
+        // A request Maelstrom redelivers while it waits only needs answering once
+        if !self.waiting.iter().any(|waiting| waiting.src == env.src && waiting.msg_id() == env.msg_id()) {
+            self.waiting.push(env.clone());
+        }
+        true
+    }
+
+    fn initialize(&mut self, replay: &mut Vec<Envelope<Message>>) {
+        if !self.initialized {
+            log::debug!("initialized {} at {}, {} requests waiting", self.name, self.value, self.waiting.len());
+            self.initialized = true;
+            replay.append(&mut self.waiting);
+        }
+    }
+
+    fn init_replied(&mut self, mut init: InitKey, reply: KvMessage, replay: &mut Vec<Envelope<Message>>, send: &mut dyn FnMut(KvMessage) -> u64) {
+        match init.reply(reply) {
+            Ok(Some(value)) => {
+                self.value = self.value.max(value);
+                self.initialize(replay);
+            }
+            Ok(None) => self.init = Some((send(init.request()), init)),
+            Err(e) => {
+                log::debug!("initializing {} failed ({e:?}), asking again", self.name);
+                self.init = Some((send(init.request()), init));
+            }
+        }
+    }
+
+    // What a client read returns: the committed total and every add still pending, so a client
+    // always sees its own adds
+    fn total(&self) -> u64 {
+        self.value + self.to_add
+    }
+
+    // The part of to_add that no update covers
+    fn unsent(&self) -> u64 {
+        self.to_add - self.in_flight.values().chain(&self.retrying).map(|cas| cas.delta).sum::<u64>()
+    }
+
+    fn outstanding(&self) -> usize {
+        self.in_flight.len() + self.retrying.len()
+    }
+
+    // Where the next CAS starts from: the total the last one in flight would leave
+    fn next_from(&self) -> u64 {
+        self.in_flight.values().next_back().and_then(|cas| cas.update.to()).unwrap_or(self.value)
+    }
+
+    // After a failure the CASes still in flight most likely started from the same stale total, so
+    // the next one waits until every update has been answered
+    fn cas_wanted(&self, max_in_flight: usize) -> bool {
+        self.initialized
+            && self.unsent() != 0
+            && self.outstanding() < max_in_flight
+            && (self.backoff.failures == 0 || self.outstanding() == 0)
+    }
+
+    fn send_update(&mut self, cas: InFlightCas, send: &mut dyn FnMut(KvMessage) -> u64) {
+        let request = cas.update.request();
+        if matches!(request, KvMessage::Cas { .. }) {
+            self.cas_attempts += 1;
+        }
+        log::debug!("{request:?} covering {} ({} in flight)", cas.delta, self.in_flight.len() + 1);
+        self.in_flight.insert(send(request), cas);
+    }
+
+    // Sends the updates that have waited out the backoff, and then one covering every add no other
+    // update covers, however many there were, if one's wanted. Returns how long until the backoff
+    // lets the next of those go, if one's waiting on it.
+    fn send_due(&mut self, max_in_flight: usize, clock: &dyn Clock, send: &mut dyn FnMut(KvMessage) -> u64) -> Option<Duration> {
+        if !self.backoff.is_ready(clock) {
+            return (!self.retrying.is_empty() || self.cas_wanted(max_in_flight)).then(|| clock.until(self.backoff.retry_at))
+        }
+        for cas in std::mem::take(&mut self.retrying) {
+            self.send_update(cas, send);
+        }
+        if self.cas_wanted(max_in_flight) {
+            let delta = self.unsent();
+            let update = Update::new(&self.name, Some(self.next_from()), |total| total + delta);
+            self.send_update(InFlightCas { update, delta }, send);
+        }
+        None
+    }
 
+    // Hands a reply from the store on to whatever of ours it answers, returning false if it answers
+    // nothing. Adds that arrived while an update was outstanding stay pending, and since updates can
+    // be answered out of order, the total only ever moves forward.
+    fn store_replied(&mut self, in_reply_to: u64, reply: KvMessage, clock: &dyn Clock, replay: &mut Vec<Envelope<Message>>,
+                     send: &mut dyn FnMut(KvMessage) -> u64) -> bool {
+        if let Some((_, init)) = self.init.take_if(|(init_id, _)| *init_id == in_reply_to) {
+            self.init_replied(init, reply, replay, send);
+            return true
+        }
+        let Some(mut cas) = self.in_flight.remove(&in_reply_to) else { return false };
+        let delta = cas.delta;
+        match cas.update.reply(reply, |total| total + delta) {
+            Ok(UpdateStep::Send) => {
+                self.value = self.value.max(cas.update.from().unwrap_or(0));
+                self.send_update(cas, send);
+            }
+            // The CAS definitely didn't happen, most likely because the total it started from was
+            // out of date. Hold off on reading it again, and on the next CAS.
+            Ok(UpdateStep::Retry) => {
+                self.cas_failures += 1;
+                self.backoff.failed(clock);
+                self.retrying.push(cas);
+            }
+            Ok(UpdateStep::Done(total)) => {
+                log::debug!("{} at {total}, covering {delta}, after {} failures", self.name, self.backoff.failures);
+                self.to_add -= delta;
+                self.value = self.value.max(total);
+                self.backoff.succeeded(clock);
+            }
+            // The delta is unsent again, and goes in the next CAS
+            Err(e) => {
+                log::debug!("giving up on an update of {} covering {delta}: {e:?}", self.name);
+                self.cas_failures += 1;
+                self.backoff.failed(clock);
+            }
+        }
+        true
+    }
+}
+
+// The counter called name, started if this is the first time anything's mentioned it
+fn get_or_start<'a>(counters: &'a mut HashMap<String, CasCounter>, name: &str, backoff: impl FnOnce() -> Backoff,
+                    send: &mut dyn FnMut(KvMessage) -> u64) -> &'a mut CasCounter {
+    counters.entry(name.to_string()).or_insert_with(|| {
+        log::debug!("starting counter {name}");
+        CasCounter::start(name, backoff(), send)
+    })
+}
+
+// All nodes add to a single shared key per counter with a CAS loop
+fn cas_counter(store: &str) {
+    let (output_sender, output_thread) = OutputHandler::start::<Message>();
+    runtime::exit_on_panic(&output_sender);
     let (incoming_sender, incoming_receiver) = mpsc::channel();
-    thread::spawn(move || read_stdin(incoming_sender));
+    thread::spawn(move || runtime::read_stdin(incoming_sender));
+
+    let Some(init) = runtime::await_init(&incoming_receiver, |e| output_sender.send(e).unwrap()) else { return };
+    let cluster = Cluster::from(init);
+    let my_node_id = cluster.local.clone();
+    log::debug!("counting in {store}");
+
+    let read_quorum = runtime::env_var(READ_QUORUM_ENV_VAR, 0).unwrap_or(0).min(cluster.peer_count());
+    let read_timeout = runtime::env_var(READ_TIMEOUT_ENV_VAR, 0).map_or(DEFAULT_READ_TIMEOUT, |ms| Duration::from_millis(ms as u64));
+    log::debug!("read quorum {read_quorum} of {}, timeout {read_timeout:?}", cluster.peer_count());
+    let mut quorum_reads: Vec<QuorumRead> = Vec::new();
+    let mut last_peer_poll = Instant::now();
+
+    let (backoff_base, backoff_max) = (runtime::env_var(CAS_BACKOFF_ENV_VAR, 0).map_or(DEFAULT_CAS_BACKOFF, |ms| Duration::from_millis(ms as u64)),
+                                       runtime::env_var(CAS_BACKOFF_MAX_ENV_VAR, 0).map_or(DEFAULT_CAS_BACKOFF_MAX, |ms| Duration::from_millis(ms as u64)));
+    log::debug!("cas backoff {backoff_base:?}, up to {backoff_max:?}");
+    let clock = SystemClock;
+    let max_in_flight = runtime::env_var(MAX_IN_FLIGHT_ENV_VAR, 1).unwrap_or(1);
+    // Requests that waited for their counter to be initialized, to be handled before anything new
+    let mut replay: Vec<Envelope<Message>> = Vec::new();
+    // Only the jitter comes from this, so it doesn't need to be reproducible
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64 ^ cluster.index_of(&my_node_id).unwrap_or(0) as u64;
+    let backoff = || Backoff::new(backoff_base, backoff_max, &clock, Rng::new(seed));
+    let mut send_to_store = |request: KvMessage| {
+        let e = Envelope::new(my_node_id.clone(), store.to_string(), None, request.into());
+        let msg_id = e.msg_id().unwrap();
+        output_sender.send(e).unwrap();
+        msg_id
+    };
+
+    // Other counters are started the first time anything mentions them
+    let mut counters: HashMap<String, CasCounter> = HashMap::new();
+    get_or_start(&mut counters, DEFAULT_COUNTER, backoff, &mut send_to_store);
 
     loop {
-        match incoming_receiver.recv_timeout(Duration::from_millis(1000)) {
+        // Like the CASes below, this runs at the top of the loop rather than when nothing's arrived
+        // for a while, so a steady stream of messages can't hold it off
+        if last_peer_poll.elapsed() >= PEER_POLL_INTERVAL {
+            last_peer_poll = Instant::now();
+            for (name, _) in counters.iter().filter(|(_, counter)| counter.to_add == 0) {
+                let key = (name != DEFAULT_COUNTER).then(|| name.clone());
+                for e in Envelope::fanout(my_node_id.clone(), cluster.others(), NodeMessage::PeerRead { key }.into()) {
+                    log::debug_envelope!(&e, "peer read {name}");
+                    output_sender.send(e).unwrap();
+                }
+            }
+        }
+
+        // This runs at the top of the loop so that no way through it (a redelivered add, a timeout)
+        // can leave deltas waiting for some other message to turn up
+        let mut timeout = (last_peer_poll + PEER_POLL_INTERVAL).saturating_duration_since(Instant::now());
+        if !quorum_reads.is_empty() {
+            timeout = timeout.min(QUORUM_READ_POLL_INTERVAL);
+        }
+        for counter in counters.values_mut() {
+            if let Some(wait) = counter.send_due(max_in_flight, &clock, &mut send_to_store) {
+                timeout = timeout.min(wait);
+            }
+        }
+
+        let received = if replay.is_empty() { incoming_receiver.recv_timeout(timeout) } else { Ok(replay.remove(0)) };
+        match received {
             Ok(env) => {
+                let env = env.map_message(Message::sent_by_store);
+                // Don't count a redelivered add twice
+                if output_sender.resend_cached_reply(&env) { continue }
+                if runtime::reply_to_repeated_init(&env, |e| output_sender.send(e).unwrap()) { continue }
+
                 match env.message() {
-                    Message::Init { node_id, node_ids } => {
-                        my_node_id = node_id.clone();
-                        all_node_ids = node_ids.clone();
-                        dispatch_message(&env.reply(Message::InitOk));
-
-                        // Initialize the counter in the kv store
-                        let e = Envelope::new(my_node_id.clone(), SEQ_KV.to_string(), None,
-                                                   Message::Cas { key: KV_KEY.to_string(), from: 0, to: 0, create_if_not_exists: Some(true) });
-                        dispatch_message(&e);
-                        cas_outstanding = true;
-                        last_cas_id = e.msg_id().unwrap();
+                    Message::Node(NodeMessage::Topology { .. }) => {
+                        if let Some(reply) = env.try_reply(NodeMessage::TopologyOk.into()) {
+                            output_sender.send(reply).unwrap();
+                        }
                     }
 
-                    Message::Topology { .. } => {
-                        dispatch_message(&env.reply(Message::TopologyOk));
+                    Message::Node(NodeMessage::Add { delta, key }) => {
+                        let counter = get_or_start(&mut counters, counter_name(key), backoff, &mut send_to_store);
+                        if counter.wait_for_init(&env) { continue }
+                        counter.to_add += *delta;
+                        log::debug!("delta {} to {}; to-add {}", delta, counter_name(key), counter.to_add);
+                        if let Some(reply) = env.try_reply(NodeMessage::AddOk.into()) {
+                            output_sender.send(reply).unwrap();
+                        }
                     }
 
-                    Message::Add { delta } => {
-                        to_add += *delta;
-                        eprintln!("delta {}; to-add {}", delta, to_add);
-                        dispatch_message(&env.reply(Message::AddOk));
+                    Message::Node(NodeMessage::Read { key }) => {
+                        let name = counter_name(key);
+                        let counter = get_or_start(&mut counters, name, backoff, &mut send_to_store);
+                        if counter.wait_for_init(&env) { continue }
+                        if read_quorum == 0 {
+                            if let Some(reply) = env.try_reply(NodeMessage::ReadOk { value: counter.total(), key: key.clone() }.into()) {
+                                output_sender.send(reply).unwrap();
+                            }
+                        } else {
+                            for e in Envelope::fanout(my_node_id.clone(), cluster.others(), NodeMessage::PeerRead { key: key.clone() }.into()) {
+                                output_sender.send(e).unwrap();
+                            }
+                            quorum_reads.push(QuorumRead { request: env.clone(), counter: name.to_string(), received: Instant::now() });
+                        }
                     }
 
-                    Message::Read { .. } => {
-                        dispatch_message(&env.reply(Message::ReadOk { value }));
+                    // Other nodes only want the committed value - they'll merge it into their own,
+                    // and our pending deltas will reach them via the kv store
+                    Message::Node(NodeMessage::PeerRead { key }) => {
+                        let value = get_or_start(&mut counters, counter_name(key), backoff, &mut send_to_store).value;
+                        if let Some(reply) = env.try_reply(NodeMessage::PeerReadOk { value, key: key.clone() }.into()) {
+                            output_sender.send(reply).unwrap();
+                        }
                     }
 
-                    Message::ReadOk { value: new_value } => {
-                        if env.is_from_node() {
-                            eprintln!("read (from {}) ok: {}", env.src, new_value);
-                            if *new_value > value { value = *new_value }
-                        } else {
-                            eprintln!("read ok: {}", new_value);
-                            value = *new_value
+                    Message::Node(NodeMessage::PeerReadOk { value: new_value, key }) => {
+                        log::debug_envelope!(&env, "peer read ok: {} {}", counter_name(key), new_value);
+                        let counter = get_or_start(&mut counters, counter_name(key), backoff, &mut send_to_store);
+                        counter.last_heard.insert(env.src.to_string(), Instant::now());
+                        if *new_value > counter.value { counter.value = *new_value }
+                    }
+
+                    Message::Kv(reply @ (KvMessage::ReadOk { .. } | KvMessage::CasOk | KvMessage::Error { .. })) => {
+                        let answered = env.in_reply_to().is_some_and(|id| {
+                            counters.values_mut().any(|counter| counter.store_replied(id, reply.clone(), &clock, &mut replay, &mut send_to_store))
+                        });
+                        if !answered {
+                            log::debug_envelope!(&env, "ignoring a late reply from the store");
                         }
                     }
 
-                    Message::CasOk => {
-                        if env.in_reply_to().unwrap() == last_cas_id {
-                            eprintln!("cas ok: {env:?} ({value} + {to_add})");
-                            to_add = 0;
-                            value = last_cas_to;
-                            last_cas_id = 0;
-                            cas_outstanding = false;
-                        } else {
-                            eprintln!("unexpected cas ok: {env:?} ({value} + {to_add})");
+                    _ => DeadLetters::from_env().handle(&env, |e| output_sender.send(e).unwrap()),
+                }
+            }
+
+            Err(RecvTimeoutError::Timeout) => {}
+            // stdin was closed
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        // Answer reads once enough nodes have reported in since they arrived, or once they've
+        // waited long enough, with the best value we know of
+        quorum_reads.retain(|read| {
+            let counter = &counters[&read.counter];
+            let fresh = counter.last_heard.values().filter(|heard| **heard >= read.received).count();
+            if fresh < read_quorum && read.received.elapsed() < read_timeout {
+                return true
+            }
+            if fresh < read_quorum {
+                log::debug_envelope!(&read.request, "read timed out with {fresh} of {read_quorum} nodes");
+            }
+            let Message::Node(NodeMessage::Read { key }) = read.request.message() else { unreachable!() };
+            if let Some(reply) = read.request.try_reply(NodeMessage::ReadOk { value: counter.total(), key: key.clone() }.into()) {
+                output_sender.send(reply).unwrap();
+            }
+            false
+        });
+    }
+
+    let cas_attempts: u64 = counters.values().map(|counter| counter.cas_attempts).sum();
+    let cas_failures: u64 = counters.values().map(|counter| counter.cas_failures).sum();
+    log::debug!("{cas_attempts} cas attempts, {cas_failures} failed");
+
+    // stdin was closed; wait for everything we've sent to be written out
+    OutputHandler::flush_and_join(output_sender, output_thread);
+}
+
+struct PendingRead {
+    request: Envelope<Message>,
+    remaining: usize,
+    total: u64,
+}
+
+fn node_key(counter: &str, node: &str, shard: usize, shards: usize) -> String {
+    let counter = if counter == DEFAULT_COUNTER { String::new() } else { format!("{counter}/") };
+    if shards == 1 {
+        format!("{NODE_KEY_PREFIX}{counter}{node}")
+    } else {
+        format!("{NODE_KEY_PREFIX}{counter}{node}:{shard}")
+    }
+}
+
+// This node's part of one counter in the g-counter strategy
+struct GCounter {
+    next_shard: usize,
+    // Our own count in each shard, including anything not yet written to the kv store
+    local_totals: Vec<u64>,
+    written_totals: Vec<u64>,
+    // The msg_id and value of the write in flight for each shard, and when it was sent
+    outstanding_writes: Vec<Option<(u64, u64, Instant)>>,
+}
+
+impl GCounter {
+    fn new(shards: usize) -> GCounter {
+        GCounter { next_shard: 0, local_totals: vec![0; shards], written_totals: vec![0; shards], outstanding_writes: vec![None; shards] }
+    }
+
+    // The shards whose total should be written now: those that have changed since their last
+    // write_ok, unless a write of them was sent less than WRITE_RETRY_INTERVAL ago. Lowers timeout
+    // to when the next of those is due to be sent again.
+    fn shards_to_write(&self, name: &str, now: Instant, timeout: &mut Duration) -> Vec<usize> {
+        let mut shards = Vec::new();
+        for shard in 0..self.local_totals.len() {
+            if let Some((_, _, sent_at)) = self.outstanding_writes[shard] {
+                let waited = now.saturating_duration_since(sent_at);
+                if waited < WRITE_RETRY_INTERVAL {
+                    *timeout = (*timeout).min(WRITE_RETRY_INTERVAL - waited);
+                    continue
+                }
+                log::debug!("no write_ok for {name} shard {shard} after {waited:?}, writing it again");
+            }
+            if self.local_totals[shard] != self.written_totals[shard] {
+                shards.push(shard);
+            }
+        }
+        shards
+    }
+}
+
+// Grow-only counter: each node only ever writes its own keys, so there's no contention between
+// nodes, and reads sum every node's keys
+fn g_counter(store: &str) {
+    let shards = runtime::env_var(SHARDS_ENV_VAR, 1).unwrap_or(1);
+    let mut counters: HashMap<String, GCounter> = HashMap::new();
+
+    // Client reads waiting on kv reads of the other nodes' keys, by an id of our own: clients each
+    // number their msg_ids from 1, so two clients' reads can have the same one
+    let mut pending_reads: HashMap<u64, PendingRead> = HashMap::new();
+    let mut next_read_id: u64 = 0;
+    // kv read msg_id -> id of the client read it's for, and the key read
+    let mut kv_reads: HashMap<u64, (u64, String)> = HashMap::new();
+
+    let (output_sender, output_thread) = OutputHandler::start::<Message>();
+    runtime::exit_on_panic(&output_sender);
+    let (incoming_sender, incoming_receiver) = mpsc::channel();
+    thread::spawn(move || runtime::read_stdin(incoming_sender));
+
+    let Some(init) = runtime::await_init(&incoming_receiver, |e| output_sender.send(e).unwrap()) else { return };
+    let cluster = Cluster::from(init);
+    let my_node_id = cluster.local.clone();
+    log::debug!("counting in {store}, {shards} shards per node");
+
+    loop {
+        // Writes go out at the top of the loop, so that no way through it can leave a total
+        // unwritten. One that's gone WRITE_RETRY_INTERVAL without a write_ok may have been lost and
+        // is sent again, however busy we are.
+        let mut timeout = WRITE_RETRY_INTERVAL;
+        for (name, counter) in counters.iter_mut() {
+            for shard in counter.shards_to_write(name, Instant::now(), &mut timeout) {
+                let e = Envelope::new(my_node_id.clone(), store.to_string(), None,
+                                      KvMessage::Write { key: node_key(name, &my_node_id, shard, shards), value: counter.local_totals[shard] }.into());
+                counter.outstanding_writes[shard] = Some((e.msg_id().unwrap(), counter.local_totals[shard], Instant::now()));
+                output_sender.send(e).unwrap();
+            }
+        }
+
+        match incoming_receiver.recv_timeout(timeout) {
+            Ok(env) => {
+                let env = env.map_message(Message::sent_by_store);
+                // Don't count a redelivered add twice
+                if output_sender.resend_cached_reply(&env) { continue }
+                if runtime::reply_to_repeated_init(&env, |e| output_sender.send(e).unwrap()) { continue }
+
+                match env.message() {
+                    Message::Node(NodeMessage::Topology { .. }) => {
+                        if let Some(reply) = env.try_reply(NodeMessage::TopologyOk.into()) {
+                            output_sender.send(reply).unwrap();
                         }
                     }
 
-                    Message::Error { code, text } => {
-                        let e = Error { code: ErrorCode::from(*code), text: text.clone() };
-                        eprintln!("error: {e:?}");
-                        if e.code == ErrorCode::PreconditionFailed {
-                            // Our last CAS failed because the "from" value was out of date
-                            cas_outstanding = false;
-                            let e = Envelope::new(my_node_id.clone(), SEQ_KV.to_string(), None,
-                                                         Message::Read { key: Some(KV_KEY.to_string()) });
-                            eprintln!("read: {e:?}");
-                            dispatch_message(&e);
+                    Message::Node(NodeMessage::Add { delta, key }) => {
+                        let counter = counters.entry(counter_name(key).to_string()).or_insert_with(|| GCounter::new(shards));
+                        counter.local_totals[counter.next_shard] += *delta;
+                        counter.next_shard = (counter.next_shard + 1) % shards;
+                        if let Some(reply) = env.try_reply(NodeMessage::AddOk.into()) {
+                            output_sender.send(reply).unwrap();
+                        }
+                    }
+
+                    Message::Node(NodeMessage::Read { key }) => {
+                        let name = counter_name(key);
+                        let read_id = next_read_id;
+                        next_read_id += 1;
+                        let total = counters.get(name).map_or(0, |counter| counter.local_totals.iter().sum());
+                        let mut pending = PendingRead { request: env.clone(), remaining: 0, total };
+                        for node in cluster.others() {
+                            for shard in 0..shards {
+                                let key = node_key(name, node, shard, shards);
+                                let e = Envelope::new(my_node_id.clone(), store.to_string(), None, KvMessage::Read { key: key.clone() }.into());
+                                kv_reads.insert(e.msg_id().unwrap(), (read_id, key));
+                                output_sender.send(e).unwrap();
+                                pending.remaining += 1;
+                            }
+                        }
+
+                        if pending.remaining == 0 {
+                            if let Some(reply) = env.try_reply(NodeMessage::ReadOk { value: pending.total, key: key.clone() }.into()) {
+                                output_sender.send(reply).unwrap();
+                            }
                         } else {
-                            panic!("Unexpected error {e:?}");
+                            pending_reads.insert(read_id, pending);
                         }
                     }
 
-                    _ => unimplemented!()
+                    Message::Kv(KvMessage::ReadOk { .. } | KvMessage::Error { .. }) if env.in_reply_to().is_some_and(|id| kv_reads.contains_key(&id)) => {
+                        let (read_id, read_key) = kv_reads.remove(&env.in_reply_to().unwrap()).unwrap();
+                        let value = match env.message() {
+                            Message::Kv(KvMessage::ReadOk { value }) => *value,
+                            Message::Kv(KvMessage::Error { code, text }) => {
+                                let e = Error::from_reply(*code, text.clone());
+                                if e.code != ErrorCode::KeyDoesNotExist {
+                                    // A read changes nothing, so whatever went wrong it's safe to ask again
+                                    log::debug_envelope!(&env, "read of {read_key} failed ({e:?}), reading it again");
+                                    let e = Envelope::new(my_node_id.clone(), store.to_string(), None, KvMessage::Read { key: read_key.clone() }.into());
+                                    kv_reads.insert(e.msg_id().unwrap(), (read_id, read_key));
+                                    output_sender.send(e).unwrap();
+                                    continue
+                                }
+                                0
+                            }
+                            _ => unreachable!(),
+                        };
+
+                        if let Some(pending) = pending_reads.get_mut(&read_id) {
+                            pending.total += value;
+                            pending.remaining -= 1;
+                            if pending.remaining == 0 {
+                                let pending = pending_reads.remove(&read_id).unwrap();
+                                let Message::Node(NodeMessage::Read { key }) = pending.request.message() else { unreachable!() };
+                                if let Some(reply) = pending.request.try_reply(NodeMessage::ReadOk { value: pending.total, key: key.clone() }.into()) {
+                                    output_sender.send(reply).unwrap();
+                                }
+                            }
+                        }
+                    }
+
+                    Message::Kv(KvMessage::WriteOk) => {
+                        for counter in counters.values_mut() {
+                            for (shard, outstanding_write) in counter.outstanding_writes.iter_mut().enumerate() {
+                                if let Some((msg_id, value, _)) = *outstanding_write {
+                                    if env.in_reply_to() == Some(msg_id) {
+                                        counter.written_totals[shard] = value;
+                                        *outstanding_write = None;
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    _ => DeadLetters::from_env().handle(&env, |e| output_sender.send(e).unwrap()),
                 }
             }
 
-            Err(RecvTimeoutError::Timeout) => {
-                if to_add == 0 {
-                    for node in &all_node_ids {
-                        if node != &my_node_id {
-                            let e = Envelope::new(my_node_id.clone(), node.to_string(), None,
-                                                         Message::Read { key: None });
-                            eprintln!("node read: {e:?}");
-                            dispatch_message(&e);
-                        }
+            Err(RecvTimeoutError::Timeout) => {}
+            // stdin was closed
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    // stdin was closed; wait for everything we've sent to be written out
+    OutputHandler::flush_and_join(output_sender, output_thread);
+}
+
+#[cfg(test)]
+mod tests {
+    use goofy_goobers::clock::ManualClock;
+    use goofy_goobers::kv::MemoryKv;
+
+    use super::*;
+
+    const CONTENDERS: usize = 5;
+    const ADDS_PER_CONTENDER: u64 = 50;
+    const TICK: Duration = Duration::from_millis(1);
+
+    // A CasCounter that's already found its key in the store at 0
+    fn initialized_counter(name: &str, clock: &dyn Clock) -> CasCounter {
+        CasCounter {
+            init: None,
+            initialized: true,
+            ..CasCounter::start(name, Backoff::new(DEFAULT_CAS_BACKOFF, DEFAULT_CAS_BACKOFF_MAX, clock, Rng::new(1)), &mut |_| 0)
+        }
+    }
+
+    // Stands in for the kv store: answers each request as it's sent, numbering them from 1, and
+    // holds the replies until the test hands them to a counter
+    #[derive(Default)]
+    struct Store {
+        kv: MemoryKv,
+        sent: u64,
+        replies: Vec<(u64, KvMessage)>,
+    }
+
+    impl Store {
+        fn send(&mut self) -> impl FnMut(KvMessage) -> u64 + '_ {
+            |request| {
+                self.sent += 1;
+                self.replies.push((self.sent, self.kv.handle(&request)));
+                self.sent
+            }
+        }
+
+        fn read(&mut self, key: &str) -> u64 {
+            let KvMessage::ReadOk { value } = self.kv.handle(&KvMessage::Read { key: key.to_string() }) else { panic!("no {key}") };
+            value
+        }
+
+        // Hands every reply to counter, and the replies to whatever it sends in turn, until it's
+        // waiting on nothing
+        fn settle(&mut self, counter: &mut CasCounter, clock: &dyn Clock) {
+            while !self.replies.is_empty() {
+                let (msg_id, reply) = self.replies.remove(0);
+                assert!(counter.store_replied(msg_id, reply, clock, &mut vec![], &mut self.send()));
+            }
+        }
+    }
+
+    fn envelope(src: &str, body: &str) -> Envelope<Message> {
+        serde_json::from_str(&format!(r#"{{"src": "{src}", "dest": "n1", "body": {body}}}"#)).unwrap()
+    }
+
+    // Adds and reads without a key are for the default counter, and replies only name a counter if
+    // the request did. Each counter has its own kv keys.
+    #[test]
+    fn counters_are_kept_apart_by_key() {
+        let Message::Node(NodeMessage::Add { key: default_key, .. }) = envelope("c1", r#"{"type": "add", "delta": 1}"#).message().clone() else { panic!("not an add") };
+        let Message::Node(NodeMessage::Add { key: other_key, .. }) = envelope("c1", r#"{"type": "add", "delta": 1, "key": "other"}"#).message().clone() else { panic!("not an add") };
+        assert_eq!((counter_name(&default_key), counter_name(&other_key)), (DEFAULT_COUNTER, "other"));
+
+        assert_eq!(serde_json::to_value(NodeMessage::ReadOk { value: 3, key: None }).unwrap(), serde_json::json!({"type": "read_ok", "value": 3}));
+        assert_eq!(serde_json::to_value(NodeMessage::ReadOk { value: 4, key: other_key }).unwrap(), serde_json::json!({"type": "read_ok", "value": 4, "key": "other"}));
+
+        assert_eq!(node_key(DEFAULT_COUNTER, "n1", 0, 1), "count:n1");
+        assert_eq!(node_key("other", "n1", 0, 1), "count:other/n1");
+        assert_eq!(node_key("other", "n1", 2, 3), "count:other/n1:2");
+
+        // In the cas strategy each counter CASes its own key
+        let clock = ManualClock::new();
+        let mut store = Store::default();
+        let mut counters = [(DEFAULT_COUNTER, 3), ("other", 4)].map(|(name, delta)| {
+            let mut counter = initialized_counter(name, &clock);
+            counter.to_add = delta;
+            counter
+        });
+        for counter in &mut counters {
+            assert_eq!(counter.send_due(1, &clock, &mut store.send()), None);
+            store.settle(counter, &clock);
+        }
+        assert_eq!((store.read(DEFAULT_COUNTER), store.read("other")), (3, 4));
+        assert_eq!(counters.map(|counter| (counter.value, counter.to_add)), [(3, 0), (4, 0)]);
+    }
+
+    // With a peer read and a kv read both outstanding, each reply is told apart by its type: a
+    // peer's is a peer_read_ok, and the store's read_ok is moved over to a KvMessage
+    #[test]
+    fn peer_and_store_replies_are_told_apart() {
+        let replies = [
+            envelope("n2", r#"{"type": "peer_read_ok", "msg_id": 4, "in_reply_to": 1, "value": 7, "key": "other"}"#),
+            envelope(SEQ_KV, r#"{"type": "read_ok", "msg_id": 5, "in_reply_to": 2, "value": 9}"#),
+            envelope("n3", r#"{"type": "peer_read_ok", "msg_id": 6, "in_reply_to": 3, "value": 8}"#),
+            envelope(SEQ_KV, r#"{"type": "error", "in_reply_to": 2, "code": 22, "text": "expected 1, but had 2"}"#),
+        ];
+        let replies: Vec<Message> = replies.into_iter().map(|env| env.map_message(Message::sent_by_store).message().clone()).collect();
+        assert!(matches!(&replies[0], Message::Node(NodeMessage::PeerReadOk { value: 7, key: Some(key) }) if key == "other"));
+        assert!(matches!(replies[1], Message::Kv(KvMessage::ReadOk { value: 9 })));
+        assert!(matches!(replies[2], Message::Node(NodeMessage::PeerReadOk { value: 8, key: None })));
+        assert!(matches!(replies[3], Message::Kv(KvMessage::Error { code: 22, .. })));
+    }
+
+    // Client reads and peer reads arriving together parse as different requests, keyed or not, so
+    // the read_oks we get can only be the store's
+    #[test]
+    fn client_and_peer_reads_are_told_apart() {
+        let requests = [
+            envelope("c1", r#"{"type": "read", "msg_id": 1}"#),
+            envelope("n2", r#"{"type": "peer_read", "msg_id": 1}"#),
+            envelope("n3", r#"{"type": "peer_read", "msg_id": 2, "key": "other"}"#),
+            envelope("c2", r#"{"type": "read", "msg_id": 2, "key": "other"}"#),
+        ];
+        assert!(matches!(requests[0].message(), Message::Node(NodeMessage::Read { key: None })));
+        assert!(matches!(requests[1].message(), Message::Node(NodeMessage::PeerRead { key: None })));
+        assert!(matches!(requests[2].message(), Message::Node(NodeMessage::PeerRead { key: Some(key) }) if key == "other"));
+        assert!(matches!(requests[3].message(), Message::Node(NodeMessage::Read { key: Some(key) }) if key == "other"));
+
+        assert_eq!(serde_json::to_value(NodeMessage::PeerRead { key: None }).unwrap(), serde_json::json!({"type": "peer_read"}));
+        assert_eq!(serde_json::to_value(NodeMessage::PeerReadOk { value: 5, key: Some("other".to_string()) }).unwrap(),
+                   serde_json::json!({"type": "peer_read_ok", "value": 5, "key": "other"}));
+        let read_ok = envelope(SEQ_KV, r#"{"type": "read_ok", "in_reply_to": 1, "value": 5}"#).map_message(Message::sent_by_store);
+        assert!(matches!(read_ok.message(), Message::Kv(KvMessage::ReadOk { value: 5 })));
+    }
+
+    // An add arriving every millisecond never leaves the g-counter loop idle, but a write that's
+    // gone unacknowledged is still sent again once WRITE_RETRY_INTERVAL has passed, and one that's
+    // merely in flight is left alone
+    #[test]
+    fn unacked_writes_are_retried_under_a_flood() {
+        let start = Instant::now();
+        let mut counter = GCounter::new(2);
+        counter.local_totals = vec![1, 0];
+        let mut timeout = WRITE_RETRY_INTERVAL;
+        assert_eq!(counter.shards_to_write(DEFAULT_COUNTER, start, &mut timeout), vec![0]);
+        counter.outstanding_writes[0] = Some((1, 1, start));
+
+        let mut retried_at = None;
+        for ms in 1..=2 * WRITE_RETRY_INTERVAL.as_millis() as u64 {
+            let now = start + Duration::from_millis(ms);
+            counter.local_totals[0] += 1;
+            timeout = WRITE_RETRY_INTERVAL;
+            let due = counter.shards_to_write(DEFAULT_COUNTER, now, &mut timeout);
+            if due.is_empty() {
+                assert_eq!(timeout, WRITE_RETRY_INTERVAL - Duration::from_millis(ms));
+            } else {
+                assert_eq!(due, vec![0]);
+                retried_at = Some(now - start);
+                break
+            }
+        }
+        assert_eq!(retried_at, Some(WRITE_RETRY_INTERVAL));
+    }
+
+    // CONTENDERS nodes each add 1 to the same key every tick for ADDS_PER_CONTENDER ticks, each
+    // CASing everything it has pending from the total it last saw. The CASes sent in one tick reach
+    // the store in a random order, and a node whose CAS fails reads the total it lost to. Returns
+    // how many CASes failed.
+    fn contend(backoff_base: Duration) -> u64 {
+        let clock = ManualClock::new();
+        let mut rng = Rng::new(7);
+        let mut store = MemoryKv::new();
+        store.handle(&KvMessage::Write { key: DEFAULT_COUNTER.to_string(), value: 0 });
+        // (total last seen, to add, backoff) for each node
+        let mut nodes: Vec<(u64, u64, Backoff)> = (0..CONTENDERS)
+            .map(|i| (0, 0, Backoff::new(backoff_base, DEFAULT_CAS_BACKOFF_MAX, &clock, Rng::new(i as u64))))
+            .collect();
+        let mut failures = 0;
+        for tick in 0.. {
+            if tick < ADDS_PER_CONTENDER {
+                nodes.iter_mut().for_each(|(_, to_add, _)| *to_add += 1);
+            } else if nodes.iter().all(|(_, to_add, _)| *to_add == 0) {
+                break
+            }
+            let mut ready: Vec<usize> = (0..CONTENDERS).filter(|i| nodes[*i].1 != 0 && nodes[*i].2.is_ready(&clock)).collect();
+            while !ready.is_empty() {
+                let (value, to_add, backoff) = &mut nodes[ready.swap_remove(rng.below(ready.len()))];
+                let cas = KvMessage::Cas { key: DEFAULT_COUNTER.to_string(), from: *value, to: *value + *to_add, create_if_not_exists: None };
+                match store.handle(&cas) {
+                    KvMessage::CasOk => {
+                        *value += *to_add;
+                        *to_add = 0;
+                        backoff.succeeded(&clock);
+                    }
+                    _ => {
+                        failures += 1;
+                        backoff.failed(&clock);
+                        let KvMessage::ReadOk { value: total } = store.handle(&KvMessage::Read { key: DEFAULT_COUNTER.to_string() }) else { panic!("no total") };
+                        *value = total;
                     }
                 }
             }
-            Err(RecvTimeoutError::Disconnected) => {}
+            clock.advance(TICK);
         }
+        let KvMessage::ReadOk { value: total } = store.handle(&KvMessage::Read { key: DEFAULT_COUNTER.to_string() }) else { panic!("no total") };
+        assert_eq!(total, CONTENDERS as u64 * ADDS_PER_CONTENDER);
+        failures
+    }
+
+    #[test]
+    fn backing_off_cuts_failed_cases_under_contention() {
+        let immediate = contend(Duration::ZERO);
+        let backed_off = contend(DEFAULT_CAS_BACKOFF);
+        assert!(backed_off * 2 < immediate, "{backed_off} CASes failed with backoff, {immediate} without");
+    }
+
+    // Adds arrive one per tick, and the store answers each CAS ROUND_TRIP ticks after it's sent.
+    // Each CAS should cover every add since the last one, so there's one per round trip rather than
+    // one per add.
+    #[test]
+    fn a_burst_of_adds_takes_one_cas_per_round_trip() {
+        const ADDS: u64 = 100;
+        const ROUND_TRIP: u64 = 5;
+        let clock = ManualClock::new();
+        let mut store = Store::default();
+        let mut counter = initialized_counter(DEFAULT_COUNTER, &clock);
+        // (tick it arrives, msg_id it answers, reply)
+        let mut in_transit: Vec<(u64, u64, KvMessage)> = Vec::new();
+        for tick in 0.. {
+            if tick >= ADDS && counter.to_add == 0 {
+                break
+            }
+            counter.send_due(1, &clock, &mut store.send());
+            for (_, msg_id, reply) in in_transit.extract_if(.., |(arrives, _, _)| *arrives == tick) {
+                assert!(matches!(reply, KvMessage::CasOk), "{reply:?}");
+                assert!(counter.store_replied(msg_id, reply, &clock, &mut vec![], &mut store.send()));
+            }
+            in_transit.extend(store.replies.drain(..).map(|(msg_id, reply)| (tick + ROUND_TRIP, msg_id, reply)));
+            if tick < ADDS {
+                counter.to_add += 1;
+            }
+            clock.advance(TICK);
+        }
+        assert_eq!((store.read(DEFAULT_COUNTER), counter.value), (ADDS, ADDS));
+        assert!(store.sent <= ADDS / ROUND_TRIP + 1, "{} CASes for {ADDS} adds", store.sent);
+    }
+
+    // A CAS in flight on one key doesn't hold up the other's, even with one in flight per counter,
+    // and with two allowed a counter chains its second CAS onto the first
+    #[test]
+    fn cases_on_different_keys_are_in_flight_together() {
+        let clock = ManualClock::new();
+        let mut store = Store::default();
+        let mut counters = [("a", 3), ("b", 4)].map(|(name, delta)| {
+            let mut counter = initialized_counter(name, &clock);
+            counter.to_add = delta;
+            counter
+        });
+        for counter in &mut counters {
+            counter.send_due(1, &clock, &mut store.send());
+        }
+        assert!(counters.iter().all(|counter| counter.in_flight.len() == 1));
+
+        let a = &mut counters[0];
+        a.to_add += 2;
+        assert!(!a.cas_wanted(1));
+        assert!(a.cas_wanted(2));
+        a.send_due(2, &clock, &mut store.send());
+        assert!(matches!(store.replies.last(), Some((3, KvMessage::CasOk))));
+        assert_eq!(a.in_flight.values().map(|cas| (cas.update.from(), cas.update.to())).collect::<Vec<_>>(), [(Some(0), Some(3)), (Some(3), Some(5))]);
 
-        if to_add != 0 && !cas_outstanding {
-            last_cas_to = value + to_add;
-            let e = Envelope::new(my_node_id.clone(), SEQ_KV.to_string(), None,
-                                         Message::Cas { key: KV_KEY.to_string(), from: value, to: last_cas_to, create_if_not_exists: None });
-            eprintln!("cas: {e:?}");
-            dispatch_message(&e);
-            last_cas_id = e.msg_id().unwrap();
-            cas_outstanding = true;
+        // The store answers b's first, then a's in the order they were sent
+        store.replies.swap(0, 1);
+        for (msg_id, reply) in store.replies.drain(..) {
+            assert!(counters.iter_mut().any(|counter| counter.store_replied(msg_id, reply.clone(), &clock, &mut vec![], &mut |_| unreachable!())));
         }
+        assert_eq!(counters.map(|counter| (counter.value, counter.to_add, counter.in_flight.len())), [(5, 0, 0), (4, 0, 0)]);
+    }
+
+    // Another node moves the total first, so the CAS fails. The update reads the total again once
+    // the backoff's up and then CASes from it, and a reply that answers nothing of ours is turned away.
+    #[test]
+    fn a_lost_cas_is_retried_from_a_fresh_read() {
+        let clock = ManualClock::new();
+        let mut store = Store::default();
+        store.kv.handle(&KvMessage::Write { key: DEFAULT_COUNTER.to_string(), value: 10 });
+        let mut counter = initialized_counter(DEFAULT_COUNTER, &clock);
+        counter.to_add = 3;
+        counter.send_due(1, &clock, &mut store.send());
+        store.settle(&mut counter, &clock);
+        assert_eq!((counter.retrying.len(), counter.cas_failures), (1, 1));
+        assert_eq!(counter.unsent(), 0);
+
+        // Nothing goes out until the backoff's up
+        assert!(counter.send_due(1, &clock, &mut |_| unreachable!()).is_some());
+        clock.advance(DEFAULT_CAS_BACKOFF);
+        assert_eq!(counter.send_due(1, &clock, &mut store.send()), None);
+        store.settle(&mut counter, &clock);
+        assert_eq!((store.read(DEFAULT_COUNTER), counter.value, counter.to_add, counter.outstanding()), (13, 13, 0, 0));
+        assert!(!counter.store_replied(1, KvMessage::CasOk, &clock, &mut vec![], &mut |_| unreachable!()));
+    }
+
+    // A read that arrives after an add but before the CAS covering it has been answered includes
+    // the add, and once the CAS succeeds the delta is counted once, not twice
+    #[test]
+    fn reads_include_pending_adds() {
+        let clock = ManualClock::new();
+        let mut store = Store::default();
+        let mut counter = initialized_counter(DEFAULT_COUNTER, &clock);
+        counter.to_add += 3;
+        assert_eq!((counter.value, counter.total()), (0, 3));
+        counter.send_due(1, &clock, &mut store.send());
+        counter.to_add += 2;
+        assert_eq!(counter.total(), 5);
+        store.settle(&mut counter, &clock);
+        assert_eq!((counter.value, counter.to_add, counter.total()), (3, 2, 5));
+        counter.send_due(1, &clock, &mut store.send());
+        store.settle(&mut counter, &clock);
+        assert_eq!((store.read(DEFAULT_COUNTER), counter.total()), (5, 5));
+    }
+
+    // A previous run left the counter at 42, so the create CAS fails. Client requests wait until the
+    // read after it comes back, and then see 42 rather than starting from 0.
+    #[test]
+    fn a_counter_picks_up_where_the_store_left_off() {
+        let clock = ManualClock::new();
+        let mut store = Store::default();
+        store.kv.handle(&KvMessage::Write { key: DEFAULT_COUNTER.to_string(), value: 42 });
+        let mut counter = CasCounter::start(DEFAULT_COUNTER, Backoff::new(DEFAULT_CAS_BACKOFF, DEFAULT_CAS_BACKOFF_MAX, &clock, Rng::new(1)), &mut store.send());
+        assert!(matches!(store.replies[0], (1, KvMessage::Error { code, .. }) if code == ErrorCode::PreconditionFailed as u64));
+
+        let add = envelope("c1", r#"{"type": "add", "msg_id": 1, "delta": 3}"#);
+        let read = envelope("c1", r#"{"type": "read", "msg_id": 2}"#);
+        assert!(counter.wait_for_init(&add) && counter.wait_for_init(&read) && counter.wait_for_init(&add));
+
+        let mut replay = vec![];
+        while !store.replies.is_empty() {
+            let (msg_id, reply) = store.replies.remove(0);
+            assert!(counter.store_replied(msg_id, reply, &clock, &mut replay, &mut store.send()));
+        }
+        assert!(counter.initialized && counter.value == 42);
+        // Held requests are handled in the order they arrived, a redelivered one only once
+        assert_eq!(replay.iter().map(Envelope::msg_id).collect::<Vec<_>>(), [Some(1), Some(2)]);
+        assert!(!counter.wait_for_init(&read));
+    }
+
+    // Each wait is the base doubled once per failure in a row, capped at the max, less up to half
+    // for jitter; a success starts it over
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let clock = ManualClock::new();
+        let base = Duration::from_millis(10);
+        let mut backoff = Backoff::new(base, Duration::from_millis(50), &clock, Rng::new(1));
+        assert!(backoff.is_ready(&clock));
+        for delay in [10, 20, 40, 50, 50].map(Duration::from_millis) {
+            backoff.failed(&clock);
+            let wait = clock.until(backoff.retry_at);
+            assert!(wait <= delay && wait >= delay / 2, "waiting {wait:?}, expected up to {delay:?}");
+            assert!(!backoff.is_ready(&clock));
+        }
+        clock.advance(Duration::from_millis(50));
+        assert!(backoff.is_ready(&clock));
+
+        backoff.succeeded(&clock);
+        backoff.failed(&clock);
+        assert!(clock.until(backoff.retry_at) <= base);
     }
 }
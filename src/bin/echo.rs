@@ -1,37 +1,156 @@
-
-
-
 use serde::{Deserialize, Serialize};
 
+use goofy_goobers::{impl_common_init_message, impl_error_message, impl_type_tag};
+use goofy_goobers::error::{ErrorCode, ErrorMessage};
+use goofy_goobers::log;
 use goofy_goobers::message::Envelope;
+use goofy_goobers::protocol::Common;
+use goofy_goobers::runtime;
+use goofy_goobers::runtime::{DeadLetters, Init, OutputSender, Workload};
+use goofy_goobers::validate;
+use goofy_goobers::validate::Step;
 
-
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
-enum Message {
-    Init { node_id: String, node_ids: Vec<String> },
-    InitOk,
+enum EchoMessage {
     Echo { echo: String },
     EchoOk { echo: String },
+    Error { code: u64, text: String },
 }
 
-fn main() {
-    let mut stdout = std::io::stdout();
-    for line in std::io::stdin().lines() {
-        let env: Envelope<Message> = serde_json::from_str(&line.unwrap()).unwrap();
-        match env.message() {
-            Message::Init { node_id, node_ids } => {
-                eprintln!("init: {} of {:?}", node_id, node_ids);
-                let r = env.reply(Message::InitOk);
-                serde_json::to_writer(&mut stdout, &r).unwrap();
-                println!();
+impl_error_message!(EchoMessage);
+impl_type_tag!(EchoMessage { Echo, EchoOk, Error });
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+enum Message {
+    Common(Common),
+    Echo(EchoMessage),
+}
+
+impl_common_init_message!(Message);
+impl_type_tag!(Message(Common, Echo));
+
+impl ErrorMessage for Message {
+    fn error(code: ErrorCode, text: String) -> Self {
+        Message::Echo(EchoMessage::error(code, text))
+    }
+}
+
+struct Echo;
+
+impl Workload for Echo {
+    type Message = Message;
+    // There's no state for a panic to leave half-changed
+    const REPLY_WITH_ERROR_ON_PANIC: bool = true;
+
+    fn new(_init: Init) -> Self {
+        Echo
+    }
+
+    fn handle(&mut self, envelope: Envelope<Message>, output: &OutputSender<Message>) {
+        match envelope.message() {
+            Message::Common(common) => match common.reply_to() {
+                Some(reply) => output.send_all(envelope.try_reply(Message::Common(reply))).unwrap(),
+                None => DeadLetters::from_env().handle(&envelope, |e| output.send(e).unwrap()),
             },
-            Message::Echo { echo  } => {
-                let r = env.reply(Message::EchoOk { echo: echo.clone() });
-                serde_json::to_writer(&mut stdout, &r).unwrap();
-                println!();
+            Message::Echo(EchoMessage::Echo { echo }) => {
+                output.send_all(envelope.try_reply(Message::Echo(EchoMessage::EchoOk { echo: echo.clone() }))).unwrap();
             }
-            _ => unimplemented!()
+            // Answering an error with another error could go back and forth forever
+            Message::Echo(EchoMessage::Error { .. }) => log::debug_envelope!(&envelope, "ignoring error: {envelope:?}"),
+            // Anything else (one of our own replies, say) goes to the dead letters
+            _ => DeadLetters::from_env().handle(&envelope, |e| output.send(e).unwrap()),
+        }
+    }
+}
+
+// What GG_VALIDATE=1 plays to this binary (see goofy_goobers::validate)
+const VALIDATE_SCRIPT: &[Step] = &[
+    validate::INIT,
+    Step::client(r#"{"type": "echo", "echo": "hello"}"#, "echo_ok"),
+    Step::client(r#"{"type": "echo_ok", "echo": "hello"}"#, "error"),
+    Step::client(r#"{"type": "topology", "topology": {"n1": []}}"#, "topology_ok"),
+];
+
+fn main() {
+    validate::run_if_requested(VALIDATE_SCRIPT);
+    runtime::run::<Echo>();
+}
+
+#[cfg(test)]
+mod tests {
+    use goofy_goobers::runtime::OutputHandler;
+
+    use super::*;
+
+    fn handle(body: &str) -> Vec<Envelope<Message>> {
+        let (output, sent) = OutputHandler::to_channel(16);
+        let envelope = serde_json::from_str(&format!(r#"{{"src": "c1", "dest": "n1", "body": {body}}}"#)).unwrap();
+        Echo::new(Init { node_id: "n1".to_string(), node_ids: vec!["n1".to_string()] }).handle(envelope, &output);
+        drop(output);
+        sent.iter().collect()
+    }
+
+    fn error_code(reply: &Envelope<Message>) -> Option<u64> {
+        match reply.message() {
+            Message::Echo(EchoMessage::Error { code, .. }) => Some(*code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn echoes() {
+        let replies = handle(r#"{"type": "echo", "msg_id": 1, "echo": "hello"}"#);
+        assert!(matches!(replies.as_slice(), [reply] if matches!(reply.message(), Message::Echo(EchoMessage::EchoOk { echo }) if echo == "hello")));
+    }
+
+    // A message of a type echo knows but doesn't expect, like one of its own replies, gets
+    // not-supported rather than a panic
+    #[test]
+    fn unexpected_messages_are_not_supported() {
+        for body in [r#"{"type": "echo_ok", "msg_id": 1, "echo": "hello"}"#, r#"{"type": "init_ok", "msg_id": 1}"#] {
+            let replies = handle(body);
+            assert_eq!(replies.len(), 1, "{body}");
+            assert_eq!(error_code(&replies[0]), Some(ErrorCode::NotSupported as u64), "{body}");
+            assert_eq!(replies[0].in_reply_to(), Some(1));
         }
     }
+
+    #[test]
+    fn errors_are_not_answered() {
+        assert!(handle(r#"{"type": "error", "msg_id": 1, "code": 10, "text": "not supported"}"#).is_empty());
+    }
+
+    // A type it's never heard of doesn't even parse, so the input reader logs and skips it
+    #[test]
+    fn unknown_types_do_not_parse() {
+        let line = br#"{"src": "c1", "dest": "n1", "body": {"type": "frobnicate", "msg_id": 1}}"#.to_vec();
+        assert!(Envelope::<Message>::parse(line).is_err());
+    }
+
+    // Common and echo messages both go out as the flat bodies Maelstrom sends, and come back as
+    // the same variant
+    #[test]
+    fn messages_round_trip_as_flat_json() {
+        for body in [
+            serde_json::json!({"type": "init", "node_id": "n1", "node_ids": ["n1", "n2"]}),
+            serde_json::json!({"type": "init_ok"}),
+            serde_json::json!({"type": "topology", "topology": {"n1": ["n2"], "n2": ["n1"]}}),
+            serde_json::json!({"type": "topology_ok"}),
+            serde_json::json!({"type": "echo", "echo": "hello"}),
+            serde_json::json!({"type": "echo_ok", "echo": "hello"}),
+        ] {
+            let message: Message = serde_json::from_value(body.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&message).unwrap(), body);
+        }
+        assert!(matches!(serde_json::from_str(r#"{"type": "init_ok"}"#).unwrap(), Message::Common(Common::InitOk)));
+        assert!(matches!(serde_json::from_str(r#"{"type": "echo_ok", "echo": "hello"}"#).unwrap(), Message::Echo(EchoMessage::EchoOk { .. })));
+    }
+
+    #[test]
+    fn topology_is_acked() {
+        let replies = handle(r#"{"type": "topology", "msg_id": 1, "topology": {"n1": []}}"#);
+        assert!(matches!(replies.as_slice(), [reply] if reply.in_reply_to() == Some(1) && matches!(reply.message(), Message::Common(Common::TopologyOk))));
+    }
 }
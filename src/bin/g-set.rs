@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+
+use serde::{Deserialize, Serialize};
+
+use goofy_goobers::clock::{Clock, SystemClock};
+use goofy_goobers::gossip::{Gossip, GossipConfig, PeerLiveness, SyncAction, SyncPolicy, SyncSchedule};
+use goofy_goobers::{impl_error_message, impl_init_message, impl_type_tag};
+use goofy_goobers::log;
+use goofy_goobers::message;
+use goofy_goobers::message::{Envelope, MessageIdGenerator};
+use goofy_goobers::runtime;
+use goofy_goobers::runtime::{Cluster, DeadLetters, InputHandler, InputHandlerHandle, OutputHandler};
+use goofy_goobers::validate;
+use goofy_goobers::validate::Step;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Message {
+    Init { node_id: String, node_ids: Vec<String> },
+    InitOk,
+    Add { element: u64 },
+    AddOk,
+    Read,
+    ReadOk { value: Vec<u64> },
+    Sync { elements: Vec<u64> },
+    SyncOk { elements: Vec<u64> },
+    // Liveness checks for neighbours we haven't heard from in a while
+    Ping,
+    Pong,
+    Error { code: u64, text: String },
+}
+
+impl_init_message!(Message);
+impl_error_message!(Message);
+impl_type_tag!(Message { Init, InitOk, Add, AddOk, Read, ReadOk, Sync, SyncOk, Ping, Pong, Error });
+
+// All of a node's g-set state. Each method handles one event and returns the envelopes to send,
+// without doing any I/O itself, so the tests below can drive a node directly.
+//
+// Since the set only ever grows, every node converges on the union of all adds once gossip gets
+// through, no matter how long a partition lasts.
+struct GSetNode<'a> {
+    node_id: String,
+    ids: &'a MessageIdGenerator,
+    elements: HashSet<u64>,
+    gossip: Gossip<u64>,
+    peers: SyncPolicy,
+}
+
+impl<'a> GSetNode<'a> {
+    fn new(cluster: &Cluster, ids: &'a MessageIdGenerator, neighbours: Vec<String>, liveness: PeerLiveness) -> GSetNode<'a> {
+        GSetNode {
+            node_id: cluster.local.clone(),
+            ids,
+            elements: HashSet::new(),
+            gossip: Gossip::new(&cluster.all, neighbours),
+            peers: SyncPolicy::new(liveness),
+        }
+    }
+
+    // Elements we already have aren't passed on again
+    fn add(&mut self, element: u64, source: Option<&str>) {
+        if self.elements.insert(element) {
+            self.gossip.forward_except(element, source);
+        }
+    }
+
+    fn step(&mut self, env: &Envelope<Message>) -> Vec<Envelope<Message>> {
+        if env.is_from_node() {
+            self.peers.heard_from(&env.src);
+        }
+
+        match env.message() {
+            Message::Add { element } => {
+                self.add(*element, None);
+                env.try_reply_with_ids(self.ids, Message::AddOk).into_iter().collect()
+            }
+
+            Message::Read => {
+                let mut value: Vec<u64> = self.elements.iter().copied().collect();
+                value.sort_unstable();
+                env.try_reply_with_ids(self.ids, Message::ReadOk { value }).into_iter().collect()
+            }
+
+            Message::Sync { elements } => {
+                for element in elements {
+                    self.add(*element, Some(&env.src));
+                }
+                // Including ones we already had and were still due to send it
+                self.gossip.already_has(&env.src, elements);
+                env.try_reply_with_ids(self.ids, Message::SyncOk { elements: elements.clone() }).into_iter().collect()
+            }
+
+            Message::SyncOk { elements } => {
+                self.gossip.sync_ok(&env.src, elements);
+                vec![]
+            }
+
+            Message::Ping => env.try_reply_with_ids(self.ids, Message::Pong).into_iter().collect(),
+
+            Message::Pong => vec![],
+
+            _ => {
+                let mut outbound = vec![];
+                DeadLetters::from_env().handle_with_ids(self.ids, env, |e| outbound.push(e));
+                outbound
+            }
+        }
+    }
+
+    // Resends everything a neighbour hasn't acked yet, or pings it if it's gone quiet (see
+    // SyncPolicy)
+    fn sync(&mut self, neighbour: &str) -> Vec<Envelope<Message>> {
+        let SyncAction::Sync { ping } = self.peers.on_sync(neighbour) else { return vec![] };
+        let mut outbound = vec![];
+        let unacked_elements = self.gossip.pending_to(neighbour);
+        if !unacked_elements.is_empty() {
+            outbound.push(Envelope::new_with_ids(self.ids, self.node_id.clone(), neighbour.to_string(), None,
+                                                 Message::Sync { elements: unacked_elements.to_vec() }));
+        }
+        self.gossip.release_deferred_to(neighbour);
+        if ping {
+            outbound.push(Envelope::new_with_ids(self.ids, self.node_id.clone(), neighbour.to_string(), None, Message::Ping));
+        }
+        outbound
+    }
+}
+
+// What GG_VALIDATE=1 plays to this binary (see goofy_goobers::validate)
+const VALIDATE_SCRIPT: &[Step] = &[
+    validate::INIT,
+    Step::client(r#"{"type": "add", "element": 7}"#, "add_ok"),
+    Step::client(r#"{"type": "read"}"#, "read_ok"),
+];
+
+fn main() {
+    validate::run_if_requested(VALIDATE_SCRIPT);
+    let (output_sender, output_thread) = OutputHandler::start::<Message>();
+    runtime::exit_on_panic(&output_sender);
+    let (main_sender, main_receiver) = channel();
+    let _input_handler: InputHandlerHandle<Message> = InputHandler::start::<Message>(vec![main_sender]);
+
+    let Some(init) = runtime::await_init(&main_receiver, |e| output_sender.send(e).unwrap()) else { return };
+    let config = GossipConfig::from_env();
+    log::debug!("gossip config: {config:?}");
+    let cluster = Cluster::from(init);
+    let node_topology = config.build_topology(&cluster.all);
+    log::debug!("generated topology: {:?}", node_topology);
+    node_topology.validate();
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let neighbours = node_topology.neighbours(&cluster.local).to_vec();
+    let mut sync_schedule = SyncSchedule::new(&cluster.all, &cluster.local, &neighbours, config.sync_interval, clock.now());
+    let liveness = PeerLiveness::with_clock(cluster.others(), config.peer_timeout, clock.clone());
+    let mut node = GSetNode::new(&cluster, message::default_ids(), neighbours, liveness);
+
+    loop {
+        let timeout = sync_schedule.next_deadline().map_or(config.sync_interval, |deadline| clock.until(deadline));
+        let mut outbound = match main_receiver.recv_timeout(timeout) {
+            Ok(env) if output_sender.resend_cached_reply(&env) => vec![],
+            Ok(env) if runtime::reply_to_repeated_init(&env, |e| output_sender.send(e).unwrap()) => vec![],
+            Ok(env) => node.step(&env),
+            Err(RecvTimeoutError::Timeout) => vec![],
+            // stdin was closed
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        for neighbour in sync_schedule.due(clock.now()) {
+            outbound.extend(node.sync(&neighbour));
+        }
+        output_sender.send_all(outbound).unwrap();
+    }
+
+    // stdin was closed; wait for everything we've sent to be written out
+    OutputHandler::flush_and_join(output_sender, output_thread);
+}
+
+#[cfg(test)]
+mod tests {
+    use goofy_goobers::clock::ManualClock;
+    use goofy_goobers::runtime::Init;
+
+    use super::*;
+
+    // n1, in a cluster of n1, n2 and n3
+    fn node_with_neighbours<'a>(ids: &'a MessageIdGenerator, neighbours: &[&str]) -> GSetNode<'a> {
+        let cluster = Cluster::from(Init { node_id: "n1".to_string(), node_ids: ["n1", "n2", "n3"].map(String::from).to_vec() });
+        let neighbours = neighbours.iter().map(|node| node.to_string()).collect();
+        let liveness = PeerLiveness::with_clock(cluster.others(), GossipConfig::default().peer_timeout, Arc::new(ManualClock::new()));
+        GSetNode::new(&cluster, ids, neighbours, liveness)
+    }
+
+    fn read(node: &mut GSetNode, client_ids: &MessageIdGenerator) -> Vec<u64> {
+        let reply = node.step(&Envelope::new_with_ids(client_ids, "c1", "n1", None, Message::Read));
+        match reply.as_slice() {
+            [e] => match e.message() {
+                Message::ReadOk { value } => value.clone(),
+                m => panic!("expected read_ok, got {m:?}"),
+            },
+            _ => panic!("expected one reply, got {reply:?}"),
+        }
+    }
+
+    // Every add is acked, repeats included, and read_ok lists each element once in ascending order
+    #[test]
+    fn adds_are_read_back_sorted() {
+        let (ids, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut node = node_with_neighbours(&ids, &["n2"]);
+        assert!(read(&mut node, &client_ids).is_empty());
+        for element in [5, 1, 9, 3, 1] {
+            let reply = node.step(&Envelope::new_with_ids(&client_ids, "c1", "n1", None, Message::Add { element }));
+            assert!(matches!(reply.as_slice(), [e] if matches!(e.message(), Message::AddOk)), "{reply:?}");
+        }
+        assert_eq!(read(&mut node, &client_ids), [1, 3, 5, 9]);
+        assert_eq!(node.sync("n2").len(), 1);
+    }
+
+    // A peer's sync is acked and merged into the set, and what's new is passed on to our other
+    // neighbours but not back to the peer
+    #[test]
+    fn a_peer_sync_is_merged() {
+        let (ids, peer_ids, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut node = node_with_neighbours(&ids, &["n2", "n3"]);
+        node.step(&Envelope::new_with_ids(&client_ids, "c1", "n1", None, Message::Add { element: 2 }));
+
+        let sync = Envelope::new_with_ids(&peer_ids, "n2", "n1", None, Message::Sync { elements: vec![4, 2] });
+        let reply = node.step(&sync);
+        assert!(matches!(reply.as_slice(), [e] if e.is_reply_to(&sync) && matches!(e.message(), Message::SyncOk { elements } if *elements == [4, 2])), "{reply:?}");
+        assert_eq!(read(&mut node, &client_ids), [2, 4]);
+
+        assert!(node.sync("n2").is_empty());
+        let to_n3 = node.sync("n3");
+        assert!(matches!(to_n3.as_slice(), [e] if e.dest == "n3" && matches!(e.message(), Message::Sync { elements } if *elements == [2, 4])), "{to_n3:?}");
+        node.step(&to_n3[0].try_reply_with_ids(&peer_ids, Message::SyncOk { elements: vec![2, 4] }).unwrap());
+        assert!(node.gossip.undelivered().is_empty());
+    }
+}
@@ -1,16 +1,76 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::io::{BufRead, Write};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::{panic, process, thread};
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
 use std::cmp::Ordering;
-use serde::de::DeserializeOwned;
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
-use goofy_goobers::error::ErrorCode;
+use goofy_goobers::error::{ErrorCode, ErrorMessage};
+use goofy_goobers::gossip::{GossipConfig, NodeHandler};
+use goofy_goobers::kv::{KvClient, KvMessage, KvPayload, LIN_TSO, SEQ_KV};
+use goofy_goobers::log;
 use goofy_goobers::message::Envelope;
+use goofy_goobers::protocol::attribute_to_sender;
+use goofy_goobers::{impl_error_message, impl_init_message, impl_type_tag};
+use goofy_goobers::runtime;
+use goofy_goobers::safe_int;
+use goofy_goobers::safe_int::SafeInt;
+use goofy_goobers::segments::SegmentedLog;
+use goofy_goobers::runtime::{Cluster, DeadLetters, InputHandler, InputHandlerHandle, OutputHandler, OutputSender};
+use goofy_goobers::validate;
+use goofy_goobers::validate::Step;
 
-const KV_ADDRESS: &str = "seq-kv";
+const KV_ADDRESS: &str = SEQ_KV;
 const XID_KEY: &str = "xid";
+// Set GG_XID_SOURCE=lin-tso to take xids from lin-tso's timestamps instead of a CAS counter in
+// seq-kv (the default, GG_XID_SOURCE=seq-kv)
+const XID_SOURCE_ENV_VAR: &str = "GG_XID_SOURCE";
+// How long to wait before asking lin-tso again after a retriable error, doubling with each error
+// in a row up to TS_RETRY_BACKOFF_MAX
+const TS_RETRY_BACKOFF: Duration = Duration::from_millis(1);
+const TS_RETRY_BACKOFF_MAX: Duration = Duration::from_millis(100);
+// Set GG_KAFKA_SPILL_DIR=path to keep only the newest transactions in memory, writing older ones
+// to segment files in path/<node id> GG_KAFKA_SEGMENT_SIZE at a time. Without it the whole log stays in memory.
+const SPILL_DIR_ENV_VAR: &str = "GG_KAFKA_SPILL_DIR";
+const SEGMENT_SIZE_ENV_VAR: &str = "GG_KAFKA_SEGMENT_SIZE";
+const DEFAULT_SEGMENT_SIZE: usize = 10_000;
+// Set GG_XID_BATCH to reserve that many xids per CAS on the shared counter. Batching cuts seq-kv
+// traffic by the batch factor, but nodes then use their reserved xids out of order with each other,
+// leaving gaps in the log that may never be filled. Polls then wait on gaps in each node's seqs
+// instead, which can't see a node's latest transactions until something after them arrives.
+const XID_BATCH_ENV_VAR: &str = "GG_XID_BATCH";
+const DEFAULT_XID_BATCH: usize = 1;
+// Set GG_POLL_MAX_WAIT_MS to change how long a poll waits for a gap in the log to be filled before
+// it's answered anyway. A transaction that's lost for good (say its node crashed before sending it
+// anywhere) would otherwise hold up every poll past it until the client gave up.
+const POLL_MAX_WAIT_ENV_VAR: &str = "GG_POLL_MAX_WAIT_MS";
+const DEFAULT_POLL_MAX_WAIT: Duration = Duration::from_millis(1000);
+// Set GG_KAFKA_POLL_CONSISTENCY=quorum to keep a message sent through this node out of polls until
+// a majority of nodes (counting this one) have acknowledged its transaction, so a poll never
+// returns a message that would be lost if this node crashed. The default,
+// GG_KAFKA_POLL_CONSISTENCY=local, returns every message as soon as this node has it. Messages
+// sent through other nodes are always returned once they arrive here: by then both this node and
+// the one it came from have it, which is a majority of up to 3 nodes but not of more.
+const POLL_CONSISTENCY_ENV_VAR: &str = "GG_KAFKA_POLL_CONSISTENCY";
+// Set GG_KAFKA_POLL_LIMIT to change how many messages a poll_ok returns per key. A poll from an old
+// offset gets the oldest messages from that offset onwards, with none skipped; the client sees the
+// rest by polling again from just past the last offset it got back, as it would anyway.
+const POLL_LIMIT_ENV_VAR: &str = "GG_KAFKA_POLL_LIMIT";
+const DEFAULT_POLL_LIMIT: usize = 100;
+// Set GG_KAFKA_CHECK=1 to check every poll_ok and list_committed_offsets_ok this node sends against
+// its log and its earlier replies, logging any inconsistency (see ConsistencyChecker). It's for
+// self-testing: a protocol bug shows up in the node's log straight away, instead of as an anomaly
+// at the end of a Maelstrom run.
+const CHECK_ENV_VAR: &str = "GG_KAFKA_CHECK";
+// How often the transactions we know we're missing from other nodes are logged, if there are any
+const GAP_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+// How many of the xids missing from the log a gap report lists
+const GAP_REPORT_XIDS: usize = 20;
+
+// An (offset, message) pair in a poll_ok
+type PolledMessage = (SafeInt<usize>, SafeInt<u64>);
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
@@ -37,19 +97,51 @@ enum Message {
     },
     CasOk,
 
+    // Timestamp oracle messages
+    Ts,
+    TsOk { ts: u64 },
+
     // Workload messages
-    Send { key: String, msg: u64 },
-    SendOk { offset: usize },
-    Poll { offsets: HashMap<String, usize> },
-    PollOk { msgs: HashMap<String, Vec<(usize, u64)>> },
-    CommitOffsets { offsets: HashMap<String, usize> },
+    // Offsets and messages go out as strings past 2^53 if GG_LARGE_NUMBERS=string
+    Send {
+        key: String,
+        #[serde(with = "safe_int")]
+        msg: u64
+    },
+    SendOk {
+        #[serde(with = "safe_int")]
+        offset: usize
+    },
+    Poll {
+        #[serde(with = "safe_int::map")]
+        offsets: HashMap<String, usize>
+    },
+    PollOk { msgs: HashMap<String, Vec<PolledMessage>> },
+    CommitOffsets {
+        #[serde(with = "safe_int::map")]
+        offsets: HashMap<String, usize>
+    },
     CommitOffsetsOk,
     ListCommittedOffsets { keys: Vec<String> },
-    ListCommittedOffsetsOk { offsets: HashMap<String, usize> },
+    ListCommittedOffsetsOk {
+        #[serde(with = "safe_int::map")]
+        offsets: HashMap<String, usize>
+    },
 
     // Node to node messages
     Transactions { transactions: Vec<Transaction>},
+    // The reply to a pushed Transactions. Pushes are resent every sync interval until they're acked.
+    TransactionsOk { transaction_ids: Vec<usize> },
     PollTransactions { first_xid: usize },
+    // Committed offsets, kept apart from the log. Every node keeps the highest offset it's seen for
+    // each key, so they converge whatever order these arrive in. Resent every sync interval until
+    // they're acked.
+    Offsets { offsets: HashMap<String, usize> },
+    OffsetsOk { offsets: HashMap<String, usize> },
+
+    // Debugging: which transactions this node knows it's missing (see SequenceGaps)
+    Gaps,
+    GapsOk { missing_xids: Vec<usize>, nodes: HashMap<String, Vec<MissingTransaction>> },
 
     Error {
         code: u64,
@@ -57,336 +149,1094 @@ enum Message {
     },
 }
 
+impl_init_message!(Message);
+impl_error_message!(Message);
+impl_type_tag!(Message {
+    Init, InitOk, Topology, TopologyOk, Read, ReadOk, Write, WriteOk, Cas, CasOk, Ts, TsOk, Send, SendOk, Poll, PollOk,
+    CommitOffsets, CommitOffsetsOk, ListCommittedOffsets, ListCommittedOffsetsOk, Transactions, TransactionsOk,
+    PollTransactions, Offsets, OffsetsOk, Gaps, GapsOk, Error,
+});
+
+impl From<KvMessage> for Message {
+    fn from(value: KvMessage) -> Self {
+        match value {
+            KvMessage::Read { key } => Message::Read { key: Some(key) },
+            KvMessage::ReadOk { value } => Message::ReadOk { value },
+            KvMessage::Write { key, value } => Message::Write { key, value },
+            KvMessage::WriteOk => Message::WriteOk,
+            KvMessage::Cas { key, from, to, create_if_not_exists } => Message::Cas { key, from, to, create_if_not_exists },
+            KvMessage::CasOk => Message::CasOk,
+            KvMessage::Error { code, text } => Message::Error { code, text },
+        }
+    }
+}
+
+impl KvPayload for Message {
+    fn to_kv_message(&self) -> Option<KvMessage> {
+        Some(match self.clone() {
+            Message::Read { key: Some(key) } => KvMessage::Read { key },
+            Message::ReadOk { value } => KvMessage::ReadOk { value },
+            Message::Write { key, value } => KvMessage::Write { key, value },
+            Message::WriteOk => KvMessage::WriteOk,
+            Message::Cas { key, from, to, create_if_not_exists } => KvMessage::Cas { key, from, to, create_if_not_exists },
+            Message::CasOk => KvMessage::CasOk,
+            Message::Error { code, text } => KvMessage::Error { code, text },
+            _ => return None,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 struct Transaction {
+    // Transactions from before this was added have none (see attribute_to_sender)
+    #[serde(default)]
     node: String,
     transaction_id: usize,
+    // Each node numbers its own transactions from 0, which xids can't do since they're shared.
+    // Transactions from before this was added have none, and SequenceGaps can't track them.
+    #[serde(default)]
+    seq: Option<usize>,
     key: String,
     message: u64,
 }
 
 impl PartialOrd<Self> for Transaction {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.transaction_id.partial_cmp(&other.transaction_id)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Transaction {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        self.transaction_id.cmp(&other.transaction_id)
     }
 }
 
-struct InputHandler;
+// One transaction we know another node has sent but haven't received: the seq before it and the
+// one after have arrived, so its xid is somewhere between theirs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MissingTransaction {
+    seq: usize,
+    after_xid: usize,
+    before_xid: usize,
+}
 
-struct InputHandlerHandle<B: Clone + Debug + Send> {
-    new_subscriber_sender: Sender<Sender<Envelope<B>>>
+#[derive(Default)]
+struct NodeSequence {
+    next_seq: usize,
+    last_xid: usize,
+    missing: BTreeMap<usize, MissingTransaction>,
 }
 
-impl<B: Clone + Debug + Send> InputHandlerHandle<B> {
-    fn new_receiver(&self) -> Receiver<Envelope<B>> {
-        let (sender, receiver) = channel();
-        self.new_subscriber_sender.send(sender).unwrap();
-        receiver
-    }
+// Which other nodes' transactions have been skipped over, worked out from their seqs, so a poll
+// held up by a gap in the log can be traced back to the node whose transaction it's waiting for
+#[derive(Default)]
+struct SequenceGaps {
+    nodes: HashMap<String, NodeSequence>,
 }
 
-impl InputHandler {
-    pub fn start<B: Clone + Debug + Send + DeserializeOwned + 'static>(mut subscribers: Vec<Sender<Envelope<B>>>) -> InputHandlerHandle<B> {
-        let (new_subscriber_sender, new_subscriber_receiver) = channel();
+impl SequenceGaps {
+    // Called for each transaction from another node as it arrives. Repeats are harmless, since a
+    // transaction can only be missing until its first copy arrives. A transaction without a seq
+    // is ignored, so gaps in the transactions from a node that doesn't send seqs go unnoticed.
+    fn received(&mut self, txn: &Transaction) {
+        let Some(txn_seq) = txn.seq else { return };
+        let node = self.nodes.entry(txn.node.clone()).or_default();
+        if txn_seq < node.next_seq {
+            node.missing.remove(&txn_seq);
+            return
+        }
+        for seq in node.next_seq..txn_seq {
+            node.missing.insert(seq, MissingTransaction { seq, after_xid: node.last_xid, before_xid: txn.transaction_id });
+        }
+        node.next_seq = txn_seq + 1;
+        node.last_xid = txn.transaction_id;
+    }
 
-        thread::spawn(move || {
-            loop {
-                for line in std::io::stdin().lock().lines().map(Result::unwrap) {
-                    while let Ok(r) = new_subscriber_receiver.try_recv() {
-                        subscribers.push(r);
-                    };
+    // Every node we're missing transactions from, and the ones we're missing
+    fn missing(&self) -> HashMap<String, Vec<MissingTransaction>> {
+        self.nodes.iter()
+            .filter(|(_, sequence)| !sequence.missing.is_empty())
+            .map(|(node, sequence)| (node.clone(), sequence.missing.values().cloned().collect()))
+            .collect()
+    }
 
-                    let env: Envelope<B> = serde_json::from_str(&line).unwrap();
-                    for subscriber in subscribers.iter() {
-                        let _ = subscriber.send(env.clone());
-                    }
-                }
-            }
-        });
+    // The last xid before the first transaction we know we're missing from any node. Each node takes
+    // its xids in ascending order, so everything it sent up to here has arrived.
+    fn first_gap(&self) -> Option<usize> {
+        self.nodes.values()
+            .flat_map(|sequence| sequence.missing.values())
+            .map(|txn| txn.after_xid)
+            .min()
+    }
+}
+
+// Hands out xids from a local pool, reserving batch_size more from the XidAssigner whenever it
+// runs dry. Each clone has its own pool, so clones can be handed to other threads.
+struct XidRequester {
+    request_sender: Sender<(usize, Sender<Vec<usize>>)>,
+    batch_size: usize,
+    pool: VecDeque<usize>,
+}
 
-        InputHandlerHandle { new_subscriber_sender }
+impl Clone for XidRequester {
+    fn clone(&self) -> Self {
+        XidRequester { request_sender: self.request_sender.clone(), batch_size: self.batch_size, pool: VecDeque::new() }
     }
 }
 
-struct OutputHandler;
+impl XidRequester {
+    // None once the XidAssigner has stopped, because the node is shutting down
+    fn get_xid(&mut self) -> Option<usize> {
+        if self.pool.is_empty() {
+            self.pool = self.get_xids(self.batch_size)?.into();
+        }
+        self.pool.pop_front()
+    }
 
-impl OutputHandler {
-    fn start<B: Debug + Serialize + Send + 'static>() -> Sender<Envelope<B>> {
+    // Reserves n xids, in ascending order, in a single round trip
+    fn get_xids(&mut self, n: usize) -> Option<Vec<usize>> {
         let (sender, receiver) = channel();
+        self.request_sender.send((n, sender)).ok()?;
+        receiver.recv().ok()
+    }
+}
+
+// Somewhere to get xids from. Every xid it returns has to be unique across the cluster.
+trait XidSource: Send {
+    // The service it talks to, whose replies the main loop should leave alone
+    fn address(&self) -> &'static str;
+
+    // Returns n new xids in ascending order, or None if the source's input has closed, which means
+    // the node is shutting down
+    fn generate_xids(&mut self, n: usize) -> Option<Vec<usize>>;
+}
+
+struct XidAssigner;
+
+impl XidAssigner {
+    // Requests that arrive while the source is busy are queued up and then served together by a
+    // single call covering all of them, so concurrent callers share round trips instead of waiting
+    // for one each. Once the source's input closes the thread stops, and so does every request
+    // after that.
+    pub fn start(mut source: impl XidSource + 'static, batch_size: usize) -> XidRequester {
+        let (request_sender, request_receiver) = channel::<(usize, Sender<Vec<usize>>)>();
 
         thread::spawn(move || {
-            let mut stdout = std::io::stdout().lock();
-            for envelope in receiver {
-                serde_json::to_writer(&mut stdout, &envelope).unwrap();
-                stdout.write(b"\n").unwrap();
-                stdout.flush().unwrap();
+            while let Ok(request) = request_receiver.recv() {
+                let mut requests = vec![request];
+                requests.extend(request_receiver.try_iter());
+
+                let Some(xids) = source.generate_xids(requests.iter().map(|(n, _)| n).sum()) else {
+                    log::debug!("input closed, no more xids from {}", source.address());
+                    break
+                };
+                let mut xids = xids.into_iter();
+                for (n, response_channel) in requests {
+                    // The caller may have given up waiting
+                    let _ = response_channel.send(xids.by_ref().take(n).collect());
+                }
             }
         });
 
-        sender
+        XidRequester { request_sender, batch_size, pool: VecDeque::new() }
     }
 }
 
-#[derive(Clone)]
-struct XidRequester {
-    request_sender: Sender<Sender<usize>>
+// Claims xids by moving a shared counter in seq-kv on with a CAS: one round trip for any number
+// of xids, plus another read and CAS whenever another node got there first
+struct CasXidSource {
+    kv: KvClient<Message>,
+    // The counter's value after our last CAS, so the next one can usually skip the read
+    last_seen_xid: Option<u64>,
 }
 
-impl XidRequester {
-    fn get_xid(&mut self) -> usize {
-        let (sender, receiver) = channel();
-        self.request_sender.send(sender).unwrap();
-        receiver.recv().unwrap()
+impl CasXidSource {
+    fn new(local_node: String, incoming: Receiver<Envelope<Message>>, outgoing: OutputSender<Message>) -> CasXidSource {
+        CasXidSource { kv: KvClient::new(local_node, KV_ADDRESS.to_string(), incoming, outgoing), last_seen_xid: None }
     }
 }
 
-struct XidAssigner {
-    local_node: String,
-    incoming: Receiver<Envelope<Message>>,
-    outgoing: Sender<Envelope<Message>>,
-    request_receiver: Receiver<Sender<usize>>,
-    last_seen_xid: usize,
-}
+impl XidSource for CasXidSource {
+    fn address(&self) -> &'static str {
+        KV_ADDRESS
+    }
 
-impl XidAssigner {
-    // This only allows a single outstanding request at a time - that may need
-    // to be optimized later to handle high latency
-    pub fn start(local_node: String, incoming: Receiver<Envelope<Message>>, outgoing: Sender<Envelope<Message>>) -> XidRequester {
-        let (request_sender, request_receiver) = channel();
-        let mut assigner = XidAssigner {
-            local_node,
-            incoming,
-            outgoing,
-            request_receiver,
-            last_seen_xid: 0
+    // KvClient gives a crash error when the node's input or output closes while it's waiting, which
+    // only happens as the node shuts down
+    fn generate_xids(&mut self, n: usize) -> Option<Vec<usize>> {
+        if self.last_seen_xid.is_none() {
+            let xid = match self.kv.init_key(XID_KEY, 0) {
+                Ok(xid) => xid,
+                Err(e) if e.code == ErrorCode::Crash => return None,
+                Err(e) => panic!("Couldn't initialize {XID_KEY}: {e:?}"),
+            };
+            self.last_seen_xid = Some(xid);
+        }
+        let last_xid = match self.kv.update_from(XID_KEY, self.last_seen_xid, |xid| xid + n as u64) {
+            Ok(last_xid) => last_xid,
+            Err(e) if e.code == ErrorCode::Crash => return None,
+            Err(e) => panic!("Couldn't claim {n} xids: {e:?}"),
         };
+        self.last_seen_xid = Some(last_xid);
+        Some((last_xid as usize + 1 - n..last_xid as usize + 1).collect())
+    }
+}
 
-        thread::spawn(move || {
-            assigner.initialize_xid();
-            loop {
-                let response_channel = assigner.request_receiver.recv().unwrap();
-                response_channel.send(assigner.generate_xid()).unwrap()
-            }
-        });
+// Takes xids from lin-tso's timestamps, which are unique and only ever increase. There's no CAS to
+// lose, but each xid needs a timestamp of its own; the requests for a batch are all sent at once,
+// so a batch still costs one round trip.
+struct TsoXidSource {
+    local_node: String,
+    incoming: Receiver<Envelope<Message>>,
+    outgoing: OutputSender<Message>,
+}
 
-        XidRequester { request_sender }
+impl XidSource for TsoXidSource {
+    fn address(&self) -> &'static str {
+        LIN_TSO
     }
 
-    fn initialize_xid(&mut self) {
-        let e = Envelope::new(self.local_node.clone(), KV_ADDRESS.to_string(), None,
-        Message::Cas { key: XID_KEY.to_string(), from: 0, to: 0, create_if_not_exists: Some(true) });
-        self.outgoing.send(e).unwrap();
+    fn generate_xids(&mut self, n: usize) -> Option<Vec<usize>> {
+        let mut waiting_for = HashSet::new();
+        for _ in 0..n {
+            waiting_for.insert(self.request_ts());
+        }
+
+        let mut xids = Vec::with_capacity(n);
+        let mut backoff = TS_RETRY_BACKOFF;
         for env in self.incoming.iter() {
-            if env.src == KV_ADDRESS {
-                match env.message() {
-                    Message::CasOk => {
-                        self.last_seen_xid = 0;
-                        return;
-                    },
-                    Message::Error { code, text} if *code == ErrorCode::PreconditionFailed as u64 => {
-                        // If we can't initialize it to 0, it must already have been initialized (and incremented)
-                        eprintln!("initialize_xid: {text}");
-                        self.last_seen_xid = self.fetch_last_xid();
-                        return;
-                    },
-                    _ => panic!("initialize_xid: unexpected message {env:?}"),
+            if env.src != LIN_TSO || !waiting_for.remove(&env.in_reply_to()) {
+                continue
+            }
+            match env.message() {
+                Message::TsOk { ts } => xids.push(*ts as usize),
+                // The timestamp wasn't handed out, so asking again can't skip or repeat one
+                Message::Error { code, text } if ErrorCode::try_from(*code).is_ok_and(|code| code.is_retriable()) => {
+                    log::debug_envelope!(&env, "ts failed ({text}), retrying in {backoff:?}");
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(TS_RETRY_BACKOFF_MAX);
+                    waiting_for.insert(self.request_ts());
                 }
+                _ => panic!("Expected ts_ok but got {env:?}"),
+            }
+            if waiting_for.is_empty() {
+                // Replies can arrive out of order
+                xids.sort_unstable();
+                return Some(xids)
             }
         }
+        None
     }
+}
 
-    fn try_cas(&mut self) -> Option<usize> {
-        let possible_xid = self.last_seen_xid + 1;
-        let e = Envelope::new(self.local_node.clone(), KV_ADDRESS.to_string(), None,
-        Message::Cas { key: XID_KEY.to_string(), from: self.last_seen_xid as u64, to: possible_xid as u64, create_if_not_exists: None });
+impl TsoXidSource {
+    // Sends a ts request, returning its msg_id
+    fn request_ts(&self) -> Option<u64> {
+        let e = Envelope::new(self.local_node.clone(), LIN_TSO.to_string(), None, Message::Ts);
+        let msg_id = e.msg_id();
         self.outgoing.send(e).unwrap();
-        for env in self.incoming.iter() {
-            if env.src == KV_ADDRESS {
-                return match env.message() {
-                    Message::CasOk => {
-                        self.last_seen_xid = possible_xid;
-                        Some(possible_xid)
-                    },
-                    Message::Error { code, text} if *code == ErrorCode::PreconditionFailed as u64 => {
-                        eprintln!("try_cas: {text}");
-                        None
-                    },
-                    _ => panic!("Expected cas_ok but got {env:?}"),
+        msg_id
+    }
+}
+
+// Checks the replies to polls and list_committed_offsets against the log and against each other:
+//  - a poll returns messages in increasing offset order, from the polled offset onwards, each the
+//    same as the log's, and skips none of the log's messages for the key before the last it returns
+//  - a message, once polled, is never dropped: a later poll covering its offset returns it again,
+//    with the same value, up to the last offset returned or the committed offset for the key,
+//    whichever is higher (unless the poll limit cut the reply short)
+//  - the committed offset listed for a key never goes down
+// The replies are sent whatever it finds. It remembers every message it's seen polled, so it's
+// meant for tests rather than long runs.
+#[derive(Default)]
+struct ConsistencyChecker {
+    polled: HashMap<String, BTreeMap<usize, u64>>,
+    listed: HashMap<String, usize>,
+    violations: usize,
+}
+
+impl ConsistencyChecker {
+    fn check_poll(&mut self, poll: &Envelope<Message>, msgs: &HashMap<String, Vec<PolledMessage>>, log: &SegmentedLog<Transaction>,
+                  committed_offsets: &HashMap<String, usize>, poll_limit: usize) {
+        let Message::Poll { offsets } = poll.message() else { return };
+        for (key, offset) in offsets {
+            let returned: Vec<(usize, u64)> = msgs.get(key).into_iter().flatten().map(|(offset, msg)| (offset.0, msg.0)).collect();
+            let last_returned = returned.last().map(|(offset, _)| *offset);
+
+            if let Some((first, _)) = returned.first().filter(|(first, _)| first < offset) {
+                self.violation(poll, format!("{key}: returned offset {first}, below the polled offset {offset}"));
+            }
+            if let Some(pair) = returned.windows(2).find(|pair| pair[0].0 >= pair[1].0) {
+                self.violation(poll, format!("{key}: offsets out of order, {} then {}", pair[0].0, pair[1].0));
+            }
+
+            let in_log = match log.for_key(key, *offset) {
+                Ok(in_log) => in_log,
+                Err(e) => {
+                    log::debug_envelope!(poll, "can't check {key} against the log: {e}");
+                    continue
                 }
+            };
+            let in_log: BTreeMap<usize, u64> = in_log.into_iter()
+                .filter(|txn| last_returned.is_some_and(|last| txn.transaction_id <= last))
+                .map(|txn| (txn.transaction_id, txn.message))
+                .collect();
+            let returned_map: BTreeMap<usize, u64> = returned.iter().copied().collect();
+            if let Some((xid, msg)) = in_log.iter().find(|(xid, _)| !returned_map.contains_key(xid)) {
+                self.violation(poll, format!("{key}: skipped message {msg} at offset {xid}"));
+            }
+            if let Some((xid, msg)) = returned.iter().find(|(xid, msg)| in_log.get(xid) != Some(msg)) {
+                self.violation(poll, format!("{key}: returned message {msg} at offset {xid}, which the log has as {:?}", in_log.get(xid)));
+            }
+
+            let seen = self.polled.entry(key.clone()).or_default();
+            let covered_up_to = match last_returned {
+                Some(last) if returned.len() >= poll_limit => Some(last),
+                _ => last_returned.max(committed_offsets.get(key).copied()),
+            };
+            let dropped: Vec<(usize, u64)> = covered_up_to.into_iter()
+                .flat_map(|up_to| seen.range(*offset..=up_to.max(*offset)))
+                .filter(|(xid, _)| !returned_map.contains_key(xid))
+                .map(|(xid, msg)| (*xid, *msg))
+                .collect();
+            let changed: Vec<(usize, u64, u64)> = returned.iter()
+                .filter_map(|(xid, msg)| seen.get(xid).filter(|before| *before != msg).map(|before| (*xid, *before, *msg)))
+                .collect();
+            seen.extend(returned.iter().copied());
+            for (xid, msg) in dropped {
+                self.violation(poll, format!("{key}: dropped message {msg} at offset {xid}, which an earlier poll returned"));
+            }
+            for (xid, before, after) in changed {
+                self.violation(poll, format!("{key}: message at offset {xid} was {before}, now {after}"));
             }
         }
-        panic!("Incoming channel closed while waiting for cas_ok");
     }
 
-    fn fetch_last_xid(&mut self) -> usize {
-        let e = Envelope::new(self.local_node.clone(), KV_ADDRESS.to_string(), None,
-        Message::Read { key: Some(XID_KEY.to_string()) });
-        self.outgoing.send(e).unwrap();
-        for env in self.incoming.iter() {
-            if env.src == KV_ADDRESS {
-                return match env.message() {
-                    Message::ReadOk { value } => *value as usize,
-                    _ => panic!("Expected read_ok but got {env:?}"),
-                }
+    fn check_list(&mut self, request: &Envelope<Message>, offsets: &HashMap<String, usize>) {
+        for (key, offset) in offsets {
+            let listed = self.listed.entry(key.clone()).or_default();
+            let before = *listed;
+            *listed = before.max(*offset);
+            if *offset < before {
+                self.violation(request, format!("{key}: committed offset went down from {before} to {offset}"));
             }
         }
-        panic!("Incoming channel closed while waiting for read_ok");
     }
 
-    fn generate_xid(&mut self) -> usize {
-        loop {
-            if let Some(xid) = self.try_cas() {
-                return xid
-            } else {
-                eprintln!("generate_xid: got error, retrying");
-                self.last_seen_xid = self.fetch_last_xid()
-            }
+    fn violation(&mut self, envelope: &Envelope<Message>, problem: String) {
+        self.violations += 1;
+        log::debug_envelope!(envelope, "check: {problem} ({} inconsistencies so far)", self.violations);
+    }
+}
+
+// Raises the key's offset to offset, unless it's already at least that high. Returns whether it changed.
+fn merge_offset(offsets: &mut HashMap<String, usize>, key: &str, offset: usize) -> bool {
+    match offsets.get_mut(key) {
+        Some(current) if *current >= offset => false,
+        Some(current) => {
+            *current = offset;
+            true
+        }
+        None => {
+            offsets.insert(key.to_string(), offset);
+            true
         }
     }
 }
 
+// Up to poll_limit messages for each key from its polled offset on. Keys with nothing to return
+// are left out.
+fn poll(transaction_log: &SegmentedLog<Transaction>, offsets: &HashMap<String, usize>, local_node: &str,
+        unreplicated: &HashMap<usize, HashSet<String>>, poll_limit: usize) -> io::Result<HashMap<String, Vec<PolledMessage>>> {
+    let mut reply: HashMap<String, Vec<PolledMessage>> = HashMap::new();
+    for (key, offset) in offsets {
+        // Stop at the first message that isn't replicated yet rather than skipping it,
+        // since the client would never go back for it once it had seen a later offset
+        let msgs: Vec<PolledMessage> = transaction_log.for_key(key, *offset)?.into_iter()
+            .take_while(|transaction| transaction.node != local_node || !unreplicated.contains_key(&transaction.transaction_id))
+            .take(poll_limit)
+            .map(|transaction| (SafeInt(transaction.transaction_id), SafeInt(transaction.message)))
+            .collect();
+        if !msgs.is_empty() {
+            reply.insert(key.clone(), msgs);
+        }
+    }
+    Ok(reply)
+}
+
+// The committed offsets of those keys that have one
+fn list_offsets(committed_offsets: &HashMap<String, usize>, keys: &[String]) -> HashMap<String, usize> {
+    keys.iter()
+        .filter_map(|key| committed_offsets.get(key).map(|offset| (key.clone(), *offset)))
+        .collect()
+}
+
+// What GG_VALIDATE=1 plays to this binary (see goofy_goobers::validate)
+const VALIDATE_SCRIPT: &[Step] = &[
+    validate::INIT,
+    Step::client(r#"{"type": "send", "key": "k1", "msg": 5}"#, "send_ok"),
+    Step::client(r#"{"type": "poll", "offsets": {"k1": 0}}"#, "poll_ok"),
+    Step::client(r#"{"type": "commit_offsets", "offsets": {"k1": 1}}"#, "commit_offsets_ok"),
+    Step::client(r#"{"type": "list_committed_offsets", "keys": ["k1"]}"#, "list_committed_offsets_ok"),
+];
+
 fn main() {
-    // https://stackoverflow.com/questions/35988775/how-can-i-cause-a-panic-on-a-thread-to-immediately-end-the-main-thread
-    let orig_hook = panic::take_hook();
-    panic::set_hook(Box::new(move |panic_info| {
-        orig_hook(panic_info);
-        process::exit(1);
-    }));
-
-    let output_sender = OutputHandler::start::<Message>();
+    validate::run_if_requested(VALIDATE_SCRIPT);
+    let (output_sender, output_thread) = OutputHandler::start::<Message>();
+    runtime::exit_on_panic(&output_sender);
     let (main_sender, main_receiver) = channel();
     let input_handler: InputHandlerHandle<Message> = InputHandler::start::<Message>(vec![main_sender]);
-    let mut local_node = Default::default();
-    let mut other_nodes = Vec::new();
-
-    for envelope in main_receiver.iter() {
-        match envelope.message() {
-            Message::Init { node_id, node_ids } => {
-                eprintln!("init: {:?}", envelope);
-                local_node = node_id.clone();
-                other_nodes.extend(node_ids.into_iter().filter(|n| **n != local_node).cloned());
-                output_sender.send(envelope.reply(Message::InitOk)).unwrap();
-                break;
-            }
-            // Message::Topology { .. } => {
-            //     eprintln!("topology: {:?}", envelope);
-            //     output_sender.send(envelope.reply(Message::TopologyOk)).unwrap();
-            // },
-            _ => panic!("Unexpected message at init time: {envelope:?}")
+
+    let Some(init) = runtime::await_init(&main_receiver, |e| output_sender.send(e).unwrap()) else { return };
+    let cluster = Cluster::from(init);
+    let local_node = cluster.local.clone();
+
+    let xid_batch = runtime::env_var(XID_BATCH_ENV_VAR, 1).unwrap_or(DEFAULT_XID_BATCH);
+    log::debug!("reserving {xid_batch} xids at a time");
+    let poll_max_wait = runtime::env_var(POLL_MAX_WAIT_ENV_VAR, 1).map_or(DEFAULT_POLL_MAX_WAIT, |ms| Duration::from_millis(ms as u64));
+    let poll_limit = runtime::env_var(POLL_LIMIT_ENV_VAR, 1).unwrap_or(DEFAULT_POLL_LIMIT);
+    log::debug!("returning up to {poll_limit} messages per key per poll");
+    let xid_source = std::env::var(XID_SOURCE_ENV_VAR).unwrap_or_else(|_| SEQ_KV.to_string());
+    log::debug!("taking xids from {xid_source}");
+    let (xid_source_address, mut xid_assigner) = match xid_source.as_str() {
+        SEQ_KV => {
+            let source = CasXidSource::new(local_node.clone(), input_handler.new_receiver(), output_sender.clone());
+            (source.address(), XidAssigner::start(source, xid_batch))
         }
-    }
+        LIN_TSO => {
+            let source = TsoXidSource { local_node: local_node.clone(), incoming: input_handler.new_receiver(), outgoing: output_sender.clone() };
+            (source.address(), XidAssigner::start(source, xid_batch))
+        }
+        _ => panic!("{XID_SOURCE_ENV_VAR} must be {SEQ_KV} or {LIN_TSO}, got {xid_source}"),
+    };
 
-    let mut xid_assigner = XidAssigner::start(local_node.clone(), input_handler.new_receiver(), output_sender.clone());
+    // Keyed by (transaction_id, node), so it's always in xid order and duplicates are found by
+    // lookup. xids come from a shared counter, but only the pair is guaranteed to be unique.
+    let mut transaction_log: SegmentedLog<Transaction> = match std::env::var(SPILL_DIR_ENV_VAR) {
+        Ok(dir) => {
+            let segment_size = runtime::env_var(SEGMENT_SIZE_ENV_VAR, 1).unwrap_or(DEFAULT_SEGMENT_SIZE);
+            // Every node in a test gets the same environment
+            let dir = Path::new(&dir).join(&local_node);
+            log::debug!("spilling the log to {} {segment_size} transactions at a time", dir.display());
+            SegmentedLog::with_spill(&dir, segment_size).unwrap_or_else(|e| panic!("can't create {}: {e}", dir.display()))
+        }
+        Err(_) => SegmentedLog::new(),
+    };
+    let quorum_polls = match std::env::var(POLL_CONSISTENCY_ENV_VAR).as_deref() {
+        Ok("local") | Err(_) => false,
+        Ok("quorum") => true,
+        Ok(consistency) => panic!("{POLL_CONSISTENCY_ENV_VAR} must be local or quorum, got {consistency}"),
+    };
+    // How many other nodes have to acknowledge one of our sends before it can be polled
+    let acks_needed = if quorum_polls { cluster.peer_count().div_ceil(2) } else { 0 };
+    log::debug!("polls wait for {acks_needed} other nodes to have each of our sends");
+    // Our sends that haven't been acknowledged by enough nodes yet, and who has acknowledged them
+    let mut unreplicated: HashMap<usize, HashSet<String>> = HashMap::new();
 
-    let mut transaction_log: Vec<Transaction> = Vec::new();
-    let mut poll_replies = Vec::new();
+    // The xids of our transactions each other node hasn't acked yet, and by xid the transactions
+    // themselves with the nodes that haven't acked them, so an ack is matched without a scan
+    let mut unacked: HashMap<String, NodeHandler<usize>> = cluster.others().iter().map(|node| (node.clone(), NodeHandler::new())).collect();
+    let mut unacked_transactions: HashMap<usize, (Transaction, HashSet<String>)> = HashMap::new();
+    // The highest offset committed for each key, and the ones each other node hasn't acked yet
+    let mut committed_offsets: HashMap<String, usize> = HashMap::new();
+    let mut unacked_offsets: HashMap<String, HashMap<String, usize>> = cluster.others().iter().map(|node| (node.clone(), HashMap::new())).collect();
+    let resend_interval = GossipConfig::from_env().sync_interval;
+    let mut resend_deadline = Instant::now() + resend_interval;
 
-    for envelope in main_receiver.iter() {
-        if envelope.src == KV_ADDRESS { continue }
-        match envelope.message() {
-            Message::Topology { .. } => {
-                eprintln!("topology: {:?}", envelope);
-                output_sender.send(envelope.reply(Message::TopologyOk)).unwrap();
-            },
+    // (last xid when the poll arrived, when it arrived, poll)
+    let mut poll_replies: Vec<(usize, Instant, Envelope<Message>)> = Vec::new();
 
-            Message::Send { key, msg } => {
-                let xid = xid_assigner.get_xid();
-                let transaction = Transaction {
-                    node: local_node.clone(),
-                    transaction_id: xid,
-                    key: key.to_string(),
-                    message: *msg,
-                };
-                transaction_log.push(transaction.clone());
+    let mut next_seq = 0;
+    let mut sequence_gaps = SequenceGaps::default();
+    let mut gap_report_deadline = Instant::now() + GAP_REPORT_INTERVAL;
 
-                // eprintln!("outgoing txn: {transaction:?}");
-                for other_node in &other_nodes {
-                    output_sender.send(Envelope::new(local_node.clone(), (*other_node).clone(), None, Message::Transactions { transactions: vec![transaction.clone()] })).unwrap();
-                }
+    let mut checker = match std::env::var(CHECK_ENV_VAR).as_deref() {
+        Ok("1") => Some(ConsistencyChecker::default()),
+        Ok("0") | Err(_) => None,
+        Ok(value) => panic!("{CHECK_ENV_VAR} must be 0 or 1, got {value}"),
+    };
+    log::debug!("checking polls and committed offsets: {}", checker.is_some());
 
-                output_sender.send(envelope.reply(Message::SendOk { offset: xid })).unwrap();
-            }
+    loop {
+        // Wake up in time to answer the oldest stashed poll even if nothing else arrives
+        let deadline = poll_replies.iter().map(|(_, stashed_at, _)| *stashed_at + poll_max_wait).min().unwrap_or(resend_deadline).min(resend_deadline).min(gap_report_deadline);
+        let received = main_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()));
 
-            Message::Poll { offsets } => {
-                poll_replies.push((transaction_log.last().map(|t| t.transaction_id).unwrap_or(0), envelope));
-            }
+        match received {
+            Ok(envelope) if output_sender.resend_cached_reply(&envelope) => {}
+            Ok(envelope) if runtime::reply_to_repeated_init(&envelope, |e| output_sender.send(e).unwrap()) => {}
+            Ok(envelope) if envelope.src == xid_source_address => {}
+            Ok(envelope) => match envelope.message() {
+                Message::Topology { .. } => {
+                    log::debug_envelope!(&envelope, "topology");
+                    output_sender.send_all(envelope.try_reply(Message::TopologyOk)).unwrap();
+                },
 
-            Message::CommitOffsets { offsets } => {
-                let mut transactions = vec![];
-                for (key, offset) in offsets {
-                    let xid = xid_assigner.get_xid();
-                    let txn = Transaction {
+                Message::Send { key, msg } => {
+                    let Some(xid) = xid_assigner.get_xid() else {
+                        log::debug_envelope!(&envelope, "no xids while shutting down");
+                        output_sender.send_all(envelope.try_reply(Message::error(ErrorCode::TemporarilyUnavailable, "shutting down".to_string()))).unwrap();
+                        continue
+                    };
+                    let transaction = Transaction {
                         node: local_node.clone(),
                         transaction_id: xid,
-                        key: format!("offsets:{key}"),
-                        message: *offset as u64,
+                        seq: Some(next_seq),
+                        key: key.to_string(),
+                        message: *msg,
                     };
-                    transaction_log.push(txn.clone());
-                    transactions.push(txn);
+                    // The xid's a new one, so this can only fail if the log can't check that
+                    if let Err(e) = transaction_log.insert((xid, local_node.clone()), transaction.key.clone(), transaction.clone()) {
+                        log::debug_envelope!(&envelope, "can't add xid {xid} to the log: {e}");
+                        let text = format!("can't add the message to the log: {e}");
+                        output_sender.send_all(envelope.try_reply(Message::error(ErrorCode::TemporarilyUnavailable, text))).unwrap();
+                        continue
+                    }
+                    next_seq += 1;
+                    if acks_needed > 0 {
+                        unreplicated.insert(xid, HashSet::new());
+                    }
+
+                    output_sender.send_all(Envelope::fanout(local_node.clone(), cluster.others(), Message::Transactions { transactions: vec![transaction.clone()] })).unwrap();
+                    for other_node in cluster.others() {
+                        unacked.get_mut(other_node).unwrap().send_message(xid);
+                    }
+                    if !cluster.others().is_empty() {
+                        unacked_transactions.insert(xid, (transaction, cluster.others().iter().cloned().collect()));
+                    }
+
+                    output_sender.send_all(envelope.try_reply(Message::SendOk { offset: xid })).unwrap();
                 }
 
-                // eprintln!("outgoing txns: {transactions:?}");
-                for other_node in &other_nodes {
-                    output_sender.send(Envelope::new(local_node.clone(), (*other_node).clone(), None, Message::Transactions { transactions: transactions.clone() })).unwrap();
+                // Keys with no messages at or after the polled offset (including keys that have never
+                // been sent to) are left out of the reply
+                Message::Poll { .. } => {
+                    poll_replies.push((transaction_log.last_id().map(|(xid, _)| *xid).unwrap_or(0), Instant::now(), envelope));
                 }
 
-                output_sender.send(envelope.reply(Message::CommitOffsetsOk)).unwrap();
-            }
+                // Offsets are accepted for keys we haven't seen any messages for, since those
+                // messages may have been sent through another node and not reached us yet. An offset
+                // no higher than the one already committed for the key is ignored, and not passed on
+                // to the other nodes, so a reordered or repeated commit can't move a key backwards.
+                Message::CommitOffsets { offsets } => {
+                    let mut raised: HashMap<String, usize> = HashMap::new();
+                    for (key, offset) in offsets {
+                        if !transaction_log.contains_key(key) {
+                            log::debug_envelope!(&envelope, "committing offset {offset} for {key}, which we have no messages for yet");
+                        }
+                        if merge_offset(&mut committed_offsets, key, *offset) {
+                            for pending in unacked_offsets.values_mut() {
+                                merge_offset(pending, key, *offset);
+                            }
+                            raised.insert(key.clone(), *offset);
+                        } else {
+                            log::debug_envelope!(&envelope, "ignoring offset {offset} for {key}, {} is already committed", committed_offsets[key]);
+                        }
+                    }
+                    if !raised.is_empty() {
+                        output_sender.send_all(Envelope::fanout(local_node.clone(), cluster.others(), Message::Offsets { offsets: raised })).unwrap();
+                    }
+                    output_sender.send_all(envelope.try_reply(Message::CommitOffsetsOk)).unwrap();
+                }
+
+                // Keys that have never had an offset committed are left out of the reply, so a
+                // missing key means no committed offset and 0 means offset 0 was committed
+                Message::ListCommittedOffsets { keys } => {
+                    let offsets = list_offsets(&committed_offsets, keys);
+                    if let Some(checker) = &mut checker {
+                        checker.check_list(&envelope, &offsets);
+                    }
+                    output_sender.send_all(envelope.try_reply(Message::ListCommittedOffsetsOk { offsets })).unwrap();
+                }
+
+                Message::Offsets { offsets } => {
+                    for (key, offset) in offsets {
+                        merge_offset(&mut committed_offsets, key, *offset);
+                    }
+                    output_sender.send_all(envelope.try_reply(Message::OffsetsOk { offsets: offsets.clone() })).unwrap();
+                }
+
+                // Anything committed since the acked offsets were sent is still pending
+                Message::OffsetsOk { offsets } => {
+                    if let Some(pending) = unacked_offsets.get_mut(envelope.src.as_str()) {
+                        pending.retain(|key, offset| offsets.get(key).is_none_or(|acked| acked < offset));
+                    }
+                }
 
-            Message::ListCommittedOffsets { keys } => {
-                // FIXME: optimize
-                let mut offsets: HashMap<String, usize> = Default::default();
-                for transaction in &transaction_log {
-                    for query_key in keys {
-                        if transaction.key == format!("offsets:{query_key}") {
-                            offsets.insert(query_key.to_string(), transaction.message as usize);
+                Message::Transactions { transactions } => {
+                    // A transaction the log can't take is left unacked, so the sender tries again
+                    let mut transaction_ids = Vec::new();
+                    for txn in transactions {
+                        let mut new_txn = txn.clone();
+                        attribute_to_sender(&mut new_txn.node, &envelope.src);
+                        if let Err(e) = transaction_log.insert((new_txn.transaction_id, new_txn.node.clone()), new_txn.key.clone(), new_txn.clone()) {
+                            log::debug_envelope!(&envelope, "can't add xid {} to the log: {e}", new_txn.transaction_id);
+                            continue
                         }
+                        if new_txn.node != local_node {
+                            sequence_gaps.received(&new_txn);
+                        }
+                        transaction_ids.push(txn.transaction_id);
+                    }
+                    // Replies to our PollTransactions don't need acknowledging
+                    if envelope.in_reply_to().is_none() {
+                        output_sender.send_all(envelope.try_reply(Message::TransactionsOk { transaction_ids })).unwrap();
                     }
                 }
-                output_sender.send(envelope.reply(Message::ListCommittedOffsetsOk { offsets })).unwrap();
-            }
 
-            Message::Transactions { transactions } => {
-                // FIXME: optimize
-                // eprintln!("incoming txns: {transactions:?}");
-                for new_txn in transactions {
-                    if !transaction_log.iter().any(|committed_txn| committed_txn.transaction_id == new_txn.transaction_id) {
-                        transaction_log.push(new_txn.clone());
+                Message::TransactionsOk { transaction_ids } => {
+                    if let Some(handler) = unacked.get_mut(envelope.src.as_str()) {
+                        handler.sync_ok(transaction_ids);
+                    }
+                    for xid in transaction_ids {
+                        if let Some((_, waiting_for)) = unacked_transactions.get_mut(xid) {
+                            waiting_for.remove(envelope.src.as_str());
+                            if waiting_for.is_empty() {
+                                unacked_transactions.remove(xid);
+                            }
+                        }
+                        let Some(acks) = unreplicated.get_mut(xid) else { continue };
+                        acks.insert(envelope.src.to_string());
+                        if acks.len() >= acks_needed {
+                            unreplicated.remove(xid);
+                        }
                     }
                 }
-                transaction_log.sort_unstable();
-            }
 
-            Message::PollTransactions { first_xid } => {
-                let transactions = transaction_log.iter().filter(|txn| txn.transaction_id >= *first_xid && txn.node == local_node).cloned().collect();
-                output_sender.send(envelope.reply(Message::Transactions { transactions })).unwrap();
+                Message::PollTransactions { first_xid } => {
+                    match transaction_log.range_from(*first_xid) {
+                        Ok(transactions) => {
+                            let transactions = transactions.into_iter().filter(|txn| txn.node == local_node).collect();
+                            output_sender.send_all(envelope.try_reply(Message::Transactions { transactions })).unwrap();
+                        }
+                        // The other node asks again
+                        Err(e) => log::debug_envelope!(&envelope, "can't read the log from xid {first_xid}: {e}"),
+                    }
+                }
+
+                Message::Gaps => {
+                    let missing_xids = transaction_log.missing_xids(GAP_REPORT_XIDS);
+                    output_sender.send_all(envelope.try_reply(Message::GapsOk { missing_xids, nodes: sequence_gaps.missing() })).unwrap();
+                }
+
+                _ => DeadLetters::from_env().handle(&envelope, |e| output_sender.send(e).unwrap()),
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            // stdin was closed
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if Instant::now() >= resend_deadline {
+            for (node, handler) in &unacked {
+                if handler.unacked_messages().is_empty() {
+                    continue
+                }
+                let transactions: Vec<Transaction> = handler.unacked_messages().iter().map(|xid| unacked_transactions[xid].0.clone()).collect();
+                log::debug!("resending {} transactions to {node}", transactions.len());
+                output_sender.send(Envelope::new(local_node.clone(), node.clone(), None, Message::Transactions { transactions })).unwrap();
+            }
+            for (node, offsets) in &unacked_offsets {
+                if offsets.is_empty() {
+                    continue
+                }
+                log::debug!("resending {} committed offsets to {node}", offsets.len());
+                output_sender.send(Envelope::new(local_node.clone(), node.clone(), None, Message::Offsets { offsets: offsets.clone() })).unwrap();
             }
+            resend_deadline = Instant::now() + resend_interval;
+        }
 
-            _ => panic!("Unexpected message at runtime: {envelope:?}")
+        if Instant::now() >= gap_report_deadline {
+            for (node, missing) in sequence_gaps.missing() {
+                let seqs: Vec<usize> = missing.iter().map(|txn| txn.seq).collect();
+                log::debug!("gaps: missing {} transactions from {node}, seqs {seqs:?}", missing.len());
+            }
+            if let Some(last_good_xid) = transaction_log.first_gap() {
+                log::debug!("gaps: log is complete up to xid {last_good_xid}, missing {:?}", transaction_log.missing_xids(GAP_REPORT_XIDS));
+            }
+            gap_report_deadline = Instant::now() + GAP_REPORT_INTERVAL;
         }
 
         if !poll_replies.is_empty() {
-            let last_good_txn = transaction_log.windows(2).find(|ts| ts[1].transaction_id - ts[0].transaction_id > 1).map(|t| t[0].transaction_id) .unwrap_or(usize::MAX);
-            while let Some(idx) = poll_replies.iter().position(|(t, pr)| *t <= last_good_txn) {
-                let (_, env) = poll_replies.remove(idx);
+            // With batched xids a gap in the log may just be another node's unused reservation
+            let first_gap = if xid_batch > 1 { sequence_gaps.first_gap() } else { transaction_log.first_gap() };
+            let last_good_txn = first_gap.unwrap_or(usize::MAX);
+            while let Some(idx) = poll_replies.iter().position(|(t, stashed_at, _)| *t <= last_good_txn || stashed_at.elapsed() >= poll_max_wait) {
+                let (t, stashed_at, env) = poll_replies.remove(idx);
+                if t > last_good_txn {
+                    log::debug_envelope!(&env, "gap after xid {last_good_txn} still unfilled after {:?}, answering poll anyway", stashed_at.elapsed());
+                }
                 let Message::Poll { offsets } = env.message() else {
                     panic!("Unexpected message in poll_replies: {:?}", env);
                 };
 
-                let mut reply: HashMap<String, Vec<(usize, u64)>> = HashMap::new();
-                for transaction in &transaction_log {
-                    if offsets.contains_key(&transaction.key) && transaction.transaction_id >= *offsets.get(&transaction.key).unwrap() {
-                        reply.entry(transaction.key.clone()).or_default().push((transaction.transaction_id, transaction.message));
+                let reply = match poll(&transaction_log, offsets, &local_node, &unreplicated, poll_limit) {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        log::debug_envelope!(&env, "can't read the log: {e}");
+                        let text = format!("can't read the log: {e}");
+                        output_sender.send_all(env.try_reply(Message::error(ErrorCode::TemporarilyUnavailable, text))).unwrap();
+                        continue
                     }
+                };
+                if let Some(checker) = &mut checker {
+                    checker.check_poll(&env, &reply, &transaction_log, &committed_offsets, poll_limit);
                 }
-                output_sender.send(env.reply(Message::PollOk { msgs: reply })).unwrap();
+                output_sender.send_all(env.try_reply(Message::PollOk { msgs: reply })).unwrap();
+            }
+        }
+    }
+
+    // stdin was closed; wait for everything we've sent to be written out
+    drop(xid_assigner);
+    OutputHandler::flush_and_join(output_sender, output_thread);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+    use goofy_goobers::sim::Rng;
+
+    use super::*;
+
+    const XID_CALLERS: usize = 8;
+    const XIDS_PER_CALLER: usize = 100;
+
+    fn transaction(node: &str, transaction_id: usize, seq: usize) -> Transaction {
+        Transaction { node: node.to_string(), transaction_id, seq: Some(seq), key: "k".to_string(), message: 0 }
+    }
+
+    // Peers from before transactions carried their node leave the field out
+    #[test]
+    fn transactions_without_a_node_are_the_senders() {
+        let json = r#"{"type": "transactions", "transactions": [{"transaction_id": 3, "seq": 0, "key": "k", "message": 0}]}"#;
+        let Message::Transactions { transactions } = serde_json::from_str(json).unwrap() else { panic!("not transactions") };
+        let mut txn = transactions[0].clone();
+        assert_eq!(txn.node, "");
+        attribute_to_sender(&mut txn.node, "n2");
+        assert_eq!(txn, transaction("n2", 3, 0));
+
+        attribute_to_sender(&mut txn.node, "n3");
+        assert_eq!(txn.node, "n2");
+    }
+
+    // Peers from before transactions carried a seq leave the field out
+    #[test]
+    fn transactions_without_a_seq_are_accepted_but_not_tracked() {
+        let json = r#"{"type": "transactions", "transactions": [{"node": "n2", "transaction_id": 3, "key": "k", "message": 0}]}"#;
+        let Message::Transactions { transactions } = serde_json::from_str(json).unwrap() else { panic!("not transactions") };
+        assert_eq!(transactions[0], Transaction { seq: None, ..transaction("n2", 3, 0) });
+
+        let mut gaps = SequenceGaps::default();
+        gaps.received(&transactions[0]);
+        assert!(gaps.missing().is_empty());
+        // Transactions that do have one are tracked as usual
+        gaps.received(&transaction("n2", 5, 1));
+        assert_eq!(gaps.missing()["n2"].iter().map(|txn| txn.seq).collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn sequence_gaps_ignore_other_nodes_reservations() {
+        // n1 reserved 1-10 and n2 11-20, so the log has a gap that will never fill
+        let mut gaps = SequenceGaps::default();
+        gaps.received(&transaction("n1", 1, 0));
+        gaps.received(&transaction("n1", 2, 1));
+        gaps.received(&transaction("n2", 11, 0));
+        assert_eq!(gaps.first_gap(), None);
+    }
+
+    #[test]
+    fn sequence_gaps_hold_polls_until_a_missing_seq_arrives() {
+        let mut gaps = SequenceGaps::default();
+        gaps.received(&transaction("n1", 1, 0));
+        gaps.received(&transaction("n2", 11, 0));
+        gaps.received(&transaction("n2", 14, 2));
+        gaps.received(&transaction("n1", 5, 3));
+        // n1's seqs 1 and 2 went somewhere between xids 1 and 5, n2's seq 1 between 11 and 14
+        assert_eq!(gaps.first_gap(), Some(1));
+        assert_eq!(gaps.missing()["n1"].iter().map(|txn| txn.seq).collect::<Vec<_>>(), vec![1, 2]);
+
+        gaps.received(&transaction("n1", 2, 1));
+        gaps.received(&transaction("n1", 3, 2));
+        assert_eq!(gaps.first_gap(), Some(11));
+        gaps.received(&transaction("n2", 12, 1));
+        assert_eq!(gaps.first_gap(), None);
+    }
+
+    // Hands out xids from a counter, and counts how often it's asked. With a gate, the first call
+    // waits until the gate is opened.
+    struct CountingXidSource {
+        next_xid: usize,
+        calls: Arc<AtomicUsize>,
+        gate: Option<Receiver<()>>,
+    }
+
+    impl XidSource for CountingXidSource {
+        fn address(&self) -> &'static str {
+            "counter"
+        }
+
+        fn generate_xids(&mut self, n: usize) -> Option<Vec<usize>> {
+            self.calls.fetch_add(1, AtomicOrdering::SeqCst);
+            if let Some(gate) = self.gate.take() {
+                gate.recv().unwrap();
+            }
+            self.next_xid += n;
+            Some((self.next_xid - n..self.next_xid).collect())
+        }
+    }
+
+    // Everyone who asks while the source is busy is served by the one call after it
+    #[test]
+    fn concurrent_xid_requests_share_one_call() {
+        let (calls, (open_gate, gate)) = (Arc::new(AtomicUsize::new(0)), channel());
+        let requester = XidAssigner::start(CountingXidSource { next_xid: 1, calls: calls.clone(), gate: Some(gate) }, 1);
+
+        let mut first = requester.clone();
+        let first = thread::spawn(move || vec![first.get_xid().unwrap()]);
+        while calls.load(AtomicOrdering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let callers: Vec<_> = (0..XID_CALLERS).map(|_| {
+            let mut requester = requester.clone();
+            thread::spawn(move || requester.get_xids(2).unwrap())
+        }).collect();
+        // Long enough for every caller's request to be queued behind the first
+        thread::sleep(Duration::from_millis(200));
+        open_gate.send(()).unwrap();
+
+        let mut xids: Vec<usize> = first.join().unwrap();
+        for caller in callers {
+            let caller_xids = caller.join().unwrap();
+            assert_eq!(caller_xids.len(), 2);
+            assert!(caller_xids[0] < caller_xids[1]);
+            xids.extend(caller_xids);
+        }
+        assert_eq!(calls.load(AtomicOrdering::SeqCst), 2);
+        xids.sort();
+        assert_eq!(xids, (1..=1 + 2 * XID_CALLERS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn concurrent_callers_get_unique_xids() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let requester = XidAssigner::start(CountingXidSource { next_xid: 1, calls: calls.clone(), gate: None }, 3);
+        let callers: Vec<_> = (0..XID_CALLERS).map(|_| {
+            let mut requester = requester.clone();
+            thread::spawn(move || (0..XIDS_PER_CALLER).map(|_| requester.get_xid().unwrap()).collect::<Vec<_>>())
+        }).collect();
+
+        let mut xids = HashSet::new();
+        for caller in callers {
+            let caller_xids = caller.join().unwrap();
+            // Each caller's own xids still go up
+            assert!(caller_xids.windows(2).all(|pair| pair[0] < pair[1]));
+            for xid in caller_xids {
+                assert!(xids.insert(xid), "xid {xid} handed out twice");
+            }
+        }
+        assert_eq!(xids.len(), XID_CALLERS * XIDS_PER_CALLER);
+    }
+
+    fn log_of(transactions: &[Transaction]) -> SegmentedLog<Transaction> {
+        let mut log = SegmentedLog::new();
+        for txn in transactions {
+            log.insert((txn.transaction_id, txn.node.clone()), txn.key.clone(), txn.clone()).unwrap();
+        }
+        log
+    }
+
+    fn offsets(pairs: &[(&str, usize)]) -> HashMap<String, usize> {
+        pairs.iter().map(|(key, offset)| (key.to_string(), *offset)).collect()
+    }
+
+    // Keys nothing was sent to, or with nothing at or after the offset, aren't in the reply
+    #[test]
+    fn polls_leave_out_keys_with_no_messages() {
+        let log = log_of(&[transaction("n1", 1, 0), transaction("n1", 2, 1)]);
+        let reply = poll(&log, &offsets(&[("k", 0), ("never-sent", 0)]), "n1", &HashMap::new(), 10).unwrap();
+        assert_eq!(reply.keys().collect::<Vec<_>>(), vec!["k"]);
+        assert_eq!(reply["k"].iter().map(|(offset, _)| offset.0).collect::<Vec<_>>(), vec![1, 2]);
+
+        let reply = poll(&log, &offsets(&[("k", 3)]), "n1", &HashMap::new(), 10).unwrap();
+        assert!(reply.is_empty());
+        let json = serde_json::to_value(Message::PollOk { msgs: reply }).unwrap();
+        assert_eq!(json, serde_json::json!({"type": "poll_ok", "msgs": {}}));
+    }
+
+    // Transactions land in the log in xid order however they arrive, and one that's already there
+    // is turned away rather than added twice
+    #[test]
+    fn shuffled_xids_end_up_in_order() {
+        let mut rng = Rng::new(3);
+        let mut arrived: Vec<Transaction> = (1..=50).map(|xid| transaction(["n1", "n2"][xid % 2], xid, xid / 2)).collect();
+        for i in (1..arrived.len()).rev() {
+            arrived.swap(i, rng.below(i + 1));
+        }
+        let mut log = log_of(&arrived);
+        assert!(arrived.iter().all(|txn| !log.insert((txn.transaction_id, txn.node.clone()), txn.key.clone(), txn.clone()).unwrap()));
+
+        let xids: Vec<usize> = log.range_from(0).unwrap().iter().map(|txn| txn.transaction_id).collect();
+        assert_eq!(xids, (1..=50).collect::<Vec<_>>());
+        let reply = poll(&log, &offsets(&[("k", 20)]), "n1", &HashMap::new(), 100).unwrap();
+        assert_eq!(reply["k"].iter().map(|(offset, _)| offset.0).collect::<Vec<_>>(), (20..=50).collect::<Vec<_>>());
+        assert_eq!(log.last_id(), Some(&(50, "n1".to_string())));
+    }
+
+    // xids are only unique per node, so two nodes' transactions with the same one are both kept,
+    // and a repeat of either is still turned away
+    #[test]
+    fn the_same_xid_from_two_nodes_is_two_transactions() {
+        let (from_n1, from_n2) = (transaction("n1", 7, 0), Transaction { message: 1, ..transaction("n2", 7, 0) });
+        let mut log = log_of(&[from_n1.clone(), from_n2.clone()]);
+        for txn in [&from_n2, &from_n1] {
+            assert!(!log.insert((txn.transaction_id, txn.node.clone()), txn.key.clone(), txn.clone()).unwrap());
+        }
+        assert_eq!(log.for_key("k", 0).unwrap(), [from_n1, from_n2]);
+        assert_eq!(log.missing_xids(10), Vec::<usize>::new());
+    }
+
+    // Their messages may have gone through another node and not reached us yet
+    #[test]
+    fn offsets_can_be_committed_for_keys_with_no_messages() {
+        let mut committed = HashMap::new();
+        assert!(merge_offset(&mut committed, "never-sent", 4));
+        assert_eq!(list_offsets(&committed, &["never-sent".to_string()]), offsets(&[("never-sent", 4)]));
+    }
+
+    #[test]
+    fn listed_offsets_tell_uncommitted_keys_from_offset_zero() {
+        let mut committed = HashMap::new();
+        merge_offset(&mut committed, "zero", 0);
+        let listed = list_offsets(&committed, &["zero".to_string(), "uncommitted".to_string()]);
+        assert_eq!(listed, offsets(&[("zero", 0)]));
+        let json = serde_json::to_value(Message::ListCommittedOffsetsOk { offsets: listed }).unwrap();
+        assert_eq!(json, serde_json::json!({"type": "list_committed_offsets_ok", "offsets": {"zero": 0}}));
+    }
+
+    // In quorum mode our own sends stay hidden until enough nodes have them, and so does everything
+    // after them, so a client never skips past one
+    #[test]
+    fn polls_stop_at_our_first_unreplicated_send() {
+        let log = log_of(&[transaction("n1", 1, 0), transaction("n2", 2, 0), transaction("n1", 3, 1), transaction("n2", 4, 1)]);
+        let polled = |unreplicated: &[usize]| -> Vec<usize> {
+            let unreplicated = unreplicated.iter().map(|xid| (*xid, HashSet::new())).collect();
+            poll(&log, &offsets(&[("k", 0)]), "n1", &unreplicated, 10).unwrap().get("k").map_or(vec![], |msgs| msgs.iter().map(|(offset, _)| offset.0).collect())
+        };
+        assert_eq!(polled(&[]), vec![1, 2, 3, 4]);
+        assert_eq!(polled(&[3]), vec![1, 2]);
+        assert_eq!(polled(&[1, 3]), Vec::<usize>::new());
+        // Nobody waits for acks of another node's send
+        assert_eq!(polled(&[2]), vec![1, 2, 3, 4]);
+    }
+
+    // A poll returns at most poll_limit messages per key, the first ones from the polled offset on,
+    // so a client can carry on from just after the last one
+    #[test]
+    fn polls_return_a_capped_prefix() {
+        let sent: Vec<Transaction> = (1..=20).map(|xid| Transaction { key: if xid % 3 == 0 { "other" } else { "k" }.to_string(), ..transaction("n1", xid, xid) }).collect();
+        let log = log_of(&sent);
+        let xids: Vec<usize> = sent.iter().filter(|txn| txn.key == "k").map(|txn| txn.transaction_id).collect();
+
+        let mut offset = 0;
+        let mut polled = vec![];
+        loop {
+            let reply = poll(&log, &offsets(&[("k", offset)]), "n1", &HashMap::new(), 4).unwrap();
+            let Some(msgs) = reply.get("k") else { break };
+            assert!(msgs.len() <= 4);
+            let batch: Vec<usize> = msgs.iter().map(|(offset, _)| offset.0).collect();
+            // Nothing in between was skipped
+            let first = xids.iter().position(|xid| *xid == batch[0]).unwrap();
+            assert_eq!(batch, xids[first..first + batch.len()]);
+            assert!(xids[..first].iter().all(|xid| *xid < offset));
+            offset = batch.last().unwrap() + 1;
+            polled.extend(batch);
+        }
+        assert_eq!(polled, xids);
+    }
+
+    // A commit of 3 after 5, or of 5 again, leaves the key at 5 and isn't passed on
+    #[test]
+    fn commits_never_lower_an_offset() {
+        let mut committed = HashMap::new();
+        assert!(merge_offset(&mut committed, "k", 5));
+        assert!(!merge_offset(&mut committed, "k", 3));
+        assert!(!merge_offset(&mut committed, "k", 5));
+        assert_eq!(list_offsets(&committed, &["k".to_string()]), offsets(&[("k", 5)]));
+        assert!(merge_offset(&mut committed, "k", 6));
+    }
+
+    // Each node keeps the highest offset it's seen for each key, so nodes that get the same commits
+    // in different orders end up with the same offsets
+    #[test]
+    fn committed_offsets_converge_whatever_the_order() {
+        let commits = [("a", 3), ("b", 1), ("a", 7), ("b", 4), ("a", 5), ("c", 0)];
+        let mut rng = Rng::new(3);
+        let mut orders = vec![commits.to_vec(), commits.iter().rev().copied().collect()];
+        for _ in 0..10 {
+            let mut order = commits.to_vec();
+            for i in (1..order.len()).rev() {
+                order.swap(i, rng.below(i + 1));
+            }
+            orders.push(order);
+        }
+        for order in orders {
+            let mut committed = HashMap::new();
+            for (key, offset) in &order {
+                merge_offset(&mut committed, key, *offset);
             }
+            assert_eq!(committed, offsets(&[("a", 7), ("b", 4), ("c", 0)]), "committed in the order {order:?}");
         }
     }
+
+    // The gaps report says which node each missing transaction is from and which xids it falls
+    // between, and the log lists the xids it has no transaction for
+    #[test]
+    fn gaps_are_reported_per_node() {
+        let arrived = [transaction("n2", 1, 0), transaction("n2", 6, 3), transaction("n3", 2, 0), transaction("n3", 4, 1), transaction("n3", 9, 4)];
+        let mut gaps = SequenceGaps::default();
+        arrived.iter().for_each(|txn| gaps.received(txn));
+        let missing = gaps.missing();
+        let between = |node: &str| missing[node].iter().map(|txn| (txn.seq, txn.after_xid, txn.before_xid)).collect::<Vec<_>>();
+        assert_eq!(between("n2"), vec![(1, 1, 6), (2, 1, 6)]);
+        assert_eq!(between("n3"), vec![(2, 4, 9), (3, 4, 9)]);
+
+        let log = log_of(&arrived);
+        assert_eq!(log.missing_xids(10), vec![3, 5, 7, 8]);
+        assert_eq!(log.missing_xids(2), vec![3, 5]);
+
+        gaps.received(&transaction("n2", 3, 1));
+        gaps.received(&transaction("n2", 5, 2));
+        assert!(!gaps.missing().contains_key("n2"));
+    }
+
+    // Replies made by poll and list_offsets pass the checker, and ones that drop, reorder or change
+    // messages, or lower a committed offset, are each counted
+    #[test]
+    fn the_checker_counts_inconsistent_replies() {
+        let log = log_of(&[transaction("n1", 1, 0), transaction("n1", 2, 1), transaction("n1", 3, 2)]);
+        let request = |message: Message| Envelope::new("c1".to_string(), "n1".to_string(), None, message);
+        let poll_from = |offset: usize| request(Message::Poll { offsets: offsets(&[("k", offset)]) });
+        let msgs = |pairs: &[(usize, u64)]| HashMap::from([("k".to_string(), pairs.iter().map(|(xid, msg)| (SafeInt(*xid), SafeInt(*msg))).collect())]);
+        let mut checker = ConsistencyChecker::default();
+        let committed = offsets(&[("k", 2)]);
+
+        checker.check_poll(&poll_from(0), &poll(&log, &offsets(&[("k", 0)]), "n1", &HashMap::new(), 10).unwrap(), &log, &committed, 10);
+        checker.check_poll(&poll_from(2), &poll(&log, &offsets(&[("k", 2)]), "n1", &HashMap::new(), 10).unwrap(), &log, &committed, 10);
+        checker.check_list(&request(Message::ListCommittedOffsets { keys: vec!["k".to_string()] }), &list_offsets(&committed, &["k".to_string()]));
+        assert_eq!(checker.violations, 0);
+
+        // Skips offset 2, which the log has and the first poll returned
+        checker.check_poll(&poll_from(1), &msgs(&[(1, 0), (3, 0)]), &log, &committed, 10);
+        assert_eq!(checker.violations, 2);
+        checker.check_poll(&poll_from(1), &msgs(&[(2, 0), (1, 0)]), &log, &committed, 10);
+        assert!(checker.violations > 2);
+        let before = checker.violations;
+        checker.check_poll(&poll_from(3), &msgs(&[(3, 9)]), &log, &committed, 10);
+        assert_eq!(checker.violations, before + 2);
+        checker.check_list(&request(Message::ListCommittedOffsets { keys: vec!["k".to_string()] }), &offsets(&[("k", 1)]));
+        assert_eq!(checker.violations, before + 3);
+    }
 }
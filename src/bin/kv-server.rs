@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use goofy_goobers::kv::{KvMessage, MemoryKv, LIN_KV, LWW_KV, SEQ_KV};
+use goofy_goobers::log;
+use goofy_goobers::message::{Envelope, NodeId};
+use goofy_goobers::runtime::{OutputHandler, StdinReader};
+use goofy_goobers::validate;
+use goofy_goobers::validate::Step;
+
+// Stands in for Maelstrom's seq-kv, lin-kv and lww-kv services, so a workload's kv traffic can be piped
+// through it without a full Maelstrom run. Requests are answered in the order they arrive, and each
+// service gets its own MemoryKv. There's no init handshake; anything that isn't a kv request
+// addressed to one of the services is logged and dropped.
+const SERVICES: [&str; 3] = [SEQ_KV, LIN_KV, LWW_KV];
+
+// What GG_VALIDATE=1 plays to this binary (see goofy_goobers::validate)
+const VALIDATE_SCRIPT: &[Step] = &[
+    Step { src: "n1", dest: LIN_KV, body: r#"{"type": "write", "key": "a", "value": 2}"#, reply: "write_ok" },
+    Step { src: "n1", dest: LIN_KV, body: r#"{"type": "cas", "key": "a", "from": 2, "to": 3}"#, reply: "cas_ok" },
+    Step { src: "n1", dest: LIN_KV, body: r#"{"type": "read", "key": "a"}"#, reply: "read_ok" },
+    Step { src: "n1", dest: LIN_KV, body: r#"{"type": "read", "key": "b"}"#, reply: "error" },
+];
+
+fn main() {
+    validate::run_if_requested(VALIDATE_SCRIPT);
+    let (output_sender, output_thread) = OutputHandler::start::<KvMessage>();
+    let mut stores: HashMap<NodeId, MemoryKv> = HashMap::new();
+
+    for result in StdinReader::<KvMessage>::new() {
+        let envelope: Envelope<KvMessage> = match result {
+            Ok(envelope) => envelope,
+            Err(e) if e.is_fatal() => {
+                log::debug!("{e}");
+                break
+            }
+            Err(e) => {
+                log::debug!("skipping input: {e}");
+                continue
+            }
+        };
+
+        if !SERVICES.contains(&envelope.dest.as_str()) {
+            log::debug_envelope!(&envelope, "not addressed to a kv service, dropping");
+            continue
+        }
+
+        match envelope.message() {
+            KvMessage::Read { .. } | KvMessage::Write { .. } | KvMessage::Cas { .. } => {
+                let store = stores.entry(envelope.dest.clone()).or_default();
+                let reply = store.handle(envelope.message());
+                log::debug_envelope!(&envelope, "{:?} -> {reply:?}", envelope.message());
+                match envelope.try_reply(reply) {
+                    Some(reply) => output_sender.send(reply).unwrap(),
+                    None => log::debug_envelope!(&envelope, "request has no msg_id, not replying"),
+                }
+            }
+            _ => log::debug_envelope!(&envelope, "not a kv request, dropping: {envelope:?}"),
+        }
+    }
+
+    // stdin was closed; wait for everything we've sent to be written out
+    OutputHandler::flush_and_join(output_sender, output_thread);
+}
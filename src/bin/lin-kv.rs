@@ -0,0 +1,119 @@
+use std::sync::mpsc::channel;
+
+use serde::{Deserialize, Serialize};
+
+use goofy_goobers::error::{ErrorCode, ErrorMessage};
+use goofy_goobers::impl_type_tag;
+use goofy_goobers::log;
+use goofy_goobers::kv::{KvClient, KvMessage, KvPayload, LIN_KV};
+use goofy_goobers::runtime;
+use goofy_goobers::runtime::{DeadLetters, Init, InitMessage, InputHandler, InputHandlerHandle, OutputHandler};
+use goofy_goobers::validate;
+use goofy_goobers::validate::Step;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum NodeMessage {
+    Init { node_id: String, node_ids: Vec<String> },
+    InitOk,
+}
+
+impl_type_tag!(NodeMessage { Init, InitOk });
+
+// Client requests have the same shape as the lin-kv service's, so they're just KvMessages
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+enum Message {
+    Node(NodeMessage),
+    Kv(KvMessage),
+}
+
+impl_type_tag!(Message(Node, Kv));
+
+impl From<KvMessage> for Message {
+    fn from(value: KvMessage) -> Self {
+        Message::Kv(value)
+    }
+}
+
+impl ErrorMessage for Message {
+    fn error(code: ErrorCode, text: String) -> Self {
+        KvMessage::error(code, text).into()
+    }
+}
+
+impl InitMessage for Message {
+    fn as_init(&self) -> Option<Init> {
+        match self {
+            Message::Node(NodeMessage::Init { node_id, node_ids }) => Some(Init { node_id: node_id.clone(), node_ids: node_ids.clone() }),
+            _ => None,
+        }
+    }
+
+    fn init_ok() -> Self {
+        Message::Node(NodeMessage::InitOk)
+    }
+}
+
+impl KvPayload for Message {
+    fn to_kv_message(&self) -> Option<KvMessage> {
+        match self {
+            Message::Kv(m) => Some(m.clone()),
+            _ => None,
+        }
+    }
+}
+
+// What GG_VALIDATE=1 plays to this binary (see goofy_goobers::validate)
+const VALIDATE_SCRIPT: &[Step] = &[
+    validate::INIT,
+    Step::client(r#"{"type": "write", "key": 1, "value": 2}"#, "write_ok"),
+    Step::client(r#"{"type": "cas", "key": 1, "from": 2, "to": 3}"#, "cas_ok"),
+    Step::client(r#"{"type": "read", "key": 1}"#, "read_ok"),
+    Step::client(r#"{"type": "read", "key": 2}"#, "error"),
+];
+
+fn main() {
+    validate::run_if_requested(VALIDATE_SCRIPT);
+    let (output_sender, output_thread) = OutputHandler::start::<Message>();
+    runtime::exit_on_panic(&output_sender);
+    let (main_sender, main_receiver) = channel();
+    let input_handler: InputHandlerHandle<Message> = InputHandler::start::<Message>(vec![main_sender]);
+
+    let Some(init) = runtime::await_init(&main_receiver, |e| output_sender.send(e).unwrap()) else { return };
+
+    // Requests are proxied one at a time, which keeps them trivially linearizable
+    let kv = KvClient::new(init.node_id, LIN_KV.to_string(), input_handler.new_receiver(), output_sender.clone());
+
+    for envelope in main_receiver.iter() {
+        if output_sender.resend_cached_reply(&envelope) { continue }
+        if runtime::reply_to_repeated_init(&envelope, |e| output_sender.send(e).unwrap()) { continue }
+        if envelope.src == LIN_KV { continue }
+        let result = match envelope.message() {
+            Message::Kv(KvMessage::Read { key }) => {
+                kv.read(key).map(|value| KvMessage::ReadOk { value })
+            }
+            Message::Kv(KvMessage::Write { key, value }) => {
+                kv.write(key, *value).map(|_| KvMessage::WriteOk)
+            }
+            Message::Kv(KvMessage::Cas { key, from, to, create_if_not_exists }) => {
+                kv.cas(key, *from, *to, create_if_not_exists.unwrap_or(false)).map(|_| KvMessage::CasOk)
+            }
+            _ => {
+                DeadLetters::from_env().handle(&envelope, |e| output_sender.send(e).unwrap());
+                continue
+            }
+        };
+
+        // Errors from lin-kv (key-does-not-exist, precondition-failed) are passed straight back
+        let reply = result.unwrap_or_else(|e| {
+            log::debug_envelope!(&envelope, "failed: {e:?}");
+            KvMessage::Error { code: e.code as u64, text: e.text }
+        });
+        output_sender.send_all(envelope.try_reply(reply.into())).unwrap();
+    }
+
+    // stdin was closed; wait for everything we've sent to be written out
+    drop(kv);
+    OutputHandler::flush_and_join(output_sender, output_thread);
+}
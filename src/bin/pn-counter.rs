@@ -0,0 +1,198 @@
+use std::sync::mpsc::channel;
+
+use serde::{Deserialize, Serialize};
+
+use goofy_goobers::error::{Error, ErrorCode, ErrorMessage};
+use goofy_goobers::impl_type_tag;
+use goofy_goobers::kv::{KvClient, KvMessage, KvPayload, SEQ_KV};
+use goofy_goobers::log;
+use goofy_goobers::runtime;
+use goofy_goobers::runtime::{Cluster, DeadLetters, Init, InitMessage, InputHandler, InputHandlerHandle, OutputHandler};
+use goofy_goobers::validate;
+use goofy_goobers::validate::Step;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum NodeMessage {
+    Init { node_id: String, node_ids: Vec<String> },
+    InitOk,
+    Add { delta: i64 },
+    AddOk,
+    Read,
+    ReadOk { value: i64 },
+}
+
+impl_type_tag!(NodeMessage { Init, InitOk, Add, AddOk, Read, ReadOk });
+
+// KvMessage comes first so that seq-kv's read_ok isn't mistaken for a workload read_ok
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+enum Message {
+    Kv(KvMessage),
+    Node(NodeMessage),
+}
+
+impl_type_tag!(Message(Kv, Node));
+
+impl From<KvMessage> for Message {
+    fn from(value: KvMessage) -> Self {
+        Message::Kv(value)
+    }
+}
+
+impl KvPayload for Message {
+    fn to_kv_message(&self) -> Option<KvMessage> {
+        match self {
+            Message::Kv(m) => Some(m.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl ErrorMessage for Message {
+    fn error(code: ErrorCode, text: String) -> Self {
+        KvMessage::error(code, text).into()
+    }
+}
+
+impl InitMessage for Message {
+    fn as_init(&self) -> Option<Init> {
+        match self {
+            Message::Node(NodeMessage::Init { node_id, node_ids }) => Some(Init { node_id: node_id.clone(), node_ids: node_ids.clone() }),
+            _ => None,
+        }
+    }
+
+    fn init_ok() -> Self {
+        Message::Node(NodeMessage::InitOk)
+    }
+}
+
+// Each node keeps two grow-only counters in the kv store, one for the sum of its increments and
+// one for the sum of its decrements, so the stored values never have to go negative
+fn increments_key(node: &str) -> String {
+    format!("pn:{node}:p")
+}
+
+fn decrements_key(node: &str) -> String {
+    format!("pn:{node}:n")
+}
+
+// Reads one of another node's counters, which won't exist until that node's first add
+fn read_counter(kv: &KvClient<Message>, key: &str) -> Result<u64, Error> {
+    match kv.read(key) {
+        Ok(value) => Ok(value),
+        Err(e) if e.code == ErrorCode::KeyDoesNotExist => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+// Adds delta to one of our counters in the kv store. Our copy only keeps the new total if the
+// write may have happened: after a definite error the store still has the old one, but after an
+// indefinite one it may have the new one, and writing a smaller total later would lose this add.
+fn add(kv: &KvClient<Message>, counter: &mut u64, key: &str, delta: i64) -> Result<(), Error> {
+    let total = counter.checked_add(delta.unsigned_abs())
+        .ok_or_else(|| Error { code: ErrorCode::MalformedRequest, text: format!("adding {delta} to {key} would overflow it") })?;
+    let written = kv.write(key, total);
+    if !matches!(&written, Err(e) if e.code.is_definite()) {
+        *counter = total;
+    }
+    written
+}
+
+// The counter's value, from the sums of every node's increments and decrements
+fn value(total_increments: u64, total_decrements: u64) -> Result<i64, Error> {
+    i64::try_from(i128::from(total_increments) - i128::from(total_decrements))
+        .map_err(|_| Error { code: ErrorCode::Crash, text: format!("+{total_increments} -{total_decrements} doesn't fit in an i64") })
+}
+
+fn read(kv: &KvClient<Message>, cluster: &Cluster, increments: u64, decrements: u64) -> Result<i64, Error> {
+    let overflow = || Error { code: ErrorCode::Crash, text: "the counter's increments or decrements overflowed".to_string() };
+    let mut total_increments = increments;
+    let mut total_decrements = decrements;
+    for node in cluster.others() {
+        total_increments = total_increments.checked_add(read_counter(kv, &increments_key(node))?).ok_or_else(overflow)?;
+        total_decrements = total_decrements.checked_add(read_counter(kv, &decrements_key(node))?).ok_or_else(overflow)?;
+    }
+    value(total_increments, total_decrements)
+}
+
+// What GG_VALIDATE=1 plays to this binary (see goofy_goobers::validate)
+const VALIDATE_SCRIPT: &[Step] = &[
+    validate::INIT,
+    Step::client(r#"{"type": "add", "delta": -3}"#, "add_ok"),
+    Step::client(r#"{"type": "read"}"#, "read_ok"),
+];
+
+fn main() {
+    validate::run_if_requested(VALIDATE_SCRIPT);
+    let (output_sender, output_thread) = OutputHandler::start::<Message>();
+    runtime::exit_on_panic(&output_sender);
+    let (main_sender, main_receiver) = channel();
+    let input_handler: InputHandlerHandle<Message> = InputHandler::start::<Message>(vec![main_sender]);
+
+    let Some(init) = runtime::await_init(&main_receiver, |e| output_sender.send(e).unwrap()) else { return };
+    let cluster = Cluster::from(init);
+    let local_node = cluster.local.clone();
+
+    let kv = KvClient::new(local_node.clone(), SEQ_KV.to_string(), input_handler.new_receiver(), output_sender.clone());
+
+    // Only this node writes its own counters, so our copies are always up to date
+    let mut increments: u64 = 0;
+    let mut decrements: u64 = 0;
+
+    for envelope in main_receiver.iter() {
+        if output_sender.resend_cached_reply(&envelope) { continue }
+        if runtime::reply_to_repeated_init(&envelope, |e| output_sender.send(e).unwrap()) { continue }
+        if envelope.src == SEQ_KV { continue }
+        match envelope.message() {
+            Message::Node(NodeMessage::Add { delta }) => {
+                let added = if *delta >= 0 {
+                    add(&kv, &mut increments, &increments_key(&local_node), *delta)
+                } else {
+                    add(&kv, &mut decrements, &decrements_key(&local_node), *delta)
+                };
+                log::debug_envelope!(&envelope, "add {delta}: +{increments} -{decrements}");
+                let reply = match added {
+                    Ok(()) => Message::Node(NodeMessage::AddOk),
+                    Err(e) => Message::error(e.code, e.text),
+                };
+                output_sender.send_all(envelope.try_reply(reply)).unwrap();
+            }
+
+            Message::Node(NodeMessage::Read) => {
+                let reply = match read(&kv, &cluster, increments, decrements) {
+                    Ok(value) => Message::Node(NodeMessage::ReadOk { value }),
+                    Err(e) => Message::error(e.code, e.text),
+                };
+                output_sender.send_all(envelope.try_reply(reply)).unwrap();
+            }
+
+            _ => DeadLetters::from_env().handle(&envelope, |e| output_sender.send(e).unwrap()),
+        }
+    }
+
+    // stdin was closed; wait for everything we've sent to be written out
+    drop(kv);
+    OutputHandler::flush_and_join(output_sender, output_thread);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_is_the_difference_of_the_totals() {
+        assert_eq!(value(10, 3).unwrap(), 7);
+        assert_eq!(value(3, 10).unwrap(), -7);
+        assert_eq!(value(u64::MAX, u64::MAX).unwrap(), 0);
+        assert_eq!(value(i64::MAX as u64, 0).unwrap(), i64::MAX);
+        assert_eq!(value(0, i64::MAX as u64 + 1).unwrap(), i64::MIN);
+    }
+
+    #[test]
+    fn a_value_outside_i64_is_an_error() {
+        assert_eq!(value(i64::MAX as u64 + 1, 0).unwrap_err().code, ErrorCode::Crash);
+        assert_eq!(value(0, u64::MAX).unwrap_err().code, ErrorCode::Crash);
+    }
+}
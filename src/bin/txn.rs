@@ -1,18 +1,38 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
-use std::io::{BufRead, Write};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::{panic, process, thread};
-use std::char::ParseCharError;
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::cmp::Ordering;
-use std::sync::{Arc, atomic, Mutex};
-use std::sync::atomic::{AtomicU64, AtomicUsize};
-use std::time::Duration;
-use serde::de::DeserializeOwned;
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize, Serializer};
 use serde::ser::SerializeSeq;
-use goofy_goobers::error::ErrorCode;
-use goofy_goobers::message::Envelope;
+use goofy_goobers::clock::{Clock, SystemClock};
+use goofy_goobers::error::{ErrorCode, ErrorMessage};
+use goofy_goobers::gossip::{GossipConfig, NodeHandler};
+use goofy_goobers::log;
+use goofy_goobers::message;
+use goofy_goobers::message::{Envelope, MessageIdGenerator};
+use goofy_goobers::protocol::attribute_to_sender;
+use goofy_goobers::{impl_error_message, impl_init_message, impl_type_tag};
+use goofy_goobers::runtime;
+use goofy_goobers::runtime::{Cluster, DeadLetters, InputHandler, InputHandlerHandle, OutputHandler};
+use goofy_goobers::sim::Rng;
+use goofy_goobers::validate;
+use goofy_goobers::validate::Step;
+
+// Transactions more recent than this (per node) are never compacted, so peers polling with a
+// slightly stale first_xid can still be served
+const RETAINED_TRANSACTIONS: usize = 100;
+// Compact after this many transactions have been appended to the log, from any node
+const COMPACTION_INTERVAL: usize = 50;
+// Give up on a transaction the other nodes haven't all answered a PrepareTxn for after this many
+// resends. Nothing has been committed by then, so the client gets a definite abort.
+const PREPARE_RESENDS: usize = 5;
+// How often to ask the other nodes for the transactions we've missed, on top of the pushes. Set
+// GG_TXN_POLL_INTERVAL_MS to change it. Each wait is picked at random from half to one and a half
+// times the interval, so the nodes don't all poll each other at once.
+const POLL_INTERVAL_ENV_VAR: &str = "GG_TXN_POLL_INTERVAL_MS";
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1000);
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 #[serde(try_from="char", into="char")]
@@ -41,9 +61,9 @@ impl TryFrom<char> for OpType {
     }
 }
 
-impl Into<char> for OpType {
-    fn into(self) -> char {
-        match self {
+impl From<OpType> for char {
+    fn from(value: OpType) -> Self {
+        match value {
             OpType::Read => 'r',
             OpType::Write => 'w',
         }
@@ -57,6 +77,17 @@ struct Operation {
     value: Option<u64>,
 }
 
+impl Operation {
+    // Clients send writes with the value to write and reads with a null value for us to fill in
+    fn validate(&self) -> Result<(), String> {
+        match (&self.optype, self.value) {
+            (OpType::Write, None) => Err(format!("write to key {} has no value", self.key)),
+            (OpType::Read, Some(value)) => Err(format!("read of key {} already has a value ({value})", self.key)),
+            _ => Ok(()),
+        }
+    }
+}
+
 impl Serialize for Operation {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
         let mut seq = serializer.serialize_seq(Some(3))?;
@@ -67,22 +98,223 @@ impl Serialize for Operation {
     }
 }
 
+// How many transactions from each node had been applied where a transaction ran, including itself
+type VectorClock = HashMap<String, usize>;
+
+// Whether everything a has seen, b has seen too, and b has seen more
+fn happens_before(a: &VectorClock, b: &VectorClock) -> bool {
+    a.iter().all(|(node, count)| b.get(node).is_some_and(|b_count| count <= b_count))
+        && b.iter().any(|(node, count)| a.get(node).is_none_or(|a_count| a_count < count))
+}
+
+fn merge_clocks(into: &mut VectorClock, other: &VectorClock) {
+    for (node, count) in other {
+        let entry = into.entry(node.clone()).or_default();
+        *entry = (*entry).max(*count);
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 struct Transaction {
+    // Transactions from before this was added have none (see attribute_to_sender)
+    #[serde(default)]
     node: String,
     transaction_id: usize,
     operations: Vec<Operation>,
+    // Transactions from before clocks were added have none, and are ordered by xid alone
+    #[serde(default)]
+    clock: VectorClock,
+}
+
+// The transaction whose write is currently reflected in the view for a key. Which one a node has
+// for a key is that key's version as far as conflicts are concerned.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+struct Writer {
+    transaction_id: usize,
+    node: String,
+    clock: VectorClock,
+}
+
+impl Writer {
+    fn of(txn: &Transaction) -> Writer {
+        Writer { transaction_id: txn.transaction_id, node: txn.node.clone(), clock: txn.clock.clone() }
+    }
+
+    fn is_same_transaction(&self, other: &Writer) -> bool {
+        (self.transaction_id, &self.node) == (other.transaction_id, &other.node)
+    }
+
+    // A causally later transaction always wins; concurrent ones are ordered by (transaction_id, node).
+    // A transaction never supersedes itself, so applying one twice changes nothing.
+    fn is_superseded_by(&self, other: &Writer) -> bool {
+        if self.is_same_transaction(other) || happens_before(&other.clock, &self.clock) {
+            false
+        } else if happens_before(&self.clock, &other.clock) {
+            true
+        } else {
+            (self.transaction_id, &self.node) <= (other.transaction_id, &other.node)
+        }
+    }
+}
+
+// The writer a transaction saw for one of the keys it reads or writes, or None if nothing had
+// been written to the key yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyVersion {
+    key: u64,
+    writer: Option<Writer>,
+}
+
+impl Transaction {
+    fn writes(&self) -> impl Iterator<Item=(u64, u64)> + '_ {
+        self.operations.iter()
+            .filter(|op| op.optype == OpType::Write)
+            .map(|op| (op.key, op.value.unwrap()))
+    }
 }
 
 impl PartialOrd<Self> for Transaction {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.transaction_id.partial_cmp(&other.transaction_id)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Transaction {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        self.transaction_id.cmp(&other.transaction_id)
+    }
+}
+
+// Every transaction known to this node, along with a materialized view of the state they produce.
+//
+// Transactions are merged in causal order: for each key, a write from a transaction whose vector
+// clock is later than the current writer's always wins, regardless of the order the transactions
+// arrive in. Concurrent writes are ordered by (transaction_id, node). Local xids also behave as
+// Lamport clocks (they're bumped past every xid we receive), so this only differs from plain xid
+// order for transactions with no clock.
+//
+// Since each key only compares the incoming write against its current writer, a transaction that
+// arrives after later ones from the same node have been applied only changes the keys where it
+// wins, which leaves the view the same as if everything had arrived in order.
+#[derive(Default)]
+struct TransactionLog {
+    // Materialized view of every transaction applied so far, including compacted ones
+    state: HashMap<u64, u64>,
+    // The write currently reflected in `state` for each key
+    writers: HashMap<u64, Writer>,
+    // Per-node tail of transactions that haven't been compacted yet
+    transactions: HashMap<String, Vec<Transaction>>,
+    // Highest compacted xid per node. A transaction at or below it that turns up late is applied to
+    // the view but not kept.
+    compacted_xids: HashMap<String, usize>,
+    // Transactions appended since the last compaction
+    appended: usize,
+}
+
+impl TransactionLog {
+    fn append(&mut self, txn: Transaction) {
+        // Only a transaction's last write to each key is visible outside it
+        let writes: HashMap<u64, u64> = txn.writes().collect();
+        let txn_writer = Writer::of(&txn);
+        for (key, value) in writes {
+            if self.writers.get(&key).map(|writer| writer.is_superseded_by(&txn_writer)).unwrap_or(true) {
+                self.state.insert(key, value);
+                self.writers.insert(key, txn_writer.clone());
+            }
+        }
+
+        let compacted_xid = self.compacted_xids.get(&txn.node).copied();
+        let node_txns = self.transactions.entry(txn.node.clone()).or_default();
+        if let Some(newest_xid) = node_txns.last().map(|newest| newest.transaction_id).or(compacted_xid) {
+            if txn.transaction_id < newest_xid {
+                log::debug!("late transaction {} from {}, already applied up to {newest_xid}", txn.transaction_id, txn.node);
+            }
+        }
+        if compacted_xid.is_some_and(|xid| txn.transaction_id <= xid) {
+            return
+        }
+        let position = node_txns.partition_point(|known_txn| *known_txn < txn);
+        node_txns.insert(position, txn);
+
+        self.appended += 1;
+        if self.appended >= COMPACTION_INTERVAL {
+            self.compact();
+        }
+    }
+
+    // The current value and writer of each of the given keys
+    fn snapshot(&self, keys: impl IntoIterator<Item=u64>) -> Snapshot {
+        let versions: Vec<KeyVersion> = keys.into_iter()
+            .collect::<BTreeSet<u64>>()
+            .into_iter()
+            .map(|key| KeyVersion { key, writer: self.writers.get(&key).cloned() })
+            .collect();
+        Snapshot {
+            values: versions.iter().filter_map(|version| Some((version.key, *self.state.get(&version.key)?))).collect(),
+            versions,
+        }
+    }
+
+    // Whether the transaction is in its node's tail. Compacted transactions aren't tracked
+    // individually, so one of those that's sent again is appended again, which leaves the view as
+    // it was. Each tail is kept in xid order, so this is a binary search.
+    fn is_known(&self, txn: &Transaction) -> bool {
+        self.transactions.get(&txn.node)
+                .is_some_and(|txns| txns.binary_search_by_key(&txn.transaction_id, |known_txn| known_txn.transaction_id).is_ok())
+    }
+
+    // Drops old transactions from each node's tail. Their writes are already reflected in the
+    // materialized view, so this only limits how far back PollTransactions can serve - at least
+    // RETAINED_TRANSACTIONS per node are always kept.
+    fn compact(&mut self) {
+        self.appended = 0;
+        for (node, txns) in self.transactions.iter_mut() {
+            let count = txns.len().saturating_sub(RETAINED_TRANSACTIONS);
+            if count > 0 {
+                self.compacted_xids.insert(node.clone(), txns[count - 1].transaction_id);
+                txns.drain(..count);
+                log::debug!("compacted {count} transactions from {node}, {} left", txns.len());
+            }
+        }
+    }
+}
+
+// The state a transaction reads from, taken in one go when it starts, along with the writer of
+// every key it reads or writes.
+//
+// Every read in a transaction is served from its snapshot with the transaction's own earlier writes
+// on top, so a transaction never sees a value change between two of its reads.
+//
+// A transaction that writes only commits once every other node has checked it with a PrepareTxn
+// (see TxnNode::prepare_conflict). Each node vouches for its own transactions: a node answers with
+// a conflict if it has committed a write to one of the keys that the snapshot doesn't include, or
+// if one of its own transactions that's waiting to commit writes a key this one touches, or touches
+// a key this one writes. When every node has agreed the snapshot is checked against the local log
+// once more, since a transaction from another node may have been applied while we waited. So a
+// committed transaction has seen every committed write to the keys it touches, and no two
+// transactions that read a key and then write it can both commit having read the same value. A
+// conflict is reported to the client as txn-conflict, and nothing is sent to the other nodes.
+//
+// Read-only transactions aren't checked at all, and can see a node's view from before writes it
+// hasn't heard about yet.
+struct Snapshot {
+    values: HashMap<u64, u64>,
+    versions: Vec<KeyVersion>,
+}
+
+impl Snapshot {
+    fn value(&self, key: u64) -> Option<u64> {
+        self.values.get(&key).copied()
+    }
+
+    // The first key whose writer in the log is no longer the one in the snapshot
+    fn conflict(&self, log: &TransactionLog) -> Option<u64> {
+        self.versions.iter()
+            .find(|version| match (&version.writer, log.writers.get(&version.key)) {
+                (Some(seen), Some(current)) => !seen.is_same_transaction(current),
+                (seen, current) => seen.is_some() != current.is_some(),
+            })
+            .map(|version| version.key)
     }
 }
 
@@ -105,7 +337,14 @@ enum Message {
 
     // Node to node messages
     Transactions { transactions: Vec<Transaction>},
+    // The reply to a pushed Transactions. Pushes are resent every sync interval until they're acked.
+    TransactionsOk { transaction_ids: Vec<usize> },
     PollTransactions { first_xid: usize },
+    // Asks another node whether a transaction can commit, given the writer it saw for each key it
+    // touches. It's resent every sync interval until it's answered.
+    PrepareTxn { prepare_id: usize, versions: Vec<KeyVersion>, written_keys: Vec<u64> },
+    PrepareTxnOk { prepare_id: usize },
+    PrepareTxnConflict { prepare_id: usize, key: u64 },
 
     Error {
         code: u64,
@@ -113,205 +352,907 @@ enum Message {
     },
 }
 
-struct InputHandler;
+impl_init_message!(Message);
+impl_error_message!(Message);
+impl_type_tag!(Message { Init, InitOk, Topology, TopologyOk, Txn, TxnOk, Transactions, TransactionsOk, PollTransactions, PrepareTxn, PrepareTxnOk, PrepareTxnConflict, Error });
 
-struct InputHandlerHandle<B: Clone + Debug + Send> {
-    new_subscriber_sender: Sender<Sender<Envelope<B>>>
+// A local transaction that writes, waiting for the other nodes to answer its PrepareTxn
+struct PendingTxn {
+    request: Envelope<Message>,
+    operations: Vec<Operation>,
+    snapshot: Snapshot,
+    written_keys: Vec<u64>,
+    unanswered: HashSet<String>,
+    resends: usize,
 }
 
-impl<B: Clone + Debug + Send> InputHandlerHandle<B> {
-    fn new_receiver(&self) -> Receiver<Envelope<B>> {
-        let (sender, receiver) = channel();
-        self.new_subscriber_sender.send(sender).unwrap();
-        receiver
+impl PendingTxn {
+    // The first key one of the transactions writes that the other reads or writes
+    fn overlap(&self, versions: &[KeyVersion], written_keys: &[u64]) -> Option<u64> {
+        let touched_keys = |versions: &[KeyVersion]| versions.iter().map(|version| version.key).collect::<HashSet<u64>>();
+        let (our_keys, their_keys) = (touched_keys(&self.snapshot.versions), touched_keys(versions));
+        self.written_keys.iter().find(|key| their_keys.contains(key))
+            .or_else(|| written_keys.iter().find(|key| our_keys.contains(key)))
+            .copied()
     }
 }
 
-impl InputHandler {
-    pub fn start<B: Clone + Debug + Send + DeserializeOwned + 'static>(mut subscribers: Vec<Sender<Envelope<B>>>) -> InputHandlerHandle<B> {
-        let (new_subscriber_sender, new_subscriber_receiver) = channel();
-
-        thread::spawn(move || {
-            loop {
-                for line in std::io::stdin().lock().lines().map(Result::unwrap) {
-                    while let Ok(r) = new_subscriber_receiver.try_recv() {
-                        subscribers.push(r);
-                    };
-                    // eprintln!("{}", line);
-                    let env: Envelope<B> = serde_json::from_str(&line).unwrap();
-                    for subscriber in subscribers.iter() {
-                        let _ = subscriber.send(env.clone());
+// All of a node's transaction state. Each method handles one event and returns the envelopes to
+// send, without doing any I/O itself, so several nodes can be run in one process with whatever
+// delivery schedule a test wants (see the tests below), each with its own MessageIdGenerator.
+struct TxnNode<'a> {
+    node_id: String,
+    others: Vec<String>,
+    ids: &'a MessageIdGenerator,
+    local_xid: usize,
+    // Every transaction we've applied, by node
+    clock: VectorClock,
+    log: TransactionLog,
+    // The xids of our transactions each other node hasn't acked yet, and the transactions themselves
+    unacked: HashMap<String, NodeHandler<usize>>,
+    unacked_transactions: HashMap<usize, Transaction>,
+    // Our latest committed write to each key, which other nodes' transactions have to have seen
+    own_writers: HashMap<u64, Writer>,
+    // Our transactions waiting for the other nodes to agree they can commit, by prepare_id
+    pending: BTreeMap<usize, PendingTxn>,
+    next_prepare_id: usize,
+}
+
+impl<'a> TxnNode<'a> {
+    fn new(cluster: &Cluster, ids: &'a MessageIdGenerator) -> TxnNode<'a> {
+        TxnNode {
+            node_id: cluster.local.clone(),
+            others: cluster.others().to_vec(),
+            ids,
+            local_xid: 0,
+            clock: VectorClock::new(),
+            log: TransactionLog::default(),
+            unacked: cluster.others().iter().map(|node| (node.clone(), NodeHandler::new())).collect(),
+            unacked_transactions: HashMap::new(),
+            own_writers: HashMap::new(),
+            pending: BTreeMap::new(),
+            next_prepare_id: 0,
+        }
+    }
+
+    fn step(&mut self, envelope: &Envelope<Message>) -> Vec<Envelope<Message>> {
+        match envelope.message() {
+            Message::Topology { .. } => {
+                log::debug_envelope!(envelope, "topology");
+                envelope.try_reply_with_ids(self.ids, Message::TopologyOk).into_iter().collect()
+            },
+
+            Message::Txn { operations } => self.txn(envelope, operations),
+
+            Message::Transactions { transactions } => {
+                for txn in transactions {
+                    let mut new_txn = txn.clone();
+                    attribute_to_sender(&mut new_txn.node, &envelope.src);
+                    if !self.log.is_known(&new_txn) {
+                        self.local_xid = self.local_xid.max(new_txn.transaction_id + 1);
+                        merge_clocks(&mut self.clock, &new_txn.clock);
+                        self.log.append(new_txn);
                     }
                 }
+                // Replies to our PollTransactions don't need acknowledging
+                if envelope.in_reply_to().is_some() {
+                    return vec![]
+                }
+                let transaction_ids = transactions.iter().map(|txn| txn.transaction_id).collect();
+                envelope.try_reply_with_ids(self.ids, Message::TransactionsOk { transaction_ids }).into_iter().collect()
             }
-        });
 
-        InputHandlerHandle { new_subscriber_sender }
-    }
-}
+            Message::TransactionsOk { transaction_ids } => {
+                if let Some(handler) = self.unacked.get_mut(envelope.src.as_str()) {
+                    handler.sync_ok(transaction_ids);
+                }
+                for xid in transaction_ids {
+                    if self.unacked.values().all(|handler| !handler.unacked_messages().contains(xid)) {
+                        self.unacked_transactions.remove(xid);
+                    }
+                }
+                vec![]
+            }
+
+            Message::PrepareTxn { prepare_id, versions, written_keys } => {
+                let prepare_id = *prepare_id;
+                let reply = match self.prepare_conflict(versions, written_keys) {
+                    Some(key) => {
+                        log::debug_envelope!(envelope, "prepare {prepare_id} conflicts on key {key}");
+                        Message::PrepareTxnConflict { prepare_id, key }
+                    }
+                    None => Message::PrepareTxnOk { prepare_id },
+                };
+                envelope.try_reply_with_ids(self.ids, reply).into_iter().collect()
+            }
+
+            Message::PrepareTxnOk { prepare_id } => {
+                let Some(pending) = self.pending.get_mut(prepare_id) else { return vec![] };
+                pending.unanswered.remove(envelope.src.as_str());
+                if !pending.unanswered.is_empty() {
+                    return vec![]
+                }
+                let pending = self.pending.remove(prepare_id).unwrap();
+                // Another node's transaction may have been applied while we were waiting
+                if let Some(key) = pending.snapshot.conflict(&self.log) {
+                    return pending.request.try_reply_with_ids(self.ids, conflict_error(key)).into_iter().collect()
+                }
+                self.commit(&pending.request, pending.operations)
+            }
 
-struct OutputHandler;
+            Message::PrepareTxnConflict { prepare_id, key } => {
+                let Some(pending) = self.pending.remove(prepare_id) else { return vec![] };
+                log::debug_envelope!(&pending.request, "txn conflict on key {key} at {}", envelope.src);
+                pending.request.try_reply_with_ids(self.ids, conflict_error(*key)).into_iter().collect()
+            }
 
-impl OutputHandler {
-    fn start<B: Debug + Serialize + Send + 'static>() -> Sender<Envelope<B>> {
-        let (sender, receiver) = channel();
+            Message::PollTransactions { first_xid } => {
+                let transactions = match self.log.transactions.get(&self.node_id) {
+                    Some(node_txns) => node_txns.iter().filter(|txn| txn.transaction_id >= *first_xid).cloned().collect(),
+                    None => vec![],
+                };
+                envelope.try_reply_with_ids(self.ids, Message::Transactions { transactions }).into_iter().collect()
+            }
 
-        thread::spawn(move || {
-            let mut stdout = std::io::stdout().lock();
-            for envelope in receiver {
-                serde_json::to_writer(&mut stdout, &envelope).unwrap();
-                stdout.write(b"\n").unwrap();
-                stdout.flush().unwrap();
+            _ => {
+                let mut outbound = vec![];
+                DeadLetters::from_env().handle_with_ids(self.ids, envelope, |e| outbound.push(e));
+                outbound
             }
+        }
+    }
+
+    fn txn(&mut self, envelope: &Envelope<Message>, operations: &[Operation]) -> Vec<Envelope<Message>> {
+        if let Some(e) = operations.iter().find_map(|op| op.validate().err()) {
+            log::debug_envelope!(envelope, "malformed txn: {e}");
+            return envelope.try_reply_with_ids(self.ids, Message::error(ErrorCode::MalformedRequest, e)).into_iter().collect()
+        }
+
+        let snapshot = self.log.snapshot(operations.iter().map(|op| op.key));
+
+        // Fill in the reads from the snapshot, with our own writes on top
+        let mut own_writes: HashMap<u64, u64> = Default::default();
+        let mut filled_in_operations: Vec<Operation> = Default::default();
+        for op in operations {
+            filled_in_operations.push(match op.optype {
+                OpType::Read => Operation {
+                    optype: OpType::Read,
+                    key: op.key,
+                    value: own_writes.get(&op.key).copied().or_else(|| snapshot.value(op.key)),
+                },
+                OpType::Write => {
+                    own_writes.insert(op.key, op.value.expect("writes are validated to have a value"));
+                    op.to_owned()
+                }
+            });
+        }
+
+        // A read-only transaction changes nothing, so there's nothing to commit or broadcast
+        if own_writes.is_empty() {
+            return envelope.try_reply_with_ids(self.ids, Message::TxnOk { operations: filled_in_operations }).into_iter().collect()
+        }
+
+        let written_keys: Vec<u64> = own_writes.into_keys().collect::<BTreeSet<u64>>().into_iter().collect();
+        if let Some(key) = self.pending.values().find_map(|pending| pending.overlap(&snapshot.versions, &written_keys)) {
+            log::debug_envelope!(envelope, "txn conflict on key {key} with one of our own pending transactions");
+            return envelope.try_reply_with_ids(self.ids, conflict_error(key)).into_iter().collect()
+        }
+        if self.others.is_empty() {
+            return self.commit(envelope, filled_in_operations)
+        }
+
+        let prepare_id = self.next_prepare_id;
+        self.next_prepare_id += 1;
+        let prepare = Message::PrepareTxn { prepare_id, versions: snapshot.versions.clone(), written_keys: written_keys.clone() };
+        self.pending.insert(prepare_id, PendingTxn {
+            request: envelope.clone(),
+            operations: filled_in_operations,
+            snapshot,
+            written_keys,
+            unanswered: self.others.iter().cloned().collect(),
+            resends: 0,
         });
+        Envelope::fanout_with_ids(self.ids, self.node_id.clone(), &self.others, prepare)
+    }
 
-        sender
+    // Why another node's transaction, which saw the given writers for the keys it touches, can't
+    // commit: the first key that one of our committed writes it didn't see, or one of our pending
+    // transactions, conflicts on
+    fn prepare_conflict(&self, versions: &[KeyVersion], written_keys: &[u64]) -> Option<u64> {
+        versions.iter()
+            .find(|version| self.own_writers.get(&version.key).is_some_and(|own| {
+                !version.writer.as_ref().is_some_and(|seen| seen.is_same_transaction(own) || own.is_superseded_by(seen))
+            }))
+            .map(|version| version.key)
+            .or_else(|| self.pending.values().find_map(|pending| pending.overlap(versions, written_keys)))
     }
-}
 
-fn roll_up_transactions(transactions: &Vec<Transaction>) -> HashMap<u64, u64> {
-    let mut values = HashMap::new();
-    for txn in transactions {
-        for operation in &txn.operations {
-            if operation.optype == OpType::Write {
-                values.insert(operation.key, operation.value.unwrap());
+    // Applies one of our transactions, sends it to the other nodes and replies to the client
+    fn commit(&mut self, request: &Envelope<Message>, operations: Vec<Operation>) -> Vec<Envelope<Message>> {
+        *self.clock.entry(self.node_id.clone()).or_default() += 1;
+        let txn = Transaction {
+            node: self.node_id.clone(),
+            transaction_id: self.local_xid,
+            operations: operations.clone(),
+            clock: self.clock.clone(),
+        };
+        self.local_xid += 1;
+
+        let writer = Writer::of(&txn);
+        for (key, _) in txn.writes() {
+            self.own_writers.insert(key, writer.clone());
+        }
+        self.log.append(txn.clone());
+
+        // Broadcast the transaction to other nodes
+        let mut outbound = Envelope::fanout_with_ids(self.ids, self.node_id.clone(), &self.others, Message::Transactions { transactions: vec![txn.clone()] });
+        for other_node in &self.others {
+            self.unacked.get_mut(other_node).unwrap().send_message(txn.transaction_id);
+        }
+        if !self.others.is_empty() {
+            self.unacked_transactions.insert(txn.transaction_id, txn);
+        }
+
+        outbound.extend(request.try_reply_with_ids(self.ids, Message::TxnOk { operations }));
+        outbound
+    }
+
+    // Resends every transaction another node hasn't acked yet, and every PrepareTxn that hasn't been
+    // answered. A transaction whose PrepareTxn has been resent PREPARE_RESENDS times is given up on.
+    fn resend(&mut self) -> Vec<Envelope<Message>> {
+        let mut outbound = vec![];
+        let mut given_up = vec![];
+        for (prepare_id, pending) in self.pending.iter_mut() {
+            if pending.resends == PREPARE_RESENDS {
+                given_up.push(*prepare_id);
+                continue
             }
+            pending.resends += 1;
+            let unanswered: Vec<String> = self.others.iter().filter(|node| pending.unanswered.contains(*node)).cloned().collect();
+            let prepare = Message::PrepareTxn { prepare_id: *prepare_id, versions: pending.snapshot.versions.clone(), written_keys: pending.written_keys.clone() };
+            outbound.extend(Envelope::fanout_with_ids(self.ids, self.node_id.clone(), &unanswered, prepare));
+        }
+        for prepare_id in given_up {
+            let pending = self.pending.remove(&prepare_id).unwrap();
+            log::debug_envelope!(&pending.request, "giving up on prepare {prepare_id}, no answer from {:?}", pending.unanswered);
+            let error = Message::error(ErrorCode::Abort, format!("no answer from {} other nodes", pending.unanswered.len()));
+            outbound.extend(pending.request.try_reply_with_ids(self.ids, error));
         }
+
+        // In the order of others rather than unacked's, so a simulation sends them in the same order every time
+        outbound.extend(self.others.iter()
+            .map(|node| (node, &self.unacked[node]))
+            .filter(|(_, handler)| !handler.unacked_messages().is_empty())
+            .map(|(node, handler)| {
+                let transactions: Vec<Transaction> = handler.unacked_messages().iter().map(|xid| self.unacked_transactions[xid].clone()).collect();
+                log::debug!("resending {} transactions to {node}", transactions.len());
+                Envelope::new_with_ids(self.ids, self.node_id.clone(), node.clone(), None, Message::Transactions { transactions })
+            }));
+        outbound
     }
-    values
+
+    // Asks every other node for its transactions from the newest one we have onwards, to catch
+    // up on any we missed
+    fn poll_others(&self) -> Vec<Envelope<Message>> {
+        self.others.iter()
+            .map(|other_node| {
+                let first_xid = self.log.transactions.get(other_node)
+                    .and_then(|txns| txns.iter().map(|txn| txn.transaction_id).max())
+                    .unwrap_or(0);
+                Envelope::new_with_ids(self.ids, self.node_id.clone(), other_node.clone(), None, Message::PollTransactions { first_xid })
+            })
+            .collect()
+    }
+}
+
+fn conflict_error(key: u64) -> Message {
+    Message::error(ErrorCode::TransactionConflict, format!("key {key} was written by a concurrent transaction"))
+}
+
+// What GG_VALIDATE=1 plays to this binary (see goofy_goobers::validate)
+const VALIDATE_SCRIPT: &[Step] = &[
+    validate::INIT,
+    Step::client(r#"{"type": "txn", "txn": [["w", 1, 2], ["r", 1, null]]}"#, "txn_ok"),
+];
+
+// When to next poll the other nodes: somewhere from half to one and a half intervals from now
+fn next_poll(clock: &dyn Clock, rng: &mut Rng, interval: Duration) -> Instant {
+    clock.now() + interval / 2 + interval.mul_f64(rng.below(1000) as f64 / 1000.0)
 }
 
 fn main() {
-    // https://stackoverflow.com/questions/35988775/how-can-i-cause-a-panic-on-a-thread-to-immediately-end-the-main-thread
-    let orig_hook = panic::take_hook();
-    panic::set_hook(Box::new(move |panic_info| {
-        orig_hook(panic_info);
-        process::exit(1);
-    }));
-
-    let output_sender = OutputHandler::start::<Message>();
+    validate::run_if_requested(VALIDATE_SCRIPT);
+
+    let (output_sender, output_thread) = OutputHandler::start::<Message>();
+    runtime::exit_on_panic(&output_sender);
     let (main_sender, main_receiver) = channel();
-    let input_handler: InputHandlerHandle<Message> = InputHandler::start::<Message>(vec![main_sender]);
-    let mut local_node = Default::default();
-    let mut other_nodes = Vec::new();
-    // Doesn't actually need to be atomic but what the heck
-    let mut local_xid = AtomicUsize::new(0);
+    let _input_handler: InputHandlerHandle<Message> = InputHandler::start::<Message>(vec![main_sender]);
 
-    for envelope in main_receiver.iter() {
-        match envelope.message() {
-            Message::Init { node_id, node_ids } => {
-                eprintln!("init: {:?}", envelope);
-                local_node = node_id.clone();
-                other_nodes.extend(node_ids.into_iter().filter(|n| **n != local_node).cloned());
-                output_sender.send(envelope.reply(Message::InitOk)).unwrap();
-                break;
-            }
-            // Message::Topology { .. } => {
-            //     eprintln!("topology: {:?}", envelope);
-            //     output_sender.send(envelope.reply(Message::TopologyOk)).unwrap();
-            // },
-            _ => panic!("Unexpected message at init time: {envelope:?}")
-        }
-    }
-
-    let mut node_transactions: Arc<Mutex<HashMap<String, Vec<Transaction>>>> = Default::default();
-
-    if !other_nodes.is_empty() {
-        let local_node = local_node.clone();
-        let other_nodes = other_nodes.clone();
-        let node_transactions = node_transactions.clone();
-        let sender = output_sender.clone();
-        thread::spawn(move || {
-            thread::sleep(Duration::from_millis(1000));
-            for other_node in &other_nodes {
-                let poll = Envelope::new(local_node.clone(), other_node.clone(), None, Message::PollTransactions {
-                    first_xid: node_transactions.lock().unwrap()
-                        .get(other_node)
-                        .map(|txns| txns.iter().max_by_key(|txn| txn.transaction_id).map(|txn| txn.transaction_id).unwrap_or(0))
-                        .unwrap_or(0),
-                });
-                sender.send(poll).unwrap();
-            }
-        });
+    let Some(init) = runtime::await_init(&main_receiver, |e| output_sender.send(e).unwrap()) else { return };
+    let cluster = Cluster::from(init);
+    let mut node = TxnNode::new(&cluster, message::default_ids());
+
+    let wall_clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let resend_interval = GossipConfig::from_env().sync_interval;
+    let mut resend_deadline = wall_clock.now() + resend_interval;
+    let poll_interval = runtime::env_var(POLL_INTERVAL_ENV_VAR, 1).map_or(DEFAULT_POLL_INTERVAL, |ms| Duration::from_millis(ms as u64));
+    // Only the jitter comes from this, so it doesn't need to be reproducible
+    let mut rng = Rng::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64 ^ cluster.index_of(&cluster.local).unwrap_or(0) as u64);
+    let mut poll_deadline = (!cluster.others().is_empty()).then(|| next_poll(&*wall_clock, &mut rng, poll_interval));
+
+    loop {
+        let mut outbound = vec![];
+        if wall_clock.now() >= resend_deadline {
+            outbound.extend(node.resend());
+            resend_deadline = wall_clock.now() + resend_interval;
+        }
+        if poll_deadline.is_some_and(|deadline| wall_clock.now() >= deadline) {
+            outbound.extend(node.poll_others());
+            poll_deadline = Some(next_poll(&*wall_clock, &mut rng, poll_interval));
+        }
+        for envelope in outbound {
+            output_sender.send(envelope).unwrap();
+        }
+
+        let deadline = poll_deadline.map_or(resend_deadline, |poll| poll.min(resend_deadline));
+        let envelope = match main_receiver.recv_timeout(wall_clock.until(deadline)) {
+            Ok(envelope) => envelope,
+            Err(RecvTimeoutError::Timeout) => continue,
+            // stdin was closed
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if output_sender.resend_cached_reply(&envelope) { continue }
+        if runtime::reply_to_repeated_init(&envelope, |e| output_sender.send(e).unwrap()) { continue }
+        for reply in node.step(&envelope) {
+            output_sender.send(reply).unwrap();
+        }
     }
 
-    for envelope in main_receiver.iter() {
-        match envelope.message() {
-            Message::Topology { .. } => {
-                eprintln!("topology: {:?}", envelope);
-                output_sender.send(envelope.reply(Message::TopologyOk)).unwrap();
-            },
+    // stdin was closed; wait for everything we've sent to be written out
+    OutputHandler::flush_and_join(output_sender, output_thread);
+}
 
-            Message::Txn { operations } => {
-                let mut node_transactions = node_transactions.lock().unwrap();
-
-                // TODO: do we have to merge in our own txns last?
-                let mut state = node_transactions.values()
-                    .map(|v| roll_up_transactions(v))
-                    .reduce(|rollup, elem| rollup.into_iter().chain(elem).collect())
-                    .unwrap_or_default();
-
-                // Fill in the reads
-                let mut filled_in_operations: Vec<Operation> = Default::default();
-                for op in operations {
-                    filled_in_operations.push(match op.optype {
-                        OpType::Read => {
-                            Operation {
-                                optype: OpType::Read,
-                                key: op.key,
-                                value: state.get(&op.key).copied(),
-                            }
-                        }
-                        OpType::Write => {
-                            state.insert(op.key, op.value.unwrap());
-                            op.to_owned()
-                        }
-                    });
-                }
+#[cfg(test)]
+mod tests {
+    use goofy_goobers::clock::ManualClock;
+    use goofy_goobers::runtime::Init;
+    use goofy_goobers::sim::{Scheduler, StateMachine};
 
-                let txn = Transaction {
-                    node: local_node.clone(),
-                    transaction_id: local_xid.fetch_add(1, atomic::Ordering::SeqCst),
-                    operations: filled_in_operations.clone(),
-                };
+    use super::*;
 
-                node_transactions.entry(local_node.to_string()).or_default().push(txn.clone());
+    const SIMULATED_NODES: usize = 3;
+    const SIMULATED_TXNS: usize = 500;
+    const SIMULATED_KEYS: usize = 8;
+    const SIMULATED_DROP_PERCENT: u64 = 10;
 
-                // Broadcast the transaction to other nodes
-                let transactions = vec![txn];
-                for other_node in &other_nodes {
-                    output_sender.send(Envelope::new(local_node.clone(), (*other_node).clone(), None, Message::Transactions { transactions: transactions.clone() })).unwrap();
-                }
+    impl StateMachine<Message> for TxnNode<'_> {
+        fn step(&mut self, envelope: &Envelope<Message>) -> Vec<Envelope<Message>> {
+            TxnNode::step(self, envelope)
+        }
+
+        fn tick(&mut self) -> Vec<Envelope<Message>> {
+            self.resend()
+        }
+    }
 
-                output_sender.send(envelope.reply(Message::TxnOk { operations: filled_in_operations })).unwrap();
+    // Runs random transactions against SIMULATED_NODES nodes while the scheduler reorders and drops
+    // messages, then stops dropping, lets resends and polls catch everyone up, and checks that:
+    // - every node ends up with the same state
+    // - every value a committed transaction read, and every value left in the state, was written to
+    //   that key by a committed transaction (so nothing from an aborted or lost transaction leaks out)
+    // - no two committed transactions read the same value of a key before both writing it (so no
+    //   update is lost)
+    // Every write is of a value no other write uses, so each value identifies the write it came from.
+    fn simulate(seed: u64) {
+        let node_ids: Vec<String> = (1..=SIMULATED_NODES).map(|i| format!("n{i}")).collect();
+        let ids: Vec<MessageIdGenerator> = node_ids.iter().map(|_| MessageIdGenerator::new()).collect();
+        let mut scheduler = Scheduler::new(seed);
+        for (node_id, ids) in node_ids.iter().zip(&ids) {
+            let cluster = Cluster::from(Init { node_id: node_id.clone(), node_ids: node_ids.clone() });
+            scheduler.add_node(node_id.clone(), TxnNode::new(&cluster, ids));
+        }
+        scheduler.set_drop_percent(SIMULATED_DROP_PERCENT);
+
+        let client_ids = MessageIdGenerator::new();
+        let mut rng = Rng::new(!seed);
+        let mut next_value = 1;
+        for i in 0..SIMULATED_TXNS {
+            let operations = (0..1 + rng.below(3)).map(|_| {
+                let key = rng.below(SIMULATED_KEYS) as u64;
+                if rng.below(2) == 0 {
+                    Operation { optype: OpType::Read, key, value: None }
+                } else {
+                    next_value += 1;
+                    Operation { optype: OpType::Write, key, value: Some(next_value) }
+                }
+            }).collect();
+            let node = node_ids[rng.below(node_ids.len())].clone();
+            scheduler.send(Envelope::new_with_ids(&client_ids, "c1", node, None, Message::Txn { operations }));
+            // Let a few messages through between transactions, so they overlap with each other's gossip
+            for _ in 0..rng.below(8) {
+                scheduler.step();
             }
+            if i % 25 == 0 {
+                scheduler.tick();
+            }
+        }
 
-            Message::Transactions { transactions } => {
-                // FIXME: optimize
-                // eprintln!("incoming txns: {transactions:?}");
-                let mut node_transactions = node_transactions.lock().unwrap();
-                for new_txn in transactions {
-                    let txn_is_known = node_transactions.get(&new_txn.node)
-                        .map(|txns| txns.iter().any(|committed_txn| committed_txn.transaction_id == new_txn.transaction_id))
-                        .unwrap_or(false);
-                    if !txn_is_known {
-                        let node_txns = node_transactions.entry(new_txn.node.to_string()).or_default();
-                        node_txns.push(new_txn.to_owned());
-                        node_txns.sort_unstable();
+        scheduler.set_drop_percent(0);
+        let polls: Vec<Envelope<Message>> = scheduler.nodes().flat_map(|(_, node)| node.poll_others()).collect();
+        for poll in polls {
+            scheduler.send(poll);
+        }
+        for _ in 0..10 {
+            scheduler.tick();
+            assert!(scheduler.run(1_000_000), "seed {seed}: messages still in flight after 1000000 steps");
+        }
+
+        let mut written: HashMap<u64, u64> = HashMap::new();
+        for reply in scheduler.replies() {
+            match reply.message() {
+                Message::TxnOk { operations } => {
+                    written.extend(operations.iter().filter(|op| op.optype == OpType::Write).map(|op| (op.value.unwrap(), op.key)));
+                }
+                Message::Error { code, .. } => assert!([ErrorCode::TransactionConflict as u64, ErrorCode::Abort as u64].contains(code), "seed {seed}: unexpected error {reply:?}"),
+                m => panic!("seed {seed}: unexpected reply {m:?}"),
+            }
+        }
+        let mut updated_from: HashMap<(u64, Option<u64>), &[Operation]> = HashMap::new();
+        for reply in scheduler.replies() {
+            let Message::TxnOk { operations } = reply.message() else { continue };
+            for op in operations.iter().filter(|op| op.optype == OpType::Read) {
+                if let Some(value) = op.value {
+                    assert_eq!(written.get(&value), Some(&op.key), "seed {seed}: read of key {} saw {value}, which no committed transaction wrote there", op.key);
+                }
+            }
+            for (i, op) in operations.iter().enumerate().filter(|(_, op)| op.optype == OpType::Write) {
+                let earlier = &operations[..i];
+                if earlier.iter().any(|earlier| earlier.key == op.key && earlier.optype == OpType::Write) {
+                    continue
+                }
+                if let Some(read) = earlier.iter().find(|earlier| earlier.key == op.key) {
+                    if let Some(other) = updated_from.insert((op.key, read.value), operations) {
+                        panic!("seed {seed}: {operations:?} and {other:?} both read {:?} from key {} and then wrote it", read.value, op.key);
                     }
                 }
             }
+        }
 
-            Message::PollTransactions { first_xid } => {
-                let transactions = if let Some(node_txns) = node_transactions.lock().unwrap().get(&local_node) {
-                    node_txns.iter().filter(|txn| txn.transaction_id >= *first_xid).cloned().collect()
-                } else {
-                    vec![]
-                };
-                output_sender.send(envelope.reply(Message::Transactions { transactions })).unwrap();
+        let (first_id, first) = scheduler.nodes().next().unwrap();
+        for (node_id, node) in scheduler.nodes() {
+            assert!(node.unacked_transactions.is_empty(), "seed {seed}: {node_id} still has unacked transactions");
+            assert!(node.pending.is_empty(), "seed {seed}: {node_id} still has transactions waiting to commit");
+            assert_eq!(node.log.state, first.log.state, "seed {seed}: {node_id} and {first_id} disagree");
+        }
+        for (key, value) in &first.log.state {
+            assert_eq!(written.get(value), Some(key), "seed {seed}: key {key} ended up as {value}, which no committed transaction wrote there");
+        }
+
+        let (delivered, dropped) = scheduler.counts();
+        log::debug!("simulation with seed {seed}: {delivered} messages delivered, {dropped} dropped; all {SIMULATED_NODES} nodes agree on {} keys", first.log.state.len());
+    }
+
+    fn write_txn(node: &str, transaction_id: usize, writes: &[(u64, u64)]) -> Transaction {
+        Transaction {
+            node: node.to_string(),
+            transaction_id,
+            operations: writes.iter().map(|(key, value)| write(*key, *value)).collect(),
+            clock: VectorClock::new(),
+        }
+    }
+
+    fn read(key: u64) -> Operation {
+        Operation { optype: OpType::Read, key, value: None }
+    }
+
+    fn write(key: u64, value: u64) -> Operation {
+        Operation { optype: OpType::Write, key, value: Some(value) }
+    }
+
+    fn txn_node<'a>(node_id: &str, node_ids: &[&str], ids: &'a MessageIdGenerator) -> TxnNode<'a> {
+        let node_ids = node_ids.iter().map(|id| id.to_string()).collect();
+        TxnNode::new(&Cluster::from(Init { node_id: node_id.to_string(), node_ids }), ids)
+    }
+
+    // Delivers envelopes to the nodes they're addressed to, and everything the nodes send in turn,
+    // in the order they're sent, until there are none left. Returns the pushes of committed
+    // transactions without delivering them, so each test can decide when they arrive, and whatever
+    // was sent to anything other than the nodes.
+    fn deliver(nodes: &mut [&mut TxnNode], envelopes: Vec<Envelope<Message>>) -> Vec<Envelope<Message>> {
+        let mut queue = std::collections::VecDeque::from(envelopes);
+        let mut undelivered = vec![];
+        while let Some(envelope) = queue.pop_front() {
+            let node = nodes.iter_mut().find(|node| envelope.dest == node.node_id.as_str());
+            match node {
+                Some(node) if !matches!(envelope.message(), Message::Transactions { .. }) => queue.extend(node.step(&envelope)),
+                _ => undelivered.push(envelope),
             }
+        }
+        undelivered
+    }
+
+    // Runs a client transaction on nodes[0], letting the nodes check it with each other first,
+    // returning the reply and the committed transaction's pushes to the other nodes
+    fn send_txn(nodes: &mut [&mut TxnNode], client_ids: &MessageIdGenerator, operations: Vec<Operation>) -> (Message, Vec<Envelope<Message>>) {
+        let request = Envelope::new_with_ids(client_ids, "c1", nodes[0].node_id.clone(), None, Message::Txn { operations });
+        let (replies, gossip): (Vec<_>, Vec<_>) = deliver(nodes, vec![request]).into_iter().partition(|e| e.dest == "c1");
+        match replies.as_slice() {
+            [reply] => (reply.message().clone(), gossip),
+            _ => panic!("expected one reply, got {replies:?}"),
+        }
+    }
 
-            _ => panic!("Unexpected message at runtime: {envelope:?}")
+    // send_txn for a transaction that's expected to commit, returning the operations from its txn_ok
+    fn run_txn(nodes: &mut [&mut TxnNode], client_ids: &MessageIdGenerator, operations: Vec<Operation>) -> (Vec<Operation>, Vec<Envelope<Message>>) {
+        match send_txn(nodes, client_ids, operations) {
+            (Message::TxnOk { operations }, gossip) => (operations, gossip),
+            (m, _) => panic!("expected txn_ok, got {m:?}"),
         }
     }
+
+    fn is_conflict(message: &Message) -> bool {
+        matches!(message, Message::Error { code, .. } if *code == ErrorCode::TransactionConflict as u64)
+    }
+
+    #[test]
+    fn writes_need_a_value_and_reads_must_not_have_one() {
+        assert!(read(1).validate().is_ok() && write(1, 10).validate().is_ok());
+        assert!(Operation { optype: OpType::Write, key: 1, value: None }.validate().is_err());
+        assert!(Operation { optype: OpType::Read, key: 1, value: Some(10) }.validate().is_err());
+    }
+
+    // A malformed operation anywhere in a txn gets malformed-request, and none of the txn is applied
+    #[test]
+    fn malformed_transactions_are_rejected() {
+        let (ids1, ids2, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut n1 = txn_node("n1", &["n1", "n2"], &ids1);
+        let mut n2 = txn_node("n2", &["n1", "n2"], &ids2);
+        let malformed = [
+            vec![write(1, 10), Operation { optype: OpType::Write, key: 2, value: None }],
+            vec![write(1, 10), Operation { optype: OpType::Read, key: 2, value: Some(20) }],
+        ];
+        for operations in malformed {
+            let (reply, gossip) = send_txn(&mut [&mut n1, &mut n2], &client_ids, operations);
+            assert!(matches!(reply, Message::Error { code, .. } if code == ErrorCode::MalformedRequest as u64), "{reply:?}");
+            assert!(gossip.is_empty());
+        }
+        assert!(n1.log.state.is_empty() && n2.log.state.is_empty());
+
+        // Without a value on the wire, a write still parses, and is turned away by validate
+        let json = r#"{"type": "txn", "msg_id": 1, "txn": [["w", 1, null], ["r", 2, null]]}"#;
+        let Message::Txn { operations } = serde_json::from_str(json).unwrap() else { panic!("not a txn") };
+        assert!(operations[0].validate().is_err() && operations[1].validate().is_ok());
+    }
+
+    // Another node's transaction is applied between a transaction's snapshot and its reads
+    #[test]
+    fn snapshot_is_unaffected_by_an_interleaved_transaction() {
+        let mut log = TransactionLog::default();
+        log.append(write_txn("n1", 0, &[(1, 10)]));
+        let snapshot = log.snapshot([1, 2, 3]);
+
+        log.append(write_txn("n2", 1, &[(2, 20)]));
+        assert_eq!((snapshot.value(1), snapshot.value(2)), (Some(10), None));
+        assert_eq!(log.snapshot([2]).value(2), Some(20));
+    }
+
+    #[test]
+    fn reads_within_a_transaction_are_repeatable() {
+        let (ids, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut node = txn_node("n1", &["n1"], &ids);
+        run_txn(&mut [&mut node], &client_ids, vec![write(1, 10)]);
+
+        let (ops, _) = run_txn(&mut [&mut node], &client_ids, vec![read(1), read(2), read(1), write(1, 11), read(1)]);
+        assert_eq!(ops.iter().map(|op| op.value).collect::<Vec<_>>(), [Some(10), None, Some(10), Some(11), Some(11)]);
+    }
+
+    // Two nodes each read and write the same key before hearing about the other's transaction. Each
+    // node sees the other's PrepareTxn while its own transaction is waiting, so both are aborted.
+    #[test]
+    fn concurrent_transactions_on_the_same_key_conflict() {
+        let (ids1, ids2, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut n1 = txn_node("n1", &["n1", "n2"], &ids1);
+        let mut n2 = txn_node("n2", &["n1", "n2"], &ids2);
+
+        let prepare1 = n1.step(&Envelope::new_with_ids(&client_ids, "c1", "n1", None, Message::Txn { operations: vec![read(1), write(1, 100)] }));
+        let prepare2 = n2.step(&Envelope::new_with_ids(&client_ids, "c1", "n2", None, Message::Txn { operations: vec![read(1), write(1, 200)] }));
+        let replies = deliver(&mut [&mut n1, &mut n2], prepare1.into_iter().chain(prepare2).collect());
+
+        assert_eq!(replies.len(), 2, "{replies:?}");
+        assert!(replies.iter().all(|reply| reply.dest == "c1" && is_conflict(reply.message())), "{replies:?}");
+        assert!(n1.log.state.is_empty() && n2.log.state.is_empty());
+        assert!(n1.pending.is_empty() && n2.pending.is_empty());
+    }
+
+    // n1 commits a write to key 1, but n2 hasn't had the push yet. A transaction on n2 that reads
+    // key 1 and writes it would lose n1's update, so n1 answers its PrepareTxn with a conflict.
+    #[test]
+    fn a_transaction_that_missed_a_committed_write_conflicts() {
+        let (ids1, ids2, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut n1 = txn_node("n1", &["n1", "n2"], &ids1);
+        let mut n2 = txn_node("n2", &["n1", "n2"], &ids2);
+
+        let (_, to_n2) = run_txn(&mut [&mut n1, &mut n2], &client_ids, vec![write(1, 100)]);
+        let (reply, _) = send_txn(&mut [&mut n2, &mut n1], &client_ids, vec![read(1), write(1, 200)]);
+        assert!(is_conflict(&reply), "{reply:?}");
+
+        // Once n2 has n1's transaction, the retry reads it and commits
+        for envelope in to_n2 {
+            n2.step(&envelope);
+        }
+        let (ops, to_n1) = run_txn(&mut [&mut n2, &mut n1], &client_ids, vec![read(1), write(1, 200)]);
+        assert_eq!(ops[0].value, Some(100));
+        for envelope in to_n1 {
+            n1.step(&envelope);
+        }
+        assert_eq!(n1.log.state.get(&1), Some(&200));
+        assert_eq!(n1.log.state, n2.log.state);
+    }
+
+    // Transactions on different keys don't get in each other's way, even while both are waiting
+    #[test]
+    fn concurrent_transactions_on_different_keys_both_commit() {
+        let (ids1, ids2, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut n1 = txn_node("n1", &["n1", "n2"], &ids1);
+        let mut n2 = txn_node("n2", &["n1", "n2"], &ids2);
+
+        let prepare1 = n1.step(&Envelope::new_with_ids(&client_ids, "c1", "n1", None, Message::Txn { operations: vec![read(1), write(1, 100)] }));
+        let prepare2 = n2.step(&Envelope::new_with_ids(&client_ids, "c1", "n2", None, Message::Txn { operations: vec![read(2), write(2, 200)] }));
+        let (replies, pushes): (Vec<_>, Vec<_>) = deliver(&mut [&mut n1, &mut n2], prepare1.into_iter().chain(prepare2).collect())
+            .into_iter().partition(|e| e.dest == "c1");
+        assert!(replies.iter().all(|reply| matches!(reply.message(), Message::TxnOk { .. })), "{replies:?}");
+        assert_eq!(replies.len(), 2);
+
+        for envelope in pushes {
+            if envelope.dest == "n1" { n1.step(&envelope) } else { n2.step(&envelope) };
+        }
+        assert_eq!(n1.log.state, HashMap::from([(1, 100), (2, 200)]));
+        assert_eq!(n1.log.state, n2.log.state);
+    }
+
+    // A PrepareTxn is resent until it's answered, and the client gets a definite abort if it never is
+    #[test]
+    fn an_unanswered_prepare_is_given_up_on() {
+        let (ids, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut node = txn_node("n1", &["n1", "n2"], &ids);
+        let prepare = node.step(&Envelope::new_with_ids(&client_ids, "c1", "n1", None, Message::Txn { operations: vec![write(1, 100)] }));
+        assert!(matches!(prepare.as_slice(), [e] if matches!(e.message(), Message::PrepareTxn { .. })));
+
+        for _ in 0..PREPARE_RESENDS {
+            assert!(matches!(node.resend().as_slice(), [e] if e.dest == "n2" && matches!(e.message(), Message::PrepareTxn { .. })));
+        }
+        let gave_up = node.resend();
+        assert!(matches!(gave_up.as_slice(), [e] if e.dest == "c1" && matches!(e.message(), Message::Error { code, .. } if *code == ErrorCode::Abort as u64)), "{gave_up:?}");
+        assert!(node.pending.is_empty() && node.log.state.is_empty());
+    }
+
+    // A push that's lost is resent every sync until it's acked, and then forgotten
+    #[test]
+    fn pushes_are_resent_until_acked() {
+        let (ids1, ids2, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut n1 = txn_node("n1", &["n1", "n2"], &ids1);
+        let mut n2 = txn_node("n2", &["n1", "n2"], &ids2);
+        // The first push never arrives
+        run_txn(&mut [&mut n1, &mut n2], &client_ids, vec![write(1, 10)]);
+
+        for _ in 0..2 {
+            let resent = n1.resend();
+            assert!(matches!(resent.as_slice(), [e] if e.dest == "n2" && matches!(e.message(), Message::Transactions { transactions } if transactions.len() == 1)), "{resent:?}");
+        }
+        let push = n1.resend().remove(0);
+        let ack = n2.step(&push);
+        assert!(matches!(ack.as_slice(), [e] if e.dest == "n1" && matches!(e.message(), Message::TransactionsOk { transaction_ids } if *transaction_ids == [0])), "{ack:?}");
+        assert_eq!(n2.log.state, HashMap::from([(1, 10)]));
+
+        n1.step(&ack[0]);
+        assert!(n1.resend().is_empty());
+        assert!(n1.unacked_transactions.is_empty());
+    }
+
+    // A transaction with only reads is answered straight from the view, without taking an xid or
+    // telling anyone
+    #[test]
+    fn read_only_transactions_are_not_committed_or_broadcast() {
+        let (ids1, ids2, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut n1 = txn_node("n1", &["n1", "n2"], &ids1);
+        let mut n2 = txn_node("n2", &["n1", "n2"], &ids2);
+        run_txn(&mut [&mut n1, &mut n2], &client_ids, vec![write(1, 10)]);
+        let local_xid = n1.local_xid;
+
+        let request = Envelope::new_with_ids(&client_ids, "c1", "n1", None, Message::Txn { operations: vec![read(1), read(2)] });
+        let outbound = n1.step(&request);
+        assert!(matches!(outbound.as_slice(), [reply] if reply.dest == "c1"), "{outbound:?}");
+        let Message::TxnOk { operations } = outbound[0].message() else { panic!("expected txn_ok, got {outbound:?}") };
+        assert_eq!(operations.iter().map(|op| op.value).collect::<Vec<_>>(), [Some(10), None]);
+        assert_eq!(n1.local_xid, local_xid);
+        assert_eq!(n1.log.transactions["n1"].len(), 1);
+    }
+
+    fn clock(counts: &[(&str, usize)]) -> VectorClock {
+        counts.iter().map(|(node, count)| (node.to_string(), *count)).collect()
+    }
+
+    #[test]
+    fn happens_before_orders_only_dominated_clocks() {
+        let (a, b) = (clock(&[("n1", 1)]), clock(&[("n1", 1), ("n2", 2)]));
+        assert!(happens_before(&a, &b));
+        assert!(!happens_before(&b, &a));
+        // Equal clocks aren't before each other
+        assert!(!happens_before(&b, &b.clone()));
+        // Nor are concurrent ones
+        let c = clock(&[("n1", 2), ("n2", 1)]);
+        assert!(!happens_before(&b, &c) && !happens_before(&c, &b));
+        // A clock with nothing in it is before any other
+        assert!(happens_before(&VectorClock::new(), &a));
+        assert!(!happens_before(&VectorClock::new(), &VectorClock::new()));
+    }
+
+    #[test]
+    fn a_causally_later_write_wins_over_a_higher_xid() {
+        let earlier = Writer { transaction_id: 5, node: "n2".to_string(), clock: clock(&[("n2", 1)]) };
+        let later = Writer { transaction_id: 3, node: "n1".to_string(), clock: clock(&[("n1", 1), ("n2", 1)]) };
+        assert!(earlier.is_superseded_by(&later));
+        assert!(!later.is_superseded_by(&earlier));
+        // Concurrent writes fall back to (transaction_id, node)
+        let concurrent = Writer { transaction_id: 3, node: "n3".to_string(), clock: clock(&[("n3", 1)]) };
+        assert!(concurrent.is_superseded_by(&earlier));
+        assert!(!earlier.is_superseded_by(&concurrent));
+    }
+
+    // n2 reads n1's write before overwriting it. n3 gets n2's transaction first, and still ends up
+    // with n2's write once n1's turns up, as does n1.
+    #[test]
+    fn a_causally_dependent_read_sees_the_prior_write() {
+        let (ids1, ids2, ids3, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut n1 = txn_node("n1", &["n1", "n2", "n3"], &ids1);
+        let mut n2 = txn_node("n2", &["n1", "n2", "n3"], &ids2);
+        let mut n3 = txn_node("n3", &["n1", "n2", "n3"], &ids3);
+
+        let (_, from_n1) = run_txn(&mut [&mut n1, &mut n2, &mut n3], &client_ids, vec![write(1, 100)]);
+        let (to_n2, to_n3_from_n1): (Vec<_>, Vec<_>) = from_n1.into_iter().partition(|e| e.dest == "n2");
+        for envelope in &to_n2 {
+            n2.step(envelope);
+        }
+        let (ops, from_n2) = run_txn(&mut [&mut n2, &mut n1, &mut n3], &client_ids, vec![read(1), write(1, 200)]);
+        assert_eq!(ops[0].value, Some(100));
+        let written_by_n1 = &n1.log.transactions["n1"][0];
+        assert!(happens_before(&written_by_n1.clock, &n2.log.transactions["n2"][0].clock));
+
+        for envelope in from_n2.iter().chain(&to_n3_from_n1) {
+            let node = if envelope.dest == "n1" { &mut n1 } else { &mut n3 };
+            node.step(envelope);
+        }
+        assert_eq!(n3.log.state.get(&1), Some(&200));
+        assert_eq!(n1.log.state, n3.log.state);
+    }
+
+    // Peers from before transactions carried a clock leave it out, and their transactions are
+    // ordered by (transaction_id, node) alone
+    #[test]
+    fn transactions_without_a_clock_are_ordered_by_xid() {
+        let json = r#"{"type": "transactions", "transactions": [
+            {"node": "n2", "transaction_id": 7, "operations": [["w", 1, 70]]},
+            {"node": "n3", "transaction_id": 4, "operations": [["w", 1, 40]]}
+        ]}"#;
+        let Message::Transactions { transactions } = serde_json::from_str(json).unwrap() else { panic!("not transactions") };
+        assert!(transactions.iter().all(|txn| txn.clock.is_empty()));
+
+        let mut log = TransactionLog::default();
+        for txn in transactions {
+            log.append(txn);
+        }
+        assert_eq!(log.state.get(&1), Some(&70));
+        // A later transaction with a clock still wins over one without
+        log.append(Transaction { clock: clock(&[("n3", 1)]), ..write_txn("n3", 5, &[(1, 50)]) });
+        assert_eq!(log.state.get(&1), Some(&50));
+    }
+
+    // A peer's transactions all arrive, but newest first, so each one is older than everything
+    // already applied from that node
+    #[test]
+    fn transactions_delivered_in_reverse_give_the_same_state() {
+        let (ids1, ids2, ids3, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut in_order = txn_node("n1", &["n1", "n2"], &ids1);
+        let mut reversed = txn_node("n1", &["n1", "n2"], &ids3);
+        let mut n2 = txn_node("n2", &["n1", "n2"], &ids2);
+
+        let mut pushes = vec![];
+        for i in 0..12 {
+            let (_, to_n1) = run_txn(&mut [&mut n2, &mut in_order], &client_ids, vec![write(i % 3, i), write(3, i)]);
+            pushes.extend(to_n1);
+        }
+        for envelope in &pushes {
+            in_order.step(envelope);
+        }
+        for envelope in pushes.iter().rev() {
+            reversed.step(envelope);
+        }
+
+        assert_eq!(in_order.log.state, HashMap::from([(0, 9), (1, 10), (2, 11), (3, 11)]));
+        assert_eq!(reversed.log.state, in_order.log.state);
+        assert_eq!(reversed.log.transactions["n2"], in_order.log.transactions["n2"]);
+    }
+
+    // Compaction is triggered by the number of transactions appended, so a node that only hears
+    // about its peers' transactions compacts too. Only the oldest ones beyond RETAINED_TRANSACTIONS
+    // per node are dropped, and their writes stay in the view.
+    #[test]
+    fn compaction_drops_superseded_transactions() {
+        let (ids1, ids2, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut n1 = txn_node("n1", &["n1", "n2"], &ids1);
+        let mut n2 = txn_node("n2", &["n1", "n2"], &ids2);
+        let total = RETAINED_TRANSACTIONS + COMPACTION_INTERVAL;
+        for i in 0..total as u64 {
+            let (_, to_n1) = run_txn(&mut [&mut n2, &mut n1], &client_ids, vec![write(i % 5, i)]);
+            for envelope in to_n1 {
+                n1.step(&envelope);
+            }
+        }
+
+        let kept = &n1.log.transactions["n2"];
+        assert_eq!(kept.len(), RETAINED_TRANSACTIONS);
+        assert_eq!(kept[0].transaction_id, total - RETAINED_TRANSACTIONS);
+        assert_eq!(n1.log.compacted_xids["n2"], total - RETAINED_TRANSACTIONS - 1);
+        assert!(!n1.log.transactions.contains_key("n1"));
+        let last = total as u64 - 1;
+        assert_eq!(n1.log.state, (last - 4..=last).map(|i| (i % 5, i)).collect());
+        assert_eq!(n1.log.state, n2.log.state);
+
+        // A compacted transaction that's sent again only touches the view, where it loses
+        n1.log.append(write_txn("n2", 0, &[(0, 1000)]));
+        assert_eq!(n1.log.transactions["n2"].len(), RETAINED_TRANSACTIONS);
+        assert_eq!(n1.log.state, n2.log.state);
+    }
+
+    // Peers from before transactions carried their node leave the field out
+    #[test]
+    fn transactions_without_a_node_are_the_senders() {
+        let (ids1, ids2, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut n1 = txn_node("n1", &["n1", "n2"], &ids1);
+        let mut n2 = txn_node("n2", &["n1", "n2"], &ids2);
+        let (_, to_n1) = run_txn(&mut [&mut n2, &mut n1], &client_ids, vec![write(1, 100)]);
+
+        for envelope in to_n1 {
+            let mut json = serde_json::to_value(&envelope).unwrap();
+            for transaction in json["body"]["transactions"].as_array_mut().unwrap() {
+                transaction.as_object_mut().unwrap().remove("node").unwrap();
+            }
+            let envelope: Envelope<Message> = serde_json::from_value(json).unwrap();
+            n1.step(&envelope);
+            // A resend is recognised as the same transaction
+            n1.step(&envelope);
+        }
+        assert_eq!(n1.log.transactions.keys().collect::<Vec<_>>(), ["n2"]);
+        assert_eq!(n1.log.transactions["n2"].len(), 1);
+        assert_eq!(n1.log.state.get(&1), Some(&100));
+    }
+
+    #[test]
+    fn simulated_nodes_agree() {
+        for seed in [1, 2, 3, 42, 1234, 99999] {
+            simulate(seed);
+        }
+    }
+
+    // Driving the main loop's polling on a manual clock: it keeps polling, and the waits between
+    // polls stay within half to one and a half intervals without all being the same
+    #[test]
+    fn polls_repeat_with_jitter() {
+        let (clock, mut rng, interval) = (ManualClock::new(), Rng::new(1), Duration::from_millis(100));
+        let start = clock.now();
+        let mut deadline = next_poll(&clock, &mut rng, interval);
+        let mut polls = vec![];
+        while clock.now() < start + interval * 50 {
+            clock.advance(Duration::from_millis(1));
+            if clock.now() >= deadline {
+                polls.push(clock.now());
+                deadline = next_poll(&clock, &mut rng, interval);
+            }
+        }
+
+        assert!((30..=100).contains(&polls.len()), "{} polls", polls.len());
+        let waits: Vec<Duration> = polls.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert!(waits.iter().all(|wait| *wait >= interval / 2 && *wait <= interval * 3 / 2 + Duration::from_millis(1)), "{waits:?}");
+        assert!(waits.iter().collect::<BTreeSet<_>>().len() > waits.len() / 2, "{waits:?}");
+    }
 }
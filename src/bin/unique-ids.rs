@@ -1,43 +1,199 @@
-
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 
+use goofy_goobers::{impl_error_message, impl_init_message, impl_type_tag};
+use goofy_goobers::log;
 use goofy_goobers::message::Envelope;
+use goofy_goobers::runtime;
+use goofy_goobers::runtime::{DeadLetters, Cluster, Init, OutputSender, Workload};
+use goofy_goobers::safe_int;
+use goofy_goobers::validate;
+use goofy_goobers::validate::Step;
+
+// Set GG_UNIQUE_IDS=snowflake for 64-bit numeric ids instead of the default <node>.<n> strings,
+// GG_UNIQUE_IDS=named. Snowflake ids are denser and roughly sorted by when they were generated.
+const ID_FORMAT_ENV_VAR: &str = "GG_UNIQUE_IDS";
+
+// A snowflake id is, from the top: 41 bits of milliseconds since SNOWFLAKE_EPOCH, 10 bits of the
+// node's index in node_ids and 12 bits of sequence within the millisecond
+const SNOWFLAKE_EPOCH_MS: u64 = 1_704_067_200_000; // 2024-01-01T00:00:00Z
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
 
-static ID: AtomicUsize = AtomicUsize::new(0);
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+enum Id {
+    Named(String),
+    // Snowflakes are well past 2^53, so they're sent as strings if GG_LARGE_NUMBERS=string
+    Snowflake(#[serde(with = "safe_int")] u64),
+}
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
 enum Message {
     Init { node_id: String, node_ids: Vec<String> },
     InitOk,
     Generate,
-    GenerateOk { id: String },
+    GenerateOk { id: Id },
+    Error { code: u64, text: String },
 }
 
-fn main() {
-    let mut stdout = std::io::stdout();
-    let mut my_node_id = "".to_string();
-    for line in std::io::stdin().lines() {
-        let env: Envelope<Message> = serde_json::from_str(&line.unwrap()).unwrap();
-        match env.message() {
-            Message::Init { node_id, node_ids } => {
-                eprintln!("init: {} of {:?}", node_id, node_ids);
-                my_node_id = node_id.clone();
-                let r = env.reply(Message::InitOk);
-                serde_json::to_writer(&mut stdout, &r).unwrap();
-                println!();
-            },
+impl_init_message!(Message);
+impl_error_message!(Message);
+impl_type_tag!(Message { Init, InitOk, Generate, GenerateOk, Error });
+
+// Ids are unique across the cluster because each one includes the node that generated it: as a
+// prefix for named ids, and as the node's index for snowflake ids. Each node has its own generator,
+// so several nodes in one process don't share a counter.
+enum UniqueIds {
+    Named { node_id: String, next_id: usize },
+    Snowflake(Snowflake),
+}
+
+impl UniqueIds {
+    fn next_id(&mut self) -> Id {
+        match self {
+            UniqueIds::Named { node_id, next_id } => {
+                let id = format!("{node_id}.{next_id}");
+                *next_id += 1;
+                Id::Named(id)
+            }
+            UniqueIds::Snowflake(snowflake) => Id::Snowflake(snowflake.next_id(now_ms())),
+        }
+    }
+}
+
+struct Snowflake {
+    node_index: u64,
+    // The timestamp and sequence number of the last id handed out
+    last_ms: u64,
+    sequence: u64,
+}
+
+impl Snowflake {
+    fn new(node_index: usize) -> Snowflake {
+        assert!(node_index < 1 << NODE_BITS, "snowflake ids only have room for {} nodes", 1 << NODE_BITS);
+        Snowflake { node_index: node_index as u64, last_ms: 0, sequence: 0 }
+    }
+
+    // Ids only ever go up. If the clock goes backwards, ids keep using the last timestamp; if a
+    // millisecond's sequence numbers run out, they carry on into the next millisecond early
+    // rather than waiting for it.
+    fn next_id(&mut self, now_ms: u64) -> u64 {
+        if now_ms > self.last_ms {
+            self.last_ms = now_ms;
+            self.sequence = 0;
+        } else if self.sequence + 1 < 1 << SEQUENCE_BITS {
+            self.sequence += 1;
+        } else {
+            self.last_ms += 1;
+            self.sequence = 0;
+        }
+        assert!(self.last_ms < 1 << (64 - NODE_BITS - SEQUENCE_BITS), "ran out of snowflake timestamps");
+        self.last_ms << (NODE_BITS + SEQUENCE_BITS) | self.node_index << SEQUENCE_BITS | self.sequence
+    }
+}
+
+fn now_ms() -> u64 {
+    let since_unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    since_unix.saturating_sub(SNOWFLAKE_EPOCH_MS)
+}
+
+impl Workload for UniqueIds {
+    type Message = Message;
 
+    fn new(init: Init) -> Self {
+        let cluster = Cluster::from(init);
+        match std::env::var(ID_FORMAT_ENV_VAR).as_deref() {
+            Ok("named") | Err(_) => UniqueIds::Named { node_id: cluster.local, next_id: 0 },
+            Ok("snowflake") => UniqueIds::Snowflake(Snowflake::new(cluster.index_of(&cluster.local).unwrap())),
+            Ok(format) => panic!("unknown {ID_FORMAT_ENV_VAR} {format}, expected named or snowflake"),
+        }
+    }
+
+    fn handle(&mut self, envelope: Envelope<Message>, output: &OutputSender<Message>) {
+        match envelope.message() {
             Message::Generate => {
-                let next_id = ID.fetch_add(1, Ordering::SeqCst);
-                let r = env.reply(Message::GenerateOk { id: format!("{}.{}", my_node_id, next_id) });
-                serde_json::to_writer(&mut stdout, &r).unwrap();
-                println!();
+                let id = self.next_id();
+                output.send_all(envelope.try_reply(Message::GenerateOk { id })).unwrap();
             }
+            // Answering an error with another error could go back and forth forever
+            Message::Error { .. } => log::debug_envelope!(&envelope, "ignoring error: {envelope:?}"),
+            // Anything else (one of our own replies, say) goes to the dead letters
+            _ => DeadLetters::from_env().handle(&envelope, |e| output.send(e).unwrap()),
+        }
+    }
+}
+
+// What GG_VALIDATE=1 plays to this binary (see goofy_goobers::validate)
+const VALIDATE_SCRIPT: &[Step] = &[
+    validate::INIT,
+    Step::client(r#"{"type": "generate"}"#, "generate_ok"),
+    Step::client(r#"{"type": "generate"}"#, "generate_ok"),
+];
+
+fn main() {
+    validate::run_if_requested(VALIDATE_SCRIPT);
+    runtime::run::<UniqueIds>();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
 
-            _ => unimplemented!()
+    const NODES: usize = 5;
+    const IDS_PER_NODE: usize = 10_000;
+
+    // Every node generating at once, in the same milliseconds, with more ids per millisecond than
+    // the sequence has room for
+    #[test]
+    fn snowflakes_are_unique_across_nodes() {
+        let mut nodes: Vec<Snowflake> = (0..NODES).map(Snowflake::new).collect();
+        let mut ids = HashSet::new();
+        for i in 0..IDS_PER_NODE {
+            let now_ms = 1000 + i as u64 / 5000;
+            for node in &mut nodes {
+                let id = node.next_id(now_ms);
+                assert!(ids.insert(id), "{id} generated twice");
+            }
+        }
+    }
+
+    #[test]
+    fn snowflakes_only_go_up() {
+        let mut snowflake = Snowflake::new(3);
+        // Forwards, the same millisecond for longer than the sequence lasts, then backwards
+        let times = (0..10).map(|ms| 100 + ms)
+            .chain(std::iter::repeat_n(110, 2 << SEQUENCE_BITS))
+            .chain([50, 0, 105, 200, 200]);
+        let mut last_id = 0;
+        for now_ms in times {
+            let id = snowflake.next_id(now_ms);
+            assert!(id > last_id, "{id} after {last_id} at {now_ms}ms");
+            last_id = id;
+        }
+    }
+
+    #[test]
+    fn snowflakes_hold_their_fields() {
+        let id = Snowflake::new(7).next_id(12345);
+        assert_eq!(id >> (NODE_BITS + SEQUENCE_BITS), 12345);
+        assert_eq!(id >> SEQUENCE_BITS & ((1 << NODE_BITS) - 1), 7);
+        assert_eq!(id & ((1 << SEQUENCE_BITS) - 1), 0);
+    }
+
+    #[test]
+    fn named_ids_are_unique_across_nodes() {
+        let mut ids = HashSet::new();
+        for node_id in ["n1", "n2", "n11"] {
+            let mut unique_ids = UniqueIds::Named { node_id: node_id.to_string(), next_id: 0 };
+            for _ in 0..IDS_PER_NODE {
+                let Id::Named(id) = unique_ids.next_id() else { panic!("not a named id") };
+                assert!(ids.insert(id.clone()), "{id} generated twice");
+            }
         }
     }
 }
@@ -0,0 +1,116 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// Where time-based logic gets the time from. Loops take one of these instead of calling
+// Instant::now and thread::sleep themselves, so a harness can give them a ManualClock and decide
+// exactly when each deadline passes.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration);
+
+    // How long until the deadline, or zero if it's passed
+    fn until(&self, deadline: Instant) -> Duration {
+        deadline.saturating_duration_since(self.now())
+    }
+}
+
+// The real time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+// A clock that only moves when advance is called. It starts at the real time it was created, and
+// sleeping on it blocks until another thread has advanced it far enough.
+//
+// Channel timeouts (recv_timeout and so on) still wait in real time, so a loop driven by a
+// ManualClock wakes up as usual but only sees a deadline pass once the clock has been moved past it.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<Instant>,
+    advanced: Condvar,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock { now: Mutex::new(Instant::now()), advanced: Condvar::new() }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+        self.advanced.notify_all();
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let now = self.now.lock().unwrap();
+        let wake_at = *now + duration;
+        drop(self.advanced.wait_while(now, |now| *now < wake_at).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn manual_clocks_only_move_when_advanced() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let deadline = start + Duration::from_millis(100);
+        assert_eq!(clock.until(deadline), Duration::from_millis(100));
+        clock.advance(Duration::from_millis(60));
+        assert_eq!(clock.now(), start + Duration::from_millis(60));
+        assert_eq!(clock.until(deadline), Duration::from_millis(40));
+        clock.advance(Duration::from_millis(60));
+        assert_eq!(clock.until(deadline), Duration::ZERO);
+    }
+
+    // A sleep on a ManualClock ends once another thread has advanced it far enough, not before
+    #[test]
+    fn manual_sleeps_wait_for_the_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let start = clock.now();
+        let sleeper = {
+            let clock = clock.clone();
+            thread::spawn(move || {
+                clock.sleep(Duration::from_secs(10));
+                clock.now()
+            })
+        };
+        // However late the sleep started, it can't end before the clock is 10s past start
+        for _ in 0..9 {
+            clock.advance(Duration::from_secs(1));
+            thread::sleep(Duration::from_millis(2));
+        }
+        assert!(!sleeper.is_finished());
+        while !sleeper.is_finished() {
+            clock.advance(Duration::from_secs(1));
+            thread::sleep(Duration::from_millis(2));
+        }
+        assert!(sleeper.join().unwrap() >= start + Duration::from_secs(10));
+    }
+}
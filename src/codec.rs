@@ -0,0 +1,230 @@
+use std::io::{BufRead, ErrorKind};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::runtime::ReadError;
+
+// Set GG_CODEC=msgpack to exchange envelopes on stdin and stdout as MessagePack, each one preceded
+// by its length as a 4 byte big-endian integer, instead of as lines of JSON. Maelstrom only speaks
+// JSON, so this is only useful with a harness of our own driving the nodes, e.g. to benchmark the
+// protocol logic without the cost of JSON. The default, GG_CODEC=json, is what Maelstrom expects.
+const CODEC_ENV_VAR: &str = "GG_CODEC";
+// The longest MessagePack payload read_frame accepts. The length comes straight off the input, so
+// without a cap a corrupt or hostile one would have us allocate up to 4GiB before reading anything.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+static WIRE_FORMAT: Lazy<WireFormat> = Lazy::new(|| match std::env::var(CODEC_ENV_VAR).as_deref() {
+    Ok("json") | Err(_) => WireFormat::Json,
+    Ok("msgpack") => WireFormat::MessagePack,
+    Ok(codec) => panic!("unknown {CODEC_ENV_VAR} {codec}, expected json or msgpack"),
+});
+
+// How envelopes are encoded on the wire. Each frame holds one envelope; `encode` and `decode` deal
+// with a frame's payload and `read_frame` and `write_frame` with the framing around it.
+pub trait Codec {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Vec<u8>;
+
+    fn decode<'de, T: Deserialize<'de>>(&self, payload: &'de [u8]) -> Result<T, ReadError>;
+
+    // The payload of the next frame, or None at the end of the input
+    fn read_frame(&self, input: &mut impl BufRead) -> std::io::Result<Option<Vec<u8>>>;
+
+    // Appends a frame holding the payload to out
+    fn write_frame(&self, payload: &[u8], out: &mut Vec<u8>);
+}
+
+// One line of JSON per frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Vec<u8> {
+        serde_json::to_vec(value).unwrap()
+    }
+
+    fn decode<'de, T: Deserialize<'de>>(&self, payload: &'de [u8]) -> Result<T, ReadError> {
+        let payload = std::str::from_utf8(payload).map_err(ReadError::Utf8)?;
+        serde_json::from_str(payload).map_err(ReadError::Json)
+    }
+
+    fn read_frame(&self, input: &mut impl BufRead) -> std::io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None)
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+
+    fn write_frame(&self, payload: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(payload);
+        out.push(b'\n');
+    }
+}
+
+// A 4 byte big-endian length, then that many bytes of MessagePack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    // Structs are written as maps, since flattened and internally tagged types can't be read back
+    // from arrays
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Vec<u8> {
+        rmp_serde::to_vec_named(value).unwrap()
+    }
+
+    fn decode<'de, T: Deserialize<'de>>(&self, payload: &'de [u8]) -> Result<T, ReadError> {
+        rmp_serde::from_slice(payload).map_err(ReadError::MessagePack)
+    }
+
+    fn read_frame(&self, input: &mut impl BufRead) -> std::io::Result<Option<Vec<u8>>> {
+        // Read by hand rather than with read_exact, which would consume a length cut off part way
+        // through and leave us unable to tell it from the end of the input
+        let mut length = [0; 4];
+        let mut filled = 0;
+        while filled < length.len() {
+            let available = match input.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                if filled == 0 {
+                    return Ok(None)
+                }
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, format!("input ended {filled} bytes into a frame length")))
+            }
+            let n = available.len().min(length.len() - filled);
+            length[filled..filled + n].copy_from_slice(&available[..n]);
+            input.consume(n);
+            filled += n;
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_FRAME_LEN {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, format!("frame length {length} is over the {MAX_FRAME_LEN} byte limit")))
+        }
+        let mut payload = vec![0; length];
+        input.read_exact(&mut payload)?;
+        Ok(Some(payload))
+    }
+
+    fn write_frame(&self, payload: &[u8], out: &mut Vec<u8>) {
+        let length = u32::try_from(payload.len()).expect("envelope too big for a MessagePack frame");
+        out.extend_from_slice(&length.to_be_bytes());
+        out.extend_from_slice(payload);
+    }
+}
+
+// The codec GG_CODEC picks. Codec's methods are generic, so it can't be used as `dyn Codec`; the
+// input and output handlers hold one of these instead and it hands each call on to the real codec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    MessagePack,
+}
+
+impl WireFormat {
+    // The same for the whole process
+    pub fn current() -> WireFormat {
+        *WIRE_FORMAT
+    }
+}
+
+impl Codec for WireFormat {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Vec<u8> {
+        match self {
+            WireFormat::Json => Json.encode(value),
+            WireFormat::MessagePack => MessagePack.encode(value),
+        }
+    }
+
+    fn decode<'de, T: Deserialize<'de>>(&self, payload: &'de [u8]) -> Result<T, ReadError> {
+        match self {
+            WireFormat::Json => Json.decode(payload),
+            WireFormat::MessagePack => MessagePack.decode(payload),
+        }
+    }
+
+    fn read_frame(&self, input: &mut impl BufRead) -> std::io::Result<Option<Vec<u8>>> {
+        match self {
+            WireFormat::Json => Json.read_frame(input),
+            WireFormat::MessagePack => MessagePack.read_frame(input),
+        }
+    }
+
+    fn write_frame(&self, payload: &[u8], out: &mut Vec<u8>) {
+        match self {
+            WireFormat::Json => Json.write_frame(payload, out),
+            WireFormat::MessagePack => MessagePack.write_frame(payload, out),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn frames(codec: impl Codec, payloads: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for payload in payloads {
+            codec.write_frame(payload, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        for codec in [WireFormat::Json, WireFormat::MessagePack] {
+            let mut input = Cursor::new(frames(codec, &[b"one", b"", b"three"]));
+            assert_eq!(codec.read_frame(&mut input).unwrap(), Some(b"one".to_vec()));
+            assert_eq!(codec.read_frame(&mut input).unwrap(), Some(Vec::new()));
+            assert_eq!(codec.read_frame(&mut input).unwrap(), Some(b"three".to_vec()));
+            assert_eq!(codec.read_frame(&mut input).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn truncated_length_is_an_error() {
+        let mut input = frames(MessagePack, &[b"one"]);
+        input.extend_from_slice(&[0, 0]);
+        let mut input = Cursor::new(input);
+        assert_eq!(MessagePack.read_frame(&mut input).unwrap(), Some(b"one".to_vec()));
+        let error = MessagePack.read_frame(&mut input).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncated_payload_is_an_error() {
+        let mut input = frames(MessagePack, &[b"three"]);
+        input.truncate(6);
+        let error = MessagePack.read_frame(&mut Cursor::new(input)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_length_is_an_error() {
+        let mut input = Vec::from(u32::MAX.to_be_bytes());
+        input.extend_from_slice(b"not that long");
+        let error = MessagePack.read_frame(&mut Cursor::new(input)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        // Right at the limit is still read, as far as the input goes
+        let input = Vec::from((MAX_FRAME_LEN as u32).to_be_bytes());
+        let error = MessagePack.read_frame(&mut Cursor::new(input)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn length_split_across_reads() {
+        // A BufReader with a tiny buffer hands the length over a byte at a time
+        let input = frames(MessagePack, &[b"payload"]);
+        let mut input = std::io::BufReader::with_capacity(1, Cursor::new(input));
+        assert_eq!(MessagePack.read_frame(&mut input).unwrap(), Some(b"payload".to_vec()));
+        assert_eq!(MessagePack.read_frame(&mut input).unwrap(), None);
+    }
+}
@@ -10,7 +10,18 @@
 // 22	precondition-failed	✓	The requested operation expected some conditions to hold, and those conditions were not met. For instance, a compare-and-set operation might assert that the value of a key is currently 5; if the value is 3, the server would return precondition-failed.
 // 30	txn-conflict	✓	The requested transaction has been aborted because of a conflict with another transaction. Servers need not return this error on every conflict: they may choose to retry automatically instead.
 
-#[derive(Debug, Eq, PartialEq)]
+use std::fmt;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::message::{Envelope, MessageIdGenerator};
+
+// Serializes as Maelstrom's name for the code, e.g. "precondition-failed"; use `as u64` and
+// ErrorCode::try_from for the numeric code that goes on the wire
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum ErrorCode {
     Timeout = 0,
     NodeNotFound = 1,
@@ -22,30 +33,176 @@ pub enum ErrorCode {
     KeyDoesNotExist = 20,
     KeyAlreadyExists = 21,
     PreconditionFailed = 22,
+    #[serde(rename = "txn-conflict")]
     TransactionConflict = 30
 }
 
-impl From<u64> for ErrorCode {
-    fn from(value: u64) -> Self {
-        match value {
-            0 => ErrorCode::Timeout,
-            1 => ErrorCode::NodeNotFound,
-            10 => ErrorCode::NotSupported,
-            11 => ErrorCode::TemporarilyUnavailable,
-            12 => ErrorCode::MalformedRequest,
-            13 => ErrorCode::Crash,
-            14 => ErrorCode::Abort,
-            20 => ErrorCode::KeyDoesNotExist,
-            21 => ErrorCode::KeyAlreadyExists,
-            22 => ErrorCode::PreconditionFailed,
-            30 => ErrorCode::TransactionConflict,
-            _ => panic!("invalid error code: {}", value),
+const ALL_ERROR_CODES: [ErrorCode; 11] = [
+    ErrorCode::Timeout,
+    ErrorCode::NodeNotFound,
+    ErrorCode::NotSupported,
+    ErrorCode::TemporarilyUnavailable,
+    ErrorCode::MalformedRequest,
+    ErrorCode::Crash,
+    ErrorCode::Abort,
+    ErrorCode::KeyDoesNotExist,
+    ErrorCode::KeyAlreadyExists,
+    ErrorCode::PreconditionFailed,
+    ErrorCode::TransactionConflict,
+];
+
+impl ErrorCode {
+    // Whether the operation definitely did not happen - the codes with a ✓ in the table above, from
+    // https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors
+    pub fn is_definite(&self) -> bool {
+        !self.is_indefinite()
+    }
+
+    // Whether the operation may or may not have happened, so it's only safe to retry if it's
+    // idempotent
+    pub fn is_indefinite(&self) -> bool {
+        matches!(self, ErrorCode::Timeout | ErrorCode::Crash)
+    }
+
+    // Whether the operation definitely didn't happen and trying it again (perhaps after refreshing
+    // whatever state it was based on) might succeed. The other definite errors will keep failing
+    // until something about the request changes.
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, ErrorCode::TemporarilyUnavailable | ErrorCode::Abort | ErrorCode::PreconditionFailed | ErrorCode::TransactionConflict)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::NodeNotFound => "node-not-found",
+            ErrorCode::NotSupported => "not-supported",
+            ErrorCode::TemporarilyUnavailable => "temporarily-unavailable",
+            ErrorCode::MalformedRequest => "malformed-request",
+            ErrorCode::Crash => "crash",
+            ErrorCode::Abort => "abort",
+            ErrorCode::KeyDoesNotExist => "key-does-not-exist",
+            ErrorCode::KeyAlreadyExists => "key-already-exists",
+            ErrorCode::PreconditionFailed => "precondition-failed",
+            ErrorCode::TransactionConflict => "txn-conflict",
         }
     }
 }
 
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ErrorCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ALL_ERROR_CODES.into_iter().find(|code| code.as_str() == s).ok_or_else(|| format!("unknown error code: {s}"))
+    }
+}
+
+// Codes off the wire that aren't in the table above (Maelstrom leaves 1000 and up to workloads)
+// give back the number
+impl TryFrom<u64> for ErrorCode {
+    type Error = u64;
+
+    fn try_from(value: u64) -> Result<Self, u64> {
+        ALL_ERROR_CODES.into_iter().find(|code| *code as u64 == value).ok_or(value)
+    }
+}
+
 #[derive(Debug)]
 pub struct Error {
     pub code: ErrorCode,
     pub text: String,
 }
+
+impl Error {
+    // The error an error reply carries. A code we don't know is taken as crash, since there's no
+    // telling whether the operation happened, with the number kept in the text.
+    pub fn from_reply(code: u64, text: String) -> Error {
+        match ErrorCode::try_from(code) {
+            Ok(code) => Error { code, text },
+            Err(code) => Error { code: ErrorCode::Crash, text: format!("unknown error code {code}: {text}") },
+        }
+    }
+}
+
+// A workload message type that can carry an error reply. Enums with the usual
+// `Error { code: u64, text: String }` variant can use impl_error_message!.
+pub trait ErrorMessage {
+    fn error(code: ErrorCode, text: String) -> Self;
+}
+
+#[macro_export]
+macro_rules! impl_error_message {
+    ($message:ty) => {
+        impl $crate::error::ErrorMessage for $message {
+            fn error(code: $crate::error::ErrorCode, text: String) -> Self {
+                Self::Error { code: code as u64, text }
+            }
+        }
+    };
+}
+
+// For Envelope<Value>, the passthrough form (see message::Body)
+impl ErrorMessage for serde_json::Value {
+    fn error(code: ErrorCode, text: String) -> Self {
+        serde_json::json!({"type": "error", "code": code as u64, "text": text})
+    }
+}
+
+impl<B: Debug + ErrorMessage> Envelope<B> {
+    // An error reply to this message, or None if there's nothing to reply to (no msg_id)
+    pub fn reply_error(&self, code: ErrorCode, text: impl Into<String>) -> Option<Envelope<B>> {
+        self.try_reply(B::error(code, text.into()))
+    }
+
+    pub fn reply_error_with_ids(&self, ids: &MessageIdGenerator, code: ErrorCode, text: impl Into<String>) -> Option<Envelope<B>> {
+        self.try_reply_with_ids(ids, B::error(code, text.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every code goes to and from its name, by serde and by FromStr, and to and from its number
+    #[test]
+    fn every_code_round_trips() {
+        for (code, number) in ALL_ERROR_CODES.into_iter().zip(ALL_ERROR_CODES.map(|code| code as u64)) {
+            let name = serde_json::to_value(code).unwrap();
+            assert_eq!(name, code.as_str(), "{code:?}");
+            assert_eq!(serde_json::from_value::<ErrorCode>(name).unwrap(), code);
+            assert_eq!(code.to_string().parse::<ErrorCode>().unwrap(), code);
+            assert_eq!(ErrorCode::try_from(number), Ok(code));
+        }
+    }
+
+    #[test]
+    fn codes_have_maelstroms_numbers() {
+        let numbers: Vec<u64> = ALL_ERROR_CODES.into_iter().map(|code| code as u64).collect();
+        assert_eq!(numbers, [0, 1, 10, 11, 12, 13, 14, 20, 21, 22, 30]);
+        assert_eq!(serde_json::to_value(ErrorCode::TransactionConflict).unwrap(), "txn-conflict");
+    }
+
+    #[test]
+    fn unknown_names_are_errors() {
+        assert!("no-such-code".parse::<ErrorCode>().is_err());
+        assert!("TransactionConflict".parse::<ErrorCode>().is_err());
+        assert!(serde_json::from_str::<ErrorCode>(r#""transaction-conflict""#).is_err());
+    }
+
+    // A number from the network that isn't one of ours is an error, not a panic, and a reply
+    // carrying one is treated as indefinite
+    #[test]
+    fn unknown_numbers_are_errors() {
+        for number in [2, 15, 1000, u64::MAX] {
+            assert_eq!(ErrorCode::try_from(number), Err(number));
+            let e = Error::from_reply(number, "custom".to_string());
+            assert!(e.code.is_indefinite() && e.text.contains(&number.to_string()), "{e:?}");
+        }
+        assert_eq!(Error::from_reply(22, "expected 1".to_string()).code, ErrorCode::PreconditionFailed);
+    }
+}
@@ -0,0 +1,791 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SystemClock};
+use crate::log::debug;
+use crate::runtime::env_var;
+
+// How much each new round trip time sample counts towards the estimate, as in TCP's smoothed RTT
+const RTT_SAMPLE_WEIGHT: f64 = 0.125;
+
+// New messages go straight to this many of our fastest neighbours; the rest get them a sync later,
+// by which time they've often heard about them from someone else
+pub const FAST_NEIGHBOURS: usize = 2;
+
+// Tracks the messages we've sent to one node that it hasn't acknowledged yet, and how quickly it
+// tends to acknowledge them
+pub struct NodeHandler<T> {
+    unacked_messages: Vec<T>,
+    // Messages that will be sent from the next sync on
+    deferred_messages: Vec<T>,
+    rtt: Option<Duration>,
+}
+
+impl<T: Clone + Debug + Eq + Hash> NodeHandler<T> {
+    pub fn new() -> NodeHandler<T> {
+        NodeHandler {
+            unacked_messages: Default::default(),
+            deferred_messages: Default::default(),
+            rtt: None,
+        }
+    }
+
+    pub fn send_message(&mut self, message: T) {
+        self.unacked_messages.push(message);
+    }
+
+    pub fn defer_message(&mut self, message: T) {
+        self.deferred_messages.push(message);
+    }
+
+    // Makes the deferred messages due to be sent
+    pub fn release_deferred(&mut self) {
+        self.unacked_messages.append(&mut self.deferred_messages);
+    }
+
+    // Returns how many messages were acked by this call; acks for messages that were already acked
+    // (or never sent) are ignored. Acked messages that were still deferred are dropped too.
+    pub fn sync_ok(&mut self, messages: &[T]) -> usize {
+        let acked: HashSet<&T> = messages.iter().collect();
+        self.deferred_messages.retain(|m| !acked.contains(m));
+        let before = self.unacked_messages.len();
+        self.unacked_messages.retain(|m| !acked.contains(m));
+        before - self.unacked_messages.len()
+    }
+
+    pub fn unacked_messages(&self) -> &[T] {
+        &self.unacked_messages
+    }
+
+    // Everything this node still has to be sent, deferred or not
+    pub fn undelivered(&self) -> impl Iterator<Item=&T> {
+        self.unacked_messages.iter().chain(&self.deferred_messages)
+    }
+
+    // How many messages the node has yet to ack, deferred or not. One that stays high (or keeps
+    // growing) is a node that's slow or not answering.
+    pub fn pending_count(&self) -> usize {
+        self.unacked_messages.len() + self.deferred_messages.len()
+    }
+
+    pub fn record_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt.mul_f64(1.0 - RTT_SAMPLE_WEIGHT) + sample.mul_f64(RTT_SAMPLE_WEIGHT),
+            None => sample,
+        });
+    }
+
+    // An exponentially weighted moving average of the round trip times recorded so far
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+}
+
+impl<T: Clone + Debug + Eq + Hash> Default for NodeHandler<T> {
+    fn default() -> Self {
+        NodeHandler::new()
+    }
+}
+
+// Set GG_SYNC_INTERVAL_MS and GG_FANOUT to tune gossip without recompiling
+const SYNC_INTERVAL_ENV_VAR: &str = "GG_SYNC_INTERVAL_MS";
+const FANOUT_ENV_VAR: &str = "GG_FANOUT";
+const ANTI_ENTROPY_INTERVAL_ENV_VAR: &str = "GG_ANTI_ENTROPY_INTERVAL_MS";
+const PEER_TIMEOUT_ENV_VAR: &str = "GG_PEER_TIMEOUT_MS";
+// Set GG_TOPOLOGY=tree to connect the nodes as a tree (see tree_topology) instead of the default
+// fanout pattern
+const TOPOLOGY_ENV_VAR: &str = "GG_TOPOLOGY";
+
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_millis(250);
+// Problem 3d wants fewer messages per op (2); problem 3e wants lower latency (4)
+pub const DEFAULT_FANOUT: usize = 4;
+// Full state comparisons only matter after a partition heals, so they can be much rarer than syncs
+pub const DEFAULT_ANTI_ENTROPY_INTERVAL: Duration = Duration::from_millis(2000);
+// Long enough to ride out Maelstrom's usual latency, short enough that we stop hammering a
+// partitioned node within a few syncs
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_millis(2000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopologyStrategy {
+    Fanout,
+    Tree,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GossipConfig {
+    pub sync_interval: Duration,
+    // The stride of the fanout topology, or the number of children per node in the tree topology
+    pub fanout: usize,
+    pub anti_entropy_interval: Duration,
+    pub peer_timeout: Duration,
+    pub topology: TopologyStrategy,
+}
+
+impl GossipConfig {
+    pub fn from_env() -> GossipConfig {
+        let sync_interval_ms = env_var(SYNC_INTERVAL_ENV_VAR, 1).unwrap_or(DEFAULT_SYNC_INTERVAL.as_millis() as usize);
+        let anti_entropy_interval_ms = env_var(ANTI_ENTROPY_INTERVAL_ENV_VAR, 1).unwrap_or(DEFAULT_ANTI_ENTROPY_INTERVAL.as_millis() as usize);
+        let peer_timeout_ms = env_var(PEER_TIMEOUT_ENV_VAR, 1).unwrap_or(DEFAULT_PEER_TIMEOUT.as_millis() as usize);
+        GossipConfig {
+            sync_interval: Duration::from_millis(sync_interval_ms as u64),
+            fanout: env_var(FANOUT_ENV_VAR, 1).unwrap_or(DEFAULT_FANOUT),
+            anti_entropy_interval: Duration::from_millis(anti_entropy_interval_ms as u64),
+            peer_timeout: Duration::from_millis(peer_timeout_ms as u64),
+            topology: match std::env::var(TOPOLOGY_ENV_VAR).as_deref() {
+                Ok("fanout") | Err(_) => TopologyStrategy::Fanout,
+                Ok("tree") => TopologyStrategy::Tree,
+                Ok(topology) => panic!("unknown {TOPOLOGY_ENV_VAR} {topology}, expected fanout or tree"),
+            },
+        }
+    }
+
+    // Every node's neighbours, given the node_ids from init
+    pub fn build_topology(&self, node_ids: &[String]) -> Topology {
+        Topology::from(match self.topology {
+            TopologyStrategy::Fanout => fanout_topology(node_ids, self.fanout),
+            TopologyStrategy::Tree => tree_topology(node_ids, self.fanout),
+        })
+    }
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig { sync_interval: DEFAULT_SYNC_INTERVAL, fanout: DEFAULT_FANOUT, anti_entropy_interval: DEFAULT_ANTI_ENTROPY_INTERVAL, peer_timeout: DEFAULT_PEER_TIMEOUT, topology: TopologyStrategy::Fanout }
+    }
+}
+
+// Each node forwards to every fanout'th node, starting from an offset based on its own position.
+// With fewer nodes than fanout, the offset skips right past the end for some of them, leaving them
+// no one to forward to, so small clusters have every node forward to every other instead.
+pub fn fanout_topology(node_ids: &[String], fanout: usize) -> HashMap<String, Vec<String>> {
+    if node_ids.len() < fanout {
+        return node_ids.iter()
+            .map(|node_id| (node_id.clone(), node_ids.iter().filter(|other| *other != node_id).cloned().collect()))
+            .collect()
+    }
+    node_ids.iter().enumerate()
+        .map(|(idx, node_id)| (node_id.clone(), node_ids.iter().skip((idx + 1) % fanout).step_by(fanout).cloned().collect()))
+        .collect()
+}
+
+// A tree rooted at the first node, in which node i's children are nodes branching*i + 1 to
+// branching*i + branching. Each node's neighbours are its parent and its children, so a message
+// reaches every node within about 2 log(n) hops of wherever it starts, and no node is sent it twice.
+// There's only one path between any two nodes, though, so a partition holds messages up until it heals.
+pub fn tree_topology(node_ids: &[String], branching: usize) -> HashMap<String, Vec<String>> {
+    node_ids.iter().enumerate()
+        .map(|(idx, node_id)| {
+            let parent = idx.checked_sub(1).map(|i| i / branching);
+            let children = (branching * idx + 1..=branching * idx + branching).take_while(|child| *child < node_ids.len());
+            (node_id.clone(), parent.into_iter().chain(children).map(|i| node_ids[i].clone()).collect())
+        })
+        .collect()
+}
+
+// Which nodes each node gossips to. Links can go one way only: a node sends to its neighbours, and
+// hears back from them in replies.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Topology(HashMap<String, Vec<String>>);
+
+impl Topology {
+    // A node the topology doesn't mention has no neighbours
+    pub fn neighbours(&self, node: &str) -> &[String] {
+        self.0.get(node).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    // Logs anything about the topology that could keep messages from getting everywhere, or that
+    // looks like a mistake, and returns whether every node can reach every other node through it.
+    // One-way links are only logged, since gossip works fine over them.
+    pub fn validate(&self) -> bool {
+        let nodes: HashSet<&String> = self.0.keys().chain(self.0.values().flatten()).collect();
+
+        let unlisted: Vec<&&String> = nodes.iter().filter(|node| !self.0.contains_key(**node)).collect();
+        if !unlisted.is_empty() {
+            debug!("topology: {unlisted:?} appear only as neighbours, so have no neighbours of their own");
+        }
+        let one_way: Vec<(&String, &String)> = self.0.iter()
+            .flat_map(|(node, neighbours)| neighbours.iter().map(move |neighbour| (node, neighbour)))
+            .filter(|(node, neighbour)| !self.neighbours(neighbour).contains(node))
+            .collect();
+        if !one_way.is_empty() {
+            debug!("topology: {} of its links only go one way, e.g. {} -> {}", one_way.len(), one_way[0].0, one_way[0].1);
+        }
+
+        let mut connected = true;
+        for node in &nodes {
+            let unreachable = nodes.len() - self.reachable_from(node).len();
+            if unreachable > 0 {
+                debug!("topology: {unreachable} nodes can't be reached from {node}");
+                connected = false;
+            }
+        }
+        connected
+    }
+
+    // Every node that gossip starting at node gets to, node included
+    fn reachable_from<'a>(&'a self, node: &'a String) -> HashSet<&'a String> {
+        let mut reached = HashSet::from([node]);
+        let mut frontier = vec![node];
+        while let Some(next) = frontier.pop() {
+            for neighbour in self.neighbours(next) {
+                if reached.insert(neighbour) {
+                    frontier.push(neighbour);
+                }
+            }
+        }
+        reached
+    }
+}
+
+impl From<HashMap<String, Vec<String>>> for Topology {
+    fn from(neighbours: HashMap<String, Vec<String>>) -> Self {
+        Topology(neighbours)
+    }
+}
+
+// Reliable delivery of messages to our neighbours: every message is resent to a neighbour on each
+// sync until that neighbour acks it
+pub struct Gossip<T> {
+    neighbours: Vec<String>,
+    node_handlers: HashMap<String, NodeHandler<T>>,
+}
+
+impl<T: Clone + Debug + Eq + Hash> Gossip<T> {
+    // A topology can name neighbours that aren't among the node_ids. They're logged here and then
+    // never sent anything.
+    pub fn new(node_ids: &[String], neighbours: Vec<String>) -> Gossip<T> {
+        let unknown: Vec<&String> = neighbours.iter().filter(|neighbour| !node_ids.contains(neighbour)).collect();
+        if !unknown.is_empty() {
+            debug!("skipping neighbours {unknown:?}, which aren't in the cluster");
+        }
+        Gossip {
+            neighbours,
+            node_handlers: node_ids.iter().map(|node_id| (node_id.clone(), NodeHandler::new())).collect(),
+        }
+    }
+
+    pub fn neighbours(&self) -> &[String] {
+        &self.neighbours
+    }
+
+    // Our neighbours, fastest first. Neighbours we haven't timed yet come before all the others so
+    // that they get timed; ties keep the topology's order. Neighbours that aren't in the cluster are
+    // left out (see new).
+    pub fn neighbours_by_rtt(&self) -> Vec<&String> {
+        let mut neighbours: Vec<(&String, Option<Duration>)> = self.neighbours.iter()
+            .filter_map(|neighbour| Some((neighbour, self.node_handlers.get(neighbour)?.rtt())))
+            .collect();
+        neighbours.sort_by_key(|(_, rtt)| *rtt);
+        neighbours.into_iter().map(|(neighbour, _)| neighbour).collect()
+    }
+
+    // Queues a message for delivery to all our neighbours. Only the FAST_NEIGHBOURS fastest get it
+    // on the next sync; the others are sent it on the sync after that, unless they've told us they
+    // have it by then.
+    pub fn forward(&mut self, message: T) {
+        self.forward_except(message, None)
+    }
+
+    // Like forward, but not to the node we got the message from, which obviously has it already.
+    // It doesn't take up one of the FAST_NEIGHBOURS places either. Neighbours that are sent a
+    // message and ack it, or send it to us themselves (see already_has), drop out the same way
+    // for everything they have.
+    pub fn forward_except(&mut self, message: T, source: Option<&str>) {
+        let neighbours: Vec<String> = self.neighbours_by_rtt().into_iter()
+            .filter(|neighbour| Some(neighbour.as_str()) != source)
+            .cloned()
+            .collect();
+        for (rank, neighbour) in neighbours.iter().enumerate() {
+            let Some(handler) = self.node_handlers.get_mut(neighbour) else { continue };
+            if rank < FAST_NEIGHBOURS {
+                handler.send_message(message.clone());
+            } else {
+                handler.defer_message(message.clone());
+            }
+        }
+    }
+
+    // Called after each sync, so deferred messages go out on the next one
+    pub fn release_deferred(&mut self) {
+        for handler in self.node_handlers.values_mut() {
+            handler.release_deferred();
+        }
+    }
+
+    // Like release_deferred, for a node that's synced on its own schedule (see SyncSchedule)
+    pub fn release_deferred_to(&mut self, node: &str) {
+        if let Some(handler) = self.node_handlers.get_mut(node) {
+            handler.release_deferred();
+        }
+    }
+
+    // A node sent us these messages, so there's no need to send them back to it
+    pub fn already_has(&mut self, node: &str, messages: &[T]) {
+        if let Some(handler) = self.node_handlers.get_mut(node) {
+            handler.sync_ok(messages);
+        }
+    }
+
+    pub fn record_rtt(&mut self, node: &str, sample: Duration) {
+        if let Some(handler) = self.node_handlers.get_mut(node) {
+            handler.record_rtt(sample);
+        }
+    }
+
+    pub fn rtt(&self, node: &str) -> Option<Duration> {
+        self.node_handlers.get(node)?.rtt()
+    }
+
+    pub fn sync_ok(&mut self, node: &str, messages: &[T]) {
+        let Some(handler) = self.node_handlers.get_mut(node) else {
+            debug!("ignoring sync_ok from unknown node {node}");
+            return;
+        };
+
+        let acked = handler.sync_ok(messages);
+        if acked < messages.len() {
+            debug!("{node} acked {acked} of {} messages, {} left", messages.len(), handler.unacked_messages().len());
+        }
+    }
+
+    // Messages that still need to be sent to each node
+    pub fn pending(&self) -> impl Iterator<Item=(&String, &[T])> {
+        self.node_handlers.iter()
+            .filter(|(_, handler)| !handler.unacked_messages().is_empty())
+            .map(|(node, handler)| (node, handler.unacked_messages()))
+    }
+
+    // Messages that some node hasn't acked yet, including ones deferred until the next sync
+    pub fn undelivered(&self) -> HashSet<&T> {
+        self.node_handlers.values().flat_map(NodeHandler::undelivered).collect()
+    }
+
+    // Messages that still need to be sent to one node
+    pub fn pending_to(&self, node: &str) -> &[T] {
+        self.node_handlers.get(node).map_or(&[], |handler| handler.unacked_messages())
+    }
+
+    // See NodeHandler::pending_count
+    pub fn pending_count(&self, node: &str) -> usize {
+        self.node_handlers.get(node).map_or(0, NodeHandler::pending_count)
+    }
+}
+
+// Spreads a node's syncs across the sync interval instead of sending one to every neighbour at the
+// same moment. Each (node, neighbour) pair in the cluster gets its own slot in the interval, so the
+// nodes' syncs don't line up with each other either, and the cluster sends a steady trickle of
+// syncs rather than a burst every interval. Each neighbour is still synced once per interval.
+pub struct SyncSchedule {
+    interval: Duration,
+    // Each neighbour and when it's next due, in the topology's order
+    deadlines: Vec<(String, Instant)>,
+}
+
+impl SyncSchedule {
+    pub fn new(node_ids: &[String], local: &str, neighbours: &[String], interval: Duration, now: Instant) -> SyncSchedule {
+        let position = node_ids.iter().position(|node| node == local).unwrap_or(0);
+        let slots = (node_ids.len() * neighbours.len()).max(1) as u32;
+        let deadlines = neighbours.iter().enumerate()
+            .map(|(i, neighbour)| {
+                let slot = (i * node_ids.len() + position) as u32;
+                (neighbour.clone(), now + interval / slots * slot)
+            })
+            .collect();
+        SyncSchedule { interval, deadlines }
+    }
+
+    // The neighbours whose syncs are due, each of which is then due again an interval later
+    pub fn due(&mut self, now: Instant) -> Vec<String> {
+        let mut due = vec![];
+        for (neighbour, deadline) in &mut self.deadlines {
+            if now >= *deadline {
+                due.push(neighbour.clone());
+                *deadline += self.interval;
+            }
+        }
+        due
+    }
+
+    // When the next neighbour is due, if there are any neighbours
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.iter().map(|(_, deadline)| *deadline).min()
+    }
+}
+
+// A compact summary of a set of integers as sorted, inclusive (first, last) runs. Workloads tend to
+// use consecutive values, so this is usually a handful of runs however big the set is, and unlike a
+// count or a bloom filter it tells the other side exactly what we're missing.
+pub fn range_digest<'a>(values: impl IntoIterator<Item=&'a u64>) -> Vec<(u64, u64)> {
+    let mut values: Vec<u64> = values.into_iter().copied().collect();
+    values.sort_unstable();
+
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for value in values {
+        match ranges.last_mut() {
+            Some((_, last)) if *last == value || *last + 1 == value => *last = value,
+            _ => ranges.push((value, value)),
+        }
+    }
+    ranges
+}
+
+// The values that aren't covered by a digest
+pub fn missing_from<'a>(digest: &[(u64, u64)], values: impl IntoIterator<Item=&'a u64>) -> Vec<u64> {
+    values.into_iter()
+        .filter(|value| {
+            // The first range that ends at or after value is the only one that could contain it
+            let idx = digest.partition_point(|(_, last)| *last < **value);
+            digest.get(idx).is_none_or(|(first, _)| *first > **value)
+        })
+        .copied()
+        .collect()
+}
+
+// When we last heard anything from each peer. A peer that's been silent for longer than the timeout
+// is presumed dead until we hear from it again. Every peer starts out alive.
+pub struct PeerLiveness {
+    timeout: Duration,
+    last_seen: HashMap<String, Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl PeerLiveness {
+    pub fn new(peers: &[String], timeout: Duration) -> PeerLiveness {
+        PeerLiveness::with_clock(peers, timeout, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(peers: &[String], timeout: Duration, clock: Arc<dyn Clock>) -> PeerLiveness {
+        let now = clock.now();
+        PeerLiveness {
+            timeout,
+            last_seen: peers.iter().map(|peer| (peer.clone(), now)).collect(),
+            clock,
+        }
+    }
+
+    pub fn heard_from(&mut self, node: &str) {
+        let now = self.clock.now();
+        if let Some(last_seen) = self.last_seen.get_mut(node) {
+            if now - *last_seen > self.timeout {
+                debug!("{node} is back");
+            }
+            *last_seen = now;
+        }
+    }
+
+    pub fn is_peer_alive(&self, node: &str) -> bool {
+        self.silent_for(node).is_some_and(|silence| silence <= self.timeout)
+    }
+
+    // Whether it's been quiet long enough that we should check in on the peer, well before it
+    // would be presumed dead
+    pub fn is_quiet(&self, node: &str) -> bool {
+        self.silent_for(node).is_some_and(|silence| silence > self.timeout / 2)
+    }
+
+    fn silent_for(&self, node: &str) -> Option<Duration> {
+        self.last_seen.get(node).map(|last_seen| self.clock.now() - *last_seen)
+    }
+}
+
+// Dead neighbours are only synced with (and pinged) on every this many of their syncs, so we notice
+// when they come back without flooding them while they're down
+pub const DEAD_PEER_SYNC_EVERY: usize = 8;
+
+// What to do when a neighbour's sync comes round (see SyncPolicy::on_sync)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncAction {
+    Skip,
+    // Send the neighbour what it hasn't acked yet, and a ping too if it's gone quiet
+    Sync { ping: bool },
+}
+
+// Decides which of the syncs a SyncSchedule hands out actually go to the neighbour, going by when
+// we last heard from it (see PeerLiveness)
+pub struct SyncPolicy {
+    liveness: PeerLiveness,
+    // How many times each neighbour's sync has come round
+    syncs: HashMap<String, usize>,
+}
+
+impl SyncPolicy {
+    pub fn new(liveness: PeerLiveness) -> SyncPolicy {
+        SyncPolicy { liveness, syncs: HashMap::new() }
+    }
+
+    pub fn heard_from(&mut self, node: &str) {
+        self.liveness.heard_from(node);
+    }
+
+    pub fn is_peer_alive(&self, node: &str) -> bool {
+        self.liveness.is_peer_alive(node)
+    }
+
+    // Called each time a neighbour's sync is due. A dead neighbour is skipped, apart from every
+    // DEAD_PEER_SYNC_EVERY'th time (starting with the first).
+    pub fn on_sync(&mut self, neighbour: &str) -> SyncAction {
+        let syncs = match self.syncs.get_mut(neighbour) {
+            Some(syncs) => syncs,
+            None => self.syncs.entry(neighbour.to_string()).or_default(),
+        };
+        let retry_dead = syncs.is_multiple_of(DEAD_PEER_SYNC_EVERY);
+        *syncs += 1;
+        if !retry_dead && !self.liveness.is_peer_alive(neighbour) {
+            return SyncAction::Skip
+        }
+        SyncAction::Sync { ping: self.liveness.is_quiet(neighbour) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_ids(n: usize) -> Vec<String> {
+        (1..=n).map(|i| format!("n{i}")).collect()
+    }
+
+    // Every tree links every node with n - 1 two-way links, which makes it connected and acyclic,
+    // and keeps every node close to the root
+    #[test]
+    fn tree_topology_is_a_spanning_tree() {
+        for n in 1..=40 {
+            for branching in 1..=4 {
+                let node_ids = node_ids(n);
+                let topology = Topology::from(tree_topology(&node_ids, branching));
+                assert_eq!(topology.0.len(), n);
+                for (node, neighbours) in &topology.0 {
+                    assert!(!neighbours.contains(node), "{node} is its own neighbour");
+                    for neighbour in neighbours {
+                        assert!(topology.neighbours(neighbour).contains(node), "{node} -> {neighbour} only goes one way");
+                    }
+                }
+                let links: usize = topology.0.values().map(Vec::len).sum();
+                assert_eq!(links, 2 * (n - 1), "{n} nodes, branching {branching}");
+                assert!(topology.validate(), "{n} nodes, branching {branching} isn't connected");
+
+                if branching > 1 {
+                    let depth = depth_from(&topology, &node_ids[0]);
+                    assert!(depth <= n.ilog(branching) as usize + 1, "{n} nodes, branching {branching}: depth {depth}");
+                }
+            }
+        }
+    }
+
+    fn topology(json: &str) -> Topology {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn neighbours_of_an_unknown_node_are_empty() {
+        let topology = topology(r#"{"n1": ["n2"], "n2": ["n1"]}"#);
+        assert_eq!(topology.neighbours("n1"), ["n2"]);
+        assert!(topology.neighbours("n3").is_empty());
+        assert!(Topology::default().neighbours("n1").is_empty());
+    }
+
+    // One-way links are fine as long as gossip can still get from every node to every other
+    #[test]
+    fn asymmetric_topologies_are_valid_if_connected() {
+        assert!(topology(r#"{"n1": ["n2"], "n2": ["n3"], "n3": ["n1"]}"#).validate());
+        assert!(topology(r#"{"n1": ["n2", "n3"], "n2": ["n1"], "n3": ["n2"]}"#).validate());
+    }
+
+    #[test]
+    fn disconnected_topologies_are_invalid() {
+        // Nothing leads to n3
+        assert!(!topology(r#"{"n1": ["n2"], "n2": ["n1"], "n3": ["n1"]}"#).validate());
+        // n3 is only ever a neighbour, so nothing leaves it
+        assert!(!topology(r#"{"n1": ["n2", "n3"], "n2": ["n1"]}"#).validate());
+        // Two halves
+        assert!(!topology(r#"{"n1": ["n2"], "n2": ["n1"], "n3": ["n4"], "n4": ["n3"]}"#).validate());
+    }
+
+    // Including clusters smaller than the fanout, where the stride alone would strand some nodes
+    #[test]
+    fn fanout_topology_is_connected() {
+        for n in 1..=25 {
+            for fanout in 1..=4 {
+                assert!(Topology::from(fanout_topology(&node_ids(n), fanout)).validate(), "{n} nodes, fanout {fanout}");
+            }
+        }
+    }
+
+    fn gossip(neighbours: &[&str]) -> Gossip<u64> {
+        let neighbours: Vec<String> = neighbours.iter().map(|n| n.to_string()).collect();
+        Gossip::new(&node_ids(5), neighbours)
+    }
+
+    #[test]
+    fn rtt_is_a_moving_average() {
+        let mut handler: NodeHandler<u64> = NodeHandler::new();
+        assert_eq!(handler.rtt(), None);
+        handler.record_rtt(Duration::from_millis(80));
+        assert_eq!(handler.rtt(), Some(Duration::from_millis(80)));
+        handler.record_rtt(Duration::from_millis(160));
+        assert_eq!(handler.rtt(), Some(Duration::from_millis(90)));
+        // One slow ack doesn't undo a run of fast ones
+        for _ in 0..20 {
+            handler.record_rtt(Duration::from_millis(10));
+        }
+        handler.record_rtt(Duration::from_millis(1000));
+        assert!(handler.rtt().unwrap() < Duration::from_millis(200), "{:?}", handler.rtt());
+    }
+
+    // Synthetic ack latencies: n3 is fast, n5 slow, n2 in between and n4 not yet timed
+    #[test]
+    fn neighbours_are_ranked_by_rtt() {
+        let mut gossip = gossip(&["n2", "n3", "n4", "n5"]);
+        for (node, ms) in [("n2", 40), ("n3", 5), ("n5", 300), ("n2", 60), ("n3", 15), ("n5", 200)] {
+            gossip.record_rtt(node, Duration::from_millis(ms));
+        }
+        gossip.record_rtt("n9", Duration::from_millis(1));
+        assert_eq!(gossip.neighbours_by_rtt(), ["n4", "n3", "n2", "n5"]);
+
+        gossip.record_rtt("n4", Duration::from_millis(500));
+        assert_eq!(gossip.neighbours_by_rtt(), ["n3", "n2", "n5", "n4"]);
+    }
+
+    // New messages go straight to the fastest neighbours, and the rest a sync later
+    #[test]
+    fn forward_prefers_the_fastest_neighbours() {
+        let mut gossip = gossip(&["n2", "n3", "n4", "n5"]);
+        for (node, ms) in [("n2", 40), ("n3", 5), ("n4", 500), ("n5", 300)] {
+            gossip.record_rtt(node, Duration::from_millis(ms));
+        }
+        gossip.forward(7);
+        let sent: HashSet<&str> = ["n2", "n3", "n4", "n5"].into_iter().filter(|node| gossip.pending_to(node) == [7]).collect();
+        assert_eq!(sent, HashSet::from(["n3", "n2"]));
+        gossip.release_deferred();
+        assert!(["n2", "n3", "n4", "n5"].iter().all(|node| gossip.pending_to(node) == [7]));
+
+        // The sender doesn't count towards the fast ones
+        gossip.forward_except(8, Some("n3"));
+        assert!(!gossip.pending_to("n3").contains(&8));
+        assert!(gossip.pending_to("n2").contains(&8) && gossip.pending_to("n5").contains(&8));
+        assert!(!gossip.pending_to("n4").contains(&8));
+    }
+
+    // A topology can name neighbours that aren't in node_ids; they're skipped rather than panicked on
+    #[test]
+    fn unknown_neighbours_are_skipped() {
+        let mut gossip = gossip(&["n2", "n9", "n3"]);
+        assert_eq!(gossip.neighbours_by_rtt(), ["n2", "n3"]);
+        gossip.forward(7);
+        gossip.forward_except(8, Some("n2"));
+        assert_eq!(gossip.pending_to("n2"), [7]);
+        assert_eq!(gossip.pending_to("n3"), [7, 8]);
+        assert!(gossip.pending_to("n9").is_empty());
+    }
+
+    // How many hops it takes to reach the furthest node from root
+    fn depth_from(topology: &Topology, root: &String) -> usize {
+        let mut reached = HashSet::from([root]);
+        let (mut frontier, mut depth) = (vec![root], 0);
+        loop {
+            let next: Vec<&String> = frontier.iter().flat_map(|node| topology.neighbours(node)).filter(|node| reached.insert(*node)).collect();
+            if next.is_empty() {
+                return depth
+            }
+            frontier = next;
+            depth += 1;
+        }
+    }
+
+    // A neighbour is pinged once it's gone quiet, and once it's presumed dead only every
+    // DEAD_PEER_SYNC_EVERY'th sync goes out, until it's heard from again
+    #[test]
+    fn dead_neighbours_are_synced_with_less_often() {
+        let clock = Arc::new(crate::clock::ManualClock::new());
+        let timeout = Duration::from_millis(100);
+        let mut policy = SyncPolicy::new(PeerLiveness::with_clock(&node_ids(2), timeout, clock.clone()));
+        assert_eq!(policy.on_sync("n2"), SyncAction::Sync { ping: false });
+        clock.advance(timeout * 3 / 4);
+        assert_eq!(policy.on_sync("n2"), SyncAction::Sync { ping: true });
+
+        clock.advance(timeout);
+        let actions: Vec<SyncAction> = (0..2 * DEAD_PEER_SYNC_EVERY).map(|_| policy.on_sync("n2")).collect();
+        let sent: Vec<usize> = (0..actions.len()).filter(|i| actions[*i] != SyncAction::Skip).collect();
+        assert_eq!(sent, [DEAD_PEER_SYNC_EVERY - 2, 2 * DEAD_PEER_SYNC_EVERY - 2]);
+        assert!(!policy.is_peer_alive("n2"));
+
+        policy.heard_from("n2");
+        assert_eq!(policy.on_sync("n2"), SyncAction::Sync { ping: false });
+    }
+
+    // Over one interval every node syncs with each of its neighbours once, and no two syncs in the
+    // whole cluster are due at the same instant
+    #[test]
+    fn syncs_are_spread_across_the_interval() {
+        let node_ids = node_ids(6);
+        let topology = fanout_topology(&node_ids, 3);
+        let interval = Duration::from_millis(300);
+        let start = Instant::now();
+        let mut sync_times = HashSet::new();
+        for node in &node_ids {
+            let mut schedule = SyncSchedule::new(&node_ids, node, &topology[node], interval, start);
+            let mut synced = vec![];
+            while let Some(deadline) = schedule.next_deadline().filter(|deadline| *deadline < start + interval) {
+                let due = schedule.due(deadline);
+                assert_eq!(due.len(), 1, "{node} syncs with {due:?} at once");
+                assert!(sync_times.insert(deadline), "two syncs are due {:?} into the interval", deadline - start);
+                synced.extend(due);
+            }
+            synced.sort();
+            let mut neighbours = topology[node].clone();
+            neighbours.sort();
+            assert_eq!(synced, neighbours);
+        }
+    }
+
+    // A node's backlog counts every message it hasn't acked, whether it's been sent yet or is still
+    // deferred, and drops as acks come in
+    #[test]
+    fn pending_counts_are_the_unacked_messages() {
+        let node_ids = node_ids(FAST_NEIGHBOURS + 2);
+        let neighbours = node_ids[1..].to_vec();
+        let mut gossip: Gossip<u64> = Gossip::new(&node_ids, neighbours.clone());
+        for message in 0..10 {
+            gossip.forward(message);
+        }
+        let slow = &neighbours[FAST_NEIGHBOURS];
+        assert!(gossip.pending_to(slow).is_empty());
+        for neighbour in &neighbours {
+            assert_eq!(gossip.pending_count(neighbour), 10, "{neighbour}");
+        }
+
+        gossip.release_deferred();
+        gossip.sync_ok(slow, &[0, 1, 2]);
+        gossip.already_has(&neighbours[0], &[9]);
+        assert_eq!(gossip.pending_count(slow), 7);
+        assert_eq!(gossip.pending_count(&neighbours[0]), 9);
+        gossip.sync_ok(slow, &(0..10).collect::<Vec<_>>());
+        assert_eq!(gossip.pending_count(slow), 0);
+        assert_eq!(gossip.pending_count("n99"), 0);
+    }
+
+    // Maelstrom can deliver a sync_ok twice, and one can turn up from a node that isn't in the
+    // cluster. Neither changes what's left to send.
+    #[test]
+    fn repeated_and_unknown_sync_oks_are_ignored() {
+        let mut gossip = gossip(&["n2", "n3"]);
+        for message in 0..5 {
+            gossip.forward(message);
+        }
+        gossip.sync_ok("n2", &[0, 1]);
+        let pending = |gossip: &Gossip<u64>| (gossip.pending_to("n2").to_vec(), gossip.pending_to("n3").to_vec(), gossip.undelivered().len());
+        let before = pending(&gossip);
+        assert_eq!(before, (vec![2, 3, 4], vec![0, 1, 2, 3, 4], 5));
+
+        gossip.sync_ok("n2", &[0, 1]);
+        gossip.sync_ok("n99", &[2, 3, 4]);
+        gossip.sync_ok("n99", &[2, 3, 4]);
+        assert_eq!(pending(&gossip), before);
+    }
+}
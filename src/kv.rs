@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::error::{Error, ErrorCode, ErrorMessage};
+use crate::log;
+use crate::message::Envelope;
+use crate::runtime::OutputSender;
+
+pub const SEQ_KV: &str = "seq-kv";
+pub const LIN_KV: &str = "lin-kv";
+pub const LWW_KV: &str = "lww-kv";
+// Not a kv store, but Maelstrom's other service: a timestamp oracle
+pub const LIN_TSO: &str = "lin-tso";
+
+// Messages understood by Maelstrom's key-value services
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum KvMessage {
+    Read {
+        #[serde(deserialize_with = "deserialize_key")]
+        key: String
+    },
+    ReadOk { value: u64 },
+    Write {
+        #[serde(deserialize_with = "deserialize_key")]
+        key: String,
+        value: u64
+    },
+    WriteOk,
+    Cas {
+        #[serde(deserialize_with = "deserialize_key")]
+        key: String,
+        from: u64,
+        to: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        create_if_not_exists: Option<bool>,
+    },
+    CasOk,
+
+    Error {
+        code: u64,
+        text: String
+    },
+}
+
+crate::impl_error_message!(KvMessage);
+crate::impl_type_tag!(KvMessage { Read, ReadOk, Write, WriteOk, Cas, CasOk, Error });
+
+// Workloads like lin-kv use integer keys; we treat every key as a string
+fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Key {
+        String(String),
+        Number(u64),
+    }
+
+    Ok(match Key::deserialize(deserializer)? {
+        Key::String(s) => s,
+        Key::Number(n) => n.to_string(),
+    })
+}
+
+// How long KvClient::update waits before retrying after its CAS loses to another writer. The wait
+// doubles with each loss in a row, up to UPDATE_BACKOFF_MAX.
+const UPDATE_BACKOFF: Duration = Duration::from_millis(1);
+const UPDATE_BACKOFF_MAX: Duration = Duration::from_millis(100);
+
+// Whether an error reply to a CAS with create_if_not_exists means the key was already there with
+// another value. seq-kv and lin-kv say precondition-failed; other stores say key-already-exists.
+pub fn key_already_exists(code: &ErrorCode) -> bool {
+    matches!(code, ErrorCode::PreconditionFailed | ErrorCode::KeyAlreadyExists)
+}
+
+// One kv service's keys, held in memory and answered the way Maelstrom answers them. Requests are
+// applied in the order they're handled, which is trivially linearizable. kv-server keeps one per
+// service, and tests use it in place of Maelstrom.
+#[derive(Debug, Default)]
+pub struct MemoryKv {
+    values: HashMap<String, u64>,
+}
+
+impl MemoryKv {
+    pub fn new() -> MemoryKv {
+        MemoryKv::default()
+    }
+
+    // The reply to a read, write or cas
+    pub fn handle(&mut self, request: &KvMessage) -> KvMessage {
+        match request {
+            KvMessage::Read { key } => match self.values.get(key) {
+                Some(value) => KvMessage::ReadOk { value: *value },
+                None => KvMessage::error(ErrorCode::KeyDoesNotExist, "key does not exist".to_string()),
+            },
+
+            KvMessage::Write { key, value } => {
+                self.values.insert(key.clone(), *value);
+                KvMessage::WriteOk
+            }
+
+            // Like Maelstrom, a CAS on a missing key with create_if_not_exists just sets it to `to`,
+            // whatever `from` was
+            KvMessage::Cas { key, from, to, create_if_not_exists } => match self.values.get_mut(key) {
+                Some(value) if *value == *from => {
+                    *value = *to;
+                    KvMessage::CasOk
+                }
+                Some(value) => KvMessage::error(ErrorCode::PreconditionFailed, format!("expected {from}, but had {value}")),
+                None if create_if_not_exists.unwrap_or(false) => {
+                    self.values.insert(key.clone(), *to);
+                    KvMessage::CasOk
+                }
+                None => KvMessage::error(ErrorCode::KeyDoesNotExist, "key does not exist".to_string()),
+            },
+
+            _ => unreachable!("handle is only called with requests"),
+        }
+    }
+}
+
+// KvClient::update as a state machine, for callers that can't block waiting for each reply, such
+// as a workload's main loop with several keys on the go. Send request(), hand its reply to reply()
+// and do what the UpdateStep says; KvClient::update drives one of these itself. A key that doesn't
+// exist yet counts as 0. f may be called several times, so it should only compute the new value.
+#[derive(Debug)]
+pub struct Update {
+    key: String,
+    // The values the CAS goes from and to, or None while the key's being read
+    cas: Option<(u64, u64)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UpdateStep {
+    // Send request() next
+    Send,
+    // Another writer changed the key first. Wait a while, so as not to collide with it again, and
+    // then send request(), which reads the key afresh.
+    Retry,
+    // The CAS went through, leaving the key at this value
+    Done(u64),
+}
+
+impl Update {
+    // An update that starts with a CAS from `expected`, if the caller thinks it knows the value, and
+    // with a read otherwise
+    pub fn new(key: &str, expected: Option<u64>, f: impl FnOnce(u64) -> u64) -> Update {
+        Update { key: key.to_string(), cas: expected.map(|from| (from, f(from))) }
+    }
+
+    // The value the CAS goes from: the one expected, or the one last read
+    pub fn from(&self) -> Option<u64> {
+        self.cas.map(|(from, _)| from)
+    }
+
+    // The value the CAS would leave the key at
+    pub fn to(&self) -> Option<u64> {
+        self.cas.map(|(_, to)| to)
+    }
+
+    // Only a CAS from 0 creates the key, so one from a value the key never had can't
+    pub fn request(&self) -> KvMessage {
+        match self.cas {
+            Some((from, to)) => KvMessage::Cas { key: self.key.clone(), from, to, create_if_not_exists: (from == 0).then_some(true) },
+            None => KvMessage::Read { key: self.key.clone() },
+        }
+    }
+
+    // Takes the reply to request(). Errors other than a lost CAS or a missing key come back as they
+    // are; a CAS that failed with one of those definitely didn't happen, but may have to be redone
+    // differently.
+    pub fn reply(&mut self, reply: KvMessage, f: impl FnOnce(u64) -> u64) -> Result<UpdateStep, Error> {
+        let from = match (self.cas, reply) {
+            (Some((_, to)), KvMessage::CasOk) => return Ok(UpdateStep::Done(to)),
+            (None, KvMessage::ReadOk { value }) => value,
+            (cas, KvMessage::Error { code, text }) => {
+                let e = Error::from_reply(code, text);
+                match (cas, e.code) {
+                    (_, ErrorCode::KeyDoesNotExist) => 0,
+                    (Some((from, _)), code) if code.is_retriable() => {
+                        log::debug!("update of {} from {from} failed ({e:?}), reading it again", self.key);
+                        self.cas = None;
+                        return Ok(UpdateStep::Retry)
+                    }
+                    _ => return Err(e),
+                }
+            }
+            (_, reply) => return Err(Error { code: ErrorCode::Crash, text: format!("{reply:?} in reply to {:?}", self.request()) }),
+        };
+        self.cas = Some((from, f(from)));
+        Ok(UpdateStep::Send)
+    }
+}
+
+// KvClient::init_key as a state machine, like Update: send request() and hand its reply to reply()
+// until that returns the key's value
+#[derive(Debug)]
+pub struct InitKey {
+    key: String,
+    default: u64,
+    // Whether the create failed, and the key's being read
+    reading: bool,
+}
+
+impl InitKey {
+    pub fn new(key: &str, default: u64) -> InitKey {
+        InitKey { key: key.to_string(), default, reading: false }
+    }
+
+    // The create is a CAS from `default` to itself, so an existing key holding `default` doesn't
+    // cost a read
+    pub fn request(&self) -> KvMessage {
+        match self.reading {
+            false => KvMessage::Cas { key: self.key.clone(), from: self.default, to: self.default, create_if_not_exists: Some(true) },
+            true => KvMessage::Read { key: self.key.clone() },
+        }
+    }
+
+    // Takes the reply to request(), returning the key's value once it's known and None if
+    // request() should be sent next. Neither request changes the key, so after an error it's safe
+    // to send request() again.
+    pub fn reply(&mut self, reply: KvMessage) -> Result<Option<u64>, Error> {
+        match (self.reading, reply) {
+            (false, KvMessage::CasOk) => Ok(Some(self.default)),
+            (true, KvMessage::ReadOk { value }) => Ok(Some(value)),
+            (false, KvMessage::Error { code, text }) => match Error::from_reply(code, text) {
+                e if key_already_exists(&e.code) => {
+                    self.reading = true;
+                    Ok(None)
+                }
+                e => Err(e),
+            },
+            (_, KvMessage::Error { code, text }) => Err(Error::from_reply(code, text)),
+            (_, reply) => Err(Error { code: ErrorCode::Crash, text: format!("{reply:?} in reply to {:?}", self.request()) }),
+        }
+    }
+}
+
+// A workload message type that can carry KvMessages, so a KvClient can share the workload's
+// input and output channels
+pub trait KvPayload: Clone + Debug + From<KvMessage> {
+    // The kv message this is, if it is one
+    fn to_kv_message(&self) -> Option<KvMessage>;
+}
+
+impl KvPayload for KvMessage {
+    fn to_kv_message(&self) -> Option<KvMessage> {
+        Some(self.clone())
+    }
+}
+
+// Blocking client for one of the kv services. Only one request is outstanding at a time; replies
+// are matched to requests by in_reply_to and anything else on the incoming channel is ignored.
+pub struct KvClient<B: KvPayload> {
+    local_node: String,
+    address: String,
+    incoming: Receiver<Envelope<B>>,
+    outgoing: OutputSender<B>,
+}
+
+impl<B: KvPayload> KvClient<B> {
+    pub fn new(local_node: String, address: String, incoming: Receiver<Envelope<B>>, outgoing: OutputSender<B>) -> KvClient<B> {
+        KvClient { local_node, address, incoming, outgoing }
+    }
+
+    pub fn read(&self, key: &str) -> Result<u64, Error> {
+        match self.call(KvMessage::Read { key: key.to_string() })? {
+            KvMessage::ReadOk { value } => Ok(value),
+            m => panic!("Expected read_ok but got {m:?}"),
+        }
+    }
+
+    pub fn write(&self, key: &str, value: u64) -> Result<(), Error> {
+        match self.call(KvMessage::Write { key: key.to_string(), value })? {
+            KvMessage::WriteOk => Ok(()),
+            m => panic!("Expected write_ok but got {m:?}"),
+        }
+    }
+
+    pub fn cas(&self, key: &str, from: u64, to: u64, create_if_not_exists: bool) -> Result<(), Error> {
+        let create_if_not_exists = if create_if_not_exists { Some(true) } else { None };
+        match self.call(KvMessage::Cas { key: key.to_string(), from, to, create_if_not_exists })? {
+            KvMessage::CasOk => Ok(()),
+            m => panic!("Expected cas_ok but got {m:?}"),
+        }
+    }
+
+    // Creates the key with the value `default` if it doesn't exist yet, and returns the value it
+    // has now: `default` unless an earlier run or another node already set it (see InitKey)
+    pub fn init_key(&self, key: &str, default: u64) -> Result<u64, Error> {
+        let mut init = InitKey::new(key, default);
+        loop {
+            if let Some(value) = init.reply(self.send_and_wait(init.request())?)? {
+                return Ok(value)
+            }
+        }
+    }
+
+    // Replaces the key's value v with f(v), retrying with a fresh read each time another writer
+    // changes the key first, and returns the value written (see Update)
+    pub fn update(&self, key: &str, f: impl FnMut(u64) -> u64) -> Result<u64, Error> {
+        self.update_from(key, None, f)
+    }
+
+    // Like update, but the first attempt assumes the value is `expected` instead of reading it,
+    // which saves a round trip for callers that remember what they last wrote
+    pub fn update_from(&self, key: &str, expected: Option<u64>, mut f: impl FnMut(u64) -> u64) -> Result<u64, Error> {
+        let mut update = Update::new(key, expected, &mut f);
+        let mut backoff = UPDATE_BACKOFF;
+        loop {
+            match update.reply(self.send_and_wait(update.request())?, &mut f)? {
+                UpdateStep::Send => {}
+                UpdateStep::Retry => {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(UPDATE_BACKOFF_MAX);
+                }
+                UpdateStep::Done(value) => return Ok(value),
+            }
+        }
+    }
+
+    // Sends a request and waits for its reply, turning an error reply into an Err
+    fn call(&self, request: KvMessage) -> Result<KvMessage, Error> {
+        match self.send_and_wait(request)? {
+            KvMessage::Error { code, text } => Err(Error::from_reply(code, text)),
+            reply => Ok(reply),
+        }
+    }
+
+    // Sends a request and waits for its reply, whatever it is. If the incoming channel closes first
+    // (stdin ended, say) there's no reply coming, and that's a crash error, since the request may or
+    // may not have been carried out.
+    fn send_and_wait(&self, request: KvMessage) -> Result<KvMessage, Error> {
+        let e = Envelope::new(self.local_node.clone(), self.address.clone(), None, request.into());
+        if self.outgoing.send(e.clone()).is_err() {
+            return Err(Error { code: ErrorCode::Crash, text: format!("output closed before sending a request to {}", self.address) })
+        }
+
+        for env in self.incoming.iter() {
+            if !env.is_reply_to(&e) {
+                continue
+            }
+
+            return match env.message().to_kv_message() {
+                Some(m) => Ok(m),
+                None => panic!("Expected a kv reply but got {env:?}"),
+            }
+        }
+        Err(Error { code: ErrorCode::Crash, text: format!("incoming channel closed while waiting for a reply from {}", self.address) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+
+    use crate::runtime::OutputHandler;
+
+    use super::*;
+
+    const UPDATERS: usize = 4;
+    const UPDATES: u64 = 50;
+
+    // A KvClient whose requests are answered from store, on a thread of its own
+    fn client(node: &str, store: &Arc<Mutex<MemoryKv>>) -> KvClient<KvMessage> {
+        let store = store.clone();
+        client_answered_by(node, move |request| store.lock().unwrap().handle(request))
+    }
+
+    // A KvClient whose requests are answered by answer, on a thread of its own
+    fn client_answered_by(node: &str, mut answer: impl FnMut(&KvMessage) -> KvMessage + Send + 'static) -> KvClient<KvMessage> {
+        let (outgoing, requests) = OutputHandler::to_channel(16);
+        let (replies, incoming) = channel();
+        thread::spawn(move || {
+            for request in requests {
+                let reply = answer(request.message());
+                if replies.send(request.try_reply(reply).unwrap()).is_err() {
+                    break
+                }
+            }
+        });
+        KvClient::new(node.to_string(), LIN_KV.to_string(), incoming, outgoing)
+    }
+
+    // However the updaters' reads and CASes interleave, no update is lost
+    #[test]
+    fn concurrent_updates_all_count() {
+        let store = Arc::new(Mutex::new(MemoryKv::new()));
+        let updaters: Vec<_> = (0..UPDATERS).map(|i| {
+            let kv = client(&format!("n{i}"), &store);
+            thread::spawn(move || {
+                for _ in 0..UPDATES {
+                    kv.update("total", |value| value + 1).unwrap();
+                }
+            })
+        }).collect();
+        for updater in updaters {
+            updater.join().unwrap();
+        }
+        assert_eq!(client("n0", &store).read("total").unwrap(), UPDATERS as u64 * UPDATES);
+    }
+
+    // A wrong guess at the current value costs a retry from a fresh read, not a lost update
+    #[test]
+    fn updates_from_a_stale_value_retry() {
+        let store = Arc::new(Mutex::new(MemoryKv::new()));
+        let kv = client("n1", &store);
+        kv.write("total", 10).unwrap();
+        let mut seen = vec![];
+        assert_eq!(kv.update_from("total", Some(3), |value| {
+            seen.push(value);
+            value + 1
+        }).unwrap(), 11);
+        assert_eq!(seen, [3, 10]);
+        // A key that isn't there yet counts as 0, whatever was expected
+        assert_eq!(kv.update("missing", |value| value + 5).unwrap(), 5);
+        assert_eq!(kv.update_from("also-missing", Some(3), |value| value + 5).unwrap(), 5);
+        assert_eq!(kv.read("also-missing").unwrap(), 5);
+    }
+
+    // A lost CAS is retried from a fresh read, an error that won't go away is handed back, and a
+    // CAS from anything but 0 never creates the key
+    #[test]
+    fn update_steps() {
+        let mut update = Update::new("k", Some(4), |value| value + 1);
+        assert!(matches!(update.request(), KvMessage::Cas { from: 4, to: 5, create_if_not_exists: None, .. }));
+        let lost = KvMessage::error(ErrorCode::PreconditionFailed, "expected 4, but had 6".to_string());
+        assert_eq!(update.reply(lost, |value| value + 1).unwrap(), UpdateStep::Retry);
+        assert!(matches!(update.request(), KvMessage::Read { .. }));
+        assert_eq!(update.reply(KvMessage::ReadOk { value: 6 }, |value| value + 1).unwrap(), UpdateStep::Send);
+        assert_eq!((update.from(), update.to()), (Some(6), Some(7)));
+        assert_eq!(update.reply(KvMessage::CasOk, |value| value + 1).unwrap(), UpdateStep::Done(7));
+
+        let mut update = Update::new("k", None, |value| value + 1);
+        let missing = KvMessage::error(ErrorCode::KeyDoesNotExist, "key does not exist".to_string());
+        assert_eq!(update.reply(missing, |value| value + 1).unwrap(), UpdateStep::Send);
+        assert!(matches!(update.request(), KvMessage::Cas { from: 0, to: 1, create_if_not_exists: Some(true), .. }));
+        let unsupported = KvMessage::error(ErrorCode::NotSupported, "no cas here".to_string());
+        assert_eq!(update.reply(unsupported, |value| value + 1).unwrap_err().code, ErrorCode::NotSupported);
+    }
+
+    // A fresh key is created with the default, and a key that's already there keeps its value,
+    // whichever error the store reports the failed create with
+    #[test]
+    fn init_key_creates_or_reads() {
+        let store = Arc::new(Mutex::new(MemoryKv::new()));
+        let kv = client("n1", &store);
+        assert_eq!(kv.init_key("fresh", 7).unwrap(), 7);
+        assert_eq!(kv.read("fresh").unwrap(), 7);
+        kv.write("existing", 40).unwrap();
+        assert_eq!(kv.init_key("existing", 0).unwrap(), 40);
+        assert_eq!(kv.read("existing").unwrap(), 40);
+
+        let answering = store.clone();
+        let kv = client_answered_by("n1", move |request| match request {
+            KvMessage::Cas { create_if_not_exists: Some(true), .. } => KvMessage::error(ErrorCode::KeyAlreadyExists, "key already exists".to_string()),
+            request => answering.lock().unwrap().handle(request),
+        });
+        assert_eq!(kv.init_key("existing", 0).unwrap(), 40);
+    }
+}
@@ -1,2 +1,16 @@
 pub mod message;
 pub mod error;
+pub mod runtime;
+pub mod kv;
+pub mod gossip;
+pub mod log;
+pub mod trace;
+pub mod metrics;
+pub mod segments;
+pub mod codec;
+pub mod clock;
+pub mod safe_int;
+pub mod sim;
+pub mod type_tag;
+pub mod validate;
+pub mod protocol;
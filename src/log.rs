@@ -0,0 +1,54 @@
+use std::fmt::{Arguments, Debug};
+
+use once_cell::sync::OnceCell;
+
+use crate::message::Envelope;
+
+static NODE_ID: OnceCell<String> = OnceCell::new();
+
+// Sets the node id that prefixes every log line. Only the first call has any effect, so it's safe
+// to call again if we ever see a second Init.
+pub fn set_node_id(node_id: &str) {
+    let _ = NODE_ID.set(node_id.to_string());
+}
+
+pub fn node_id() -> &'static str {
+    NODE_ID.get().map(String::as_str).unwrap_or("-")
+}
+
+pub fn write(args: Arguments) {
+    eprintln!("[{}] {}", node_id(), args);
+}
+
+// With GG_TIMESTAMPS set, lines about envelopes we've received also say how long they took to
+// arrive and how many hops they'd made
+pub fn write_envelope<B: Debug>(envelope: &Envelope<B>, args: Arguments) {
+    let msg_id = envelope.msg_id().map(|id| id.to_string()).unwrap_or_else(|| "-".to_string());
+    let transit = match (envelope.dest == node_id(), envelope.elapsed_since_sent()) {
+        (true, Some(elapsed)) => format!(" ({elapsed:?} in transit, {} hops)", envelope.hops().unwrap_or(0)),
+        _ => String::new(),
+    };
+    match envelope.in_reply_to() {
+        Some(in_reply_to) => eprintln!("[{} {}->{} #{} re #{}] {}{}", node_id(), envelope.src, envelope.dest, msg_id, in_reply_to, args, transit),
+        None => eprintln!("[{} {}->{} #{}] {}{}", node_id(), envelope.src, envelope.dest, msg_id, args, transit),
+    }
+}
+
+// debug!("format", args...) logs a line prefixed with the node id
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::write(format_args!($($arg)*))
+    };
+}
+
+// debug_envelope!(envelope, "format", args...) also includes the envelope's addresses, msg_id and
+// in_reply_to, so requests can be matched up with their replies
+#[macro_export]
+macro_rules! debug_envelope {
+    ($envelope:expr, $($arg:tt)*) => {
+        $crate::log::write_envelope($envelope, format_args!($($arg)*))
+    };
+}
+
+pub use crate::{debug, debug_envelope};
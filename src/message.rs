@@ -1,16 +1,223 @@
-use std::fmt::Debug;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use serde::{Deserialize, Serialize};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 
-static MESSAGE_ID: AtomicUsize = AtomicUsize::new(0);
+use crate::log;
+use crate::metrics;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Body<B: Debug> {
+// Set GG_TIMESTAMPS=1 to stamp every envelope we build with the time it was sent and the number of
+// node-to-node hops behind it (see Envelope::forward), so that when both ends are our binaries the
+// receiver can tell how long it spent in transit. The fields are left out entirely otherwise.
+const TIMESTAMPS_ENV_VAR: &str = "GG_TIMESTAMPS";
+
+static TIMESTAMPS: Lazy<bool> = Lazy::new(|| std::env::var(TIMESTAMPS_ENV_VAR).is_ok());
+
+// Used by Envelope::new and Envelope::try_reply, for binaries that only ever run a single node
+static MESSAGE_IDS: MessageIdGenerator = MessageIdGenerator::new();
+
+// Allocates msg_ids for the envelopes sent by one node. Each logical node should own its own
+// generator so ids don't collide when several nodes share a process.
+//
+// Ids are u64 whatever the pointer width, so they can't realistically run out: at a billion
+// messages a second it would take centuries. If they ever did, the counter would wrap back to 0
+// and replies could be matched to the wrong requests, so debug builds panic instead.
+//
+// Envelope::new and Envelope::try_reply share one generator for the whole process, so the ids they
+// hand out depend on everything else that's been sent. Code that needs ids it can predict, such
+// as a harness comparing output against a golden file, should build envelopes with new_with_ids
+// and try_reply_with_ids and a generator of its own, from new or starting_at.
+#[derive(Debug, Default)]
+pub struct MessageIdGenerator {
+    next_id: AtomicU64,
+}
+
+impl MessageIdGenerator {
+    pub const fn new() -> MessageIdGenerator {
+        MessageIdGenerator::starting_at(0)
+    }
+
+    // A generator whose first id is first_id
+    pub const fn starting_at(first_id: u64) -> MessageIdGenerator {
+        MessageIdGenerator { next_id: AtomicU64::new(first_id) }
+    }
+
+    pub fn next_id(&self) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        debug_assert_ne!(id, u64::MAX, "ran out of msg_ids");
+        id
+    }
+
+    // The id the next call to next_id will return
+    pub fn current(&self) -> u64 {
+        self.next_id.load(Ordering::SeqCst)
+    }
+}
+
+// The generator Envelope::new and Envelope::try_reply use, for code that takes a generator but runs as
+// the process's only node
+pub fn default_ids() -> &'static MessageIdGenerator {
+    &MESSAGE_IDS
+}
+
+// The address of a node, client or service. Cloning one (as every reply does, twice) only bumps a
+// reference count. Serializes as a plain string.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(Arc<str>);
+
+impl NodeId {
+    // Maelstrom names nodes n1, n2, ...; clients are c1, c2, ... and services are seq-kv and so on
+    pub fn is_node(&self) -> bool {
+        self.0.as_bytes().first() == Some(&b'n')
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // Every new NodeId goes through here, so GG_METRICS=1 shows how many a node makes. Parsing an
+    // envelope makes two; sending one shouldn't make any.
+    fn allocate(value: &str) -> NodeId {
+        metrics::count("node id allocations");
+        NodeId(value.into())
+    }
+}
+
+impl Deref for NodeId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for NodeId {
+    fn from(value: &str) -> Self {
+        NodeId::allocate(value)
+    }
+}
+
+impl From<String> for NodeId {
+    fn from(value: String) -> Self {
+        NodeId::allocate(&value)
+    }
+}
+
+impl From<&String> for NodeId {
+    fn from(value: &String) -> Self {
+        NodeId::allocate(value)
+    }
+}
+
+impl From<NodeId> for String {
+    fn from(value: NodeId) -> Self {
+        value.0.to_string()
+    }
+}
+
+impl PartialEq<str> for NodeId {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for NodeId {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl PartialEq<String> for NodeId {
+    fn eq(&self, other: &String) -> bool {
+        *self.0 == **other
+    }
+}
+
+impl PartialEq<NodeId> for String {
+    fn eq(&self, other: &NodeId) -> bool {
+        **self == *other.0
+    }
+}
+
+impl Debug for NodeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&*self.0, f)
+    }
+}
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for NodeId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(NodeId::from(String::deserialize(deserializer)?))
+    }
+}
+
+// Everything in a body apart from the message itself. New fields added here are carried over to
+// replies automatically, apart from the timestamps, which every new envelope sets afresh.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    msg_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_reply_to: Option<u64>,
+    // Nanoseconds since the Unix epoch when the envelope was built. Instants can't be compared
+    // between processes, so this is wall-clock time; Maelstrom runs every node on one machine, so
+    // the nodes' clocks agree.
     #[serde(skip_serializing_if = "Option::is_none")]
-    msg_id: Option<usize>,
+    send_ts: Option<u64>,
+    // How many times what's in the envelope has been passed on from one node to another before
+    // this: 0 for a message a node came up with itself
     #[serde(skip_serializing_if = "Option::is_none")]
-    in_reply_to: Option<usize>,
+    hops: Option<u32>,
+    // Stands in for a field added later, so the tests can check that one is carried over to replies
+    #[cfg(test)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extra: Option<String>,
+}
+
+impl Metadata {
+    fn new(msg_id: u64, in_reply_to: Option<u64>, hops: u32) -> Metadata {
+        let mut metadata = Metadata { msg_id: Some(msg_id), in_reply_to, ..Metadata::default() };
+        metadata.stamp(hops);
+        metadata
+    }
+
+    // Sets the timestamps for an envelope about to be sent, or clears them if GG_TIMESTAMPS isn't
+    // set, so a reply never passes on the request's
+    fn stamp(&mut self, hops: u32) {
+        self.send_ts = TIMESTAMPS.then(now_nanos);
+        self.hops = TIMESTAMPS.then_some(hops);
+    }
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+}
+
+// The message is flattened into the body alongside the metadata. That also makes Envelope<Value>
+// a passthrough form, for proxies and the like that don't know the workload's message type: the
+// Value gets every field of the body apart from msg_id and in_reply_to, and writes them back out
+// the same way. It has to be a JSON object for the envelope to serialize.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Body<B: Debug> {
+    #[serde(flatten)]
+    metadata: Metadata,
 
     #[serde(flatten)]
     message: B
@@ -19,17 +226,28 @@ pub struct Body<B: Debug> {
 impl<B: Clone + Debug> Clone for Body<B> {
     fn clone(&self) -> Self {
         Body {
-            msg_id: self.msg_id.clone(),
-            in_reply_to: self.in_reply_to.clone(),
+            metadata: self.metadata.clone(),
             message: self.message.clone(),
         }
     }
 }
 
+impl<B: Debug> Body<B> {
+    // A body for the reply to this one: the same metadata, apart from a new msg_id, an
+    // in_reply_to pointing back at this body and timestamps of its own
+    pub fn respond(&self, msg_id: u64, message: B) -> Body<B> {
+        let mut metadata = self.metadata.clone();
+        metadata.msg_id = Some(msg_id);
+        metadata.in_reply_to = self.metadata.msg_id;
+        metadata.stamp(0);
+        Body { metadata, message }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Envelope<B: Debug> {
-    pub src: String,
-    pub dest: String,
+    pub src: NodeId,
+    pub dest: NodeId,
     body: Body<B>
 }
 
@@ -44,43 +262,312 @@ impl<B: Clone + Debug> Clone for Envelope<B> {
 }
 
 impl<B: Debug> Envelope<B> {
-    pub fn new(src: String, dest: String, in_reply_to: Option<usize>, message: B) -> Envelope<B> {
+    pub fn new(src: impl Into<NodeId>, dest: impl Into<NodeId>, in_reply_to: Option<u64>, message: B) -> Envelope<B> {
+        Envelope::new_with_ids(&MESSAGE_IDS, src, dest, in_reply_to, message)
+    }
+
+    pub fn new_with_ids(ids: &MessageIdGenerator, src: impl Into<NodeId>, dest: impl Into<NodeId>, in_reply_to: Option<u64>, message: B) -> Envelope<B> {
         Envelope {
-            src,
-            dest,
+            src: src.into(),
+            dest: dest.into(),
             body: Body {
-                msg_id: Some(MESSAGE_ID.fetch_add(1, Ordering::SeqCst)),
-                in_reply_to,
+                metadata: Metadata::new(ids.next_id(), in_reply_to, 0),
                 message
             }
         }
     }
 
+    // Passes what's in this envelope on to another node, one hop further along than this one. The
+    // message is usually this envelope's, or part of it.
+    pub fn forward(&self, src: impl Into<NodeId>, dest: impl Into<NodeId>, message: B) -> Envelope<B> {
+        self.forward_with_ids(&MESSAGE_IDS, src, dest, message)
+    }
+
+    pub fn forward_with_ids(&self, ids: &MessageIdGenerator, src: impl Into<NodeId>, dest: impl Into<NodeId>, message: B) -> Envelope<B> {
+        let hops = self.body.metadata.hops.map_or(0, |hops| hops + 1);
+        Envelope {
+            src: src.into(),
+            dest: dest.into(),
+            body: Body { metadata: Metadata::new(ids.next_id(), None, hops), message },
+        }
+    }
+
     pub fn is_from_node(&self) -> bool {
-        self.src.starts_with('n')
+        self.src.is_node()
     }
 
     pub fn message(&self) -> &B {
         &self.body.message
     }
 
-    pub fn msg_id(&self) -> Option<usize> {
-        self.body.msg_id
+    pub fn msg_id(&self) -> Option<u64> {
+        self.body.metadata.msg_id
+    }
+
+    pub fn in_reply_to(&self) -> Option<u64> {
+        self.body.metadata.in_reply_to
+    }
+
+    // See Metadata::hops. None unless the sender had GG_TIMESTAMPS set.
+    pub fn hops(&self) -> Option<u32> {
+        self.body.metadata.hops
+    }
+
+    // How long ago the envelope was sent, for one we've just received. None unless the sender had
+    // GG_TIMESTAMPS set.
+    pub fn elapsed_since_sent(&self) -> Option<Duration> {
+        let send_ts = self.body.metadata.send_ts?;
+        Some(Duration::from_nanos(now_nanos().saturating_sub(send_ts)))
+    }
+
+    // Whether this is a reply to `request`: it answers the request's msg_id and goes back the way
+    // the request came. A request without a msg_id can't have replies.
+    pub fn is_reply_to<C: Debug>(&self, request: &Envelope<C>) -> bool {
+        request.msg_id().is_some()
+            && self.in_reply_to() == request.msg_id()
+            && self.src == request.dest
+            && self.dest == request.src
     }
 
-    pub fn in_reply_to(&self) -> Option<usize> {
-        self.body.in_reply_to
+    // Swaps the message for another, keeping src, dest and the ids, e.g. to wrap one workload's
+    // messages in another's enum
+    pub fn map_message<C: Debug>(self, f: impl FnOnce(B) -> C) -> Envelope<C> {
+        Envelope {
+            src: self.src,
+            dest: self.dest,
+            body: Body { metadata: self.body.metadata, message: f(self.body.message) },
+        }
     }
 
-    pub fn reply(&self, message: B) -> Envelope<B> {
+    // Like map_message, but leaves this envelope alone
+    pub fn map_message_ref<C: Debug>(&self, f: impl FnOnce(&B) -> C) -> Envelope<C> {
         Envelope {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            body: Body { metadata: self.body.metadata.clone(), message: f(&self.body.message) },
+        }
+    }
+
+    // Replies to a request. Returns None, and logs that the reply was dropped, if the original
+    // message had no msg_id, since there'd be nothing to reply to; every request from a Maelstrom
+    // client has one, so that's a malformed message we shouldn't fall over on.
+    pub fn try_reply(&self, message: B) -> Option<Envelope<B>> {
+        self.try_reply_with_ids(&MESSAGE_IDS, message)
+    }
+
+    pub fn try_reply_with_ids(&self, ids: &MessageIdGenerator, message: B) -> Option<Envelope<B>> {
+        if self.body.metadata.msg_id.is_none() {
+            log::debug_envelope!(self, "no msg_id to reply to, dropping reply {message:?}");
+            return None
+        }
+        Some(Envelope {
             src: self.dest.clone(),
             dest: self.src.clone(),
-            body: Body {
-                msg_id: Some(MESSAGE_ID.fetch_add(1, Ordering::SeqCst)),
-                in_reply_to: self.body.msg_id,
-                message
-            }
+            body: self.body.respond(ids.next_id(), message),
+        })
+    }
+}
+
+impl Envelope<Value> {
+    // The message's "type" field, if it has one
+    pub fn message_type(&self) -> Option<&str> {
+        self.body.message.get("type")?.as_str()
+    }
+}
+
+impl<B: Clone + Debug> Envelope<B> {
+    // Sends the same message to each of dests, e.g. every other node in the cluster, with a msg_id
+    // of its own for each. The message is cloned once for every destination but the last, which
+    // gets the original.
+    pub fn fanout(src: impl Into<NodeId>, dests: &[String], message: B) -> Vec<Envelope<B>> {
+        Envelope::fanout_with_ids(&MESSAGE_IDS, src, dests, message)
+    }
+
+    pub fn fanout_with_ids(ids: &MessageIdGenerator, src: impl Into<NodeId>, dests: &[String], message: B) -> Vec<Envelope<B>> {
+        let Some((last, rest)) = dests.split_last() else { return vec![] };
+        let src = src.into();
+        let mut envelopes: Vec<Envelope<B>> = rest.iter()
+            .map(|dest| Envelope::new_with_ids(ids, src.clone(), dest, None, message.clone()))
+            .collect();
+        envelopes.push(Envelope::new_with_ids(ids, src, last, None, message));
+        envelopes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::codec::{Codec, MessagePack};
+    use crate::error::ErrorCode;
+    use crate::protocol::Common;
+
+    use super::*;
+
+    fn parse(json: &str) -> Envelope<Common> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn replies_go_back_to_the_sender() {
+        let request = parse(r#"{"src": "c1", "dest": "n1", "body": {"type": "topology", "msg_id": 7, "topology": {}}}"#);
+        let reply = request.try_reply(Common::TopologyOk).unwrap();
+        assert_eq!((reply.src.as_str(), reply.dest.as_str()), ("n1", "c1"));
+        assert_eq!(reply.in_reply_to(), Some(7));
+    }
+
+    // A field added to the metadata is copied into replies without respond having to know about it,
+    // while the ids are replaced
+    #[test]
+    fn new_metadata_fields_survive_a_reply() {
+        let request = parse(r#"{"src": "c1", "dest": "n1", "body": {"type": "topology", "msg_id": 7, "extra": "carried", "topology": {}}}"#);
+        assert_eq!(request.body.metadata.extra.as_deref(), Some("carried"));
+
+        let reply = request.try_reply(Common::TopologyOk).unwrap();
+        assert_eq!(reply.body.metadata.extra.as_deref(), Some("carried"));
+        let json = serde_json::to_value(&reply).unwrap();
+        assert_eq!(json["body"]["extra"], "carried");
+        assert_eq!(json["body"]["in_reply_to"], 7);
+        assert_ne!(json["body"]["msg_id"], 7);
+    }
+
+    #[test]
+    fn no_reply_without_a_msg_id() {
+        let request = parse(r#"{"src": "c1", "dest": "n1", "body": {"type": "topology", "topology": {}}}"#);
+        assert!(request.try_reply(Common::TopologyOk).is_none());
+    }
+
+    // A workload that carries Common messages inside its own enum
+    #[derive(Debug)]
+    enum Wrapper {
+        Common(Common),
+    }
+
+    #[test]
+    fn mapped_messages_keep_the_envelope() {
+        let request = parse(r#"{"src": "c1", "dest": "n1", "body": {"type": "init", "msg_id": 3, "in_reply_to": 2, "node_id": "n1", "node_ids": ["n1", "n2"]}}"#);
+        let expected = serde_json::to_value(&request).unwrap();
+
+        let wrapped = request.map_message_ref(|message| Wrapper::Common(message.clone()));
+        assert!(matches!(wrapped.message(), Wrapper::Common(Common::Init { node_id, .. }) if node_id == "n1"));
+        assert_eq!((wrapped.src.as_str(), wrapped.dest.as_str()), ("c1", "n1"));
+        assert_eq!((wrapped.msg_id(), wrapped.in_reply_to()), (Some(3), Some(2)));
+
+        let unwrapped = wrapped.map_message(|Wrapper::Common(message)| message);
+        assert_eq!(serde_json::to_value(&unwrapped).unwrap(), expected);
+        // And through the passthrough form
+        let passthrough = unwrapped.map_message(|message| serde_json::to_value(message).unwrap());
+        assert_eq!(passthrough.message_type(), Some("init"));
+        assert_eq!(serde_json::to_value(&passthrough).unwrap(), expected);
+    }
+
+    #[test]
+    fn replies_match_their_request() {
+        let request = parse(r#"{"src": "n1", "dest": "seq-kv", "body": {"type": "topology_ok", "msg_id": 7}}"#);
+        assert!(parse(r#"{"src": "seq-kv", "dest": "n1", "body": {"type": "topology_ok", "in_reply_to": 7}}"#).is_reply_to(&request));
+        assert!(request.try_reply(Common::TopologyOk).unwrap().is_reply_to(&request));
+
+        // Another request's reply, one from somewhere else, one for someone else, and the request itself
+        for json in [
+            r#"{"src": "seq-kv", "dest": "n1", "body": {"type": "topology_ok", "in_reply_to": 8}}"#,
+            r#"{"src": "lin-kv", "dest": "n1", "body": {"type": "topology_ok", "in_reply_to": 7}}"#,
+            r#"{"src": "seq-kv", "dest": "n2", "body": {"type": "topology_ok", "in_reply_to": 7}}"#,
+            r#"{"src": "seq-kv", "dest": "n1", "body": {"type": "topology_ok", "msg_id": 7}}"#,
+        ] {
+            assert!(!parse(json).is_reply_to(&request), "{json}");
+        }
+    }
+
+    // Without a msg_id there's nothing to match on, even for a message without an in_reply_to
+    #[test]
+    fn nothing_is_a_reply_to_a_request_without_a_msg_id() {
+        let request = parse(r#"{"src": "n1", "dest": "seq-kv", "body": {"type": "topology_ok"}}"#);
+        assert!(!parse(r#"{"src": "seq-kv", "dest": "n1", "body": {"type": "topology_ok"}}"#).is_reply_to(&request));
+        assert!(!parse(r#"{"src": "seq-kv", "dest": "n1", "body": {"type": "topology_ok", "in_reply_to": 0}}"#).is_reply_to(&request));
+    }
+
+    #[test]
+    fn fanout_sends_one_envelope_per_dest() {
+        let ids = MessageIdGenerator::new();
+        let dests = ["n2".to_string(), "n3".to_string(), "n4".to_string()];
+        let envelopes = Envelope::fanout_with_ids(&ids, "n1", &dests, Common::TopologyOk);
+        assert_eq!(envelopes.iter().map(|e| e.dest.as_str()).collect::<Vec<_>>(), dests);
+        assert!(envelopes.iter().all(|e| e.src == "n1" && e.in_reply_to().is_none() && matches!(e.message(), Common::TopologyOk)));
+        let msg_ids: HashSet<Option<u64>> = envelopes.iter().map(|e| e.msg_id()).collect();
+        assert_eq!(msg_ids.len(), dests.len());
+        assert!(!msg_ids.contains(&None));
+
+        assert!(Envelope::fanout_with_ids(&ids, "n1", &[], Common::TopologyOk).is_empty());
+    }
+
+    // Envelopes built from generators of their own get the same ids every time, whatever else the
+    // process has sent, so their output can be compared byte for byte
+    #[test]
+    fn own_generators_give_repeatable_ids() {
+        let run = || {
+            let ids = MessageIdGenerator::starting_at(100);
+            let request = Envelope::new_with_ids(&ids, "c1", "n1", None, Common::TopologyOk);
+            // The process-wide generator moving on in between makes no difference
+            Envelope::new(NodeId::from("n1"), NodeId::from("n2"), None, Common::TopologyOk);
+            let reply = request.try_reply_with_ids(&ids, Common::TopologyOk).unwrap();
+            serde_json::to_string(&[request, reply]).unwrap()
+        };
+        let first = run();
+        assert_eq!(first, run());
+        assert!(first.contains(r#""msg_id":100"#) && first.contains(r#""msg_id":101,"in_reply_to":100"#), "{first}");
+    }
+
+    // Real messages from several workloads go through the Value form unchanged, under either codec,
+    // with their addresses, ids and type readable on the way
+    #[test]
+    fn messages_pass_through_as_values() {
+        let lines = [
+            r#"{"src": "c1", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"]}}"#,
+            r#"{"src": "c2", "dest": "n1", "body": {"type": "broadcast", "msg_id": 3, "message": 42}}"#,
+            r#"{"src": "n2", "dest": "n1", "body": {"type": "sync", "msg_id": 9, "messages": [1, 2, 3], "key": "a"}}"#,
+            r#"{"src": "n1", "dest": "c3", "body": {"type": "poll_ok", "in_reply_to": 4, "msgs": {"k1": [[1, 5], [2, "9007199254740993"]]}}}"#,
+            r#"{"src": "seq-kv", "dest": "n1", "body": {"type": "error", "in_reply_to": 12, "code": 22, "text": "expected 1, but had 2"}}"#,
+            r#"{"src": "c4", "dest": "n2", "body": {"type": "txn", "msg_id": 5, "txn": [["r", 1, null], ["w", 1, 6]]}}"#,
+        ];
+        for line in lines {
+            let original: Value = serde_json::from_str(line).unwrap();
+            let envelope: Envelope<Value> = serde_json::from_str(line).unwrap();
+            assert_eq!(Some(envelope.src.as_str()), original["src"].as_str(), "{line}");
+            assert_eq!(Some(envelope.dest.as_str()), original["dest"].as_str(), "{line}");
+            assert_eq!(envelope.msg_id(), original["body"]["msg_id"].as_u64(), "{line}");
+            assert_eq!(envelope.in_reply_to(), original["body"]["in_reply_to"].as_u64(), "{line}");
+            assert_eq!(envelope.message_type(), original["body"]["type"].as_str(), "{line}");
+            assert_eq!(serde_json::to_value(&envelope).unwrap(), original, "{line}");
+
+            let packed = MessagePack.encode(&envelope);
+            let unpacked: Envelope<Value> = MessagePack.decode(&packed).unwrap();
+            assert_eq!(serde_json::to_value(&unpacked).unwrap(), original, "{line}");
+        }
+
+        let init: Envelope<Value> = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(init.as_init().map(|init| init.node_ids), Some(vec!["n1".to_string(), "n2".to_string()]));
+        let error = init.reply_error(ErrorCode::NotSupported, "no").unwrap();
+        assert_eq!(error.message_type(), Some("error"));
+        assert_eq!(error.in_reply_to(), Some(1));
+    }
+
+    // Timestamps from a sender with GG_TIMESTAMPS set are read and written back as they came, and
+    // envelopes without them (ours, in tests) leave the fields out rather than writing nulls
+    #[test]
+    fn timestamps_round_trip_and_are_left_out_when_unset() {
+        let sent_ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() - Duration::from_secs(2);
+        let line = format!(r#"{{"src": "n2", "dest": "n1", "body": {{"type": "topology_ok", "msg_id": 3, "send_ts": {}, "hops": 2}}}}"#, sent_ts.as_nanos());
+        let stamped = parse(&line);
+        assert_eq!(stamped.hops(), Some(2));
+        assert!(stamped.elapsed_since_sent().is_some_and(|elapsed| elapsed >= Duration::from_secs(2) && elapsed < Duration::from_secs(60)));
+        assert_eq!(serde_json::to_value(&stamped).unwrap(), serde_json::from_str::<Value>(&line).unwrap());
+
+        let ids = MessageIdGenerator::new();
+        for envelope in [Envelope::new_with_ids(&ids, "n1", "n2", None, Common::TopologyOk), stamped.try_reply(Common::TopologyOk).unwrap(),
+                         stamped.forward_with_ids(&ids, "n1", "n3", Common::TopologyOk)] {
+            assert_eq!((envelope.hops(), envelope.elapsed_since_sent()), (None, None));
+            let body = serde_json::to_value(&envelope).unwrap()["body"].clone();
+            assert!(body.get("send_ts").is_none() && body.get("hops").is_none(), "{body}");
         }
     }
 }
@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+use crate::log;
+use crate::trace::Direction;
+
+// Set GG_METRICS=1 to count the messages of each type sent and received, and their sizes, with a
+// summary logged every DUMP_INTERVAL and once more at shutdown
+const METRICS_ENV_VAR: &str = "GG_METRICS";
+const DUMP_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Stats {
+    count: u64,
+    total_bytes: u64,
+    max_bytes: u64,
+}
+
+// Keyed by the message's type tag; a BTreeMap so the summary comes out in a stable order
+#[derive(Default)]
+struct Metrics {
+    stats: Mutex<BTreeMap<(&'static str, &'static str), Stats>>,
+    // Counts of other things worth watching, like allocations on a hot path, by name
+    counters: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+static METRICS: Lazy<Option<Metrics>> = Lazy::new(|| {
+    std::env::var(METRICS_ENV_VAR).ok()?;
+    thread::spawn(|| loop {
+        thread::sleep(DUMP_INTERVAL);
+        dump();
+    });
+    Some(Metrics::default())
+});
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Inbound => "in",
+        Direction::Outbound => "out",
+    }
+}
+
+// Counts one serialized envelope of `bytes` bytes (without its trailing newline or framing). The
+// type is the message's TypeTag, so nothing is decoded a second time.
+pub fn record(direction: Direction, message_type: &'static str, bytes: usize) {
+    let Some(metrics) = METRICS.as_ref() else { return };
+    let mut stats = metrics.stats.lock().unwrap();
+    let stats = stats.entry((message_type, direction_name(direction))).or_default();
+    stats.count += 1;
+    stats.total_bytes += bytes as u64;
+    stats.max_bytes = stats.max_bytes.max(bytes as u64);
+}
+
+// Adds one to a named counter, which is logged along with the message counts
+pub fn count(name: &'static str) {
+    let Some(metrics) = METRICS.as_ref() else { return };
+    *metrics.counters.lock().unwrap().entry(name).or_default() += 1;
+}
+
+// Logs the counts so far, one line per message type and direction, then one per counter
+pub fn dump() {
+    let Some(metrics) = METRICS.as_ref() else { return };
+    let stats = metrics.stats.lock().unwrap();
+    let total: u64 = stats.values().map(|s| s.count).sum();
+    log::debug!("metrics: {total} messages");
+    for ((message_type, direction), s) in stats.iter() {
+        log::debug!("metrics: {direction:>3} {message_type:<24} {:>8} msgs {:>10} bytes (avg {}, max {})",
+            s.count, s.total_bytes, s.total_bytes / s.count, s.max_bytes);
+    }
+    drop(stats);
+    for (name, count) in metrics.counters.lock().unwrap().iter() {
+        log::debug!("metrics: {name} {count}");
+    }
+}
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+use crate::gossip::Topology;
+
+// The messages every workload gets from Maelstrom whatever it is, declared once. A workload enum
+// takes them in a `Common(Common)` variant and is marked #[serde(untagged)], with its own messages
+// in an enum of their own, so both sides still go out as the flat JSON Maelstrom expects:
+//
+//     #[derive(Deserialize, Serialize, Debug, Clone)]
+//     #[serde(untagged)]
+//     enum Message {
+//         Common(Common),
+//         Workload(WorkloadMessage),
+//     }
+//     impl_common_init_message!(Message);
+//
+// Handlers should match Common messages through Common::reply_to rather than one by one, so
+// anything it learns to answer is answered everywhere.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Common {
+    Init { node_id: String, node_ids: Vec<String> },
+    InitOk,
+    Topology { topology: Topology },
+    TopologyOk,
+}
+
+crate::impl_init_message!(Common);
+crate::impl_type_tag!(Common { Init, InitOk, Topology, TopologyOk });
+
+impl Common {
+    // What a node should send back for a Common message, if anything. Maelstrom's topology is
+    // acked without being used, since workloads that gossip build their own (see GossipConfig).
+    pub fn reply_to(&self) -> Option<Common> {
+        match self {
+            Common::Topology { .. } => Some(Common::TopologyOk),
+            // init is answered by runtime::await_init, and repeats by runtime::reply_to_repeated_init
+            Common::Init { .. } | Common::InitOk | Common::TopologyOk => None,
+        }
+    }
+}
+
+// InitMessage for an enum with a Common(Common) variant
+#[macro_export]
+macro_rules! impl_common_init_message {
+    ($message:ty) => {
+        impl $crate::runtime::InitMessage for $message {
+            fn as_init(&self) -> Option<$crate::runtime::Init> {
+                match self {
+                    Self::Common(common) => $crate::runtime::InitMessage::as_init(common),
+                    _ => None,
+                }
+            }
+
+            fn init_ok() -> Self {
+                Self::Common($crate::protocol::Common::InitOk)
+            }
+        }
+    };
+}
+
+// Fills in the node of a transaction from a peer that predates the field, which arrives with it
+// empty. txn and kafka peers only ever send their own transactions in a Transactions message,
+// whether pushed or in reply to a poll, so an unattributed one is the sender's.
+pub fn attribute_to_sender(node: &mut String, src: &str) {
+    if node.is_empty() {
+        *node = src.to_string();
+    }
+}
@@ -0,0 +1,1119 @@
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::fmt::Debug;
+use std::io::{StdinLock, Write};
+use std::marker::PhantomData;
+use std::str::Utf8Error;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, SendError, Sender, SyncSender, TrySendError};
+use std::{panic, process, thread};
+use std::panic::AssertUnwindSafe;
+use std::thread::{JoinHandle, ThreadId};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::{Lazy, OnceCell};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use crate::codec::{Codec, WireFormat};
+use crate::error::{ErrorCode, ErrorMessage};
+use crate::log;
+use crate::metrics;
+use crate::message;
+use crate::message::{Envelope, MessageIdGenerator, NodeId};
+use crate::trace;
+use crate::trace::Direction;
+use crate::type_tag::TypeTag;
+
+// Set from the init message; until then we don't know which envelopes are meant for us
+static LOCAL_NODE_ID: OnceCell<String> = OnceCell::new();
+
+// The contents of Maelstrom's init message
+#[derive(Debug, Clone)]
+pub struct Init {
+    pub node_id: String,
+    pub node_ids: Vec<String>,
+}
+
+// The nodes in the cluster, as init described it: this one, all of them (in init's order) and all
+// of them except this one
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    pub local: String,
+    pub all: Vec<String>,
+    others: Vec<String>,
+}
+
+impl Cluster {
+    pub fn others(&self) -> &[String] {
+        &self.others
+    }
+
+    // The node's position in init's node_ids
+    pub fn index_of(&self, node: &str) -> Option<usize> {
+        self.all.iter().position(|n| n == node)
+    }
+
+    // How many nodes there are apart from this one
+    pub fn peer_count(&self) -> usize {
+        self.others.len()
+    }
+}
+
+impl From<Init> for Cluster {
+    fn from(init: Init) -> Self {
+        let others = init.node_ids.iter().filter(|n| **n != init.node_id).cloned().collect();
+        Cluster { local: init.node_id, all: init.node_ids, others }
+    }
+}
+
+// A workload message type with init and init_ok variants. Enums with the usual
+// `Init { node_id, node_ids }` and `InitOk` variants can use impl_init_message!.
+pub trait InitMessage: Sized {
+    fn as_init(&self) -> Option<Init>;
+    fn init_ok() -> Self;
+}
+
+#[macro_export]
+macro_rules! impl_init_message {
+    ($message:ty) => {
+        impl $crate::runtime::InitMessage for $message {
+            fn as_init(&self) -> Option<$crate::runtime::Init> {
+                match self {
+                    Self::Init { node_id, node_ids } => Some($crate::runtime::Init { node_id: node_id.clone(), node_ids: node_ids.clone() }),
+                    _ => None,
+                }
+            }
+
+            fn init_ok() -> Self {
+                Self::InitOk
+            }
+        }
+    };
+}
+
+// For Envelope<Value>, the passthrough form (see message::Body)
+impl InitMessage for serde_json::Value {
+    fn as_init(&self) -> Option<Init> {
+        if self.get("type")?.as_str()? != "init" {
+            return None
+        }
+        let node_id = self.get("node_id")?.as_str()?.to_string();
+        let node_ids = self.get("node_ids")?.as_array()?.iter().map(|node| node.as_str().map(String::from)).collect::<Option<_>>()?;
+        Some(Init { node_id, node_ids })
+    }
+
+    fn init_ok() -> Self {
+        serde_json::json!({"type": "init_ok"})
+    }
+}
+
+impl<B: Debug + InitMessage> Envelope<B> {
+    pub fn as_init(&self) -> Option<Init> {
+        self.message().as_init()
+    }
+}
+
+// Waits for the init message, replies to it with `send` and returns it. Anything received before
+// init is dropped; everything after it is left in the receiver for the main loop. Returns None if
+// the input closes first.
+pub fn await_init<B: Debug + InitMessage>(receiver: &Receiver<Envelope<B>>, send: impl FnOnce(Envelope<B>)) -> Option<Init> {
+    for envelope in receiver.iter() {
+        if let Some(init) = envelope.as_init() {
+            log::set_node_id(&init.node_id);
+            log::debug_envelope!(&envelope, "init: {:?}", init.node_ids);
+            if let Some(reply) = envelope.try_reply(B::init_ok()) {
+                send(reply);
+            }
+            return Some(init);
+        }
+        log::debug_envelope!(&envelope, "dropping message received before init: {envelope:?}");
+    }
+    None
+}
+
+// Answers an init that arrives after the first one, which a main loop would otherwise treat as an
+// unexpected message. It gets an init_ok like the first did, but changes nothing: the node keeps
+// the id and cluster it started with. Returns whether the envelope was an init.
+pub fn reply_to_repeated_init<B: Debug + InitMessage>(envelope: &Envelope<B>, send: impl FnOnce(Envelope<B>)) -> bool {
+    let Some(init) = envelope.as_init() else { return false };
+    log::debug_envelope!(envelope, "ignoring repeated init as {}: {:?}", init.node_id, init.node_ids);
+    if let Some(reply) = envelope.try_reply(B::init_ok()) {
+        send(reply);
+    }
+    true
+}
+
+// The number a GG_* variable is set to, or None if it isn't set. A value that isn't a whole number
+// of at least min is a mistake in how the test was set up, so that panics, naming the variable.
+pub fn env_var(name: &str, min: usize) -> Option<usize> {
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(n) if n >= min => Some(n),
+        _ if min == 1 => panic!("{name} must be a positive integer, got {value}"),
+        _ => panic!("{name} must be an integer of at least {min}, got {value}"),
+    }
+}
+
+// Set GG_DEAD_LETTERS to choose what a node does with a message it has no handler for, once it's
+// been logged: `reply` (the default) answers it with not-supported, `drop` ignores it and `panic`
+// takes the node down, so the first such message stops the test where it happened.
+const DEAD_LETTERS_ENV_VAR: &str = "GG_DEAD_LETTERS";
+
+static DEAD_LETTER_POLICY: Lazy<DeadLetterPolicy> = Lazy::new(|| match std::env::var(DEAD_LETTERS_ENV_VAR).as_deref() {
+    Ok("reply") | Err(_) => DeadLetterPolicy::Reply,
+    Ok("drop") => DeadLetterPolicy::Drop,
+    Ok("panic") => DeadLetterPolicy::Panic,
+    Ok(policy) => panic!("unknown {DEAD_LETTERS_ENV_VAR} {policy}, expected reply, drop or panic"),
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadLetterPolicy {
+    Drop,
+    Reply,
+    Panic,
+}
+
+// Where messages that no handler matches end up, so every binary logs and answers them the same way
+#[derive(Debug, Clone, Copy)]
+pub struct DeadLetters {
+    policy: DeadLetterPolicy,
+}
+
+impl DeadLetters {
+    pub fn new(policy: DeadLetterPolicy) -> DeadLetters {
+        DeadLetters { policy }
+    }
+
+    // The policy picked by GG_DEAD_LETTERS, the same for the whole process
+    pub fn from_env() -> DeadLetters {
+        DeadLetters::new(*DEAD_LETTER_POLICY)
+    }
+
+    pub fn handle<B: Debug + Serialize + ErrorMessage + TypeTag>(&self, envelope: &Envelope<B>, send: impl FnOnce(Envelope<B>)) {
+        self.handle_with_ids(message::default_ids(), envelope, send)
+    }
+
+    // Replies (anything with an in_reply_to, errors included) are never answered, since answering
+    // one could start two nodes bouncing errors back and forth
+    pub fn handle_with_ids<B: Debug + Serialize + ErrorMessage + TypeTag>(&self, ids: &MessageIdGenerator, envelope: &Envelope<B>, send: impl FnOnce(Envelope<B>)) {
+        log::debug_envelope!(envelope, "no handler for {:?}", envelope.message());
+        match self.policy {
+            DeadLetterPolicy::Drop => {}
+            DeadLetterPolicy::Reply => {
+                if envelope.in_reply_to().is_some() {
+                    return
+                }
+                let text = format!("unexpected message type {}", envelope.message().type_tag());
+                if let Some(reply) = envelope.reply_error_with_ids(ids, ErrorCode::NotSupported, text) {
+                    send(reply);
+                }
+            }
+            DeadLetterPolicy::Panic => panic!("No handler for {envelope:?}"),
+        }
+    }
+}
+
+// Whether an inbound envelope is addressed to this node, for input threads to call on each
+// envelope as it's read. Misaddressed envelopes are logged so the caller can just drop them.
+// Everything is accepted until the init message tells us our node id.
+pub fn is_for_local_node<B: Debug + InitMessage>(envelope: &Envelope<B>) -> bool {
+    // Only the first init counts; see reply_to_repeated_init
+    if let Some(init) = envelope.as_init() {
+        if LOCAL_NODE_ID.set(init.node_id.clone()).is_ok() {
+            log::set_node_id(&init.node_id);
+        }
+    }
+    match LOCAL_NODE_ID.get() {
+        Some(node_id) if *node_id != envelope.dest => {
+            log::debug_envelope!(envelope, "dropping message addressed to {}: {envelope:?}", envelope.dest);
+            false
+        }
+        _ => true,
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    // stdin itself failed; there's no point reading any further
+    Io(std::io::Error),
+    // A line that isn't valid UTF-8
+    Utf8(Utf8Error),
+    // A line that isn't an envelope we understand
+    Json(serde_json::Error),
+    // With GG_CODEC=msgpack, a frame that isn't an envelope we understand
+    MessagePack(rmp_serde::decode::Error),
+}
+
+impl ReadError {
+    // Whether the reader has stopped. Bad lines can just be logged and skipped.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ReadError::Io(_))
+    }
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "error reading stdin: {e}"),
+            ReadError::Utf8(e) => write!(f, "line isn't valid UTF-8: {e}"),
+            ReadError::Json(e) => write!(f, "line isn't a valid envelope: {e}"),
+            ReadError::MessagePack(e) => write!(f, "frame isn't a valid envelope: {e}"),
+        }
+    }
+}
+
+// Reads envelopes from stdin, one per line (or frame, see codec), until EOF or an IO error
+pub struct StdinReader<B> {
+    stdin: StdinLock<'static>,
+    failed: bool,
+    _message: PhantomData<B>,
+}
+
+impl<B> StdinReader<B> {
+    pub fn new() -> StdinReader<B> {
+        StdinReader { stdin: std::io::stdin().lock(), failed: false, _message: PhantomData }
+    }
+}
+
+impl<B> Default for StdinReader<B> {
+    fn default() -> Self {
+        StdinReader::new()
+    }
+}
+
+impl<B: Debug + DeserializeOwned + TypeTag> Iterator for StdinReader<B> {
+    type Item = Result<Envelope<B>, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None
+        }
+
+        let line = match WireFormat::current().read_frame(&mut self.stdin) {
+            Ok(Some(line)) => line,
+            Ok(None) => return None,
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(ReadError::Io(e)))
+            }
+        };
+
+        let bytes = line.len();
+        let result = Envelope::<B>::parse(line);
+        // A line that doesn't parse is still counted, under a type of its own
+        let message_type = result.as_ref().map_or("?", |envelope| envelope.message().type_tag());
+        metrics::record(Direction::Inbound, message_type, bytes);
+        Some(result)
+    }
+}
+
+impl<B: Debug + DeserializeOwned> Envelope<B> {
+    // Parses one line of input (without its newline) or frame payload. Never panics, whatever the
+    // bytes are.
+    pub fn parse(line: Vec<u8>) -> Result<Envelope<B>, ReadError> {
+        WireFormat::current().decode(&line)
+    }
+}
+
+// SIGINT (Ctrl-C) or SIGTERM shuts a node down the same way the end of stdin does: input stops,
+// the main loop sees its channel close and everything already sent is flushed before the process
+// exits. A second signal exits straight away, for when something is stuck (a kv call waiting for a
+// reply that isn't coming, say). Panics still exit through exit_on_panic's hook as before.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN_HOOKS: Mutex<Vec<Box<dyn FnOnce() + Send>>> = Mutex::new(Vec::new());
+static LISTEN_FOR_SIGNALS: Once = Once::new();
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+// Runs f once a shutdown signal arrives, or straight away if one already has. Input threads use
+// this to close their channels; the first call starts listening for signals.
+pub fn on_shutdown(f: impl FnOnce() + Send + 'static) {
+    LISTEN_FOR_SIGNALS.call_once(listen_for_signals);
+    let mut hooks = SHUTDOWN_HOOKS.lock().unwrap();
+    if shutdown_requested() {
+        drop(hooks);
+        f();
+    } else {
+        hooks.push(Box::new(f));
+    }
+}
+
+fn listen_for_signals() {
+    let mut signals = Signals::new([SIGINT, SIGTERM]).expect("couldn't register signal handlers");
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+                log::debug!("signal {signal} during shutdown, exiting now");
+                process::exit(128 + signal);
+            }
+            log::debug!("signal {signal}, shutting down");
+            let hooks = std::mem::take(&mut *SHUTDOWN_HOOKS.lock().unwrap());
+            for hook in hooks {
+                hook();
+            }
+        }
+    });
+}
+
+// Sends every envelope on stdin for this node to incoming_messages until stdin is closed or the
+// node is asked to shut down, for binaries that run their own main loop without an InputHandler
+pub fn read_stdin<B: Debug + Send + Serialize + DeserializeOwned + InitMessage + TypeTag + 'static>(incoming_messages: Sender<Envelope<B>>) {
+    let incoming_messages = Arc::new(Mutex::new(Some(incoming_messages)));
+    let on_signal = incoming_messages.clone();
+    on_shutdown(move || drop(on_signal.lock().unwrap().take()));
+
+    for result in StdinReader::new() {
+        if let Ok(env) = &result {
+            trace::record(Direction::Inbound, env);
+        }
+        match result {
+            Ok(env) if is_for_local_node(&env) => {
+                let Some(sender) = &*incoming_messages.lock().unwrap() else { break };
+                // The main loop may already have finished
+                let _ = sender.send(env);
+            }
+            Ok(_) => {}
+            Err(e) if e.is_fatal() => {
+                log::debug!("{e}");
+                break
+            }
+            Err(e) => log::debug!("skipping input: {e}"),
+        }
+    }
+    // The shutdown hook holds the sender too, so it has to be dropped by hand to close the channel
+    drop(incoming_messages.lock().unwrap().take());
+}
+
+// Reads envelopes from stdin on its own thread and hands a copy of each one to every subscriber.
+// A subscriber gets exactly the envelopes read after it registered, in the order they arrived:
+// nothing from before, even if it registers while a line is being read. Subscribers whose
+// receivers have been dropped are forgotten.
+pub struct InputHandler;
+
+pub struct InputHandlerHandle<B: Clone + Debug + Send> {
+    new_subscriber_sender: Sender<(Sender<Envelope<B>>, Instant)>
+}
+
+impl<B: Clone + Debug + Send> InputHandlerHandle<B> {
+    // If stdin has already been closed, the receiver is empty and its iterator ends straight away
+    pub fn new_receiver(&self) -> Receiver<Envelope<B>> {
+        let (sender, receiver) = channel();
+        let _ = self.new_subscriber_sender.send((sender, Instant::now()));
+        receiver
+    }
+}
+
+impl InputHandler {
+    pub fn start<B: Clone + Debug + Send + Serialize + DeserializeOwned + InitMessage + TypeTag + 'static>(subscribers: Vec<Sender<Envelope<B>>>) -> InputHandlerHandle<B> {
+        InputHandler::start_reading(subscribers, StdinReader::<B>::new)
+    }
+
+    // Like start, but reads from whatever `reader` returns (on the input thread) instead of stdin
+    fn start_reading<B, I>(subscribers: Vec<Sender<Envelope<B>>>, reader: impl FnOnce() -> I + Send + 'static) -> InputHandlerHandle<B>
+        where B: Clone + Debug + Send + Serialize + InitMessage + 'static, I: Iterator<Item=Result<Envelope<B>, ReadError>> {
+        let (new_subscriber_sender, new_subscriber_receiver) = channel::<(Sender<Envelope<B>>, Instant)>();
+        let started = Instant::now();
+        let subscribers: Vec<(Sender<Envelope<B>>, Instant)> = subscribers.into_iter().map(|s| (s, started)).collect();
+        let subscribers = Arc::new(Mutex::new(subscribers));
+        let on_signal = subscribers.clone();
+        on_shutdown(move || on_signal.lock().unwrap().clear());
+
+        // On EOF the thread drops the subscribers as it exits, which ends their receivers'
+        // iterators. A shutdown signal drops them straight away.
+        thread::spawn(move || {
+            for result in reader() {
+                let arrived = Instant::now();
+                if shutdown_requested() {
+                    break
+                }
+                let mut subscribers = subscribers.lock().unwrap();
+                subscribers.extend(new_subscriber_receiver.try_iter());
+
+                let env = match result {
+                    Ok(env) => env,
+                    Err(e) if e.is_fatal() => {
+                        log::debug!("{e}");
+                        break
+                    }
+                    Err(e) => {
+                        log::debug!("skipping input: {e}");
+                        continue
+                    }
+                };
+                trace::record(Direction::Inbound, &env);
+                if !is_for_local_node(&env) {
+                    continue
+                }
+                subscribers.retain(|(subscriber, registered)| {
+                    *registered >= arrived || subscriber.send(env.clone()).is_ok()
+                });
+            }
+            // The shutdown hook shares the subscribers, so they aren't dropped with this thread
+            subscribers.lock().unwrap().clear();
+        });
+
+        InputHandlerHandle { new_subscriber_sender }
+    }
+}
+
+// Set GG_OUTPUT_QUEUE_CAPACITY to change how many envelopes can be waiting to be written to stdout
+const OUTPUT_QUEUE_CAPACITY_ENV_VAR: &str = "GG_OUTPUT_QUEUE_CAPACITY";
+// Enough to absorb a sync burst to every neighbour without blocking, but small enough that a node
+// producing output faster than stdout drains gets slowed down (and warned about) quickly
+pub const DEFAULT_OUTPUT_QUEUE_CAPACITY: usize = 1024;
+
+// How many replies to client requests are kept for ReplyCache
+pub const REPLY_CACHE_CAPACITY: usize = 10_000;
+
+// Replies we've sent to clients, keyed by the (src, msg_id) of the request, so a request Maelstrom
+// redelivers can be answered with the original reply instead of being run again. The oldest
+// replies are evicted first. Every OutputSender keeps one, so binaries that send through
+// OutputHandler get this for free.
+pub struct ReplyCache<B: Debug> {
+    capacity: usize,
+    replies: HashMap<(NodeId, u64), Envelope<B>>,
+    order: VecDeque<(NodeId, u64)>,
+}
+
+impl<B: Clone + Debug> ReplyCache<B> {
+    pub fn new(capacity: usize) -> ReplyCache<B> {
+        ReplyCache { capacity, replies: HashMap::new(), order: VecDeque::new() }
+    }
+
+    // Whether an outgoing envelope is one that record keeps
+    pub fn is_client_reply(envelope: &Envelope<B>) -> bool {
+        envelope.in_reply_to().is_some() && !envelope.dest.is_node()
+    }
+
+    // Remembers an outgoing envelope if it's a reply to a client
+    pub fn record(&mut self, reply: &Envelope<B>) {
+        if !Self::is_client_reply(reply) {
+            return
+        }
+        let in_reply_to = reply.in_reply_to().unwrap();
+
+        let key = (reply.dest.clone(), in_reply_to);
+        if self.replies.insert(key.clone(), reply.clone()).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.replies.remove(&oldest);
+        }
+    }
+
+    // The reply we already sent to this request, if it's one we've seen before
+    pub fn get(&self, request: &Envelope<B>) -> Option<&Envelope<B>> {
+        self.replies.get(&(request.src.clone(), request.msg_id()?))
+    }
+}
+
+// How many envelopes have been queued, and how many of those the output thread has written out and
+// flushed, so a sender can wait for everything it's sent to reach stdout
+#[derive(Default)]
+struct OutputProgress {
+    queued: AtomicUsize,
+    written: Mutex<usize>,
+    written_changed: Condvar,
+}
+
+impl OutputProgress {
+    fn wait_until_written(&self, output_thread: ThreadId, timeout: Duration) -> bool {
+        let queued = self.queued.load(Ordering::SeqCst);
+        if thread::current().id() == output_thread {
+            return false
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        while *written < queued {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false
+            }
+            written = self.written_changed.wait_timeout(written, remaining).unwrap_or_else(|e| e.into_inner()).0;
+        }
+        true
+    }
+}
+
+// Sends envelopes to the OutputHandler. When the queue is full, send blocks until there's room,
+// counting and logging each time that happens. Replies to clients are remembered in a ReplyCache.
+pub struct OutputSender<B: Debug> {
+    sender: SyncSender<Envelope<B>>,
+    capacity: usize,
+    blocked_sends: Arc<AtomicUsize>,
+    replies: Arc<Mutex<ReplyCache<B>>>,
+    progress: Arc<OutputProgress>,
+    output_thread: ThreadId,
+}
+
+impl<B: Debug> Clone for OutputSender<B> {
+    fn clone(&self) -> Self {
+        OutputSender {
+            sender: self.sender.clone(),
+            capacity: self.capacity,
+            blocked_sends: self.blocked_sends.clone(),
+            replies: self.replies.clone(),
+            progress: self.progress.clone(),
+            output_thread: self.output_thread,
+        }
+    }
+}
+
+impl<B: Clone + Debug> OutputSender<B> {
+    // Only replies to clients take the ReplyCache's lock, so messages between nodes aren't slowed
+    // down by it
+    pub fn send(&self, envelope: Envelope<B>) -> Result<(), SendError<Envelope<B>>> {
+        if ReplyCache::is_client_reply(&envelope) {
+            self.replies.lock().unwrap().record(&envelope);
+        }
+        self.enqueue(envelope)
+    }
+
+    fn enqueue(&self, envelope: Envelope<B>) -> Result<(), SendError<Envelope<B>>> {
+        match self.sender.try_send(envelope) {
+            Ok(()) => {}
+            Err(TrySendError::Full(envelope)) => {
+                let blocked_sends = self.blocked_sends.fetch_add(1, Ordering::Relaxed) + 1;
+                if blocked_sends.is_power_of_two() {
+                    log::debug!("output queue full ({} envelopes), blocked {blocked_sends} times so far", self.capacity);
+                }
+                self.sender.send(envelope)?;
+            }
+            Err(TrySendError::Disconnected(envelope)) => return Err(SendError(envelope)),
+        }
+        self.progress.queued.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Sends each envelope in turn, e.g. the ones Envelope::fanout makes, stopping at the first
+    // that can't be sent
+    pub fn send_all(&self, envelopes: impl IntoIterator<Item = Envelope<B>>) -> Result<(), SendError<Envelope<B>>> {
+        envelopes.into_iter().try_for_each(|envelope| self.send(envelope))
+    }
+
+    // Waits until everything sent so far (by any clone of this sender) has been written to stdout
+    // and flushed. Returns false if that doesn't happen within the timeout, which is what to expect
+    // if the output thread has died, or if this is called on the output thread itself.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        self.progress.wait_until_written(self.output_thread, timeout)
+    }
+
+    // If we've already replied to this request, sends the same reply again and returns true. Main
+    // loops call this first so that redelivered requests aren't run twice. The reply is already in
+    // the cache, so it's queued without being recorded again.
+    pub fn resend_cached_reply(&self, request: &Envelope<B>) -> bool {
+        if request.src.is_node() || request.msg_id().is_none() {
+            return false
+        }
+        let Some(reply) = self.replies.lock().unwrap().get(request).cloned() else { return false };
+        log::debug_envelope!(request, "redelivered request, resending reply #{:?}", reply.msg_id());
+        self.enqueue(reply).unwrap();
+        true
+    }
+
+    // How many sends have had to wait for the queue to drain
+    pub fn blocked_sends(&self) -> usize {
+        self.blocked_sends.load(Ordering::Relaxed)
+    }
+}
+
+// Set GG_COALESCE_OUTPUT=1 to write out everything that's queued before flushing stdout, instead of
+// flushing after every envelope. A burst then costs one flush rather than one per envelope, while
+// an envelope sent to an idle node is still flushed straight away.
+const COALESCE_OUTPUT_ENV_VAR: &str = "GG_COALESCE_OUTPUT";
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutputConfig {
+    pub capacity: usize,
+    pub coalesce: bool,
+}
+
+impl OutputConfig {
+    pub fn from_env() -> OutputConfig {
+        let capacity = env_var(OUTPUT_QUEUE_CAPACITY_ENV_VAR, 1).unwrap_or(DEFAULT_OUTPUT_QUEUE_CAPACITY);
+        OutputConfig { capacity, coalesce: std::env::var(COALESCE_OUTPUT_ENV_VAR).is_ok() }
+    }
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig { capacity: DEFAULT_OUTPUT_QUEUE_CAPACITY, coalesce: false }
+    }
+}
+
+pub struct OutputHandler;
+
+impl OutputHandler {
+    // The thread exits once every sender has been dropped and everything sent has been written, so
+    // joining it after dropping the senders guarantees all output has been flushed
+    pub fn start<B: Clone + Debug + Serialize + TypeTag + Send + 'static>() -> (OutputSender<B>, JoinHandle<()>) {
+        OutputHandler::start_with_config(OutputConfig::from_env())
+    }
+
+    pub fn start_with_capacity<B: Clone + Debug + Serialize + TypeTag + Send + 'static>(capacity: usize) -> (OutputSender<B>, JoinHandle<()>) {
+        OutputHandler::start_with_config(OutputConfig { capacity, ..Default::default() })
+    }
+
+    pub fn start_with_config<B: Clone + Debug + Serialize + TypeTag + Send + 'static>(config: OutputConfig) -> (OutputSender<B>, JoinHandle<()>) {
+        OutputHandler::start_writing(config, || std::io::stdout().lock())
+    }
+
+    // Like start_with_config, but writes to whatever `writer` returns (on the output thread) instead
+    // of stdout
+    fn start_writing<B, W>(config: OutputConfig, writer: impl FnOnce() -> W + Send + 'static) -> (OutputSender<B>, JoinHandle<()>)
+        where B: Clone + Debug + Serialize + TypeTag + Send + 'static, W: Write {
+        let (sender, receiver) = sync_channel(config.capacity);
+        let progress = Arc::new(OutputProgress::default());
+
+        let thread_progress = progress.clone();
+        let handle = thread::spawn(move || {
+            let mut stdout = writer();
+            // stdout is line buffered, so each batch is built up here and written in one go
+            let mut batch = Vec::new();
+            let (mut written, mut flushes) = (0usize, 0usize);
+            for envelope in receiver.iter() {
+                encode_envelope(&mut batch, &envelope);
+                written += 1;
+                if config.coalesce {
+                    while let Ok(envelope) = receiver.try_recv() {
+                        encode_envelope(&mut batch, &envelope);
+                        written += 1;
+                    }
+                }
+                stdout.write_all(&batch).unwrap();
+                stdout.flush().unwrap();
+                batch.clear();
+                flushes += 1;
+
+                *thread_progress.written.lock().unwrap() = written;
+                thread_progress.written_changed.notify_all();
+            }
+            if config.coalesce {
+                log::debug!("wrote {written} envelopes in {flushes} flushes");
+            }
+            metrics::dump();
+        });
+
+        let replies = Arc::new(Mutex::new(ReplyCache::new(REPLY_CACHE_CAPACITY)));
+        let output_thread = handle.thread().id();
+        (OutputSender { sender, capacity: config.capacity, blocked_sends: Default::default(), replies, progress, output_thread }, handle)
+    }
+
+    // An OutputSender whose envelopes go to a channel instead of stdout, for running a node's
+    // handlers in tests or another in-process harness. There's no output thread to write them out,
+    // so flush only succeeds if nothing has been sent.
+    pub fn to_channel<B: Clone + Debug>(capacity: usize) -> (OutputSender<B>, Receiver<Envelope<B>>) {
+        let (sender, receiver) = sync_channel(capacity);
+        let replies = Arc::new(Mutex::new(ReplyCache::new(REPLY_CACHE_CAPACITY)));
+        let progress = Arc::new(OutputProgress::default());
+        (OutputSender { sender, capacity, blocked_sends: Default::default(), replies, progress, output_thread: thread::current().id() }, receiver)
+    }
+
+    // Drops the sender and waits for the output thread to write out everything that was sent. Any
+    // other clones of the sender have to be dropped first, or this never returns.
+    pub fn flush_and_join<B: Debug>(sender: OutputSender<B>, handle: JoinHandle<()>) {
+        drop(sender);
+        handle.join().unwrap();
+    }
+}
+
+// How long a panicking process waits for its queued output to be written before exiting
+pub const PANIC_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Makes a panic on any thread end the whole process, instead of leaving the other threads running
+// without it, once whatever's already been sent has reached stdout (or PANIC_FLUSH_TIMEOUT has
+// passed). Without the flush, replies still in the output queue would be lost.
+// https://stackoverflow.com/questions/35988775/how-can-i-cause-a-panic-on-a-thread-to-immediately-end-the-main-thread
+pub fn exit_on_panic<B: Debug>(output: &OutputSender<B>) {
+    // Not a clone of the sender, which would keep the output thread from ever finishing
+    let (progress, output_thread) = (output.progress.clone(), output.output_thread);
+    let orig_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |panic_info| {
+        orig_hook(panic_info);
+        // reply_with_error_on_panic will catch this one and carry on
+        if CATCHING_PANICS.get() { return }
+        progress.wait_until_written(output_thread, PANIC_FLUSH_TIMEOUT);
+        process::exit(1);
+    }));
+}
+
+thread_local! {
+    // Whether this thread is inside reply_with_error_on_panic, so panic hooks know not to end the process
+    static CATCHING_PANICS: Cell<bool> = const { Cell::new(false) };
+}
+static LOG_CAUGHT_PANICS: Once = Once::new();
+
+// Runs a message's handler, and if it panics, answers the message with a crash error instead of
+// letting the panic take down the node. The panic and its backtrace are logged. Returns None if
+// the handler panicked.
+//
+// Whatever state the handler was changing when it panicked stays half-changed, so this is only
+// for handlers that can cope with that (or have no state to speak of). The client gets a crash
+// rather than a definite failure, since the handler may already have done part of its work.
+pub fn reply_with_error_on_panic<B: Debug + ErrorMessage, R>(envelope: &Envelope<B>, send: impl FnOnce(Envelope<B>), handler: impl FnOnce() -> R) -> Option<R> {
+    LOG_CAUGHT_PANICS.call_once(|| {
+        let orig_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |panic_info| {
+            if CATCHING_PANICS.get() {
+                log::debug!("handler {panic_info}\n{}", Backtrace::force_capture());
+            } else {
+                orig_hook(panic_info);
+            }
+        }));
+    });
+
+    let was_catching = CATCHING_PANICS.replace(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(handler));
+    CATCHING_PANICS.set(was_catching);
+
+    match result {
+        Ok(result) => Some(result),
+        Err(_) => {
+            log::debug_envelope!(envelope, "replying with crash after handler panicked");
+            if let Some(reply) = envelope.reply_error(ErrorCode::Crash, "handler panicked") {
+                send(reply);
+            }
+            None
+        }
+    }
+}
+
+// Appends an envelope to out as a line of JSON (or a frame, see codec), ready to be written to
+// stdout, and counts it in the metrics and the trace. OutputHandler batches go through this too, as
+// can binaries that write to stdout themselves.
+pub fn encode_envelope<B: Debug + Serialize + TypeTag>(out: &mut Vec<u8>, envelope: &Envelope<B>) {
+    trace::record(Direction::Outbound, envelope);
+    let codec = WireFormat::current();
+    let payload = codec.encode(envelope);
+    metrics::record(Direction::Outbound, envelope.message().type_tag(), payload.len());
+    codec.write_frame(&payload, out);
+}
+
+// A workload that just reacts to messages. runtime::run handles everything else: reading stdin,
+// writing stdout and the init handshake.
+pub trait Workload: Sized {
+    type Message: Clone + Debug + Send + Serialize + DeserializeOwned + InitMessage + ErrorMessage + TypeTag + 'static;
+
+    // Called once init has been acknowledged
+    fn new(init: Init) -> Self;
+
+    // Whether a panic in handle should be answered with a crash error (see
+    // reply_with_error_on_panic) rather than ending the process
+    const REPLY_WITH_ERROR_ON_PANIC: bool = false;
+
+    // Called for every message after init, in the order they arrived
+    fn handle(&mut self, envelope: Envelope<Self::Message>, output: &OutputSender<Self::Message>);
+}
+
+// Runs a workload until stdin is closed, then waits for all of its output to be written
+pub fn run<W: Workload>() {
+    let (output_sender, output_thread) = OutputHandler::start::<W::Message>();
+    let (main_sender, main_receiver) = channel();
+    let _input_handler = InputHandler::start::<W::Message>(vec![main_sender]);
+
+    if let Some(init) = await_init(&main_receiver, |e| output_sender.send(e).unwrap()) {
+        let mut workload = W::new(init);
+        for envelope in main_receiver.iter() {
+            if output_sender.resend_cached_reply(&envelope) { continue }
+            if reply_to_repeated_init(&envelope, |e| output_sender.send(e).unwrap()) { continue }
+            if W::REPLY_WITH_ERROR_ON_PANIC {
+                let request = envelope.clone();
+                reply_with_error_on_panic(&request, |e| output_sender.send(e).unwrap(),
+                                          || workload.handle(envelope, &output_sender));
+            } else {
+                workload.handle(envelope, &output_sender);
+            }
+        }
+    }
+
+    OutputHandler::flush_and_join(output_sender, output_thread);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::RecvTimeoutError;
+
+    use serde_json::{json, Value};
+
+    use crate::codec::{Codec, Json, MessagePack, WireFormat};
+    use crate::kv::KvMessage;
+    use crate::protocol::Common;
+    use crate::sim::Rng;
+
+    use super::*;
+
+    const PARSE_CASES: usize = 500;
+
+    // Node ids with characters JSON has to escape, so strings round-trip through more than the easy path
+    const NODE_IDS: [&str; 5] = ["n1", "c12", "seq-kv", "n\"2\\", "\u{2603}\n\t"];
+
+    fn random_node(rng: &mut Rng) -> String {
+        NODE_IDS[rng.below(NODE_IDS.len())].to_string()
+    }
+
+    fn random_envelope(rng: &mut Rng, ids: &MessageIdGenerator) -> Envelope<Common> {
+        let (src, dest) = (random_node(rng), random_node(rng));
+        let node_ids: Vec<String> = (0..rng.below(4)).map(|_| random_node(rng)).collect();
+        let message = match rng.below(4) {
+            0 => Common::Init { node_id: random_node(rng), node_ids },
+            1 => Common::InitOk,
+            2 => Common::Topology { topology: serde_json::from_value(serde_json::json!({ random_node(rng): node_ids })).unwrap() },
+            _ => Common::TopologyOk,
+        };
+        let in_reply_to = (rng.below(2) == 0).then(|| rng.next_u64());
+        Envelope::new_with_ids(ids, src, dest, in_reply_to, message)
+    }
+
+    // Whatever an envelope is encoded as, decoding it gives an envelope that encodes the same way,
+    // and decoding any part of it cut short fails
+    #[test]
+    fn parse_round_trips_and_rejects_truncated_envelopes() {
+        let (mut rng, ids) = (Rng::new(1), MessageIdGenerator::new());
+        for _ in 0..PARSE_CASES {
+            let envelope = random_envelope(&mut rng, &ids);
+            for codec in [WireFormat::Json, WireFormat::MessagePack] {
+                let payload = codec.encode(&envelope);
+                let decoded: Envelope<Common> = codec.decode(&payload).unwrap_or_else(|e| panic!("{codec:?} can't decode {envelope:?}: {e:?}"));
+                assert_eq!(codec.encode(&decoded), payload, "{codec:?} changed {envelope:?}");
+                for length in 0..payload.len() {
+                    assert!(codec.decode::<Envelope<Common>>(&payload[..length]).is_err(), "{codec:?} decoded {envelope:?} cut to {length} bytes");
+                }
+            }
+            // Envelope::parse goes through whichever codec GG_CODEC picks, which for tests is JSON
+            let line = Json.encode(&envelope);
+            assert_eq!(Json.encode(&Envelope::<Common>::parse(line.clone()).unwrap()), line);
+        }
+    }
+
+    // Random bytes are an error rather than a panic, and so are valid envelopes with a byte changed
+    // (which can still parse, if the byte was in a string)
+    #[test]
+    fn parse_never_panics_on_arbitrary_bytes() {
+        let (mut rng, ids) = (Rng::new(2), MessageIdGenerator::new());
+        for _ in 0..PARSE_CASES {
+            let garbage: Vec<u8> = (0..rng.below(64)).map(|_| rng.next_u64() as u8).collect();
+            assert!(Envelope::<Common>::parse(garbage.clone()).is_err(), "parsed {garbage:?}");
+            assert!(MessagePack.decode::<Envelope<Common>>(&garbage).is_err(), "decoded {garbage:?}");
+
+            let envelope = random_envelope(&mut rng, &ids);
+            for codec in [WireFormat::Json, WireFormat::MessagePack] {
+                let mut payload = codec.encode(&envelope);
+                let position = rng.below(payload.len());
+                payload[position] = rng.next_u64() as u8;
+                let _ = codec.decode::<Envelope<Common>>(&payload);
+            }
+        }
+    }
+
+    // The local node id is set once per process, so this is the only test that sends an init
+    #[test]
+    fn misaddressed_envelopes_are_dropped_after_init() {
+        let envelope = |dest: &str, body: &str| -> Envelope<Common> {
+            serde_json::from_str(&format!(r#"{{"src": "c1", "dest": "{dest}", "body": {body}}}"#)).unwrap()
+        };
+        let topology = r#"{"type": "topology", "msg_id": 2, "topology": {}}"#;
+        // Before init there's no telling who we are
+        assert!(is_for_local_node(&envelope("n2", topology)));
+
+        assert!(is_for_local_node(&envelope("n1", r#"{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"]}"#)));
+        assert!(is_for_local_node(&envelope("n1", topology)));
+        assert!(!is_for_local_node(&envelope("n2", topology)));
+    }
+
+    #[test]
+    fn clusters_leave_the_local_node_out_of_others() {
+        let node_ids = ["n2", "n1", "n3"].map(String::from).to_vec();
+        let cluster = Cluster::from(Init { node_id: "n1".to_string(), node_ids: node_ids.clone() });
+        assert_eq!(cluster.local, "n1");
+        assert_eq!(cluster.all, node_ids);
+        assert_eq!(cluster.others(), ["n2", "n3"]);
+        assert_eq!(cluster.peer_count(), 2);
+        assert_eq!(cluster.index_of("n1"), Some(1));
+        assert_eq!(cluster.index_of("n4"), None);
+    }
+
+    // Requests nothing handles are answered with not-supported (or dropped, or panicked on, if
+    // asked), but replies never are
+    #[test]
+    fn dead_letters_follow_their_policy() {
+        let ids = MessageIdGenerator::new();
+        let request: Envelope<KvMessage> = Envelope::new_with_ids(&ids, "c1", "n1", None, KvMessage::Read { key: "k".to_string() });
+        let reply: Envelope<KvMessage> = Envelope::new_with_ids(&ids, "c1", "n1", Some(3), KvMessage::WriteOk);
+        let handled = |policy: DeadLetterPolicy, envelope: &Envelope<KvMessage>| {
+            let mut sent = vec![];
+            DeadLetters::new(policy).handle_with_ids(&ids, envelope, |e| sent.push(e));
+            sent
+        };
+
+        let answer = handled(DeadLetterPolicy::Reply, &request);
+        assert!(matches!(answer.as_slice(), [e] if e.is_reply_to(&request)
+            && matches!(e.message(), KvMessage::Error { code, text } if *code == ErrorCode::NotSupported as u64 && text == "unexpected message type read")), "{answer:?}");
+        assert!(handled(DeadLetterPolicy::Reply, &reply).is_empty());
+        assert!(handled(DeadLetterPolicy::Drop, &request).is_empty());
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| handled(DeadLetterPolicy::Panic, &request))).is_err());
+    }
+
+    fn common(body: &str) -> Envelope<Common> {
+        serde_json::from_str(&format!(r#"{{"src": "c1", "dest": "n1", "body": {body}}}"#)).unwrap()
+    }
+
+    // Maelstrom may send init again; every one gets an init_ok, but only the first one counts
+    #[test]
+    fn repeated_inits_get_init_ok() {
+        let (sender, receiver) = channel();
+        sender.send(common(r#"{"type": "topology", "msg_id": 1, "topology": {}}"#)).unwrap();
+        sender.send(common(r#"{"type": "init", "msg_id": 2, "node_id": "n1", "node_ids": ["n1", "n2"]}"#)).unwrap();
+        let mut sent = vec![];
+        let init = await_init(&receiver, |e| sent.push(e)).unwrap();
+        assert_eq!((init.node_id.as_str(), init.node_ids.len()), ("n1", 2));
+        assert!(matches!(sent.as_slice(), [reply] if reply.in_reply_to() == Some(2) && matches!(reply.message(), Common::InitOk)));
+
+        sent.clear();
+        let again = common(r#"{"type": "init", "msg_id": 3, "node_id": "n2", "node_ids": ["n2"]}"#);
+        assert!(reply_to_repeated_init(&again, |e| sent.push(e)));
+        assert!(matches!(sent.as_slice(), [reply] if reply.in_reply_to() == Some(3) && matches!(reply.message(), Common::InitOk)));
+
+        // Anything else is left to the caller
+        sent.clear();
+        assert!(!reply_to_repeated_init(&common(r#"{"type": "topology", "msg_id": 4, "topology": {}}"#), |e| sent.push(e)));
+        // And an init without a msg_id is still an init, but can't be answered
+        assert!(reply_to_repeated_init(&common(r#"{"type": "init", "node_id": "n1", "node_ids": ["n1"]}"#), |e| sent.push(e)));
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn await_init_gives_up_when_input_ends() {
+        let (sender, receiver) = channel::<Envelope<Common>>();
+        sender.send(common(r#"{"type": "topology_ok", "msg_id": 1}"#)).unwrap();
+        drop(sender);
+        assert!(await_init(&receiver, |_| panic!("nothing to reply to")).is_none());
+    }
+
+    // Only replies to clients are kept, and the oldest go first once the cache is full
+    #[test]
+    fn reply_cache_keeps_recent_client_replies() {
+        let (ids, client_ids) = (MessageIdGenerator::new(), MessageIdGenerator::new());
+        let mut cache = ReplyCache::new(2);
+        let requests: Vec<Envelope<Common>> = (0..3).map(|_| Envelope::new_with_ids(&client_ids, "c1", "n1", None, Common::TopologyOk)).collect();
+        let to_peer = Envelope::new_with_ids(&ids, "n1", "n2", Some(requests[0].msg_id().unwrap()), Common::InitOk);
+        assert!(!ReplyCache::is_client_reply(&to_peer));
+        cache.record(&to_peer);
+        assert!(cache.get(&requests[0]).is_none());
+
+        for request in &requests {
+            let reply = request.try_reply_with_ids(&ids, Common::InitOk).unwrap();
+            assert!(ReplyCache::is_client_reply(&reply));
+            cache.record(&reply);
+        }
+        assert!(cache.get(&requests[0]).is_none());
+        assert!(cache.get(&requests[1]).is_some_and(|reply| reply.is_reply_to(&requests[1])));
+        assert!(cache.get(&requests[2]).is_some_and(|reply| reply.is_reply_to(&requests[2])));
+    }
+
+    // A subscriber gets exactly the envelopes read after it registers, and the rest carry on
+    // getting theirs when one goes away
+    #[test]
+    fn subscribers_only_get_envelopes_read_after_they_register() {
+        let timeout = Duration::from_secs(5);
+        let line = |msg_id: u64| Ok(common(&format!(r#"{{"type": "topology_ok", "msg_id": {msg_id}}}"#)));
+        let (lines, read) = channel::<Result<Envelope<Common>, ReadError>>();
+        let (first_sender, first) = channel();
+        let handle = InputHandler::start_reading(vec![first_sender], move || read.into_iter());
+
+        lines.send(line(1)).unwrap();
+        assert_eq!(first.recv_timeout(timeout).unwrap().msg_id(), Some(1));
+        let second = handle.new_receiver();
+        lines.send(line(2)).unwrap();
+        assert_eq!(first.recv_timeout(timeout).unwrap().msg_id(), Some(2));
+        assert_eq!(second.recv_timeout(timeout).unwrap().msg_id(), Some(2));
+
+        drop(first);
+        lines.send(line(3)).unwrap();
+        assert_eq!(second.recv_timeout(timeout).unwrap().msg_id(), Some(3));
+        // The end of the input ends the subscribers' receivers
+        drop(lines);
+        assert_eq!(second.recv_timeout(timeout).unwrap_err(), RecvTimeoutError::Disconnected);
+    }
+
+    // Stands in for stdout, taking a while over each write so that envelopes pile up in the queue
+    struct SlowWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            thread::sleep(Duration::from_millis(1));
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Everything queued before a shutdown is written out, both when a sender waits for it (as
+    // exit_on_panic does) and when the senders are dropped and the output thread joined
+    #[test]
+    fn queued_output_is_written_before_shutdown() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let writer = SlowWriter(written.clone());
+        let (sender, handle) = OutputHandler::start_writing(OutputConfig { capacity: 4, coalesce: false }, move || writer);
+        let ids = MessageIdGenerator::new();
+        let lines = |written: &Arc<Mutex<Vec<u8>>>| -> Vec<Option<u64>> {
+            written.lock().unwrap().split(|byte| *byte == b'\n').filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice::<Envelope<Common>>(line).unwrap().msg_id())
+                .collect()
+        };
+
+        // Far more than the queue holds, so most of the sends wait for the writer
+        let send_some = |sender: &OutputSender<Common>| -> Vec<Option<u64>> {
+            (0..30).map(|_| {
+                let envelope = Envelope::new_with_ids(&ids, "n1", "c1", None, Common::TopologyOk);
+                let msg_id = envelope.msg_id();
+                sender.send(envelope).unwrap();
+                msg_id
+            }).collect()
+        };
+
+        let sent = send_some(&sender);
+        assert!(sender.flush(Duration::from_secs(5)));
+        assert_eq!(lines(&written), sent);
+
+        let more = send_some(&sender);
+        OutputHandler::flush_and_join(sender, handle);
+        assert_eq!(lines(&written), [sent, more].concat());
+    }
+
+    // A handler that panics on one message answers it with a crash and carries on with the next
+    #[test]
+    fn panicking_handlers_reply_with_a_crash() {
+        let requests: Vec<Envelope<Value>> = ["echo", "boom", "echo"].iter().enumerate()
+            .map(|(i, message_type)| serde_json::from_value(json!({"src": "c1", "dest": "n1", "body": {"type": message_type, "msg_id": i}})).unwrap())
+            .collect();
+        let (mut handled, mut sent) = (0, vec![]);
+        let results: Vec<Option<u64>> = requests.iter().map(|request| {
+            reply_with_error_on_panic(request, |e| sent.push(e), || {
+                if request.message_type() == Some("boom") {
+                    panic!("boom");
+                }
+                handled += 1;
+                request.msg_id().unwrap()
+            })
+        }).collect();
+
+        assert_eq!(results, [Some(0), None, Some(2)]);
+        assert_eq!(handled, 2);
+        let [reply] = sent.as_slice() else { panic!("expected one reply, got {sent:?}") };
+        assert_eq!((reply.dest.as_str(), reply.in_reply_to()), ("c1", Some(1)));
+        assert_eq!(reply.message_type(), Some("error"));
+        assert_eq!(reply.message()["code"], ErrorCode::Crash as u64);
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Set GG_LARGE_NUMBERS=string to send integers bigger than MAX_SAFE_INTEGER as strings, for
+// clients that read JSON numbers as doubles and would round them. The default,
+// GG_LARGE_NUMBERS=number, always sends numbers. Either way, both are accepted.
+const LARGE_NUMBERS_ENV_VAR: &str = "GG_LARGE_NUMBERS";
+
+// The largest integer a double holds exactly (JavaScript's Number.MAX_SAFE_INTEGER)
+pub const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+static LARGE_NUMBERS_AS_STRINGS: Lazy<bool> = Lazy::new(|| match std::env::var(LARGE_NUMBERS_ENV_VAR).as_deref() {
+    Ok("number") | Err(_) => false,
+    Ok("string") => true,
+    Ok(format) => panic!("unknown {LARGE_NUMBERS_ENV_VAR} {format}, expected number or string"),
+});
+
+// An unsigned integer that's sent as a string when it's too big for a double (see
+// GG_LARGE_NUMBERS). Fields that are plain u64s or usizes can use the functions below instead,
+// with #[serde(with = "goofy_goobers::safe_int")], or safe_int::map for a map of them.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SafeInt<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Debug for SafeInt<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T> From<T> for SafeInt<T> {
+    fn from(value: T) -> Self {
+        SafeInt(value)
+    }
+}
+
+impl<T: Copy + TryInto<u64>> Serialize for SafeInt<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Ok(value) = self.0.try_into() else { unreachable!("integers this wide aren't supported") };
+        serialize_u64(value, *LARGE_NUMBERS_AS_STRINGS, serializer)
+    }
+}
+
+fn serialize_u64<S: Serializer>(value: u64, large_as_string: bool, serializer: S) -> Result<S::Ok, S::Error> {
+    if large_as_string && value > MAX_SAFE_INTEGER {
+        serializer.serialize_str(&value.to_string())
+    } else {
+        serializer.serialize_u64(value)
+    }
+}
+
+impl<'de, T: TryFrom<u64>> Deserialize<'de> for SafeInt<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Int {
+            Number(u64),
+            String(String),
+        }
+
+        let value = match Int::deserialize(deserializer)? {
+            Int::Number(n) => n,
+            Int::String(s) => s.parse().map_err(|_| serde::de::Error::custom(format!("expected an integer, got {s:?}")))?,
+        };
+        T::try_from(value).map(SafeInt).map_err(|_| serde::de::Error::custom(format!("{value} is out of range")))
+    }
+}
+
+pub fn serialize<T: Copy + TryInto<u64>, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    SafeInt(*value).serialize(serializer)
+}
+
+pub fn deserialize<'de, T: TryFrom<u64>, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    SafeInt::deserialize(deserializer).map(|SafeInt(value)| value)
+}
+
+// For maps whose values are integers, with #[serde(with = "goofy_goobers::safe_int::map")]
+pub mod map {
+    use super::*;
+
+    pub fn serialize<K: Serialize + Eq + Hash, T: Copy + TryInto<u64>, S: Serializer>(map: &HashMap<K, T>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(map.iter().map(|(k, v)| (k, SafeInt(*v))))
+    }
+
+    pub fn deserialize<'de, K: Deserialize<'de> + Eq + Hash, T: TryFrom<u64>, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<K, T>, D::Error> {
+        let map: HashMap<K, SafeInt<T>> = HashMap::deserialize(deserializer)?;
+        Ok(map.into_iter().map(|(k, SafeInt(v))| (k, v)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn serialized(value: u64, large_as_string: bool) -> Value {
+        serialize_u64(value, large_as_string, serde_json::value::Serializer).unwrap()
+    }
+
+    #[test]
+    fn only_unsafe_integers_are_sent_as_strings() {
+        assert_eq!(serialized(MAX_SAFE_INTEGER, true), json!(MAX_SAFE_INTEGER));
+        assert_eq!(serialized(MAX_SAFE_INTEGER + 1, true), json!("9007199254740992"));
+        assert_eq!(serialized(u64::MAX, false), json!(u64::MAX));
+    }
+
+    // Numbers and strings are both accepted, whichever way we send them
+    #[test]
+    fn numbers_and_strings_are_both_read() {
+        let read = |json: &str| serde_json::from_str::<SafeInt<u64>>(json).map(|SafeInt(value)| value);
+        assert_eq!(read("42").unwrap(), 42);
+        assert_eq!(read(r#""18446744073709551615""#).unwrap(), u64::MAX);
+        assert!(read(r#""4x2""#).is_err());
+        assert!(read("-1").is_err());
+        assert!(serde_json::from_str::<SafeInt<u8>>("256").is_err());
+
+        let map: HashMap<String, usize> = map::deserialize(&json!({"a": 1, "b": "9007199254740993"})).unwrap();
+        assert_eq!(map, HashMap::from([("a".to_string(), 1), ("b".to_string(), 9007199254740993)]));
+    }
+}
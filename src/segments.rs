@@ -0,0 +1,311 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::log::debug;
+
+// An entry's position in a SegmentedLog: its xid, then the node that wrote it to break ties
+pub type LogId = (usize, String);
+
+// A run of entries that's been written out to disk, one JSON (id, key, value) per line. Entries
+// that arrive late can be spilled after later ones, so segments' id ranges may overlap.
+struct Segment {
+    path: PathBuf,
+    first: LogId,
+    last: LogId,
+}
+
+// A log of entries ordered by LogId, each filed under a key. Entries are kept in memory until
+// there are twice segment_size of them, then the oldest segment_size are written to a file in the
+// spill directory. Without a spill directory everything stays in memory.
+//
+// Once an entry's spilled all that's kept of it is a share of its segment's bookkeeping: the
+// segment's id range, the last xid of each key it holds, and the runs of xids the log has, which
+// only grow with the number of gaps. So queries only read back the segments that can hold what
+// they're after, and an id that's already been spilled is only looked for on disk if its xid is
+// one the log has.
+pub struct SegmentedLog<T> {
+    hot: BTreeMap<LogId, (String, T)>,
+    // The ids of each key's entries in hot
+    hot_keys: HashMap<String, BTreeSet<LogId>>,
+    // Every xid in the log, hot or spilled, as runs of consecutive xids: first xid -> last xid
+    xids: BTreeMap<usize, usize>,
+    last_id: Option<LogId>,
+    segments: Vec<Segment>,
+    // The segments holding each key, with the last xid the key has in each
+    key_segments: HashMap<String, Vec<(usize, usize)>>,
+    spill: Option<(PathBuf, usize)>,
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> SegmentedLog<T> {
+    pub fn new() -> SegmentedLog<T> {
+        SegmentedLog {
+            hot: BTreeMap::new(),
+            hot_keys: HashMap::new(),
+            xids: BTreeMap::new(),
+            last_id: None,
+            segments: Vec::new(),
+            key_segments: HashMap::new(),
+            spill: None,
+        }
+    }
+
+    pub fn with_spill(dir: impl Into<PathBuf>, segment_size: usize) -> io::Result<SegmentedLog<T>> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(SegmentedLog { spill: Some((dir, segment_size)), ..SegmentedLog::new() })
+    }
+
+    // Returns false (and keeps the existing entry) if there's already an entry with this id, and an
+    // error if a segment that might hold it can't be read. A segment that can't be written is
+    // logged and its entries stay in memory, to be spilled with the next.
+    pub fn insert(&mut self, id: LogId, key: String, value: T) -> io::Result<bool> {
+        if self.hot.contains_key(&id) || self.is_spilled(&id)? {
+            return Ok(false)
+        }
+        self.add_xid(id.0);
+        if self.last_id.as_ref().is_none_or(|last| *last < id) {
+            self.last_id = Some(id.clone());
+        }
+        self.hot_keys.entry(key.clone()).or_default().insert(id.clone());
+        self.hot.insert(id, (key, value));
+
+        if let Some((_, segment_size)) = self.spill {
+            if self.hot.len() >= 2 * segment_size {
+                if let Err(e) = self.spill_segment(segment_size) {
+                    debug!("can't spill {segment_size} log entries, keeping them in memory: {e}");
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn last_id(&self) -> Option<&LogId> {
+        self.last_id.as_ref()
+    }
+
+    // The last xid before the first gap in the xids, if there is a gap
+    pub fn first_gap(&self) -> Option<usize> {
+        (self.xids.len() > 1).then(|| *self.xids.values().next().unwrap())
+    }
+
+    // Up to limit of the xids missing between the first and last entries, lowest first
+    pub fn missing_xids(&self, limit: usize) -> Vec<usize> {
+        self.xids.iter().zip(self.xids.keys().skip(1))
+            .flat_map(|((_, last), next)| *last + 1..*next)
+            .take(limit)
+            .collect()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.key_segments.contains_key(key) || self.hot_keys.contains_key(key)
+    }
+
+    // Every entry filed under the key from first_xid on, in order. Only the segments where the key
+    // has an entry that late are read.
+    pub fn for_key(&self, key: &str, first_xid: usize) -> io::Result<Vec<T>> {
+        let mut entries: Vec<(LogId, T)> = Vec::new();
+        for (segment, last_xid) in self.key_segments.get(key).into_iter().flatten() {
+            if *last_xid < first_xid {
+                continue
+            }
+            entries.extend(self.read_segment(*segment)?.into_iter()
+                .filter(|((xid, _), k, _)| k == key && *xid >= first_xid)
+                .map(|(id, _, value)| (id, value)));
+        }
+        let hot_ids = self.hot_keys.get(key).into_iter().flat_map(|ids| ids.range((first_xid, String::new())..));
+        entries.extend(hot_ids.map(|id| (id.clone(), self.hot[id].1.clone())));
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(entries.into_iter().map(|(_, value)| value).collect())
+    }
+
+    // Every entry from first_xid on, in order
+    pub fn range_from(&self, first_xid: usize) -> io::Result<Vec<T>> {
+        let mut entries: Vec<(LogId, T)> = Vec::new();
+        for segment in 0..self.segments.len() {
+            if self.segments[segment].last.0 < first_xid {
+                continue
+            }
+            entries.extend(self.read_segment(segment)?.into_iter()
+                .filter(|((xid, _), _, _)| *xid >= first_xid)
+                .map(|(id, _, value)| (id, value)));
+        }
+        entries.extend(self.hot.range((first_xid, String::new())..).map(|(id, (_, value))| (id.clone(), value.clone())));
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(entries.into_iter().map(|(_, value)| value).collect())
+    }
+
+    // Whether the entry's in a segment. Only segments whose range covers it are read, and none at
+    // all if the log has nothing with its xid.
+    fn is_spilled(&self, id: &LogId) -> io::Result<bool> {
+        if !self.has_xid(id.0) {
+            return Ok(false)
+        }
+        for segment in 0..self.segments.len() {
+            let Segment { first, last, .. } = &self.segments[segment];
+            if first <= id && id <= last && self.read_segment(segment)?.iter().any(|(spilled, _, _)| spilled == id) {
+                return Ok(true)
+            }
+        }
+        Ok(false)
+    }
+
+    fn has_xid(&self, xid: usize) -> bool {
+        self.xids.range(..=xid).next_back().is_some_and(|(_, last)| *last >= xid)
+    }
+
+    fn add_xid(&mut self, xid: usize) {
+        if self.has_xid(xid) {
+            return
+        }
+        let mut first = xid;
+        let mut last = xid;
+        if let Some((before, before_last)) = self.xids.range(..xid).next_back().map(|(a, b)| (*a, *b)) {
+            if before_last + 1 == xid {
+                first = before;
+            }
+        }
+        if let Some(after_last) = self.xids.remove(&(xid + 1)) {
+            last = after_last;
+        }
+        self.xids.insert(first, last);
+    }
+
+    // The segment's written before anything leaves hot, so if that fails the log's as it was
+    fn spill_segment(&mut self, segment_size: usize) -> io::Result<()> {
+        let (dir, _) = self.spill.as_ref().unwrap();
+        let segment = self.segments.len();
+        let path = dir.join(format!("segment-{segment}.jsonl"));
+        let mut file = BufWriter::new(File::create(&path)?);
+        for (id, (key, value)) in self.hot.iter().take(segment_size) {
+            serde_json::to_writer(&mut file, &(id, key, value))?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+
+        let mut spilled = Vec::new();
+        for _ in 0..segment_size {
+            let Some((id, (key, _))) = self.hot.pop_first() else { break };
+            let hot_ids = self.hot_keys.get_mut(&key).unwrap();
+            hot_ids.remove(&id);
+            if hot_ids.is_empty() {
+                self.hot_keys.remove(&key);
+            }
+            let key_segments = self.key_segments.entry(key).or_default();
+            match key_segments.last_mut() {
+                Some((last_segment, last_xid)) if *last_segment == segment => *last_xid = id.0,
+                _ => key_segments.push((segment, id.0)),
+            }
+            spilled.push(id);
+        }
+
+        debug!("spilled {} log entries to {}", spilled.len(), path.display());
+        let first = spilled.first().cloned().unwrap_or_default();
+        let last = spilled.pop().unwrap_or_default();
+        self.segments.push(Segment { path, first, last });
+        Ok(())
+    }
+
+    fn read_segment(&self, segment: usize) -> io::Result<Vec<(LogId, String, T)>> {
+        let file = File::open(&self.segments[segment].path)?;
+        BufReader::new(file).lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Default for SegmentedLog<T> {
+    fn default() -> Self {
+        SegmentedLog::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    // A spill directory of the test's own, emptied first
+    fn spill_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("goofy-goobers-{}-{test}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn spilled_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    // Entries spilled to disk come back from a poll partway through them, together with the ones
+    // still in memory, in order and only for the key asked about
+    #[test]
+    fn polls_from_the_middle_read_back_spilled_entries() {
+        let dir = spill_dir("middle");
+        let mut log = SegmentedLog::with_spill(&dir, 4).unwrap();
+        for xid in 0..20 {
+            let key = if xid % 2 == 0 { "even" } else { "odd" };
+            assert!(log.insert((xid, "n1".to_string()), key.to_string(), xid).unwrap());
+        }
+        assert_eq!(spilled_files(&dir), 4);
+        assert_eq!(log.hot.len(), 4);
+
+        // The first segment holds xids 0 to 3, so a poll from 5 doesn't need it
+        std::fs::remove_file(dir.join("segment-0.jsonl")).unwrap();
+        assert_eq!(log.for_key("even", 5).unwrap(), [6, 8, 10, 12, 14, 16, 18]);
+        assert_eq!(log.for_key("odd", 13).unwrap(), [13, 15, 17, 19]);
+        assert_eq!(log.for_key("odd", 20).unwrap(), Vec::<usize>::new());
+        assert_eq!(log.range_from(10).unwrap(), (10..20).collect::<Vec<_>>());
+        assert!(log.for_key("even", 0).is_err());
+        assert!(log.contains_key("even") && !log.contains_key("other"));
+        assert_eq!(log.last_id(), Some(&(19, "n1".to_string())));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Only the hot entries' ids stay in memory, but a repeat of a spilled entry is still turned
+    // away, and one that arrives late to fill a gap is still taken
+    #[test]
+    fn spilled_ids_are_still_known() {
+        let dir = spill_dir("known");
+        let mut log = SegmentedLog::with_spill(&dir, 2).unwrap();
+        for xid in [0, 1, 2, 4, 5, 6] {
+            assert!(log.insert((xid, "n1".to_string()), "k".to_string(), xid).unwrap());
+        }
+        assert_eq!(log.hot.len(), 2);
+        assert_eq!((log.first_gap(), log.missing_xids(10)), (Some(2), vec![3]));
+
+        assert!(!log.insert((1, "n1".to_string()), "k".to_string(), 100).unwrap());
+        // The same xid from another node is a different entry
+        assert!(log.insert((1, "n2".to_string()), "k".to_string(), 101).unwrap());
+        assert!(log.insert((3, "n1".to_string()), "k".to_string(), 3).unwrap());
+        assert_eq!((log.first_gap(), log.missing_xids(10)), (None, vec![]));
+        assert_eq!(log.for_key("k", 0).unwrap(), [0, 1, 101, 2, 3, 4, 5, 6]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A spill directory that can't be written keeps everything in memory, and one that's gone
+    // when the log's read is an error rather than a panic
+    #[test]
+    fn io_errors_are_returned() {
+        let dir = spill_dir("errors");
+        let mut log = SegmentedLog::with_spill(&dir, 1).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        for xid in 0..3 {
+            assert!(log.insert((xid, "n1".to_string()), "k".to_string(), xid).unwrap());
+        }
+        assert_eq!((log.hot.len(), log.segments.len()), (3, 0));
+        assert_eq!(log.for_key("k", 0).unwrap(), [0, 1, 2]);
+
+        std::fs::create_dir_all(&dir).unwrap();
+        log.insert((3, "n1".to_string()), "k".to_string(), 3).unwrap();
+        assert_eq!(log.segments.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(log.for_key("k", 0).is_err());
+        assert!(log.insert((0, "n1".to_string()), "k".to_string(), 0).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+use crate::log;
+use crate::message::Envelope;
+
+// A node that keeps all of its state in memory and does no I/O, so a Scheduler can run several of
+// them in one process
+pub trait StateMachine<B: Debug> {
+    // Handles one envelope, returning whatever the node sends in response
+    fn step(&mut self, envelope: &Envelope<B>) -> Vec<Envelope<B>>;
+
+    // Called when the scheduler lets time pass, for whatever the node does on a timer (resends,
+    // polls and so on)
+    fn tick(&mut self) -> Vec<Envelope<B>> {
+        vec![]
+    }
+}
+
+// A small xorshift generator, so a schedule (and any workload generated alongside it) depends only
+// on its seed
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // xorshift never leaves 0
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // A number from 0 up to but not including n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+// Runs several StateMachines in one process, picking which in-flight envelope to deliver next at
+// random, so envelopes are reordered freely and, if drop_percent is set, some never arrive.
+// Envelopes addressed to anything that isn't one of the nodes (clients, mostly) are kept in
+// replies instead of being delivered.
+//
+// Everything the scheduler chooses comes from its seed, so running the same nodes with the same
+// seed and the same calls replays the same schedule. Each batch a node sends is put in order of
+// destination first, so a node that iterates over a HashMap doesn't change the schedule; nodes
+// should otherwise be deterministic themselves.
+pub struct Scheduler<B: Debug, N> {
+    nodes: BTreeMap<String, N>,
+    in_flight: Vec<Envelope<B>>,
+    replies: Vec<Envelope<B>>,
+    rng: Rng,
+    seed: u64,
+    drop_percent: u64,
+    delivered: usize,
+    dropped: usize,
+}
+
+impl<B: Clone + Debug, N: StateMachine<B>> Scheduler<B, N> {
+    pub fn new(seed: u64) -> Scheduler<B, N> {
+        Scheduler {
+            nodes: BTreeMap::new(),
+            in_flight: Vec::new(),
+            replies: Vec::new(),
+            rng: Rng::new(seed),
+            seed,
+            drop_percent: 0,
+            delivered: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn add_node(&mut self, id: impl Into<String>, node: N) {
+        self.nodes.insert(id.into(), node);
+    }
+
+    pub fn node(&self, id: &str) -> Option<&N> {
+        self.nodes.get(id)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item=(&String, &N)> {
+        self.nodes.iter()
+    }
+
+    // How many of the envelopes picked from now on are lost instead of delivered
+    pub fn set_drop_percent(&mut self, percent: u64) {
+        assert!(percent <= 100, "can't drop {percent}% of envelopes");
+        self.drop_percent = percent;
+    }
+
+    // Puts an envelope in flight, as if a client had sent it
+    pub fn send(&mut self, envelope: Envelope<B>) {
+        self.route(vec![envelope]);
+    }
+
+    // Delivers (or drops) one in-flight envelope, returning false if there weren't any
+    pub fn step(&mut self) -> bool {
+        if self.in_flight.is_empty() {
+            return false
+        }
+        let envelope = self.in_flight.swap_remove(self.rng.below(self.in_flight.len()));
+        if self.rng.below(100) < self.drop_percent as usize {
+            log::debug_envelope!(&envelope, "sim: dropping {:?}", envelope.message());
+            self.dropped += 1;
+            return true
+        }
+        self.delivered += 1;
+        let outbound = self.nodes.get_mut(envelope.dest.as_str())
+            .unwrap_or_else(|| panic!("no node {} in the simulation", envelope.dest))
+            .step(&envelope);
+        self.route(outbound);
+        true
+    }
+
+    // Ticks every node, in order of id
+    pub fn tick(&mut self) {
+        let outbound: Vec<Envelope<B>> = self.nodes.values_mut().flat_map(|node| node.tick()).collect();
+        self.route(outbound);
+    }
+
+    // Delivers envelopes until none are in flight or max_steps have been taken, returning whether
+    // everything was delivered
+    pub fn run(&mut self, max_steps: usize) -> bool {
+        for _ in 0..max_steps {
+            if !self.step() {
+                return true
+            }
+        }
+        self.in_flight.is_empty()
+    }
+
+    // Everything sent to something other than a node so far
+    pub fn replies(&self) -> &[Envelope<B>] {
+        &self.replies
+    }
+
+    // (delivered, dropped)
+    pub fn counts(&self) -> (usize, usize) {
+        (self.delivered, self.dropped)
+    }
+
+    fn route(&mut self, mut outbound: Vec<Envelope<B>>) {
+        outbound.sort_by(|a, b| a.dest.cmp(&b.dest));
+        for envelope in outbound {
+            if self.nodes.contains_key(envelope.dest.as_str()) {
+                self.in_flight.push(envelope);
+            } else {
+                self.replies.push(envelope);
+            }
+        }
+    }
+}
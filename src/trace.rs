@@ -0,0 +1,114 @@
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use crate::message::Envelope;
+
+// Set GG_TRACE=path.jsonl to record every envelope the runtime reads or writes
+const TRACE_ENV_VAR: &str = "GG_TRACE";
+
+static TRACE_FILE: Lazy<Option<Mutex<File>>> = Lazy::new(|| {
+    std::env::var(TRACE_ENV_VAR).ok().map(|path| {
+        Mutex::new(OpenOptions::new().create(true).append(true).open(&path)
+            .unwrap_or_else(|e| panic!("can't open trace file {path}: {e}")))
+    })
+});
+
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+// One line of a trace file. The timestamp is monotonic, counted from the first traced envelope.
+#[derive(Deserialize, Debug)]
+pub struct TraceRecord<B: Debug> {
+    pub direction: Direction,
+    pub elapsed_nanos: u64,
+    pub envelope: Envelope<B>,
+}
+
+#[derive(Serialize)]
+struct TraceRecordRef<'a, B: Debug> {
+    direction: Direction,
+    elapsed_nanos: u64,
+    envelope: &'a Envelope<B>,
+}
+
+pub fn record<B: Debug + Serialize>(direction: Direction, envelope: &Envelope<B>) {
+    if let Some(file) = TRACE_FILE.as_ref() {
+        write_record(&mut *file.lock().unwrap(), direction, envelope).unwrap();
+    }
+}
+
+// Writes one line of a trace file
+fn write_record<B: Debug + Serialize>(out: &mut impl Write, direction: Direction, envelope: &Envelope<B>) -> std::io::Result<()> {
+    let record = TraceRecordRef {
+        direction,
+        elapsed_nanos: START.elapsed().as_nanos() as u64,
+        envelope,
+    };
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+    out.write_all(&line)?;
+    out.flush()
+}
+
+// Reads back every record in a trace file
+pub fn read_trace<B: Debug + DeserializeOwned>(path: impl AsRef<Path>) -> std::io::Result<Vec<TraceRecord<B>>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        records.push(serde_json::from_str(&line?)?);
+    }
+    Ok(records)
+}
+
+// The envelopes a node received, in order, for feeding back into it to reproduce a run
+pub fn replay<B: Debug + DeserializeOwned>(path: impl AsRef<Path>) -> std::io::Result<Vec<Envelope<B>>> {
+    Ok(read_trace(path)?.into_iter()
+        .filter(|record| record.direction == Direction::Inbound)
+        .map(|record| record.envelope)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::Common;
+
+    use super::*;
+
+    // A trace written a record at a time reads back in order, and replaying it gives just the
+    // envelopes the node received
+    #[test]
+    fn traces_read_back_and_replay() {
+        let path = std::env::temp_dir().join(format!("goofy-goobers-{}-trace.jsonl", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        let request = Envelope::new("c1", "n1", None, Common::TopologyOk);
+        let reply = request.try_reply(Common::TopologyOk).unwrap();
+        let repeat = Envelope::new("c1", "n1", None, Common::TopologyOk);
+        for (direction, envelope) in [(Direction::Inbound, &request), (Direction::Outbound, &reply), (Direction::Inbound, &repeat)] {
+            write_record(&mut file, direction, envelope).unwrap();
+        }
+
+        let records: Vec<TraceRecord<Common>> = read_trace(&path).unwrap();
+        assert_eq!(records.iter().map(|record| record.direction).collect::<Vec<_>>(), [Direction::Inbound, Direction::Outbound, Direction::Inbound]);
+        assert!(records.windows(2).all(|pair| pair[0].elapsed_nanos <= pair[1].elapsed_nanos));
+        assert_eq!(records[1].envelope.in_reply_to(), request.msg_id());
+
+        let replayed: Vec<Envelope<Common>> = replay(&path).unwrap();
+        assert_eq!(replayed.iter().map(|envelope| envelope.msg_id()).collect::<Vec<_>>(), [request.msg_id(), repeat.msg_id()]);
+        assert!(replayed.iter().all(|envelope| envelope.src == "c1" && matches!(envelope.message(), Common::TopologyOk)));
+        std::fs::remove_file(path).unwrap();
+    }
+}
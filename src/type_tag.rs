@@ -0,0 +1,122 @@
+// The `type` of a message, as its serde tag would write it, without serializing the rest of it.
+// Every workload's message enum uses #[serde(tag = "type", rename_all = "snake_case")], so the tag
+// is the variant's name in snake_case. impl_type_tag! works that out from the variant names at
+// compile time, so getting a message's tag is just a match:
+//
+//     impl_type_tag!(EchoMessage { Echo, EchoOk, Error });
+//
+// Wrapper enums that are #[serde(untagged)] list their newtype variants in parentheses instead, and
+// look through to the message inside:
+//
+//     impl_type_tag!(Message(Common, Echo));
+pub trait TypeTag {
+    fn type_tag(&self) -> &'static str;
+}
+
+#[macro_export]
+macro_rules! impl_type_tag {
+    ($message:ident { $($variant:ident),* $(,)? }) => {
+        impl $crate::type_tag::TypeTag for $message {
+            fn type_tag(&self) -> &'static str {
+                match self {
+                    $(Self::$variant { .. } => {
+                        const TAG: [u8; $crate::type_tag::snake_case_len(stringify!($variant))] = $crate::type_tag::snake_case(stringify!($variant));
+                        const TAG_STR: &str = match std::str::from_utf8(&TAG) {
+                            Ok(tag) => tag,
+                            Err(_) => panic!("variant names are ASCII"),
+                        };
+                        TAG_STR
+                    })*
+                }
+            }
+        }
+    };
+    ($message:ident ( $($variant:ident),* $(,)? )) => {
+        impl $crate::type_tag::TypeTag for $message {
+            fn type_tag(&self) -> &'static str {
+                match self {
+                    $(Self::$variant(message) => $crate::type_tag::TypeTag::type_tag(message),)*
+                }
+            }
+        }
+    };
+}
+
+// The length of a variant name in snake_case, for sizing the array snake_case fills
+pub const fn snake_case_len(name: &str) -> usize {
+    let name = name.as_bytes();
+    let mut len = name.len();
+    let mut i = 1;
+    while i < name.len() {
+        if name[i].is_ascii_uppercase() {
+            len += 1;
+        }
+        i += 1;
+    }
+    len
+}
+
+// A variant name in snake_case, the way serde's rename_all = "snake_case" writes it: an underscore
+// before every upper case letter but the first, then everything in lower case
+pub const fn snake_case<const N: usize>(name: &str) -> [u8; N] {
+    let name = name.as_bytes();
+    let mut out = [0; N];
+    let mut i = 0;
+    let mut j = 0;
+    while i < name.len() {
+        if i > 0 && name[i].is_ascii_uppercase() {
+            out[j] = b'_';
+            j += 1;
+        }
+        out[j] = name[i].to_ascii_lowercase();
+        i += 1;
+        j += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use super::TypeTag;
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "snake_case", tag = "type")]
+    enum Tagged {
+        Read { key: u64 },
+        ReadOk(Inner),
+        CasOk,
+        #[allow(clippy::upper_case_acronyms)]
+        ABC,
+    }
+
+    #[derive(Serialize)]
+    struct Inner {
+        value: u64,
+    }
+
+    crate::impl_type_tag!(Tagged { Read, ReadOk, CasOk, ABC });
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum Wrapper {
+        Tagged(Tagged),
+    }
+
+    crate::impl_type_tag!(Wrapper(Tagged));
+
+    fn serialized_tag(message: &impl Serialize) -> String {
+        serde_json::to_value(message).unwrap()["type"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn tags_match_serde() {
+        let messages = [Tagged::Read { key: 1 }, Tagged::ReadOk(Inner { value: 2 }), Tagged::CasOk, Tagged::ABC];
+        for message in messages {
+            assert_eq!(message.type_tag(), serialized_tag(&message));
+            let wrapped = Wrapper::Tagged(message);
+            assert_eq!(wrapped.type_tag(), serialized_tag(&wrapped));
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{self, Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::kv::{KvMessage, MemoryKv, LIN_KV, LIN_TSO, LWW_KV, SEQ_KV};
+use crate::log;
+
+// Set GG_VALIDATE=1 to have a binary smoke-test itself instead of acting as a node: it runs itself
+// again as a child process, plays a canned script of requests into the child's stdin, and checks
+// that each reply that comes out of its stdout is well formed and of the expected type, answering
+// any kv or timestamp requests the child makes along the way. It exits nonzero if anything's wrong,
+// so serialization regressions turn up without a Maelstrom run.
+const VALIDATE_ENV_VAR: &str = "GG_VALIDATE";
+
+// How long to wait for each reply, and for the child to exit once its stdin is closed
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SERVICES: [&str; 4] = [SEQ_KV, LIN_KV, LWW_KV, LIN_TSO];
+
+// One request for a script, and the type of reply it should get
+pub struct Step {
+    pub src: &'static str,
+    pub dest: &'static str,
+    // The request's body as JSON, without a msg_id
+    pub body: &'static str,
+    pub reply: &'static str,
+}
+
+impl Step {
+    // A request from a client to the node under test, n1
+    pub const fn client(body: &'static str, reply: &'static str) -> Step {
+        Step { src: "c1", dest: "n1", body, reply }
+    }
+}
+
+// The init every node script starts with, for a single node cluster
+pub const INIT: Step = Step::client(r#"{"type": "init", "node_id": "n1", "node_ids": ["n1"]}"#, "init_ok");
+
+// Runs the script and exits if GG_VALIDATE is set; otherwise does nothing. Call it first thing in main.
+pub fn run_if_requested(script: &[Step]) {
+    match std::env::var(VALIDATE_ENV_VAR).as_deref() {
+        Ok("1") => {}
+        Ok("0") | Err(_) => return,
+        Ok(value) => panic!("{VALIDATE_ENV_VAR} must be 0 or 1, got {value}"),
+    }
+    match validate(script) {
+        Ok(()) => {
+            log::debug!("validate: all {} steps ok", script.len());
+            process::exit(0)
+        }
+        Err(problem) => {
+            log::debug!("validate: {problem}");
+            process::exit(1)
+        }
+    }
+}
+
+fn validate(script: &[Step]) -> Result<(), String> {
+    let mut child = Command::new(std::env::current_exe().map_err(|e| format!("can't find our own binary: {e}"))?)
+        .env_remove(VALIDATE_ENV_VAR)
+        // Replies are checked as JSON
+        .env_remove("GG_CODEC")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("can't start the node: {e}"))?;
+
+    let stdout = child.stdout.take().unwrap();
+    let (line_sender, lines) = channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else { break };
+            if line_sender.send(line).is_err() { break }
+        }
+    });
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut services = Services::default();
+    let result = script.iter().enumerate().try_for_each(|(i, step)| run_step(i as u64 + 1, step, &mut stdin, &lines, &mut services));
+    drop(stdin);
+    let exited = wait_for_exit(&mut child);
+    result?;
+    exited
+}
+
+fn run_step(msg_id: u64, step: &Step, stdin: &mut ChildStdin, lines: &Receiver<String>, services: &mut Services) -> Result<(), String> {
+    let mut body: Value = serde_json::from_str(step.body).map_err(|e| format!("bad step {}: {e}", step.body))?;
+    body["msg_id"] = json!(msg_id);
+    send(stdin, &json!({"src": step.src, "dest": step.dest, "body": body}))?;
+
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+    loop {
+        let line = match lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => return Err(format!("no reply to {} within {REPLY_TIMEOUT:?}", step.body)),
+            Err(RecvTimeoutError::Disconnected) => return Err(format!("the node exited before replying to {}", step.body)),
+        };
+        let envelope: Value = serde_json::from_str(&line).map_err(|e| format!("bad JSON from the node ({e}): {line}"))?;
+        let (Some(src), Some(dest), Some(reply_type)) = (envelope["src"].as_str(), envelope["dest"].as_str(), envelope["body"]["type"].as_str()) else {
+            return Err(format!("envelope without a src, dest or type: {line}"))
+        };
+
+        if SERVICES.contains(&dest) {
+            let reply = services.handle(&envelope).ok_or_else(|| format!("unexpected request to {dest}: {line}"))?;
+            send(stdin, &reply)?;
+            continue
+        }
+        if envelope["body"]["in_reply_to"].as_u64() != Some(msg_id) {
+            // Something else the node sends on its own, like a resend
+            continue
+        }
+
+        if src != step.dest || dest != step.src {
+            return Err(format!("reply to {} went from {src} to {dest}: {line}", step.body))
+        }
+        if envelope["body"]["msg_id"].as_u64().is_none() {
+            return Err(format!("reply to {} has no msg_id: {line}", step.body))
+        }
+        if reply_type != step.reply {
+            return Err(format!("expected {} in reply to {}, got {line}", step.reply, step.body))
+        }
+        log::debug!("validate: {} -> {reply_type}", step.body);
+        return Ok(())
+    }
+}
+
+fn send(stdin: &mut ChildStdin, envelope: &Value) -> Result<(), String> {
+    writeln!(stdin, "{envelope}").and_then(|_| stdin.flush()).map_err(|e| format!("can't write to the node: {e}"))
+}
+
+fn wait_for_exit(child: &mut Child) -> Result<(), String> {
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+    while Instant::now() < deadline {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return Ok(()),
+            Ok(Some(status)) => return Err(format!("the node exited with {status}")),
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => return Err(format!("can't wait for the node: {e}")),
+        }
+    }
+    let _ = child.kill();
+    Err(format!("the node didn't exit within {REPLY_TIMEOUT:?} of its stdin closing"))
+}
+
+// Enough of Maelstrom's kv and timestamp services for a single node to run against. Each kv
+// service is a MemoryKv of its own.
+#[derive(Default)]
+struct Services {
+    stores: HashMap<String, MemoryKv>,
+    ts: u64,
+    next_msg_id: u64,
+}
+
+impl Services {
+    fn handle(&mut self, request: &Value) -> Option<Value> {
+        let service = request["dest"].as_str()?;
+        let body = &request["body"];
+        let mut reply = if body["type"] == "ts" {
+            self.ts += 1;
+            json!({"type": "ts_ok", "ts": self.ts})
+        } else {
+            let request: KvMessage = serde_json::from_value(body.clone()).ok()?;
+            if !matches!(request, KvMessage::Read { .. } | KvMessage::Write { .. } | KvMessage::Cas { .. }) {
+                return None
+            }
+            let reply = self.stores.entry(service.to_string()).or_default().handle(&request);
+            serde_json::to_value(reply).unwrap()
+        };
+
+        reply["msg_id"] = json!(self.next_msg_id);
+        self.next_msg_id += 1;
+        reply["in_reply_to"] = body["msg_id"].clone();
+        Some(json!({"src": service, "dest": request["src"], "body": reply}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ErrorCode;
+
+    use super::*;
+
+    fn request(dest: &str, msg_id: u64, mut body: Value) -> Value {
+        body["msg_id"] = json!(msg_id);
+        json!({"src": "n1", "dest": dest, "body": body})
+    }
+
+    // The stand-in services answer the way Maelstrom's do, each with its own keys
+    #[test]
+    fn services_answer_like_maelstrom() {
+        let mut services = Services::default();
+        let mut call = |dest: &str, msg_id: u64, body: Value| {
+            let reply = services.handle(&request(dest, msg_id, body)).unwrap();
+            assert_eq!((reply["src"].as_str(), reply["dest"].as_str()), (Some(dest), Some("n1")));
+            assert_eq!(reply["body"]["in_reply_to"], json!(msg_id));
+            let mut body = reply["body"].clone();
+            body.as_object_mut().unwrap().retain(|field, _| field == "type" || field == "value" || field == "code" || field == "ts");
+            body
+        };
+
+        assert_eq!(call(SEQ_KV, 1, json!({"type": "read", "key": "k"})), json!({"type": "error", "code": ErrorCode::KeyDoesNotExist as u64}));
+        assert_eq!(call(SEQ_KV, 2, json!({"type": "cas", "key": "k", "from": 0, "to": 4, "create_if_not_exists": true})), json!({"type": "cas_ok"}));
+        assert_eq!(call(SEQ_KV, 3, json!({"type": "cas", "key": "k", "from": 0, "to": 5})), json!({"type": "error", "code": ErrorCode::PreconditionFailed as u64}));
+        assert_eq!(call(SEQ_KV, 4, json!({"type": "read", "key": "k"})), json!({"type": "read_ok", "value": 4}));
+        assert_eq!(call(LIN_KV, 5, json!({"type": "read", "key": "k"})), json!({"type": "error", "code": ErrorCode::KeyDoesNotExist as u64}));
+        assert_eq!(call(LIN_KV, 6, json!({"type": "write", "key": "k", "value": 12})), json!({"type": "write_ok"}));
+        assert_eq!(call(LIN_KV, 7, json!({"type": "read", "key": "k"})), json!({"type": "read_ok", "value": 12}));
+        assert_eq!(call(LIN_TSO, 8, json!({"type": "ts"})), json!({"type": "ts_ok", "ts": 1}));
+        assert_eq!(call(LIN_TSO, 9, json!({"type": "ts"})), json!({"type": "ts_ok", "ts": 2}));
+        assert!(services.handle(&request(SEQ_KV, 10, json!({"type": "echo"}))).is_none());
+        assert!(services.handle(&request(SEQ_KV, 11, json!({"type": "read_ok", "value": 1}))).is_none());
+    }
+}
@@ -0,0 +1,96 @@
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Longer than any binary should take to shut down once its input ends
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const INIT: &str = r#"{"src": "c0", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}"#;
+
+// Pipes init and then the given messages into a binary, closes its stdin and waits for it to exit,
+// returning what it wrote to stdout. Fails if it doesn't exit 0 within EXIT_TIMEOUT.
+fn run_to_exit(binary: &str, messages: &[&str]) -> String {
+    let mut child = Command::new(binary)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| panic!("can't start {binary}: {e}"));
+
+    let mut stdin = child.stdin.take().unwrap();
+    for line in [INIT].iter().chain(messages) {
+        writeln!(stdin, "{line}").unwrap();
+    }
+    drop(stdin);
+
+    // Read stdout as it's written, so a full pipe can't keep the binary from exiting
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).unwrap();
+        output
+    });
+
+    let deadline = Instant::now() + EXIT_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status
+        }
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            panic!("{binary} still running {EXIT_TIMEOUT:?} after stdin closed");
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(status.success(), "{binary} exited with {status}");
+
+    let output = reader.join().unwrap();
+    assert!(output.contains(r#""type":"init_ok""#), "{binary} didn't answer init: {output}");
+    output
+}
+
+#[test]
+fn echo_exits_cleanly() {
+    let output = run_to_exit(env!("CARGO_BIN_EXE_echo"), &[
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 2, "echo": "hello"}}"#,
+    ]);
+    assert!(output.contains(r#""echo":"hello""#), "{output}");
+}
+
+#[test]
+fn broadcast_exits_cleanly() {
+    let output = run_to_exit(env!("CARGO_BIN_EXE_broadcast"), &[
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "topology", "msg_id": 2, "topology": {"n1": []}}}"#,
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "broadcast", "msg_id": 3, "message": 7}}"#,
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "read", "msg_id": 4}}"#,
+    ]);
+    assert!(output.contains(r#""type":"read_ok""#), "{output}");
+}
+
+// The counter's adds wait on seq-kv, which never answers here, so it has to give up on them
+#[test]
+fn counter_exits_cleanly() {
+    run_to_exit(env!("CARGO_BIN_EXE_counter"), &[
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "add", "msg_id": 2, "delta": 3}}"#,
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "read", "msg_id": 3}}"#,
+    ]);
+}
+
+// The send needs an xid from seq-kv, which never answers here, so it's turned away as the input ends
+#[test]
+fn kafka_exits_cleanly() {
+    let output = run_to_exit(env!("CARGO_BIN_EXE_kafka"), &[
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "send", "msg_id": 2, "key": "k1", "msg": 5}}"#,
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "poll", "msg_id": 3, "offsets": {"k1": 0}}}"#,
+    ]);
+    assert!(output.contains(r#""in_reply_to":2,"type":"error","code":11"#), "{output}");
+}
+
+#[test]
+fn txn_exits_cleanly() {
+    let output = run_to_exit(env!("CARGO_BIN_EXE_txn"), &[
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "txn", "msg_id": 2, "txn": [["w", 1, 2], ["r", 1, null]]}}"#,
+    ]);
+    assert!(output.contains(r#""type":"txn_ok""#), "{output}");
+}